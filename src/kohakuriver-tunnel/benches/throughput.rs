@@ -0,0 +1,345 @@
+//! Data-plane throughput: frame encoding and decoding, bulk transfers
+//! between an in-process runner and a local TCP service, many small runner
+//! frames into one local connection, and bursts of small frames over a
+//! loopback WebSocket.
+//!
+//! Run with `cargo bench --bench throughput`. Besides criterion's timings,
+//! the loopback benchmarks print how many heap allocations a transfer makes
+//! per MiB, counted by a wrapping global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::future::BoxFuture;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::MaybeTlsStream;
+
+use kohakuriver_tunnel::connection::ConnectionManager;
+use kohakuriver_tunnel::dial::WsStream;
+use kohakuriver_tunnel::protocol::{self, Header, MsgType, Proto};
+use kohakuriver_tunnel::transport::{self, MemoryPeer, TransportSender, TransportSink, WsSink};
+use kohakuriver_tunnel::TunnelConfig;
+
+/// Counts every allocation, passing it on to the system allocator
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const FRAME_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+/// Bytes pushed through the tunnel per loopback iteration
+const TRANSFER_BYTES: usize = 8 << 20;
+
+/// Runner DATA frame size, the largest a local read produces
+const CHUNK: usize = 64 * 1024;
+
+/// Runner DATA frame sizes for chatty protocols, whose queued frames the
+/// connection's write task joins into one write
+const SMALL_FRAME_SIZES: [usize; 4] = [16, 128, 1024, 16 * 1024];
+
+/// Bytes pushed through the tunnel per small-frame iteration
+const SMALL_FRAME_BYTES: usize = 1 << 20;
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame");
+    for size in FRAME_SIZES {
+        let data = vec![0x5a; size];
+        let frame = protocol::build_data(Proto::Tcp, 7, &data);
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_with_input(BenchmarkId::new("build_data", size), &data, |b, data| {
+            b.iter(|| protocol::build_data(Proto::Tcp, black_box(7), black_box(data)))
+        });
+        group.bench_with_input(BenchmarkId::new("parse", size), &frame, |b, frame| {
+            b.iter(|| {
+                let header = Header::parse(black_box(frame)).unwrap();
+                (header, protocol::get_payload(frame).len())
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A connection from the runner to a local service, ready to carry data
+struct Loopback {
+    manager: ConnectionManager,
+    runner: MemoryPeer,
+    local: TcpStream,
+}
+
+impl Loopback {
+    async fn open() -> Self {
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let ((sink, _), mut runner) = transport::memory(1024);
+        let mut manager = ConnectionManager::new(
+            Arc::new(Mutex::new(sink)),
+            Arc::new(TunnelConfig::default()),
+        );
+        manager.handle_connect(1, Proto::Tcp, port).await;
+        let (local, _) = service.accept().await.unwrap();
+        runner.next_frame().await.unwrap(); // CONNECTED
+        Self {
+            manager,
+            runner,
+            local,
+        }
+    }
+
+    /// Send `total` bytes from the runner in `frame_size` frames and read
+    /// them at the service
+    async fn runner_to_local(&mut self, frame_size: usize, total: usize) {
+        let frame = vec![0x5a; frame_size];
+        let local = &mut self.local;
+        let reader = async {
+            let mut buf = vec![0u8; CHUNK];
+            let mut read = 0;
+            while read < total {
+                read += local.read(&mut buf).await.unwrap();
+            }
+        };
+        let manager = &mut self.manager;
+        let writer = async {
+            for _ in 0..total / frame_size {
+                manager.handle_data(1, Proto::Tcp, &frame).await;
+            }
+        };
+        tokio::join!(reader, writer);
+    }
+
+    /// Write `TRANSFER_BYTES` at the service and collect them as DATA
+    /// frames at the runner
+    async fn local_to_runner(&mut self) {
+        let chunk = vec![0x5a; CHUNK];
+        let local = &mut self.local;
+        let writer = async {
+            for _ in 0..TRANSFER_BYTES / CHUNK {
+                local.write_all(&chunk).await.unwrap();
+            }
+        };
+        let runner = &mut self.runner;
+        let reader = async {
+            let mut received = 0;
+            while received < TRANSFER_BYTES {
+                let frame = runner.next_frame().await.unwrap();
+                let header = Header::parse(&frame).unwrap();
+                if header.msg_type == MsgType::Data {
+                    received += protocol::get_payload(&frame).len();
+                }
+            }
+        };
+        tokio::join!(writer, reader);
+    }
+
+    async fn transfer(&mut self, direction: &str) {
+        match direction {
+            "runner_to_local" => self.runner_to_local(CHUNK, TRANSFER_BYTES).await,
+            _ => self.local_to_runner().await,
+        }
+    }
+}
+
+fn loopback(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("loopback");
+    group.throughput(Throughput::Bytes(TRANSFER_BYTES as u64));
+    group.sample_size(20);
+    for direction in ["runner_to_local", "local_to_runner"] {
+        runtime.block_on(async {
+            let mut loopback = Loopback::open().await;
+            let before = ALLOCATIONS.load(Ordering::Relaxed);
+            loopback.transfer(direction).await;
+            let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+            println!(
+                "loopback/{}: {:.0} allocations per MiB",
+                direction,
+                allocations as f64 / (TRANSFER_BYTES >> 20) as f64
+            );
+        });
+        group.bench_function(direction, |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut loopback = Loopback::open().await;
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        loopback.transfer(direction).await;
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Many small runner DATA frames into one local connection
+fn tcp_write(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("tcp_write");
+    group.throughput(Throughput::Bytes(SMALL_FRAME_BYTES as u64));
+    group.sample_size(20);
+    for frame_size in SMALL_FRAME_SIZES {
+        let id = BenchmarkId::from_parameter(frame_size);
+        group.bench_with_input(id, &frame_size, |b, &frame_size| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut loopback = Loopback::open().await;
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        loopback
+                            .runner_to_local(frame_size, SMALL_FRAME_BYTES)
+                            .await;
+                    }
+                    start.elapsed()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Frames per WebSocket egress iteration
+const BURST_FRAMES: usize = 4096;
+
+/// Connection tasks sending each burst
+const BURST_SENDERS: [usize; 2] = [1, 8];
+
+/// Payload of each burst frame, about an interactive keystroke or RPC reply
+const BURST_FRAME_SIZE: usize = 64;
+
+/// A client WebSocket to a loopback server that counts what arrives
+async fn ws_pair() -> (WsStream, UnboundedReceiver<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (received_tx, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut count = 0;
+        while let Some(Ok(msg)) = ws.next().await {
+            if msg.is_binary() {
+                count += 1;
+                if count % BURST_FRAMES == 0 {
+                    let _ = received_tx.send(count);
+                }
+            }
+        }
+    });
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (client, _) =
+        tokio_tungstenite::client_async(format!("ws://{}/", addr), MaybeTlsStream::Plain(stream))
+            .await
+            .unwrap();
+    (client, received)
+}
+
+/// The WebSocket egress before the writer task: `SinkExt::send` flushes
+/// every message
+struct SendEach(SplitSink<WsStream, Message>);
+
+impl TransportSink for SendEach {
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, kohakuriver_tunnel::Result<()>> {
+        Box::pin(async move { Ok(self.0.send(Message::Binary(frame.to_vec())).await?) })
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, kohakuriver_tunnel::Result<()>> {
+        Box::pin(async move { Ok(self.0.send(Message::Ping(payload.to_vec())).await?) })
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, kohakuriver_tunnel::Result<()>> {
+        Box::pin(async move { Ok(self.0.send(Message::Pong(payload.to_vec())).await?) })
+    }
+}
+
+/// Send `BURST_FRAMES` small frames from `senders` connection tasks sharing
+/// the transport, returning once the server has them all
+async fn burst(
+    transport: &TransportSender,
+    senders: usize,
+    received: &mut UnboundedReceiver<usize>,
+) {
+    let frame = Bytes::from(vec![0x5a; BURST_FRAME_SIZE]);
+    let tasks: Vec<_> = (0..senders)
+        .map(|_| {
+            let transport = transport.clone();
+            let frame = frame.clone();
+            tokio::spawn(async move {
+                for _ in 0..BURST_FRAMES / senders {
+                    transport.lock().await.send(frame.clone()).await.unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    received.recv().await.unwrap();
+}
+
+/// Bursts of small frames from one or several connections: flushing every
+/// message, against feeding them and flushing from [`WsSink`]'s flusher
+fn ws_egress(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("ws_egress");
+    group.throughput(Throughput::Elements(BURST_FRAMES as u64));
+    group.sample_size(20);
+    for senders in BURST_SENDERS {
+        for name in ["send_each", "feed_flush"] {
+            let id = BenchmarkId::new(name, format!("{}_senders", senders));
+            group.bench_function(id, |b| {
+                b.iter_custom(|iters| {
+                    runtime.block_on(async {
+                        let (client, mut received) = ws_pair().await;
+                        let (sink, _stream) = client.split();
+                        let sink: Box<dyn TransportSink> = match name {
+                            "send_each" => Box::new(SendEach(sink)),
+                            _ => Box::new(WsSink::spawn(sink)),
+                        };
+                        let transport: TransportSender = Arc::new(Mutex::new(sink));
+                        let start = Instant::now();
+                        for _ in 0..iters {
+                            burst(&transport, senders, &mut received).await;
+                        }
+                        start.elapsed()
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frames, loopback, tcp_write, ws_egress);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+//! Fuzz the message loop's dispatch of runner frames.
+//!
+//! The input is split into length-prefixed chunks so one run covers a
+//! sequence of frames, mirroring a stream of WebSocket messages. Each chunk
+//! goes through `TunnelClient::handle_message` into a `ConnectionManager`
+//! whose transport discards everything it is given. Only port 0 is allowed,
+//! so CONNECTs exercise the dial path without reaching a real service.
+
+#![no_main]
+
+use std::sync::{Arc, OnceLock};
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use kohakuriver_tunnel::config::PortSet;
+use kohakuriver_tunnel::connection::ConnectionManager;
+use kohakuriver_tunnel::error::Result;
+use kohakuriver_tunnel::transport::{TransportSender, TransportSink};
+use kohakuriver_tunnel::tunnel::{TunnelClient, TunnelConfig};
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+/// Transport that accepts and drops every frame
+struct NullSink;
+
+impl TransportSink for NullSink {
+    fn send(&mut self, _frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn ping(&mut self, _payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn pong(&mut self, _payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    runtime().block_on(async {
+        let config = TunnelConfig {
+            allowed_ports: Some("0".parse::<PortSet>().unwrap()),
+            ..Default::default()
+        };
+        let client = TunnelClient::new(config.clone());
+        let transport: TransportSender = Arc::new(Mutex::new(Box::new(NullSink)));
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        let mut rest = data;
+        while let Some((&len, tail)) = rest.split_first() {
+            let len = (len as usize).min(tail.len());
+            let (chunk, tail) = tail.split_at(len);
+            rest = tail;
+
+            // Decode errors end a real session; here they just skip the frame
+            let _ = client.handle_message(&mut manager, chunk).await;
+        }
+        manager.shutdown().await;
+    });
+});
@@ -0,0 +1,22 @@
+//! Fuzz `Header::parse` and `get_payload` with arbitrary runner input.
+
+#![no_main]
+
+use bytes::BytesMut;
+use kohakuriver_tunnel::protocol::{get_payload, Header, HEADER_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let payload = get_payload(data);
+    assert!(payload.len() <= data.len());
+
+    if let Ok(header) = Header::parse(data) {
+        assert!(data.len() >= HEADER_SIZE);
+        assert_eq!(payload.len(), data.len() - HEADER_SIZE);
+
+        // Re-encoding a parsed header reproduces the input bytes
+        let mut buf = BytesMut::new();
+        header.write_to(&mut buf);
+        assert_eq!(&buf[..], &data[..HEADER_SIZE]);
+    }
+});
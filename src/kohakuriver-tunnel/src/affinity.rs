@@ -0,0 +1,117 @@
+//! Stable local UDP ports across reconnects (`--udp-port-affinity`).
+//!
+//! Each UDP connection binds its own socket, so a local service sees one
+//! source port per client. Services that key sessions on that port (game
+//! servers, some VPNs, QUIC without migration) lose the session when the
+//! tunnel reconnects and the runner's CONNECT gets a fresh ephemeral port.
+//! With affinity the port is remembered per client ID and target, and the next
+//! connection for them binds it again with `SO_REUSEADDR` (and `SO_REUSEPORT`
+//! on Unix), so a socket from the previous session that hasn't closed yet
+//! doesn't block the bind.
+//!
+//! Ports are remembered in memory only, so a restart of the tunnel starts
+//! afresh. If the port has meanwhile been taken by a socket without the reuse
+//! options, the connection falls back to a fresh port.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+/// Most remembered ports; the oldest are forgotten first
+pub const MAX_REMEMBERED: usize = 4096;
+
+/// Local ports last used per (client ID, target)
+#[derive(Debug, Default)]
+pub struct UdpAffinity {
+    state: Mutex<AffinityState>,
+}
+
+#[derive(Debug, Default)]
+struct AffinityState {
+    ports: HashMap<(u32, SocketAddr), u16>,
+    /// Keys in the order they were first remembered
+    order: VecDeque<(u32, SocketAddr)>,
+}
+
+impl UdpAffinity {
+    /// The local port last used for `client_id` to `target`
+    pub fn port(&self, client_id: u32, target: SocketAddr) -> Option<u16> {
+        self.state
+            .lock()
+            .unwrap()
+            .ports
+            .get(&(client_id, target))
+            .copied()
+    }
+
+    /// Remember `port` as the local port for `client_id` to `target`
+    pub fn remember(&self, client_id: u32, target: SocketAddr, port: u16) {
+        let mut state = self.state.lock().unwrap();
+        let key = (client_id, target);
+        if state.ports.insert(key, port).is_none() {
+            state.order.push_back(key);
+            if state.order.len() > MAX_REMEMBERED {
+                if let Some(oldest) = state.order.pop_front() {
+                    state.ports.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+/// Bind a UDP socket to `addr` that later binds of the same address with
+/// [`bind_reusable`] can share
+pub fn bind_reusable(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remember_per_client_and_target() {
+        let affinity = UdpAffinity::default();
+        let dns: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let ntp: SocketAddr = "127.0.0.1:123".parse().unwrap();
+        affinity.remember(1, dns, 40000);
+        affinity.remember(1, ntp, 40001);
+        assert_eq!(affinity.port(1, dns), Some(40000));
+        assert_eq!(affinity.port(1, ntp), Some(40001));
+        assert_eq!(affinity.port(2, dns), None);
+
+        affinity.remember(1, dns, 40002);
+        assert_eq!(affinity.port(1, dns), Some(40002));
+    }
+
+    #[test]
+    fn test_oldest_forgotten_first() {
+        let affinity = UdpAffinity::default();
+        let target: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        for client_id in 0..=MAX_REMEMBERED as u32 {
+            affinity.remember(client_id, target, 40000);
+        }
+        assert_eq!(affinity.port(0, target), None);
+        assert_eq!(affinity.port(1, target), Some(40000));
+        assert_eq!(affinity.state.lock().unwrap().ports.len(), MAX_REMEMBERED);
+    }
+
+    #[tokio::test]
+    async fn test_bind_reusable_shares_port() {
+        let first = bind_reusable("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        // The earlier socket is still open, as after a fast reconnect
+        let second = bind_reusable(addr).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+}
@@ -0,0 +1,339 @@
+//! Connection audit log.
+//!
+//! Every forwarded connection logs one line when it opens and one when it
+//! closes, at info level under the `audit` target, e.g.
+//!
+//! ```text
+//! event="open" client_id=7 proto=TCP port=8080 local_port=80
+//! event="close" client_id=7 proto=TCP port=8080 local_port=80 bytes_in=512 bytes_out=2048 duration_ms=1530 reason=remote
+//! ```
+//!
+//! Connections the runner labeled in their CONNECT also carry `label=...`
+//! after `port`.
+//!
+//! `bytes_in` counts bytes from the runner written to the local service,
+//! `bytes_out` bytes read from the local service. The field set is stable so
+//! the lines can be filtered with `RUST_LOG=audit=info` and parsed as
+//! key=value pairs. The close also feeds the per-connection byte and duration
+//! histograms in [`Metrics`], and both events go to the [`Webhook`] if one is
+//! configured.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::metrics::Metrics;
+use crate::protocol::Proto;
+use crate::webhook::{ConnEvent, Webhook};
+
+/// Why a connection closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The runner sent CLOSE
+    Remote,
+    /// The local service closed its end
+    LocalEof,
+    /// Reading from or writing to the local service failed, or the
+    /// connection could not be resumed
+    Error,
+    /// The connection to the runner was lost
+    TransportLost,
+    /// A CONNECT reused the id and replaced the connection
+    Replaced,
+    /// The tunnel shut down
+    Shutdown,
+    /// The runner sent nothing on the connection after CONNECTED
+    RunnerSilent,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::Remote => "remote",
+            CloseReason::LocalEof => "local_eof",
+            CloseReason::Error => "error",
+            CloseReason::TransportLost => "transport_lost",
+            CloseReason::Replaced => "replaced",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::RunnerSilent => "runner_silent",
+        })
+    }
+}
+
+/// Audit state of one connection, shared by its tasks and the connection
+/// manager
+#[derive(Debug)]
+pub struct ConnAudit {
+    client_id: u32,
+    proto: Proto,
+    /// Port requested by the runner
+    port: u16,
+    /// Label the runner attached in the CONNECT
+    label: Option<Box<str>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Set by whichever path closes the connection first
+    reason: OnceLock<CloseReason>,
+}
+
+impl ConnAudit {
+    pub fn new(client_id: u32, proto: Proto, port: u16) -> Arc<Self> {
+        Self::labeled(client_id, proto, port, None)
+    }
+
+    /// Audit state for a connection the runner labeled (see
+    /// [`crate::protocol::parse_label`])
+    pub fn labeled(client_id: u32, proto: Proto, port: u16, label: Option<&str>) -> Arc<Self> {
+        Arc::new(Self {
+            client_id,
+            proto,
+            port,
+            label: label.map(Box::from),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            reason: OnceLock::new(),
+        })
+    }
+
+    /// Count bytes written to the local service
+    pub fn record_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count bytes read from the local service
+    pub fn record_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record why the connection is closing; later reasons are ignored
+    pub fn close_reason(&self, reason: CloseReason) {
+        let _ = self.reason.set(reason);
+    }
+
+    /// Bytes written to the local service so far
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from the local service so far
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Why the connection closed, if it has
+    pub fn reason(&self) -> Option<CloseReason> {
+        self.reason.get().copied()
+    }
+
+    pub fn client_id(&self) -> u32 {
+        self.client_id
+    }
+
+    pub fn proto(&self) -> Proto {
+        self.proto
+    }
+
+    /// Port requested by the runner
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Log the open event for a connection to `local_port` and list it in
+    /// `metrics` as open; the close event is logged and recorded in
+    /// `metrics` when the returned guard drops, including when the
+    /// connection's task is aborted
+    pub fn opened(self: &Arc<Self>, local_port: u16, metrics: &Arc<Metrics>) -> AuditGuard {
+        info!(
+            target: "audit",
+            event = "open",
+            client_id = self.client_id,
+            proto = %self.proto,
+            port = self.port,
+            label = self.label(),
+            local_port,
+        );
+        let opened_at = Instant::now();
+        AuditGuard {
+            audit: self.clone(),
+            metrics: metrics.clone(),
+            local_port,
+            opened_at,
+            key: metrics.register_conn(self.clone(), opened_at),
+            webhook: None,
+        }
+    }
+
+    /// Webhook event for this connection to `local_port`, without the
+    /// close fields
+    fn event(&self, event: &'static str, local_port: u16) -> ConnEvent {
+        ConnEvent {
+            event,
+            client_id: self.client_id,
+            proto: self.proto.to_string(),
+            port: self.port,
+            label: self.label.as_deref().map(String::from),
+            local_port,
+            bytes_in: None,
+            bytes_out: None,
+            duration_ms: None,
+            reason: None,
+        }
+    }
+}
+
+/// Logs a connection's close event when dropped
+#[derive(Debug)]
+pub struct AuditGuard {
+    audit: Arc<ConnAudit>,
+    metrics: Arc<Metrics>,
+    local_port: u16,
+    opened_at: Instant,
+    /// Entry in the metrics' open connections
+    key: u64,
+    webhook: Option<Arc<Webhook>>,
+}
+
+impl AuditGuard {
+    /// Also send the open event, and later the close event, to `webhook`
+    pub fn with_webhook(mut self, webhook: Option<&Arc<Webhook>>) -> Self {
+        if let Some(webhook) = webhook {
+            webhook.send(self.audit.event("open", self.local_port));
+            self.webhook = Some(webhook.clone());
+        }
+        self
+    }
+}
+
+impl Drop for AuditGuard {
+    fn drop(&mut self) {
+        let audit = &self.audit;
+        self.metrics.unregister_conn(self.key);
+        // A connection closed without a recorded reason ended with its task
+        let reason = audit.reason().unwrap_or(CloseReason::Error);
+        let duration = self.opened_at.elapsed();
+        self.metrics
+            .record_conn_closed(audit.bytes_in() + audit.bytes_out(), duration);
+        if let Some(label) = audit.label() {
+            self.metrics
+                .record_label_closed(label, audit.bytes_in(), audit.bytes_out());
+        }
+        info!(
+            target: "audit",
+            event = "close",
+            client_id = audit.client_id,
+            proto = %audit.proto,
+            port = audit.port,
+            label = audit.label(),
+            local_port = self.local_port,
+            bytes_in = audit.bytes_in(),
+            bytes_out = audit.bytes_out(),
+            duration_ms = duration.as_millis() as u64,
+            reason = %reason,
+        );
+        if let Some(webhook) = &self.webhook {
+            webhook.send(ConnEvent {
+                bytes_in: Some(audit.bytes_in()),
+                bytes_out: Some(audit.bytes_out()),
+                duration_ms: Some(duration.as_millis() as u64),
+                reason: Some(reason.to_string()),
+                ..audit.event("close", self.local_port)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Mutex;
+
+    /// Collects formatted log output
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_open_and_close_lines() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        let metrics = Arc::new(Metrics::default());
+        tracing::subscriber::with_default(subscriber, || {
+            let audit = ConnAudit::new(7, Proto::Tcp, 8080);
+            let guard = audit.opened(80, &metrics);
+            audit.record_in(512);
+            audit.record_out(2048);
+            audit.close_reason(CloseReason::Remote);
+            // Only the first reason sticks
+            audit.close_reason(CloseReason::Shutdown);
+            drop(guard);
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0]
+            .contains(r#"audit: event="open" client_id=7 proto=TCP port=8080 local_port=80"#));
+        assert!(lines[1].contains(
+            r#"audit: event="close" client_id=7 proto=TCP port=8080 local_port=80 bytes_in=512 bytes_out=2048 duration_ms="#
+        ));
+        assert!(lines[1].ends_with("reason=remote"));
+        assert_eq!(metrics.closed_connections(), 1);
+    }
+
+    #[test]
+    fn test_label_in_lines_and_metrics() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        let metrics = Arc::new(Metrics::default());
+        tracing::subscriber::with_default(subscriber, || {
+            let audit = ConnAudit::labeled(8, Proto::Tcp, 443, Some("api"));
+            let guard = audit.opened(443, &metrics);
+            audit.record_in(100);
+            audit.record_out(300);
+            drop(guard);
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        for line in output.lines() {
+            assert!(
+                line.contains(r#"port=443 label="api" local_port=443"#),
+                "{line}"
+            );
+        }
+        #[cfg(feature = "metrics")]
+        {
+            let rendered = metrics.render();
+            assert!(rendered.contains(r#"tunnel_labeled_connections_total{label="api"} 1"#));
+            assert!(rendered.contains(r#"tunnel_labeled_bytes_in_total{label="api"} 100"#));
+            assert!(rendered.contains(r#"tunnel_labeled_bytes_out_total{label="api"} 300"#));
+        }
+    }
+}
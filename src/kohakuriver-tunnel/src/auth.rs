@@ -0,0 +1,231 @@
+//! Challenge-response authentication of the tunnel handshake.
+//!
+//! With `--auth-secret`, a captured handshake can't be replayed: the runner
+//! puts a fresh random nonce in the upgrade response ([`NONCE_HEADER`], hex),
+//! and the tunnel's first frame is an AUTH carrying
+//! HMAC-SHA256(secret, nonce). The runner answers with an empty AUTH to accept
+//! or an ERROR (client ID 0) to reject, and only then does VERSION follow.
+//!
+//! With `--auth-secret-file` the secret is read from a file instead, such as a
+//! Docker or Kubernetes secret mount, before every connection attempt so a
+//! rotated secret takes effect on the next reconnect.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::info;
+
+use crate::error::{Result, TunnelError};
+use crate::protocol::{self, Frame, MsgType};
+use crate::transport::{Incoming, TransportSink, TransportStream};
+
+/// Upgrade response header carrying the runner's nonce, hex-encoded
+pub const NONCE_HEADER: &str = "x-tunnel-nonce";
+
+/// Shortest nonce accepted from the runner, in bytes
+pub const MIN_NONCE_LEN: usize = 16;
+
+/// Longest nonce accepted from the runner, in bytes
+pub const MAX_NONCE_LEN: usize = 64;
+
+/// How long the runner has to accept or reject the AUTH
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The nonce header of an upgrade response, if present and valid UTF-8
+pub fn nonce_header(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get(NONCE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Decode the runner's hex nonce, rejecting ones too short to be unique
+pub fn parse_nonce(value: &str) -> std::result::Result<Vec<u8>, String> {
+    let value = value.trim();
+    if !value.len().is_multiple_of(2) {
+        return Err("nonce has an odd number of hex digits".into());
+    }
+    let nonce = (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(|_| "nonce is not hex".to_string())?;
+    if !(MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce.len()) {
+        return Err(format!(
+            "nonce of {} bytes is outside {MIN_NONCE_LEN}..={MAX_NONCE_LEN}",
+            nonce.len()
+        ));
+    }
+    Ok(nonce)
+}
+
+/// Response to the runner's challenge: HMAC-SHA256 of `nonce` keyed with
+/// `secret`
+pub fn respond(secret: &str, nonce: &[u8]) -> [u8; 32] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(nonce);
+    mac.finalize().into_bytes().into()
+}
+
+/// Read the secret held in `path`, without surrounding whitespace such as
+/// a trailing newline
+pub fn read_secret_file(path: &Path) -> Result<String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| TunnelError::AuthFailed(format!("cannot read {}: {e}", path.display())))?;
+    let secret = contents.trim();
+    if secret.is_empty() {
+        return Err(TunnelError::AuthFailed(format!(
+            "{} holds no secret",
+            path.display()
+        )));
+    }
+    Ok(secret.to_owned())
+}
+
+/// Answer the runner's challenge over a freshly connected transport and wait
+/// for its verdict. Fails if the runner sent no nonce, rejects the response,
+/// or doesn't answer within [`AUTH_TIMEOUT`].
+pub async fn authenticate(
+    sink: &mut dyn TransportSink,
+    stream: &mut dyn TransportStream,
+    secret: &str,
+    nonce: Option<&str>,
+) -> Result<()> {
+    let nonce = nonce.ok_or_else(|| {
+        TunnelError::AuthFailed(format!(
+            "runner sent no {NONCE_HEADER} header; does it support authentication?"
+        ))
+    })?;
+    let nonce = parse_nonce(nonce).map_err(TunnelError::AuthFailed)?;
+    sink.send(protocol::build_auth(&respond(secret, &nonce)))
+        .await?;
+
+    let verdict = async {
+        loop {
+            let frame = match stream.recv().await {
+                None => {
+                    return Err(TunnelError::AuthFailed(
+                        "runner closed the connection".into(),
+                    ))
+                }
+                Some(Err(e)) => return Err(e),
+                Some(Ok(Incoming::Frame(frame))) => frame,
+                Some(Ok(Incoming::Ping(payload))) => {
+                    sink.pong(payload).await?;
+                    continue;
+                }
+                Some(Ok(_)) => continue,
+            };
+            let Frame { header, payload } = Frame::decode(&frame)?;
+            return match header.msg_type {
+                MsgType::Auth => Ok(()),
+                MsgType::Error => Err(TunnelError::AuthFailed(
+                    protocol::parse_error(payload).into_owned(),
+                )),
+                other => Err(TunnelError::AuthFailed(format!(
+                    "expected AUTH, runner sent {other:?}"
+                ))),
+            };
+        }
+    };
+    tokio::time::timeout(AUTH_TIMEOUT, verdict)
+        .await
+        .map_err(|_| TunnelError::AuthFailed("runner did not answer the AUTH".into()))??;
+    info!("Authenticated with runner");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Proto;
+    use crate::transport;
+
+    #[test]
+    fn test_parse_nonce() {
+        let nonce = parse_nonce("000102030405060708090a0b0c0d0e0F").unwrap();
+        assert_eq!(nonce, (0..16).collect::<Vec<u8>>());
+
+        assert!(parse_nonce("abc").is_err());
+        assert!(parse_nonce(&"zz".repeat(16)).is_err());
+        assert!(parse_nonce(&"00".repeat(MIN_NONCE_LEN - 1)).is_err());
+        assert!(parse_nonce(&"00".repeat(MAX_NONCE_LEN + 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let nonce = "ab".repeat(MIN_NONCE_LEN);
+        let ((mut sink, mut stream), mut runner) = transport::memory(8);
+
+        // Accepted: the response is the HMAC over the decoded nonce
+        let runner_side = async {
+            let frame = runner.next_frame().await.unwrap();
+            let Frame { header, payload } = Frame::decode(&frame).unwrap();
+            assert_eq!(header.msg_type, MsgType::Auth);
+            assert_eq!(payload, respond("s3cret", &[0xab; MIN_NONCE_LEN]));
+            runner
+                .send(Incoming::Frame(protocol::build_auth(&[])))
+                .await;
+        };
+        let (authed, ()) = tokio::join!(
+            authenticate(&mut *sink, &mut *stream, "s3cret", Some(&nonce)),
+            runner_side
+        );
+        authed.unwrap();
+
+        // Rejected with an ERROR
+        let runner_side = async {
+            runner.next_frame().await.unwrap();
+            let error = protocol::build_error(Proto::Tcp, 0, "bad response");
+            runner.send(Incoming::Frame(error)).await;
+        };
+        let (authed, ()) = tokio::join!(
+            authenticate(&mut *sink, &mut *stream, "wrong", Some(&nonce)),
+            runner_side
+        );
+        assert!(matches!(authed, Err(TunnelError::AuthFailed(reason)) if reason == "bad response"));
+
+        // No challenge to answer
+        assert!(matches!(
+            authenticate(&mut *sink, &mut *stream, "s3cret", None).await,
+            Err(TunnelError::AuthFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_secret_file() {
+        let path = std::env::temp_dir().join(format!("kohakuriver-auth-{}", std::process::id()));
+        fs::write(&path, "  s3cret\n").unwrap();
+        assert_eq!(read_secret_file(&path).unwrap(), "s3cret");
+
+        // A rotated secret is seen on the next read
+        fs::write(&path, "rotated\n").unwrap();
+        assert_eq!(read_secret_file(&path).unwrap(), "rotated");
+
+        fs::write(&path, "\n").unwrap();
+        assert!(matches!(
+            read_secret_file(&path),
+            Err(TunnelError::AuthFailed(_))
+        ));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            read_secret_file(&path),
+            Err(TunnelError::AuthFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_respond_matches_rfc4231() {
+        // RFC 4231 test case 2
+        let mac = respond("Jefe", b"what do ya want for nothing?");
+        let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(
+            hex,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Cumulative byte counts on TCP DATA, for verifying the relay.
+//!
+//! A debugging aid for data-integrity complaints, not meant for production
+//! traffic. With `--verify-byte-counts` negotiated, every TCP DATA payload
+//! starts with the total number of bytes sent on the connection so far,
+//! including its own:
+//!
+//! ```text
+//! ┌─────────────────┬─────────────────┐
+//! │ Total (8B BE)   │ Data (var)      │
+//! └─────────────────┴─────────────────┘
+//! ```
+//!
+//! The receiver keeps its own running total, so a frame lost, duplicated or
+//! truncated anywhere along the relay shows up as a mismatch. The count sits
+//! inside the (optionally encrypted) payload.
+
+use thiserror::Error;
+
+/// Size of the running total in front of the data
+pub const COUNT_SIZE: usize = 8;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ByteCountError {
+    #[error("DATA too short for a byte count: {0} bytes")]
+    Missing(usize),
+
+    #[error("byte count mismatch: expected {expected}, peer sent {got}")]
+    Mismatch { expected: u64, got: u64 },
+}
+
+/// Prefix `data` with the running `total` (which includes `data`)
+pub fn prefix(total: u64, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(COUNT_SIZE + data.len());
+    out.extend_from_slice(&total.to_be_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+/// Split a payload into its running total and data
+pub fn split(payload: &[u8]) -> Result<(u64, &[u8]), ByteCountError> {
+    if payload.len() < COUNT_SIZE {
+        return Err(ByteCountError::Missing(payload.len()));
+    }
+    let (count, data) = payload.split_at(COUNT_SIZE);
+    let total = u64::from_be_bytes(count.try_into().expect("split at COUNT_SIZE"));
+    Ok((total, data))
+}
+
+/// Running total of the bytes received on one connection
+#[derive(Debug, Default)]
+pub struct ByteCounter {
+    total: u64,
+}
+
+impl ByteCounter {
+    /// Count `len` received bytes against the peer's `total`. On a mismatch
+    /// the counter takes the peer's total, so one loss is reported once.
+    pub fn check(&mut self, total: u64, len: usize) -> Result<(), ByteCountError> {
+        let expected = self.total + len as u64;
+        self.total = total;
+        if total != expected {
+            return Err(ByteCountError::Mismatch {
+                expected,
+                got: total,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_split_roundtrip() {
+        let payload = prefix(1234, b"hello");
+        assert_eq!(payload.len(), COUNT_SIZE + 5);
+        assert_eq!(split(&payload), Ok((1234, &b"hello"[..])));
+        assert_eq!(split(&[0; 3]), Err(ByteCountError::Missing(3)));
+    }
+
+    #[test]
+    fn test_counter_detects_loss_once() {
+        let mut counter = ByteCounter::default();
+        assert_eq!(counter.check(5, 5), Ok(()));
+        assert_eq!(counter.check(5, 0), Ok(()));
+        // A 10-byte frame went missing before this one
+        assert_eq!(
+            counter.check(20, 5),
+            Err(ByteCountError::Mismatch {
+                expected: 10,
+                got: 20
+            })
+        );
+        assert_eq!(counter.check(25, 5), Ok(()));
+        assert_eq!(counter.total(), 25);
+    }
+}
@@ -0,0 +1,388 @@
+//! Recording of tunnel frames to a file (`--capture`) and reading them back
+//! (`--replay`).
+//!
+//! Every frame sent to or received from the runner is appended with a
+//! timestamp and its direction, as it crossed the transport (encrypted,
+//! mux-tagged or split across messages, as the case may be). Transport
+//! keepalives and control messages are not recorded. Once the file reaches
+//! its size limit it is rotated to `<file>.1`, replacing the previous one, so
+//! at most twice the limit is ever on disk.
+//!
+//! Capture file format: the magic [`MAGIC`], then a sequence of
+//! `[timestamp: u64 BE microseconds since the Unix epoch][direction: u8]
+//! [len: u32 BE][frame]` records.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use tracing::{info, warn};
+
+use crate::error::Result;
+use crate::hexdump::HexDump;
+use crate::mux;
+use crate::protocol::{Header, HEADER_SIZE};
+use crate::transport::TransportSink;
+
+/// Start of every capture file
+pub const MAGIC: &[u8; 8] = b"KRTCAP01";
+
+/// Size of the fixed part of each record
+const RECORD_HEADER_SIZE: u64 = 8 + 1 + 4;
+
+/// Payload bytes shown per frame by [`Record`]'s display
+const SHOWN_PAYLOAD: usize = 32;
+
+/// Which way a frame went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the runner
+    In,
+    /// Sent to the runner
+    Out,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::In => "<-",
+            Direction::Out => "->",
+        })
+    }
+}
+
+/// Writer appending frames to the capture file, shared by every session
+#[derive(Debug)]
+pub struct Capture {
+    path: PathBuf,
+    /// Size at which the file is rotated
+    max_bytes: u64,
+    state: Mutex<CaptureState>,
+}
+
+#[derive(Debug, Default)]
+struct CaptureState {
+    /// Open on the first record
+    file: Option<BufWriter<File>>,
+    written: u64,
+    /// Set after a write error; nothing more is recorded
+    failed: bool,
+}
+
+impl Capture {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Append one frame. A write error is logged once and ends the capture;
+    /// the tunnel itself carries on.
+    pub fn record(&self, direction: Direction, frame: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if state.failed {
+            return;
+        }
+        if let Err(e) = self.write(&mut state, direction, frame) {
+            warn!(path = %self.path.display(), error = %e, "Capture failed, no more frames are recorded");
+            state.failed = true;
+            state.file = None;
+        }
+    }
+
+    fn write(
+        &self,
+        state: &mut CaptureState,
+        direction: Direction,
+        frame: &[u8],
+    ) -> io::Result<()> {
+        let len = RECORD_HEADER_SIZE + frame.len() as u64;
+        if state.file.is_some() && state.written + len > self.max_bytes {
+            state.file = None;
+            let rotated = self.rotated_path();
+            fs::rename(&self.path, &rotated)?;
+            info!(path = %rotated.display(), "Capture file rotated");
+        }
+        let file = match &mut state.file {
+            Some(file) => file,
+            None => {
+                let mut file = BufWriter::new(
+                    OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&self.path)?,
+                );
+                file.write_all(MAGIC)?;
+                state.written = MAGIC.len() as u64;
+                state.file.insert(file)
+            }
+        };
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        file.write_all(&micros.to_be_bytes())?;
+        file.write_all(&[direction as u8])?;
+        file.write_all(&(frame.len() as u32).to_be_bytes())?;
+        file.write_all(frame)?;
+        // Flushed per record so a crash loses nothing
+        file.flush()?;
+        state.written += len;
+        Ok(())
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".1");
+        name.into()
+    }
+}
+
+/// Sink wrapper recording every frame sent to the runner
+pub struct CaptureSink {
+    inner: Box<dyn TransportSink>,
+    capture: Arc<Capture>,
+}
+
+impl CaptureSink {
+    pub fn new(inner: Box<dyn TransportSink>, capture: Arc<Capture>) -> Self {
+        Self { inner, capture }
+    }
+}
+
+impl TransportSink for CaptureSink {
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        self.capture.record(Direction::Out, &frame);
+        self.inner.send(frame)
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        self.inner.ping(payload)
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        self.inner.pong(payload)
+    }
+
+    fn text(&mut self, text: String) -> BoxFuture<'_, Result<()>> {
+        self.inner.text(text)
+    }
+}
+
+/// One recorded frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// When it was recorded
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub frame: Bytes,
+}
+
+impl fmt::Display for Record {
+    /// e.g. `1760601600.123456 -> DATA TCP client_id=7 port=0 len=5 68 65 6c 6c 6f |hello|`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "{}.{:06} {} ",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros(),
+            self.direction
+        )?;
+        let (index, frame) = match mux::split(&self.frame) {
+            Ok(split) => split,
+            Err(_) => (0, self.frame.clone()),
+        };
+        if index != 0 {
+            write!(f, "[container {index}] ")?;
+        }
+        match Header::parse(&frame) {
+            Ok(header) => {
+                let payload = &frame[HEADER_SIZE..];
+                write!(
+                    f,
+                    "{} {} client_id={} port={} len={}",
+                    format!("{:?}", header.msg_type).to_uppercase(),
+                    header.proto,
+                    header.client_id,
+                    header.port,
+                    payload.len()
+                )?;
+                if !payload.is_empty() {
+                    write!(f, " {}", HexDump::new(payload, SHOWN_PAYLOAD))?;
+                }
+                Ok(())
+            }
+            Err(e) => write!(
+                f,
+                "undecodable ({e}): {}",
+                HexDump::new(&frame, SHOWN_PAYLOAD)
+            ),
+        }
+    }
+}
+
+/// Reads the records of a capture file in order
+pub struct Replay<R> {
+    reader: R,
+}
+
+impl<R: Read> Replay<R> {
+    /// Check the magic and start reading records
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a tunnel capture file",
+            ));
+        }
+        Ok(Self { reader })
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut head = [0u8; RECORD_HEADER_SIZE as usize];
+        match self.reader.read_exact(&mut head[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        self.reader.read_exact(&mut head[1..])?;
+        let micros = u64::from_be_bytes(head[..8].try_into().unwrap());
+        let direction = match head[8] {
+            0 => Direction::In,
+            1 => Direction::Out,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid direction {other}"),
+                ))
+            }
+        };
+        let len = u32::from_be_bytes(head[9..].try_into().unwrap()) as usize;
+        let mut frame = vec![0u8; len];
+        self.reader.read_exact(&mut frame)?;
+        Ok(Some(Record {
+            time: UNIX_EPOCH + Duration::from_micros(micros),
+            direction,
+            frame: frame.into(),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Replay<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{self, MsgType, Proto};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "kohakuriver-capture-{}-{}",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn replay(path: &PathBuf) -> Vec<Record> {
+        Replay::new(File::open(path).unwrap())
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_capture_roundtrip() {
+        let path = temp_path("roundtrip");
+        let capture = Capture::new(&path, 1 << 20);
+        let connect = protocol::build_message(MsgType::Connect, Proto::Tcp, 7, 8080, &[]);
+        let data = protocol::build_data(Proto::Tcp, 7, b"hello");
+        capture.record(Direction::In, &connect);
+        capture.record(Direction::Out, &data);
+
+        let records = replay(&path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].direction, &records[0].frame),
+            (Direction::In, &connect)
+        );
+        assert_eq!(
+            (records[1].direction, &records[1].frame),
+            (Direction::Out, &data)
+        );
+        let line = records[1].to_string();
+        assert!(
+            line.ends_with("-> DATA TCP client_id=7 port=0 len=5 68 65 6c 6c 6f |hello|"),
+            "{line}"
+        );
+        assert!(records[0]
+            .to_string()
+            .contains("<- CONNECT TCP client_id=7 port=8080 len=0"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_capture_rotates_at_limit() {
+        let path = temp_path("rotate");
+        let frame = protocol::build_data(Proto::Udp, 1, &[0u8; 100]);
+        let record_len = RECORD_HEADER_SIZE + frame.len() as u64;
+        // Room for the magic and three records
+        let capture = Capture::new(&path, MAGIC.len() as u64 + 3 * record_len);
+        for _ in 0..5 {
+            capture.record(Direction::Out, &frame);
+        }
+
+        let rotated = capture.rotated_path();
+        assert_eq!(replay(&rotated).len(), 3);
+        assert_eq!(replay(&path).len(), 2);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+
+    #[test]
+    fn test_replay_rejects_other_files() {
+        assert!(Replay::new(&b"GIF89a.."[..]).is_err());
+
+        // A record cut short is an error, not a silent end
+        let mut truncated = MAGIC.to_vec();
+        truncated.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 9, 1]);
+        let mut replay = Replay::new(&truncated[..]).unwrap();
+        assert!(replay.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_display_mux_and_undecodable() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_500_000);
+        let tagged = Record {
+            time,
+            direction: Direction::In,
+            frame: mux::tag(2, &protocol::build_close(Proto::Tcp, 3)),
+        };
+        assert_eq!(
+            tagged.to_string(),
+            "1.500000 <- [container 2] CLOSE TCP client_id=3 port=0 len=0"
+        );
+        let short = Record {
+            time,
+            direction: Direction::In,
+            frame: Bytes::from_static(b"\x03\x00"),
+        };
+        assert!(short.to_string().starts_with("1.500000 <- undecodable ("));
+    }
+}
@@ -0,0 +1,398 @@
+//! Fault injection for resilience testing (`--chaos`, hidden).
+//!
+//! Wraps both halves of a session's transport so frames are randomly
+//! dropped, delayed or corrupted on their way to and from the runner, and the
+//! session is cut off now and then as if the link went down. This exercises
+//! reconnects, connection cleanup and the frame parser under the kind of
+//! adverse conditions that are hard to arrange on a real network. Keepalives
+//! and control messages are only ever delayed, never dropped or corrupted.
+//!
+//! Never enable this in production: dropped and corrupted frames break the
+//! connections they belong to.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures_util::future::BoxFuture;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::debug;
+
+use crate::config::parse_duration;
+use crate::error::{Result, TunnelError};
+use crate::transport::{Incoming, TransportPair, TransportSink, TransportStream};
+
+/// Which faults to inject and how often
+///
+/// Parsed from comma-separated `key=value` pairs, e.g.
+/// `drop=0.01,corrupt=0.01,delay=0.1,max-delay=200ms,reconnect=30s,seed=42`:
+///
+/// - `drop`, `corrupt`, `delay`: chance (0 to 1) that a frame is dropped,
+///   has a byte flipped, or is held back for up to `max-delay` (default 100ms)
+/// - `reconnect`: mean time between forced disconnects (default never)
+/// - `seed`: make the faults repeatable (default random)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChaosConfig {
+    pub drop: f64,
+    pub corrupt: f64,
+    pub delay: f64,
+    pub max_delay: Duration,
+    /// Mean session lifetime before a forced disconnect (zero = never)
+    pub reconnect: Duration,
+    pub seed: Option<u64>,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            corrupt: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(100),
+            reconnect: Duration::ZERO,
+            seed: None,
+        }
+    }
+}
+
+impl fmt::Display for ChaosConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drop={},corrupt={},delay={},max-delay={}ms,reconnect={}ms",
+            self.drop,
+            self.corrupt,
+            self.delay,
+            self.max_delay.as_millis(),
+            self.reconnect.as_millis()
+        )?;
+        if let Some(seed) = self.seed {
+            write!(f, ",seed={}", seed)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ChaosConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid chaos setting {:?} (expected KEY=VALUE)", entry))?;
+            let chance = || match value.trim().parse::<f64>() {
+                Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+                _ => Err(format!("Invalid chance in {:?} (expected 0 to 1)", entry)),
+            };
+            match key.trim() {
+                "drop" => config.drop = chance()?,
+                "corrupt" => config.corrupt = chance()?,
+                "delay" => config.delay = chance()?,
+                "max-delay" => config.max_delay = parse_duration(value)?,
+                "reconnect" => config.reconnect = parse_duration(value)?,
+                "seed" => {
+                    config.seed = Some(
+                        value
+                            .trim()
+                            .parse()
+                            .map_err(|_| format!("Invalid seed in {:?}", entry))?,
+                    )
+                }
+                other => {
+                    return Err(format!(
+                        "Unknown chaos setting {:?} (expected drop, corrupt, delay, max-delay, reconnect or seed)",
+                        other
+                    ))
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// What to do with one frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fault {
+    Pass,
+    Drop,
+    Corrupt,
+}
+
+/// Random source deciding the faults of one transport half
+struct Dice {
+    config: ChaosConfig,
+    rng: StdRng,
+}
+
+impl Dice {
+    /// Dice for one half of session number `session`; with a seed, every
+    /// session and half still gets its own sequence
+    fn new(config: ChaosConfig, session: u64, half: u64) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(session * 2 + half)),
+            None => StdRng::from_entropy(),
+        };
+        Self { config, rng }
+    }
+
+    /// How long to hold the next item back, if at all
+    fn delay(&mut self) -> Option<Duration> {
+        if self.config.delay > 0.0 && self.rng.gen_bool(self.config.delay) {
+            Some(self.rng.gen_range(Duration::ZERO..=self.config.max_delay))
+        } else {
+            None
+        }
+    }
+
+    fn fault(&mut self) -> Fault {
+        if self.config.drop > 0.0 && self.rng.gen_bool(self.config.drop) {
+            Fault::Drop
+        } else if self.config.corrupt > 0.0 && self.rng.gen_bool(self.config.corrupt) {
+            Fault::Corrupt
+        } else {
+            Fault::Pass
+        }
+    }
+
+    /// `frame` with one random bit flipped
+    fn corrupt(&mut self, frame: Bytes) -> Bytes {
+        if frame.is_empty() {
+            return frame;
+        }
+        let mut bytes = BytesMut::from(&frame[..]);
+        let at = self.rng.gen_range(0..bytes.len());
+        bytes[at] ^= 1 << self.rng.gen_range(0..8);
+        bytes.freeze()
+    }
+
+    /// When to cut the session off, drawn around the configured mean
+    fn disconnect_at(&mut self) -> Option<Instant> {
+        let mean = self.config.reconnect;
+        if mean.is_zero() {
+            return None;
+        }
+        Some(Instant::now() + self.rng.gen_range(mean / 2..=mean * 3 / 2))
+    }
+}
+
+/// Wrap both halves of the transport of session number `session` in the
+/// faults of `config`
+pub fn wrap((sink, stream): TransportPair, config: ChaosConfig, session: u64) -> TransportPair {
+    let mut dice = Dice::new(config, session, 1);
+    let disconnect_at = dice.disconnect_at();
+    (
+        Box::new(ChaosSink {
+            inner: sink,
+            dice: Dice::new(config, session, 0),
+        }),
+        Box::new(ChaosStream {
+            inner: stream,
+            dice,
+            disconnect_at,
+            held: None,
+        }),
+    )
+}
+
+/// Sending half that mangles frames on their way to the runner
+pub struct ChaosSink {
+    inner: Box<dyn TransportSink>,
+    dice: Dice,
+}
+
+impl ChaosSink {
+    async fn hold_back(&mut self) {
+        if let Some(delay) = self.dice.delay() {
+            sleep(delay).await;
+        }
+    }
+}
+
+impl TransportSink for ChaosSink {
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.hold_back().await;
+            match self.dice.fault() {
+                Fault::Pass => self.inner.send(frame).await,
+                Fault::Drop => {
+                    debug!(len = frame.len(), "Chaos: dropping outgoing frame");
+                    Ok(())
+                }
+                Fault::Corrupt => {
+                    debug!(len = frame.len(), "Chaos: corrupting outgoing frame");
+                    let frame = self.dice.corrupt(frame);
+                    self.inner.send(frame).await
+                }
+            }
+        })
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.hold_back().await;
+            self.inner.ping(payload).await
+        })
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.hold_back().await;
+            self.inner.pong(payload).await
+        })
+    }
+
+    fn text(&mut self, text: String) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            self.hold_back().await;
+            self.inner.text(text).await
+        })
+    }
+}
+
+/// Receiving half that mangles frames from the runner and ends the session
+/// when its disconnect time comes
+pub struct ChaosStream {
+    inner: Box<dyn TransportStream>,
+    dice: Dice,
+    disconnect_at: Option<Instant>,
+    /// Item being held back and when to hand it over, kept here so a
+    /// cancelled `recv` doesn't lose it
+    held: Option<(Instant, Option<Result<Incoming>>)>,
+}
+
+impl TransportStream for ChaosStream {
+    fn recv(&mut self) -> BoxFuture<'_, Option<Result<Incoming>>> {
+        Box::pin(async move {
+            loop {
+                if let Some((until, _)) = &self.held {
+                    sleep_until(*until).await;
+                    return self.held.take().and_then(|(_, incoming)| incoming);
+                }
+                let incoming = match self.disconnect_at {
+                    Some(at) => tokio::select! {
+                        incoming = self.inner.recv() => incoming,
+                        _ = sleep_until(at) => {
+                            debug!("Chaos: forcing a disconnect");
+                            return Some(Err(TunnelError::ConnectionLost(
+                                "chaos: forced disconnect".into(),
+                            )));
+                        }
+                    },
+                    None => self.inner.recv().await,
+                };
+                let incoming = match incoming {
+                    Some(Ok(Incoming::Frame(frame))) => match self.dice.fault() {
+                        Fault::Pass => Some(Ok(Incoming::Frame(frame))),
+                        Fault::Drop => {
+                            debug!(len = frame.len(), "Chaos: dropping incoming frame");
+                            continue;
+                        }
+                        Fault::Corrupt => {
+                            debug!(len = frame.len(), "Chaos: corrupting incoming frame");
+                            Some(Ok(Incoming::Frame(self.dice.corrupt(frame))))
+                        }
+                    },
+                    other => other,
+                };
+                match self.dice.delay() {
+                    Some(delay) => self.held = Some((Instant::now() + delay, incoming)),
+                    None => return incoming,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport;
+
+    #[test]
+    fn test_parse_chaos() {
+        let config: ChaosConfig =
+            "drop=0.1, corrupt=0.05,delay=1,max-delay=20ms,reconnect=2s,seed=7"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config,
+            ChaosConfig {
+                drop: 0.1,
+                corrupt: 0.05,
+                delay: 1.0,
+                max_delay: Duration::from_millis(20),
+                reconnect: Duration::from_secs(2),
+                seed: Some(7),
+            }
+        );
+        assert_eq!(config.to_string().parse::<ChaosConfig>(), Ok(config));
+        assert_eq!("".parse::<ChaosConfig>(), Ok(ChaosConfig::default()));
+        assert!("drop=1.5".parse::<ChaosConfig>().is_err());
+        assert!("drop".parse::<ChaosConfig>().is_err());
+        assert!("explode=0.1".parse::<ChaosConfig>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_faults_applied_to_frames_only() {
+        let config = ChaosConfig {
+            drop: 0.5,
+            corrupt: 0.5,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let (transport, mut runner) = transport::memory(256);
+        let (mut sink, _stream) = wrap(transport, config, 0);
+
+        let frame = Bytes::from_static(b"0123456789");
+        for _ in 0..100 {
+            sink.send(frame.clone()).await.unwrap();
+            sink.ping(Bytes::from_static(b"ts")).await.unwrap();
+        }
+        drop(sink);
+
+        let (mut frames, mut corrupted, mut pings) = (0, 0, 0);
+        while let Some(item) = runner.recv().await {
+            match item {
+                Incoming::Frame(f) => {
+                    frames += 1;
+                    corrupted += usize::from(f != frame);
+                }
+                Incoming::Ping(_) => pings += 1,
+                _ => unreachable!(),
+            }
+        }
+        // Keepalives always get through; frames are dropped or mangled
+        assert_eq!(pings, 100);
+        assert!(frames > 0 && frames < 100, "{} frames", frames);
+        assert!(
+            corrupted > 0 && corrupted < frames,
+            "{} corrupted",
+            corrupted
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forced_disconnect() {
+        let config = ChaosConfig {
+            reconnect: Duration::from_secs(10),
+            seed: Some(3),
+            ..Default::default()
+        };
+        let (transport, runner) = transport::memory(4);
+        let (_sink, mut stream) = wrap(transport, config, 0);
+
+        let start = Instant::now();
+        assert!(matches!(
+            stream.recv().await,
+            Some(Err(TunnelError::ConnectionLost(_)))
+        ));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(5) && elapsed <= Duration::from_secs(15));
+        drop(runner);
+    }
+}
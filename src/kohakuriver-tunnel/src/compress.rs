@@ -0,0 +1,110 @@
+//! Per-connection gzip compression of TCP streams (`--compression`).
+//!
+//! Unlike per-message compression, each direction of a connection is one
+//! gzip stream spanning all of its DATA frames, so the compression context
+//! carries across frames and repetitive traffic such as HTTP headers on a
+//! keep-alive connection compresses well. Every DATA payload is the output
+//! of a sync flush, so the receiver can decompress it as soon as it
+//! arrives. The stream's trailer goes out in a final DATA right before
+//! CLOSE.
+
+use std::io::{self, Write};
+use std::mem;
+
+use flate2::write::{GzDecoder, GzEncoder};
+use flate2::Compression;
+
+/// Outgoing side of one connection
+pub struct Compressor {
+    inner: GzEncoder<Vec<u8>>,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self {
+            inner: GzEncoder::new(Vec::new(), Compression::default()),
+        }
+    }
+}
+
+impl Compressor {
+    /// Compress `data` and flush it, returning the DATA payload to send
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        // Writes into a Vec cannot fail
+        self.inner.write_all(data).expect("compress into memory");
+        self.inner.flush().expect("flush into memory");
+        mem::take(self.inner.get_mut())
+    }
+
+    /// End the stream, returning the last DATA payload (the gzip trailer)
+    pub fn finish(self) -> Vec<u8> {
+        self.inner.finish().expect("finish into memory")
+    }
+}
+
+/// Incoming side of one connection
+pub struct Decompressor {
+    inner: GzDecoder<Vec<u8>>,
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self {
+            inner: GzDecoder::new(Vec::new()),
+        }
+    }
+}
+
+impl Decompressor {
+    /// Decompress one DATA payload, returning whatever bytes it completes
+    pub fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.inner.write_all(data)?;
+        self.inner.flush()?;
+        Ok(mem::take(self.inner.get_mut()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let mut compressor = Compressor::default();
+        let mut decompressor = Decompressor::default();
+        let request = b"GET /api/items HTTP/1.1\r\nHost: example\r\nAccept: */*\r\n\r\n";
+
+        let mut sizes = Vec::new();
+        for _ in 0..3 {
+            let payload = compressor.compress(request);
+            sizes.push(payload.len());
+            // Each payload decompresses on its own, without waiting for more
+            assert_eq!(decompressor.decompress(&payload).unwrap(), request);
+        }
+        // Later requests are mostly back-references into the shared context
+        assert!(sizes[2] < sizes[0] / 2, "sizes: {sizes:?}");
+
+        let trailer = compressor.finish();
+        assert!(!trailer.is_empty());
+        assert!(decompressor.decompress(&trailer).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_gzip_compatible() {
+        let mut compressor = Compressor::default();
+        let mut stream = compressor.compress(b"hello ");
+        stream.extend(compressor.compress(b"world"));
+        stream.extend(compressor.finish());
+
+        let mut decoder = flate2::read::GzDecoder::new(&stream[..]);
+        let mut out = String::new();
+        io::Read::read_to_string(&mut decoder, &mut out).unwrap();
+        assert_eq!(out, "hello world");
+    }
+
+    #[test]
+    fn test_corrupt_payload_rejected() {
+        let mut decompressor = Decompressor::default();
+        assert!(decompressor.decompress(b"not gzip at all").is_err());
+    }
+}
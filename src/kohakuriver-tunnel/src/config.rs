@@ -0,0 +1,836 @@
+//! Parsing helpers for configuration values.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::egress::Priority;
+use crate::protocol::Proto;
+
+/// Parse a human-friendly duration such as `500ms`, `30s`, `5m`, `1h`.
+///
+/// A bare number is interpreted as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid duration: {:?}", s))?;
+
+    let secs = |per_unit: u64| {
+        value
+            .checked_mul(per_unit)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("Duration too large: {:?}", s))
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => secs(1),
+        "m" => secs(60),
+        "h" => secs(3600),
+        _ => Err(format!(
+            "Invalid duration unit in {:?} (expected ms, s, m or h)",
+            s
+        )),
+    }
+}
+
+/// Translation table from runner-requested ports to local ports
+///
+/// Parsed from `requested:local` pairs, e.g. `8080:80,443:8443`. Ports
+/// without an entry pass through unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortMap {
+    entries: HashMap<u16, u16>,
+}
+
+impl PortMap {
+    /// Local port to connect to for a runner-requested port
+    pub fn resolve(&self, port: u16) -> u16 {
+        self.entries.get(&port).copied().unwrap_or(port)
+    }
+}
+
+impl FromStr for PortMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = HashMap::new();
+
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (from, to) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid port mapping {:?} (expected FROM:TO)", entry))?;
+            let from: u16 = from
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid source port in mapping {:?}", entry))?;
+            let to: u16 = to
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid target port in mapping {:?}", entry))?;
+
+            if let Some(existing) = entries.insert(from, to) {
+                if existing != to {
+                    return Err(format!(
+                        "Conflicting mappings for port {}: {} and {}",
+                        from, existing, to
+                    ));
+                }
+            }
+        }
+
+        Ok(Self { entries })
+    }
+}
+
+/// Full destination for a requested port, given as a URL
+///
+/// `tcp://10.0.0.5:9000` and `udp://[::1]:53` name a host and port;
+/// `unix:///run/app.sock` names a Unix stream socket, reached by TCP
+/// CONNECTs (Unix only). Hosts must be IP addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Tcp(SocketAddr),
+    Udp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Target {
+    /// Protocol a CONNECT must use to reach this target
+    pub fn proto(&self) -> Proto {
+        match self {
+            Self::Tcp(_) => Proto::Tcp,
+            Self::Udp(_) => Proto::Udp,
+            #[cfg(unix)]
+            Self::Unix(_) => Proto::Tcp,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Self::Udp(addr) => write!(f, "udp://{}", addr),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("Invalid target {:?} (expected SCHEME://ADDRESS)", s))?;
+        let addr = || {
+            rest.parse::<SocketAddr>()
+                .map_err(|_| format!("Invalid address in target {:?} (expected IP:PORT)", s))
+        };
+        match scheme {
+            "tcp" => Ok(Self::Tcp(addr()?)),
+            "udp" => Ok(Self::Udp(addr()?)),
+            #[cfg(unix)]
+            "unix" if rest.starts_with('/') => Ok(Self::Unix(PathBuf::from(rest))),
+            #[cfg(unix)]
+            "unix" => Err(format!("Unix socket path in {:?} must be absolute", s)),
+            #[cfg(not(unix))]
+            "unix" => Err("Unix socket targets are only supported on Unix".to_string()),
+            _ => Err(format!(
+                "Unknown scheme in target {:?} (expected tcp, udp or unix)",
+                s
+            )),
+        }
+    }
+}
+
+/// Per-port destinations that replace the requested port and target host
+///
+/// Parsed from `port=target` pairs, e.g.
+/// `9000=tcp://10.0.0.5:9000,80=unix:///run/app.sock`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortTargets {
+    targets: HashMap<u16, Target>,
+}
+
+impl PortTargets {
+    /// Destination configured for requested `port`, if any
+    pub fn get(&self, port: u16) -> Option<&Target> {
+        self.targets.get(&port)
+    }
+
+    /// Whether any target is reached over `proto`
+    pub fn uses(&self, proto: Proto) -> bool {
+        self.targets.values().any(|target| target.proto() == proto)
+    }
+}
+
+impl FromStr for PortTargets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut targets = HashMap::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (port, target) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid port target {:?} (expected PORT=URL)", entry))?;
+            let port: u16 = port
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid port in target {:?}", entry))?;
+            let target: Target = target.parse()?;
+            if let Some(existing) = targets.insert(port, target.clone()) {
+                if existing != target {
+                    return Err(format!(
+                        "Conflicting targets for port {}: {} and {}",
+                        port, existing, target
+                    ));
+                }
+            }
+        }
+        Ok(Self { targets })
+    }
+}
+
+/// Fixed local ports that replace whatever port a CONNECT requests
+///
+/// Parsed from `8080` (every protocol) or per-protocol entries such as
+/// `tcp:8080,udp:5353`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForcedPorts {
+    tcp: Option<u16>,
+    udp: Option<u16>,
+}
+
+impl ForcedPorts {
+    /// Forced local port for `proto`, if any
+    pub fn get(&self, proto: Proto) -> Option<u16> {
+        match proto {
+            Proto::Tcp => self.tcp,
+            Proto::Udp => self.udp,
+            // Raw CONNECTs name a protocol, not a port
+            Proto::Raw => None,
+        }
+    }
+}
+
+impl FromStr for ForcedPorts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut forced = Self::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (protos, port) = match entry.split_once(':') {
+                Some((proto, port)) => {
+                    let proto: Proto = proto.parse().map_err(|e| format!("{}", e))?;
+                    (vec![proto], port)
+                }
+                None => (vec![Proto::Tcp, Proto::Udp], entry),
+            };
+            let port: u16 = port
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid port in forced target {:?}", entry))?;
+            for proto in protos {
+                let slot = match proto {
+                    Proto::Tcp => &mut forced.tcp,
+                    Proto::Udp => &mut forced.udp,
+                    Proto::Raw => return Err("Raw connections have no port to force".to_string()),
+                };
+                if slot.is_some_and(|existing| existing != port) {
+                    return Err(format!("Conflicting forced target ports for {}", proto));
+                }
+                *slot = Some(port);
+            }
+        }
+
+        if forced == Self::default() {
+            return Err("Forced target port list is empty".to_string());
+        }
+        Ok(forced)
+    }
+}
+
+/// Concurrent connection caps per requested port
+///
+/// Parsed from `port=limit` pairs, e.g. `80=100,5432=20`. Ports without an
+/// entry are unlimited.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortLimits {
+    limits: HashMap<u16, usize>,
+}
+
+impl PortLimits {
+    /// Cap on concurrent connections to `port`, if any
+    pub fn get(&self, port: u16) -> Option<usize> {
+        self.limits.get(&port).copied()
+    }
+}
+
+impl FromStr for PortLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = HashMap::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (port, limit) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid port limit {:?} (expected PORT=LIMIT)", entry))?;
+            let port: u16 = port
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid port in limit {:?}", entry))?;
+            let limit: usize = limit
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid limit in {:?}", entry))?;
+            if limit == 0 {
+                return Err(format!(
+                    "Port limit {:?} must be at least 1 (use --allow-ports to block a port)",
+                    entry
+                ));
+            }
+            if let Some(existing) = limits.insert(port, limit) {
+                if existing != limit {
+                    return Err(format!(
+                        "Conflicting limits for port {}: {} and {}",
+                        port, existing, limit
+                    ));
+                }
+            }
+        }
+
+        if limits.is_empty() {
+            return Err("Port limit list is empty".to_string());
+        }
+        Ok(Self { limits })
+    }
+}
+
+/// Egress priority classes per requested port
+///
+/// Parsed from `port=class` pairs, e.g. `22=high,9000=low`. Ports without an
+/// entry are `normal`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortPriorities {
+    priorities: HashMap<u16, Priority>,
+}
+
+impl PortPriorities {
+    /// Egress class of connections to `port`
+    pub fn get(&self, port: u16) -> Priority {
+        self.priorities.get(&port).copied().unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.priorities.is_empty()
+    }
+}
+
+impl FromStr for PortPriorities {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut priorities = HashMap::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (port, priority) = entry.split_once('=').ok_or_else(|| {
+                format!("Invalid port priority {:?} (expected PORT=CLASS)", entry)
+            })?;
+            let port: u16 = port
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid port in priority {:?}", entry))?;
+            let priority: Priority = priority.parse()?;
+            if let Some(existing) = priorities.insert(port, priority) {
+                if existing != priority {
+                    return Err(format!(
+                        "Conflicting priorities for port {}: {} and {}",
+                        port, existing, priority
+                    ));
+                }
+            }
+        }
+
+        if priorities.is_empty() {
+            return Err("Port priority list is empty".to_string());
+        }
+        Ok(Self { priorities })
+    }
+}
+
+/// Local ports of named services, for CONNECTs that name a service
+/// instead of a port (see [`crate::resolver`])
+///
+/// Parsed from `name=port` pairs, e.g. `jupyter=8888,api=8000`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceMap {
+    ports: HashMap<String, u16>,
+}
+
+impl ServiceMap {
+    /// Port of the service called `name`, if it is listed
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.ports.get(name).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+}
+
+impl FromStr for ServiceMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ports = HashMap::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, port) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid service {:?} (expected NAME=PORT)", entry))?;
+            let name = name.trim();
+            match crate::protocol::parse_label(name.as_bytes()) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(format!("Missing service name in {:?}", entry)),
+                Err(e) => return Err(format!("Invalid service name in {:?}: {}", entry, e)),
+            }
+            let port: u16 = port
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&p| p != 0)
+                .ok_or_else(|| format!("Invalid port in service {:?}", entry))?;
+            if ports
+                .insert(name.to_string(), port)
+                .is_some_and(|p| p != port)
+            {
+                return Err(format!("Service {:?} is mapped to two ports", name));
+            }
+        }
+
+        if ports.is_empty() {
+            return Err("Service map is empty".to_string());
+        }
+        Ok(Self { ports })
+    }
+}
+
+/// Additional container served over a multiplexed tunnel
+///
+/// Parsed from `ID=HOST`, e.g. `web-2=172.17.0.3`. Connections for the
+/// container go to HOST instead of loopback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MuxContainer {
+    pub id: String,
+    pub host: IpAddr,
+}
+
+impl FromStr for MuxContainer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, host) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid mux container {:?} (expected ID=HOST)", s))?;
+        let id = id.trim();
+        // The IDs travel comma-separated in the tunnel URL
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(format!("Invalid container ID in {:?}", s));
+        }
+        let host = host
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid host address in mux container {:?}", s))?;
+        Ok(Self {
+            id: id.to_string(),
+            host,
+        })
+    }
+}
+
+/// Runner a `redirect` control command may send the tunnel to
+///
+/// Parsed from a hostname (`runner-2.example.com`), a wildcard for its
+/// subdomains (`*.example.com`), an IP address or a CIDR block
+/// (`10.0.0.0/8`). Hostnames are compared without resolving them, so CIDR
+/// blocks only match redirect URLs that name an IP address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunnerPattern {
+    /// Exactly this hostname, lowercased
+    Host(String),
+    /// Any subdomain of this one, lowercased with the leading `.` kept
+    Subdomains(String),
+    /// Addresses in this block
+    Network { addr: IpAddr, prefix: u8 },
+}
+
+impl RunnerPattern {
+    /// Whether `host` (from a runner URL) matches
+    pub fn matches(&self, host: &url::Host<&str>) -> bool {
+        match (self, host) {
+            (RunnerPattern::Host(name), url::Host::Domain(domain)) => {
+                domain.eq_ignore_ascii_case(name)
+            }
+            (RunnerPattern::Subdomains(suffix), url::Host::Domain(domain)) => {
+                domain.len() > suffix.len()
+                    && domain.to_ascii_lowercase().ends_with(suffix.as_str())
+            }
+            (RunnerPattern::Network { addr, prefix }, url::Host::Ipv4(ip)) => {
+                in_network(*addr, *prefix, IpAddr::V4(*ip))
+            }
+            (RunnerPattern::Network { addr, prefix }, url::Host::Ipv6(ip)) => {
+                in_network(*addr, *prefix, IpAddr::V6(*ip))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `ip` lies in the block of `prefix` leading bits of `addr`
+fn in_network(addr: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    // Bits past the prefix are shifted out, which takes u128 for a /0
+    let bits = |a: u128, width: u8| a.checked_shr(u32::from(width - prefix)).unwrap_or(0);
+    match (addr, ip) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            bits(u32::from(a).into(), 32) == bits(u32::from(b).into(), 32)
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => bits(a.into(), 128) == bits(b.into(), 128),
+        _ => false,
+    }
+}
+
+impl FromStr for RunnerPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("Invalid runner pattern {:?}", s);
+        if let Some((addr, prefix)) = s.split_once('/') {
+            let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+            let width = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            if prefix > width {
+                return Err(invalid());
+            }
+            return Ok(RunnerPattern::Network { addr, prefix });
+        }
+        if let Ok(addr) = s.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+            let prefix = if addr.is_ipv4() { 32 } else { 128 };
+            return Ok(RunnerPattern::Network { addr, prefix });
+        }
+        let (wildcard, name) = match s.strip_prefix("*.") {
+            Some(name) => (true, name),
+            None => (false, s),
+        };
+        let valid = |label: &str| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        };
+        if !name.split('.').all(valid) {
+            return Err(invalid());
+        }
+        let name = name.to_ascii_lowercase();
+        Ok(if wildcard {
+            RunnerPattern::Subdomains(format!(".{}", name))
+        } else {
+            RunnerPattern::Host(name)
+        })
+    }
+}
+
+/// Set of ports, parsed from a list of ports and ranges
+///
+/// e.g. `22,80,8000-8100`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl PortSet {
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|r| r.contains(&port))
+    }
+}
+
+impl FromStr for PortSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_port = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port {:?} in {:?}", p.trim(), s))
+        };
+
+        let mut ranges = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let range = match entry.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse_port(start)?, parse_port(end)?);
+                    if start > end {
+                        return Err(format!("Empty port range {:?}", entry));
+                    }
+                    start..=end
+                }
+                None => {
+                    let port = parse_port(entry)?;
+                    port..=port
+                }
+            };
+            ranges.push(range);
+        }
+
+        if ranges.is_empty() {
+            return Err("Port list is empty".to_string());
+        }
+        Ok(Self { ranges })
+    }
+}
+
+impl fmt::Display for PortSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if range.start() == range.end() {
+                write!(f, "{}", range.start())?;
+            } else {
+                write!(f, "{}-{}", range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10d").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX / 60)).is_err());
+        assert!(parse_duration(&format!("{}m", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_port_map_resolve() {
+        let map: PortMap = "8080:80, 443:8443".parse().unwrap();
+        assert_eq!(map.resolve(8080), 80);
+        assert_eq!(map.resolve(443), 8443);
+        // Unmapped ports pass through
+        assert_eq!(map.resolve(22), 22);
+
+        let empty: PortMap = "".parse().unwrap();
+        assert_eq!(empty, PortMap::default());
+        assert_eq!(empty.resolve(8080), 8080);
+    }
+
+    #[test]
+    fn test_port_targets() {
+        let targets: PortTargets = "9000=tcp://10.0.0.5:9000, 53=udp://[::1]:5353"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            targets.get(9000),
+            Some(&Target::Tcp("10.0.0.5:9000".parse().unwrap()))
+        );
+        assert_eq!(targets.get(53).unwrap().proto(), Proto::Udp);
+        assert_eq!(targets.get(53).unwrap().to_string(), "udp://[::1]:5353");
+        assert_eq!(targets.get(80), None);
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:///run/app.sock".parse::<Target>().unwrap(),
+            Target::Unix("/run/app.sock".into())
+        );
+
+        assert!(targets.uses(Proto::Udp));
+        assert!(!targets.uses(Proto::Raw));
+        assert_eq!("".parse::<PortTargets>().unwrap(), PortTargets::default());
+        assert!("9000".parse::<PortTargets>().is_err());
+        assert!("9000=10.0.0.5:9000".parse::<PortTargets>().is_err());
+        assert!("9000=http://10.0.0.5:9000".parse::<PortTargets>().is_err());
+        assert!("9000=tcp://db.internal:9000"
+            .parse::<PortTargets>()
+            .is_err());
+        assert!("9000=unix://run/app.sock".parse::<PortTargets>().is_err());
+        assert!("9000=tcp://10.0.0.5:9000,9000=tcp://10.0.0.6:9000"
+            .parse::<PortTargets>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_port_map_conflicts() {
+        assert!("8080:80,8080:81".parse::<PortMap>().is_err());
+        // Repeating an identical mapping is harmless
+        assert_eq!(
+            "8080:80,8080:80".parse::<PortMap>().unwrap().resolve(8080),
+            80
+        );
+    }
+
+    #[test]
+    fn test_port_map_invalid() {
+        assert!("8080".parse::<PortMap>().is_err());
+        assert!("8080:http".parse::<PortMap>().is_err());
+        assert!("70000:80".parse::<PortMap>().is_err());
+    }
+
+    #[test]
+    fn test_forced_ports() {
+        let all: ForcedPorts = "8080".parse().unwrap();
+        assert_eq!(all.get(Proto::Tcp), Some(8080));
+        assert_eq!(all.get(Proto::Udp), Some(8080));
+
+        let split: ForcedPorts = "tcp:8080, udp:5353".parse().unwrap();
+        assert_eq!(split.get(Proto::Tcp), Some(8080));
+        assert_eq!(split.get(Proto::Udp), Some(5353));
+
+        let tcp_only: ForcedPorts = "tcp:22".parse().unwrap();
+        assert_eq!(tcp_only.get(Proto::Udp), None);
+
+        assert!("8080,tcp:80".parse::<ForcedPorts>().is_err());
+        assert!("sctp:80".parse::<ForcedPorts>().is_err());
+        assert!("tcp:http".parse::<ForcedPorts>().is_err());
+        assert!("".parse::<ForcedPorts>().is_err());
+    }
+
+    #[test]
+    fn test_port_limits() {
+        let limits: PortLimits = "80=100, 5432=20".parse().unwrap();
+        assert_eq!(limits.get(80), Some(100));
+        assert_eq!(limits.get(5432), Some(20));
+        assert_eq!(limits.get(443), None);
+
+        assert!("80=1,80=2".parse::<PortLimits>().is_err());
+        assert!("80=0".parse::<PortLimits>().is_err());
+        assert!("80".parse::<PortLimits>().is_err());
+        assert!("http=5".parse::<PortLimits>().is_err());
+        assert!("".parse::<PortLimits>().is_err());
+    }
+
+    #[test]
+    fn test_port_priorities() {
+        let priorities: PortPriorities = "22=high, 9000=low".parse().unwrap();
+        assert_eq!(priorities.get(22), Priority::High);
+        assert_eq!(priorities.get(9000), Priority::Low);
+        assert_eq!(priorities.get(80), Priority::Normal);
+
+        assert!("22=high,22=low".parse::<PortPriorities>().is_err());
+        assert!("22=urgent".parse::<PortPriorities>().is_err());
+        assert!("22".parse::<PortPriorities>().is_err());
+        assert!("".parse::<PortPriorities>().is_err());
+    }
+
+    #[test]
+    fn test_service_map() {
+        let services: ServiceMap = "jupyter=8888, api=8000".parse().unwrap();
+        assert_eq!(services.get("jupyter"), Some(8888));
+        assert_eq!(services.get("api"), Some(8000));
+        assert_eq!(services.get("db"), None);
+
+        assert!("api=8000,api=9000".parse::<ServiceMap>().is_err());
+        assert!("api=0".parse::<ServiceMap>().is_err());
+        assert!("my api=8000".parse::<ServiceMap>().is_err());
+        assert!("=8000".parse::<ServiceMap>().is_err());
+        assert!("api".parse::<ServiceMap>().is_err());
+        assert!("".parse::<ServiceMap>().is_err());
+    }
+
+    #[test]
+    fn test_mux_container() {
+        let container: MuxContainer = "web-2=172.17.0.3".parse().unwrap();
+        assert_eq!(container.id, "web-2");
+        assert_eq!(container.host, IpAddr::from([172, 17, 0, 3]));
+        assert_eq!(
+            " db = ::1 ".parse::<MuxContainer>().unwrap().host,
+            IpAddr::from(std::net::Ipv6Addr::LOCALHOST)
+        );
+
+        assert!("web".parse::<MuxContainer>().is_err());
+        assert!("=10.0.0.1".parse::<MuxContainer>().is_err());
+        assert!("a,b=10.0.0.1".parse::<MuxContainer>().is_err());
+        assert!("web=example.com".parse::<MuxContainer>().is_err());
+    }
+
+    #[test]
+    fn test_runner_pattern() {
+        let matches = |pattern: &str, url: &str| {
+            let url = url::Url::parse(url).unwrap();
+            pattern
+                .parse::<RunnerPattern>()
+                .unwrap()
+                .matches(&url.host().unwrap())
+        };
+        assert!(matches(
+            "Runner-2.example.com",
+            "wss://runner-2.EXAMPLE.com:8001"
+        ));
+        assert!(!matches(
+            "runner-2.example.com",
+            "wss://runner-3.example.com"
+        ));
+
+        assert!(matches("*.example.com", "wss://runner-2.example.com"));
+        assert!(matches("*.example.com", "wss://a.b.example.com"));
+        assert!(!matches("*.example.com", "wss://example.com"));
+        assert!(!matches("*.example.com", "wss://evilexample.com"));
+
+        assert!(matches("10.0.0.0/8", "ws://10.20.30.40:8001"));
+        assert!(!matches("10.0.0.0/8", "ws://11.0.0.1"));
+        assert!(matches("0.0.0.0/0", "ws://192.168.1.1"));
+        assert!(matches("10.1.2.3", "ws://10.1.2.3"));
+        assert!(!matches("10.1.2.3", "ws://10.1.2.4"));
+        assert!(matches("fd00::/8", "ws://[fd12::1]:8001"));
+        assert!(!matches("fd00::/8", "ws://[fe80::1]"));
+        assert!(matches("[::1]", "ws://[::1]"));
+        // Names are never resolved, so a block doesn't match them
+        assert!(!matches("127.0.0.0/8", "ws://localhost"));
+        assert!(!matches("10.0.0.0/8", "ws://[::ffff:10.0.0.1]"));
+
+        for bad in ["", "*", "*.", "a..b", "10.0.0.0/33", "::/129", "x/8", "a b"] {
+            assert!(bad.parse::<RunnerPattern>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_port_set() {
+        let set: PortSet = "22, 80,8000-8100".parse().unwrap();
+        assert!(set.contains(22));
+        assert!(set.contains(8000));
+        assert!(set.contains(8100));
+        assert!(!set.contains(8101));
+        assert!(!set.contains(443));
+        assert_eq!(set.to_string(), "22,80,8000-8100");
+
+        assert!("".parse::<PortSet>().is_err());
+        assert!("90-80".parse::<PortSet>().is_err());
+        assert!("http".parse::<PortSet>().is_err());
+    }
+}
@@ -1,341 +1,5065 @@
-//! Connection handling for TCP and UDP forwarding.
-//!
-//! Manages individual connections from the tunnel to local services.
-
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
-
-use anyhow::{Context, Result};
-use bytes::Bytes;
-use futures_util::stream::SplitSink;
-use futures_util::SinkExt;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::WebSocketStream;
-use tracing::{debug, error, info, warn};
-
-use crate::protocol::{self, Proto};
-
-/// Type alias for the WebSocket sender
-pub type WsSender = Arc<Mutex<SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>, Message>>>;
-
-/// Represents an active connection with a channel for sending data
-struct ActiveConnection {
-    /// Channel to send data to the TCP/UDP writer
-    data_tx: mpsc::Sender<Bytes>,
-    /// Task handle for cleanup
-    _handle: tokio::task::JoinHandle<()>,
-}
-
-/// Manages all active connections for this tunnel client
-pub struct ConnectionManager {
-    /// Map of client_id -> active connection
-    connections: HashMap<u32, ActiveConnection>,
-    /// WebSocket sender for sending messages back to runner
-    ws_sender: WsSender,
-}
-
-impl ConnectionManager {
-    pub fn new(ws_sender: WsSender) -> Self {
-        Self {
-            connections: HashMap::new(),
-            ws_sender,
-        }
-    }
-
-    /// Handle a CONNECT message - open connection to local service
-    pub async fn handle_connect(&mut self, client_id: u32, proto: Proto, port: u16) {
-        info!(
-            client_id,
-            port,
-            proto = %proto,
-            "Opening connection"
-        );
-
-        // Check if connection already exists
-        if self.connections.contains_key(&client_id) {
-            warn!(client_id, "Connection already exists, ignoring duplicate CONNECT");
-            return;
-        }
-
-        // Create channel for forwarding data to the connection
-        let (data_tx, data_rx) = mpsc::channel::<Bytes>(256);
-        let ws_sender = self.ws_sender.clone();
-
-        // Spawn connection handler based on protocol
-        let handle = match proto {
-            Proto::Tcp => {
-                tokio::spawn(async move {
-                    if let Err(e) = handle_tcp_connection(client_id, port, ws_sender, data_rx).await {
-                        error!(client_id, error = %e, "TCP connection failed");
-                    }
-                })
-            }
-            Proto::Udp => {
-                tokio::spawn(async move {
-                    if let Err(e) = handle_udp_connection(client_id, port, ws_sender, data_rx).await {
-                        error!(client_id, error = %e, "UDP connection failed");
-                    }
-                })
-            }
-        };
-
-        self.connections.insert(client_id, ActiveConnection {
-            data_tx,
-            _handle: handle,
-        });
-    }
-
-    /// Handle a DATA message - forward to the appropriate connection
-    pub async fn handle_data(&self, client_id: u32, proto: Proto, data: &[u8]) {
-        debug!(
-            client_id,
-            proto = %proto,
-            len = data.len(),
-            "Forwarding data to connection"
-        );
-
-        if let Some(conn) = self.connections.get(&client_id) {
-            let data_bytes = Bytes::copy_from_slice(data);
-            if let Err(e) = conn.data_tx.send(data_bytes).await {
-                warn!(client_id, error = %e, "Failed to send data to connection");
-            }
-        } else {
-            warn!(client_id, "DATA for unknown connection");
-        }
-    }
-
-    /// Handle a CLOSE message - close the connection
-    pub async fn handle_close(&mut self, client_id: u32) {
-        info!(client_id, "Closing connection");
-
-        if let Some(conn) = self.connections.remove(&client_id) {
-            // Dropping the connection will:
-            // 1. Close the data channel (signals writer to stop)
-            // 2. Abort the task handle
-            drop(conn);
-        }
-    }
-
-    /// Handle a PING message - respond with PONG
-    pub async fn handle_ping(&self, client_id: u32) {
-        debug!(client_id, "Received PING, sending PONG");
-
-        let pong = protocol::build_pong(client_id);
-        if let Err(e) = self.send_message(pong).await {
-            error!(error = %e, "Failed to send PONG");
-        }
-    }
-
-    /// Send a message through the WebSocket
-    async fn send_message(&self, data: Bytes) -> Result<()> {
-        let mut sender = self.ws_sender.lock().await;
-        sender
-            .send(Message::Binary(data.to_vec().into()))
-            .await
-            .context("Failed to send WebSocket message")?;
-        Ok(())
-    }
-
-    /// Shutdown all connections
-    pub async fn shutdown(&mut self) {
-        info!("Shutting down all connections");
-        for (client_id, conn) in self.connections.drain() {
-            debug!(client_id, "Closing connection");
-            drop(conn);
-        }
-    }
-}
-
-// =============================================================================
-// TCP Connection Handler
-// =============================================================================
-
-/// Handle a single TCP connection to a local service
-async fn handle_tcp_connection(
-    client_id: u32,
-    port: u16,
-    ws_sender: WsSender,
-    mut data_rx: mpsc::Receiver<Bytes>,
-) -> Result<()> {
-    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
-
-    // Connect to local service
-    let stream = match TcpStream::connect(addr).await {
-        Ok(s) => {
-            info!(client_id, port, "TCP connection established");
-            s
-        }
-        Err(e) => {
-            error!(client_id, port, error = %e, "Failed to connect to local service");
-
-            // Send ERROR message back
-            let error_msg = protocol::build_error(Proto::Tcp, client_id, &e.to_string());
-            let mut sender = ws_sender.lock().await;
-            let _ = sender.send(Message::Binary(error_msg.to_vec().into())).await;
-
-            return Err(e.into());
-        }
-    };
-
-    // Send CONNECTED message
-    let connected = protocol::build_connected(Proto::Tcp, client_id);
-    {
-        let mut sender = ws_sender.lock().await;
-        sender
-            .send(Message::Binary(connected.to_vec().into()))
-            .await
-            .context("Failed to send CONNECTED")?;
-    }
-
-    let (mut reader, mut writer) = stream.into_split();
-
-    // Task to read from TCP and send to WebSocket
-    let ws_sender_clone = ws_sender.clone();
-    let read_task = tokio::spawn(async move {
-        let mut buf = vec![0u8; 65536];
-        loop {
-            match reader.read(&mut buf).await {
-                Ok(0) => {
-                    debug!(client_id, "TCP connection closed by remote");
-                    break;
-                }
-                Ok(n) => {
-                    debug!(client_id, bytes = n, "Read from TCP, sending to WebSocket");
-                    let data = protocol::build_data(Proto::Tcp, client_id, &buf[..n]);
-                    let mut sender = ws_sender_clone.lock().await;
-                    if sender.send(Message::Binary(data.to_vec().into())).await.is_err() {
-                        break;
-                    }
-                }
-                Err(e) => {
-                    error!(client_id, error = %e, "TCP read error");
-                    break;
-                }
-            }
-        }
-
-        // Send CLOSE message
-        let close = protocol::build_close(Proto::Tcp, client_id);
-        let mut sender = ws_sender_clone.lock().await;
-        let _ = sender.send(Message::Binary(close.to_vec().into())).await;
-    });
-
-    // Task to receive data from channel and write to TCP
-    let write_task = tokio::spawn(async move {
-        while let Some(data) = data_rx.recv().await {
-            debug!(client_id, bytes = data.len(), "Writing to TCP");
-            if let Err(e) = writer.write_all(&data).await {
-                error!(client_id, error = %e, "TCP write error");
-                break;
-            }
-            if let Err(e) = writer.flush().await {
-                error!(client_id, error = %e, "TCP flush error");
-                break;
-            }
-        }
-        debug!(client_id, "Write task ending (channel closed)");
-    });
-
-    // Wait for either task to complete
-    tokio::select! {
-        _ = read_task => {
-            debug!(client_id, "Read task completed");
-        }
-        _ = write_task => {
-            debug!(client_id, "Write task completed");
-        }
-    }
-
-    Ok(())
-}
-
-// =============================================================================
-// UDP Connection Handler
-// =============================================================================
-
-/// Handle a single UDP "connection" to a local service
-async fn handle_udp_connection(
-    client_id: u32,
-    port: u16,
-    ws_sender: WsSender,
-    mut data_rx: mpsc::Receiver<Bytes>,
-) -> Result<()> {
-    // Bind to a random local port
-    let socket = UdpSocket::bind("127.0.0.1:0").await?;
-    let target: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
-
-    // Connect the UDP socket to the target (allows send/recv instead of send_to/recv_from)
-    socket.connect(target).await?;
-
-    info!(client_id, port, "UDP socket ready");
-
-    // Send CONNECTED message
-    let connected = protocol::build_connected(Proto::Udp, client_id);
-    {
-        let mut sender = ws_sender.lock().await;
-        sender
-            .send(Message::Binary(connected.to_vec().into()))
-            .await
-            .context("Failed to send CONNECTED")?;
-    }
-
-    // Split socket for concurrent read/write
-    let socket = Arc::new(socket);
-    let socket_read = socket.clone();
-    let socket_write = socket.clone();
-
-    // Task to read from UDP and send to WebSocket
-    let ws_sender_clone = ws_sender.clone();
-    let read_task = tokio::spawn(async move {
-        let mut buf = vec![0u8; 65536];
-        loop {
-            match socket_read.recv(&mut buf).await {
-                Ok(n) => {
-                    debug!(client_id, bytes = n, "Read from UDP, sending to WebSocket");
-                    let data = protocol::build_data(Proto::Udp, client_id, &buf[..n]);
-                    let mut sender = ws_sender_clone.lock().await;
-                    if sender.send(Message::Binary(data.to_vec().into())).await.is_err() {
-                        break;
-                    }
-                }
-                Err(e) => {
-                    error!(client_id, error = %e, "UDP recv error");
-                    break;
-                }
-            }
-        }
-
-        // Send CLOSE message
-        let close = protocol::build_close(Proto::Udp, client_id);
-        let mut sender = ws_sender_clone.lock().await;
-        let _ = sender.send(Message::Binary(close.to_vec().into())).await;
-    });
-
-    // Task to receive data from channel and write to UDP
-    let write_task = tokio::spawn(async move {
-        while let Some(data) = data_rx.recv().await {
-            debug!(client_id, bytes = data.len(), "Writing to UDP");
-            if let Err(e) = socket_write.send(&data).await {
-                error!(client_id, error = %e, "UDP send error");
-                break;
-            }
-        }
-        debug!(client_id, "UDP write task ending (channel closed)");
-    });
-
-    // Wait for either task to complete
-    tokio::select! {
-        _ = read_task => {
-            debug!(client_id, "UDP read task completed");
-        }
-        _ = write_task => {
-            debug!(client_id, "UDP write task completed");
-        }
-    }
-
-    Ok(())
-}
+//! Connection handling for TCP and UDP forwarding.
+//!
+//! Manages individual connections from the tunnel to local services.
+//!
+//! # Ordering
+//!
+//! Bytes from the runner reach a local TCP service in the order the runner
+//! sent them, however DATA for different connections is interleaved. Each
+//! connection has one queue ([`spill::data_queue`]) with one producer, the
+//! [`ConnectionManager`], which handles messages one at a time in arrival
+//! order, and one consumer, the connection's write task, which writes each
+//! payload in full before taking the next. Overflow spilled to disk is
+//! replayed ahead of anything queued later, and coalescing only joins
+//! adjacent payloads. UDP and raw connections hand datagrams to the local
+//! socket in the same order. Nothing is promised across connections.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use clap::ValueEnum;
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::tcp;
+#[cfg(feature = "udp")]
+use tokio::net::UdpSocket;
+#[cfg(unix)]
+use tokio::net::{unix, UnixStream};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{mpsc, oneshot, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+#[cfg(feature = "udp")]
+use crate::affinity::{self, UdpAffinity};
+use crate::audit::{CloseReason, ConnAudit};
+use crate::bytecount::{self, ByteCounter};
+#[cfg(feature = "compression")]
+use crate::compress::{Compressor, Decompressor};
+use crate::config::Target;
+use crate::crypto::PayloadCipher;
+#[cfg(feature = "udp")]
+use crate::datagram::{self, DatagramQueue, TrySend, UdpOverflow};
+use crate::delta::{DeltaDecoder, DeltaEncoder};
+use crate::egress::{Egress, Priority};
+use crate::error::{Result, TunnelError};
+use crate::hexdump;
+use crate::metrics::Metrics;
+use crate::policy::{AllowAll, ConnectPolicy, PolicyDecision, PolicyHandle};
+use crate::protocol::{self, Capabilities, MsgType, Proto};
+#[cfg(feature = "raw")]
+use crate::raw::{self, RawSocket};
+use crate::resolver::{ConfigResolver, ServiceResolver};
+use crate::resume::ResumeState;
+use crate::spill::{self, DataReceiver, DataSender, MemoryBudget, SendError, Spill, SpillPool};
+use crate::task::AbortOnDrop;
+use crate::throttle::throttled;
+use crate::transport::TransportSender;
+use crate::tunnel::TunnelConfig;
+use crate::upstream::{ActiveGuard, Candidates, UpstreamPools};
+use crate::webhook::Webhook;
+
+/// Former name of [`TransportSender`], from when only WebSockets were supported
+#[deprecated(note = "use transport::TransportSender")]
+pub type WsSender = TransportSender;
+
+/// How long a closed client_id is remembered so late DATA is ignored quietly
+const CLOSED_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Upper bound on remembered closed client_ids
+const MAX_RECENTLY_CLOSED: usize = 1024;
+
+/// How long local EOF waits for queued runner data to be written before CLOSE
+const EOF_FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Most bytes of queued runner data joined into one local TCP write (the
+/// chunk that crosses it is still included)
+const COALESCE_MAX_BYTES: usize = 64 * 1024;
+
+/// Most queued chunks of runner data joined into one local TCP write
+const COALESCE_MAX_CHUNKS: usize = 64;
+
+/// Represents an active connection with a channel for sending data
+struct ActiveConnection {
+    /// Queue to send data to the TCP/UDP writer
+    data_tx: DataSender,
+    /// Resume bookkeeping (None = connection ends with the WebSocket)
+    resume: Option<Arc<ResumeState>>,
+    /// Audit state, for recording why the manager closed the connection
+    audit: Arc<ConnAudit>,
+    /// Previous payload from the runner (None = delta encoding not in use)
+    delta: Option<DeltaDecoder>,
+    /// Gzip stream from the runner (None = compression not in use)
+    #[cfg(feature = "compression")]
+    inflate: Option<Decompressor>,
+    /// Bytes received from the runner (None = byte counts not in use)
+    received: Option<ByteCounter>,
+    /// Shared with the handler's [`ConnContext::reset`]
+    reset: Arc<AtomicBool>,
+    /// Shared with the handler's [`ConnContext::runner_spoke`]
+    runner_spoke: Arc<AtomicBool>,
+    /// Shared with the handler's [`ConnContext::runner_eof`]
+    runner_eof: Arc<Notify>,
+    /// Task handle for cleanup
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl ActiveConnection {
+    /// Whether the connection's handler is still running. A finished one is
+    /// only waiting for the runner's CLOSE, so its id can be recycled.
+    fn is_live(&self) -> bool {
+        !self.handle.is_finished()
+    }
+
+    /// Drop the connection, recording `reason` in its audit log unless the
+    /// handler already ended it
+    fn close(self, reason: CloseReason) {
+        self.audit.close_reason(reason);
+    }
+}
+
+impl Drop for ActiveConnection {
+    /// Dropping a `JoinHandle` only detaches the task, so stop it explicitly;
+    /// the handler's read and write tasks go with it
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// What to do when a CONNECT reuses the id of a connection that is still live
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateIdPolicy {
+    /// Keep the existing connection and answer the CONNECT with an ERROR
+    #[default]
+    Reject,
+    /// Close the existing connection and open the new one
+    Replace,
+}
+
+/// Per-connection state handed to the TCP/UDP handlers
+#[derive(Clone)]
+struct ConnContext {
+    client_id: u32,
+    port: u16,
+    transport: TransportSender,
+    /// Priority-ordered path for DATA to the runner (None = send directly)
+    egress: Option<Egress>,
+    /// Egress class of this connection, from its requested port
+    priority: Priority,
+    cipher: Option<Arc<PayloadCipher>>,
+    /// Upstream pool candidates (None = connect to `port` directly)
+    upstream: Option<Candidates>,
+    /// Slots for in-progress local connects (None = unlimited)
+    connect_slots: Option<Arc<Semaphore>>,
+    metrics: Arc<Metrics>,
+    /// Last time this connection carried data, for idle keepalives
+    activity: Activity,
+    /// Idle time before a keepalive frame is sent (zero = disabled)
+    keepalive_interval: Duration,
+    /// Idle time before kernel keepalive probes on the local socket (zero =
+    /// OS default)
+    tcp_keepalive: Duration,
+    /// Set Don't Fragment on the local UDP socket
+    #[cfg(feature = "udp")]
+    udp_dont_fragment: bool,
+    /// Overflow policy for datagrams queued to the runner
+    #[cfg(feature = "udp")]
+    udp_overflow: UdpOverflow,
+    /// Time to finish writing queued datagrams after the local socket failed
+    #[cfg(feature = "udp")]
+    udp_close_drain: Duration,
+    /// Whether queued datagrams may share a BATCH (negotiated with the
+    /// runner)
+    #[cfg(feature = "udp")]
+    udp_batch: bool,
+    /// Local ports to bind again for this client ID (None = fresh ports)
+    #[cfg(feature = "udp")]
+    udp_affinity: Option<Arc<UdpAffinity>>,
+    /// Resume bookkeeping (None = connection ends with the WebSocket)
+    resume: Option<Arc<ResumeState>>,
+    /// Local address outbound sockets bind to (None = chosen by the OS)
+    source_addr: Option<IpAddr>,
+    /// Host the local service runs on (None = loopback)
+    target_host: Option<IpAddr>,
+    /// Unix socket the local service listens on, replacing host and port
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+    audit: Arc<ConnAudit>,
+    /// Receives the connection's open and close events (None = no webhook)
+    webhook: Option<Arc<Webhook>>,
+    /// Whether payloads to the runner may be sent as DELTA
+    delta: bool,
+    /// Whether the TCP stream is gzip-compressed in both directions
+    #[cfg(feature = "compression")]
+    compression: bool,
+    /// Bytes sent to the runner, carried in every TCP DATA (None = byte
+    /// counts not in use)
+    sent: Option<Arc<AtomicU64>>,
+    /// Set when the runner aborted the connection, so the local socket is
+    /// reset (RST) rather than shut down (FIN)
+    reset: Arc<AtomicBool>,
+    /// Whether the runner parses CLOSE payload flags, so an abort can be
+    /// told from a clean close
+    close_flags: bool,
+    /// Close the connection if the runner sends nothing this long after
+    /// CONNECTED (zero = never)
+    runner_silence_timeout: Duration,
+    /// Set once the runner has sent DATA for the connection
+    runner_spoke: Arc<AtomicBool>,
+    /// Notified when the runner half-closes the connection
+    runner_eof: Arc<Notify>,
+    /// Leading payload bytes hex-dumped at trace level (0 = none)
+    trace_payloads: usize,
+    /// Largest DATA or BATCH payload sent to the runner
+    max_payload: usize,
+}
+
+/// Last time a connection carried data in either direction
+#[derive(Clone)]
+struct Activity(Arc<std::sync::Mutex<Instant>>);
+
+impl Activity {
+    fn new() -> Self {
+        Self(Arc::new(std::sync::Mutex::new(Instant::now())))
+    }
+
+    fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl ConnContext {
+    /// Build a DATA frame, sealing the payload when encryption is enabled
+    fn data_frame(&self, proto: Proto, data: &[u8]) -> Bytes {
+        data_frame(self.cipher.as_deref(), proto, self.client_id, data)
+    }
+
+    /// Send a DATA (or DELTA) frame to the runner, through the egress queue
+    /// at this connection's priority when port priorities are configured
+    async fn send_data(&self, frame: Bytes) -> Result<()> {
+        match &self.egress {
+            Some(egress) => egress.send(self.priority, frame).await,
+            None => self.transport.lock().await.send(frame).await,
+        }
+    }
+
+    /// Bytes sealing adds to each payload (or datagram in a BATCH)
+    fn seal_overhead(&self) -> usize {
+        self.cipher
+            .as_ref()
+            .map_or(0, |_| crate::crypto::NONCE_SIZE + crate::crypto::TAG_SIZE)
+    }
+
+    /// Largest piece of local TCP data one DATA payload can carry
+    fn max_tcp_chunk(&self) -> usize {
+        let count = self.sent.as_ref().map_or(0, |_| bytecount::COUNT_SIZE);
+        self.max_payload - self.seal_overhead() - count
+    }
+
+    /// Build the frame for data read from the local service: a DELTA when
+    /// `encoder` has one smaller than the payload, DATA otherwise
+    fn outgoing_frame(
+        &self,
+        encoder: Option<&mut DeltaEncoder>,
+        proto: Proto,
+        data: &[u8],
+    ) -> Bytes {
+        hexdump::trace_payload(self.trace_payloads, self.client_id, "to runner", data);
+        if let Some(sent) = self.sent.as_ref().filter(|_| proto == Proto::Tcp) {
+            let total = sent.fetch_add(data.len() as u64, Ordering::Relaxed) + data.len() as u64;
+            return self.data_frame(proto, &bytecount::prefix(total, data));
+        }
+        let Some(delta) = encoder.and_then(|encoder| encoder.encode(data)) else {
+            return self.data_frame(proto, data);
+        };
+        match self.cipher.as_deref() {
+            Some(cipher) => {
+                let sealed = cipher.seal_as(MsgType::Delta, proto, self.client_id, &delta);
+                protocol::build_delta(proto, self.client_id, &sealed)
+            }
+            None => protocol::build_delta(proto, self.client_id, &delta),
+        }
+    }
+
+    /// Build the frame for datagrams read from the local service: a BATCH
+    /// for several, as [`Self::outgoing_frame`] for one
+    #[cfg(feature = "udp")]
+    fn udp_frame(&self, encoder: Option<&mut DeltaEncoder>, datagrams: &[Bytes]) -> Bytes {
+        if let [datagram] = datagrams {
+            return self.outgoing_frame(encoder, Proto::Udp, datagram);
+        }
+        let payloads: Vec<Bytes> = datagrams
+            .iter()
+            .map(|datagram| {
+                hexdump::trace_payload(self.trace_payloads, self.client_id, "to runner", datagram);
+                match self.cipher.as_deref() {
+                    Some(cipher) => cipher.seal(Proto::Udp, self.client_id, datagram).into(),
+                    None => datagram.clone(),
+                }
+            })
+            .collect();
+        protocol::build_batch(self.client_id, &payloads)
+    }
+
+    /// Wait for a free connect slot; the connect counts as pending until the
+    /// returned guard is dropped
+    async fn connect_slot(&self) -> PendingConnect {
+        let permit = match &self.connect_slots {
+            Some(slots) => slots.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        self.metrics.connect_started();
+        PendingConnect {
+            _permit: permit,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Spawn one of the connection's read/write tasks, counted while it runs
+    fn spawn<F>(&self, future: F) -> AbortOnDrop<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        AbortOnDrop(spawn_counted(&self.metrics, future))
+    }
+}
+
+/// Build a DATA frame, sealing the payload when a cipher is given
+fn data_frame(cipher: Option<&PayloadCipher>, proto: Proto, client_id: u32, data: &[u8]) -> Bytes {
+    match cipher {
+        Some(cipher) => {
+            let sealed = cipher.seal(proto, client_id, data);
+            protocol::build_data(proto, client_id, &sealed)
+        }
+        None => protocol::build_data(proto, client_id, data),
+    }
+}
+
+/// A local connect in progress, holding one of the connect slots
+struct PendingConnect {
+    _permit: Option<OwnedSemaphorePermit>,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for PendingConnect {
+    fn drop(&mut self) {
+        self.metrics.connect_finished();
+    }
+}
+
+/// Tasks a connection may run at once: its handler, plus a read and a write
+/// task
+const TASKS_PER_CONNECTION: u64 = 3;
+
+/// Counts a connection task in [`Metrics::tasks`] until dropped
+struct TaskSlot(Arc<Metrics>);
+
+impl TaskSlot {
+    fn new(metrics: &Arc<Metrics>) -> Self {
+        metrics.task_started();
+        Self(metrics.clone())
+    }
+}
+
+impl Drop for TaskSlot {
+    fn drop(&mut self) {
+        self.0.task_ended();
+    }
+}
+
+/// Spawn a connection task, counted while it runs (or until aborted)
+fn spawn_counted<F>(metrics: &Arc<Metrics>, future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let slot = TaskSlot::new(metrics);
+    tokio::spawn(async move {
+        let _slot = slot;
+        future.await
+    })
+}
+
+/// Counts a connection against its requested port's limit until dropped
+struct PortSlot(Arc<AtomicUsize>);
+
+impl Drop for PortSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A CONNECT for a named service whose port lookup is still running
+struct Resolving {
+    /// Tells this lookup's answer from one for an earlier CONNECT that
+    /// reused the client_id
+    lookup: u64,
+    proto: Proto,
+    service: String,
+    /// What the runner sent for the connection meanwhile, replayed once it
+    /// is open
+    held: Vec<Held>,
+    _task: AbortOnDrop<()>,
+}
+
+/// A message for a connection whose service is still being resolved
+enum Held {
+    Payload(MsgType, Proto, Bytes),
+    HalfClose,
+}
+
+/// A finished service lookup, for [`ConnectionManager::finish_resolved`]
+#[derive(Debug)]
+pub struct Resolved {
+    client_id: u32,
+    lookup: u64,
+    port: Option<u16>,
+}
+
+/// Manages all active connections for this tunnel client
+pub struct ConnectionManager {
+    /// Map of client_id -> active connection
+    connections: HashMap<u32, ActiveConnection>,
+    /// Transport for sending messages back to the runner
+    transport: TransportSender,
+    /// Orders connection DATA by port priority (None = no priorities set)
+    egress: Option<Egress>,
+    /// Tunnel configuration
+    config: Arc<TunnelConfig>,
+    /// Shared disk budget for overflow buffering (None = spilling disabled)
+    spill_pool: Option<Arc<SpillPool>>,
+    /// Memory all connections' queues may hold together (None = unbounded)
+    buffer_budget: Option<Arc<MemoryBudget>>,
+    /// client_id -> close time, for distinguishing late DATA from bogus ids
+    recently_closed: HashMap<u32, Instant>,
+    /// DATA payload cipher (None = encryption disabled)
+    cipher: Option<Arc<PayloadCipher>>,
+    /// Requested port -> pool of local upstreams
+    upstream_pools: UpstreamPools,
+    /// Protocol/port allowlists, swapped on config reload
+    policy: PolicyHandle,
+    /// Bounds concurrent local connects (None = unlimited)
+    connect_slots: Option<Arc<Semaphore>>,
+    metrics: Arc<Metrics>,
+    /// Embedder-supplied authorization, consulted after the allowlists
+    connect_policy: Arc<dyn ConnectPolicy>,
+    /// Looks up the ports of services named in CONNECTs
+    resolver: Arc<dyn ServiceResolver>,
+    /// client_id -> CONNECT waiting on a slow service lookup
+    resolving: HashMap<u32, Resolving>,
+    /// Lookups started so far, numbering the next one
+    lookups: u64,
+    /// Answers of the lookups running off the message loop
+    resolved_tx: mpsc::UnboundedSender<Resolved>,
+    resolved_rx: mpsc::UnboundedReceiver<Resolved>,
+    /// Features and message types from the runner's VERSION
+    runner: Capabilities,
+    /// Whether the runner advertised stream resume in its VERSION
+    runner_resume: bool,
+    /// Whether both sides advertised delta encoding
+    runner_delta: bool,
+    /// Whether both sides advertised compression
+    runner_compression: bool,
+    /// Whether the runner accepts BATCH messages
+    runner_udp_batch: bool,
+    /// Whether both sides advertised byte counts
+    runner_byte_count: bool,
+    /// Shutting down: existing connections finish, new CONNECTs are refused
+    draining: bool,
+    /// Live connections per requested port, for ports with a limit
+    port_active: HashMap<u16, Arc<AtomicUsize>>,
+    /// Host connections go to instead of loopback
+    target_host: Option<IpAddr>,
+    /// Local UDP ports remembered across sessions (None = affinity off)
+    #[cfg(feature = "udp")]
+    udp_affinity: Option<Arc<UdpAffinity>>,
+    /// Receives connection open and close events (None = no webhook)
+    webhook: Option<Arc<Webhook>>,
+}
+
+impl ConnectionManager {
+    pub fn new(transport: TransportSender, config: Arc<TunnelConfig>) -> Self {
+        let policy = PolicyHandle::from_config(&config);
+        Self::with_policy(transport, config, policy)
+    }
+
+    /// Create a manager whose access rules follow a shared [`PolicyHandle`]
+    pub fn with_policy(
+        transport: TransportSender,
+        config: Arc<TunnelConfig>,
+        policy: PolicyHandle,
+    ) -> Self {
+        let spill_pool = config
+            .spill_dir
+            .as_ref()
+            .map(|dir| Arc::new(SpillPool::new(dir, config.spill_max_bytes)));
+        let buffer_budget = config
+            .max_total_buffer_bytes
+            .map(|max_bytes| Arc::new(MemoryBudget::new(max_bytes)));
+        let upstream_pools = UpstreamPools::new(&config.upstream_pools, config.upstream_policy);
+        let cipher = config
+            .psk
+            .as_deref()
+            .map(|psk| Arc::new(PayloadCipher::new(psk)));
+        let resolver = Arc::new(ConfigResolver::from_config(&config));
+        let connect_slots = (config.max_pending_connects > 0)
+            .then(|| Arc::new(Semaphore::new(config.max_pending_connects)));
+        let egress = (!config.port_priorities.is_empty()).then(|| Egress::spawn(transport.clone()));
+        #[cfg(feature = "udp")]
+        let udp_affinity = config.udp_port_affinity.then(Arc::default);
+        let webhook = Webhook::from_config(&config).map(Arc::new);
+        let (resolved_tx, resolved_rx) = mpsc::unbounded_channel();
+        Self {
+            connections: HashMap::new(),
+            transport,
+            egress,
+            config,
+            spill_pool,
+            buffer_budget,
+            recently_closed: HashMap::new(),
+            cipher,
+            upstream_pools,
+            policy,
+            connect_slots,
+            metrics: Arc::new(Metrics::default()),
+            connect_policy: Arc::new(AllowAll),
+            resolver,
+            resolving: HashMap::new(),
+            lookups: 0,
+            resolved_tx,
+            resolved_rx,
+            runner: Capabilities::default(),
+            runner_resume: false,
+            runner_delta: false,
+            runner_compression: false,
+            runner_udp_batch: false,
+            runner_byte_count: false,
+            draining: false,
+            port_active: HashMap::new(),
+            target_host: None,
+            #[cfg(feature = "udp")]
+            udp_affinity,
+            webhook,
+        }
+    }
+
+    /// Consult a custom [`ConnectPolicy`] for every CONNECT
+    pub fn with_connect_policy(mut self, policy: Arc<dyn ConnectPolicy>) -> Self {
+        self.connect_policy = policy;
+        self
+    }
+
+    /// Resolve service names in CONNECTs with `resolver` instead of the
+    /// configured service map and command
+    pub fn with_service_resolver(mut self, resolver: Arc<dyn ServiceResolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Connect to services on `host` instead of loopback, e.g. another
+    /// container served over a multiplexed tunnel
+    pub fn with_target_host(mut self, host: IpAddr) -> Self {
+        self.target_host = Some(host);
+        self
+    }
+
+    /// Remember local UDP ports in `affinity` instead of a private map, so
+    /// they outlive this manager (only with `udp_port_affinity` set)
+    #[cfg(feature = "udp")]
+    pub fn with_udp_affinity(mut self, affinity: Arc<UdpAffinity>) -> Self {
+        if self.config.udp_port_affinity {
+            self.udp_affinity = Some(affinity);
+        }
+        self
+    }
+
+    /// Count queued data against `budget`, shared with other managers,
+    /// instead of a private one (only with `max_total_buffer_bytes` set)
+    pub fn with_buffer_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        if self.buffer_budget.is_some() {
+            self.buffer_budget = Some(budget);
+        }
+        self
+    }
+
+    /// Send connection events through `webhook` instead of a private one
+    /// (only with `webhook_url` set)
+    pub fn with_webhook(mut self, webhook: Arc<Webhook>) -> Self {
+        if self.webhook.is_some() {
+            self.webhook = Some(webhook);
+        }
+        self
+    }
+
+    /// Record into shared metrics instead of a private set
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Transport shared with the connection tasks; replacing the sink inside
+    /// it moves every connection onto a new connection to the runner
+    pub fn transport(&self) -> TransportSender {
+        self.transport.clone()
+    }
+
+    /// Note the runner's VERSION feature bits, for a runner that doesn't
+    /// list the message types it parses (see [`Self::set_runner_capabilities`])
+    pub fn set_runner_features(&mut self, features: u32) {
+        self.set_runner_capabilities(Capabilities::from_features(features));
+    }
+
+    /// Note what the runner's VERSION announced. A feature is only used for
+    /// new connections if the runner advertised it and parses the message
+    /// types it sends, e.g. resume needs ACK since the runner has to send
+    /// ACKs too; anything else falls back to plain DATA and CLOSE.
+    pub fn set_runner_capabilities(&mut self, runner: Capabilities) {
+        self.runner = runner;
+        self.runner_resume = runner.has(protocol::FEATURE_RESUME) && runner.accepts(MsgType::Ack);
+        self.runner_delta = self.config.delta
+            && runner.has(protocol::FEATURE_DELTA)
+            && runner.accepts(MsgType::Delta);
+        self.runner_compression =
+            self.config.compression_enabled() && runner.has(protocol::FEATURE_COMPRESSION);
+        self.runner_byte_count =
+            self.config.verify_byte_counts && runner.has(protocol::FEATURE_BYTE_COUNT);
+        self.runner_udp_batch = self.config.udp_enabled()
+            && runner.has(protocol::FEATURE_UDP_BATCH)
+            && runner.accepts(MsgType::Batch);
+    }
+
+    /// What the runner announced in its VERSION (base messages only until
+    /// it has sent one)
+    pub fn runner_capabilities(&self) -> Capabilities {
+        self.runner
+    }
+
+    /// Stop accepting CONNECTs ahead of shutdown; open connections carry on
+    pub fn begin_drain(&mut self) {
+        self.draining = true;
+    }
+
+    /// Whether new CONNECTs are being refused
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Connections whose handler is still running
+    pub fn live_connections(&self) -> usize {
+        self.connections.values().filter(|c| c.is_live()).count()
+    }
+
+    /// Live connections to a requested port that has a limit (0 otherwise)
+    pub fn port_connections(&self, port: u16) -> usize {
+        self.port_active
+            .get(&port)
+            .map_or(0, |active| active.load(Ordering::Relaxed))
+    }
+
+    /// Handle a CONNECT message - open connection to local service
+    pub async fn handle_connect(&mut self, client_id: u32, proto: Proto, port: u16) {
+        self.handle_connect_labeled(client_id, proto, port, &[])
+            .await;
+    }
+
+    /// Handle a CONNECT message whose payload may carry a connection label,
+    /// which is added to the connection's log lines, audit and metrics. A
+    /// TCP or UDP CONNECT for port 0 names a service instead, whose port the
+    /// [`ServiceResolver`] looks up; the name is then the label. A lookup
+    /// that doesn't answer at once runs off the message loop, and the
+    /// connection opens in [`finish_resolved`](Self::finish_resolved).
+    pub async fn handle_connect_labeled(
+        &mut self,
+        client_id: u32,
+        proto: Proto,
+        port: u16,
+        label: &[u8],
+    ) {
+        let label = match protocol::parse_label(label) {
+            Ok(label) => label,
+            Err(reason) => {
+                warn!(client_id, %reason, "CONNECT with an invalid label, rejecting");
+                self.reject_connect(proto, client_id, &reason).await;
+                return;
+            }
+        };
+        // A CONNECT reusing the client_id replaces one still resolving
+        self.resolving.remove(&client_id);
+        let port = match label {
+            Some(service) if port == 0 && proto != Proto::Raw => {
+                let resolver = self.resolver.clone();
+                let name = service.to_string();
+                let mut lookup: BoxFuture<'static, Option<u16>> =
+                    Box::pin(async move { resolver.resolve(&name).await });
+                match futures_util::poll!(lookup.as_mut()) {
+                    Poll::Ready(port) => {
+                        match self.resolved_port(client_id, proto, service, port).await {
+                            Some(port) => port,
+                            None => return,
+                        }
+                    }
+                    Poll::Pending => {
+                        self.start_lookup(client_id, proto, service, lookup);
+                        return;
+                    }
+                }
+            }
+            _ => port,
+        };
+        self.connect(client_id, proto, port, label, false).await;
+    }
+
+    /// Finish a service lookup off the message loop, holding the runner's
+    /// messages for the connection until it answers
+    fn start_lookup(
+        &mut self,
+        client_id: u32,
+        proto: Proto,
+        service: &str,
+        lookup: BoxFuture<'static, Option<u16>>,
+    ) {
+        debug!(client_id, service, "Resolving service");
+        self.lookups += 1;
+        let id = self.lookups;
+        let resolved_tx = self.resolved_tx.clone();
+        let task = AbortOnDrop(spawn_counted(
+            &self.metrics,
+            async move {
+                let port = lookup.await;
+                let _ = resolved_tx.send(Resolved {
+                    client_id,
+                    lookup: id,
+                    port,
+                });
+            }
+            .in_current_span(),
+        ));
+        self.resolving.insert(
+            client_id,
+            Resolving {
+                lookup: id,
+                proto,
+                service: service.to_string(),
+                held: Vec::new(),
+                _task: task,
+            },
+        );
+    }
+
+    /// The port a service lookup found; for an unknown service, refuse the
+    /// CONNECT instead
+    async fn resolved_port(
+        &self,
+        client_id: u32,
+        proto: Proto,
+        service: &str,
+        port: Option<u16>,
+    ) -> Option<u16> {
+        match port {
+            Some(port) => {
+                debug!(client_id, service, port, "Resolved service");
+                Some(port)
+            }
+            None => {
+                warn!(
+                    client_id,
+                    service, "CONNECT for an unknown service, rejecting"
+                );
+                let reason = format!("Unknown service {service}");
+                self.reject_connect(proto, client_id, &reason).await;
+                None
+            }
+        }
+    }
+
+    /// Poll for a service lookup that finished off the message loop, to be
+    /// passed to [`finish_resolved`](Self::finish_resolved)
+    pub fn poll_resolved(&mut self, cx: &mut Context<'_>) -> Poll<Resolved> {
+        match self.resolved_rx.poll_recv(cx) {
+            Poll::Ready(Some(resolved)) => Poll::Ready(resolved),
+            // The manager keeps a sender, so the channel never closes
+            _ => Poll::Pending,
+        }
+    }
+
+    /// Open the connection a finished lookup was for, then hand it what the
+    /// runner sent meanwhile. Lookups for CONNECTs since closed or replaced
+    /// are ignored.
+    pub async fn finish_resolved(&mut self, resolved: Resolved) {
+        let Resolved {
+            client_id,
+            lookup,
+            port,
+        } = resolved;
+        let pending = match self.resolving.entry(client_id) {
+            Entry::Occupied(entry) if entry.get().lookup == lookup => entry.remove(),
+            _ => return,
+        };
+        let Some(port) = self
+            .resolved_port(client_id, pending.proto, &pending.service, port)
+            .await
+        else {
+            self.remember_closed(client_id);
+            return;
+        };
+        self.connect(
+            client_id,
+            pending.proto,
+            port,
+            Some(&pending.service),
+            false,
+        )
+        .await;
+        for held in pending.held {
+            match held {
+                Held::Payload(msg_type, proto, payload) => {
+                    self.deliver(msg_type, client_id, proto, &payload).await
+                }
+                Held::HalfClose => self.handle_half_close(client_id),
+            }
+        }
+    }
+
+    /// Handle a PROBE message - try the local connect and report CONNECTED or
+    /// ERROR, then close it again without relaying anything. The probe
+    /// doesn't occupy `client_id`.
+    pub async fn handle_probe(&mut self, client_id: u32, proto: Proto, port: u16) {
+        self.connect(client_id, proto, port, None, true).await;
+    }
+
+    async fn connect(
+        &mut self,
+        client_id: u32,
+        proto: Proto,
+        port: u16,
+        label: Option<&str>,
+        probe: bool,
+    ) {
+        info!(
+            client_id,
+            port,
+            proto = %proto,
+            label,
+            probe,
+            "Opening connection"
+        );
+
+        if self.draining {
+            info!(client_id, "Shutting down, rejecting CONNECT");
+            self.reject_connect(proto, client_id, "Tunnel shutting down")
+                .await;
+            return;
+        }
+
+        // Sealed DATA would be unreadable to a runner that can't open it, and
+        // sending it in the clear would defeat --psk
+        if self.cipher.is_some() && !self.runner.has(protocol::FEATURE_ENCRYPTION) {
+            warn!(
+                client_id,
+                "Runner did not announce payload encryption, rejecting CONNECT"
+            );
+            self.reject_connect(
+                proto,
+                client_id,
+                "Runner does not support payload encryption",
+            )
+            .await;
+            return;
+        }
+
+        // Refuse rather than pile more tasks onto the runtime; a probe runs
+        // only one
+        let max_tasks = self.config.max_tasks as u64;
+        let needed = if probe { 1 } else { TASKS_PER_CONNECTION };
+        let tasks = self.metrics.tasks();
+        if max_tasks > 0 && tasks + needed > max_tasks {
+            warn!(
+                client_id,
+                tasks, max_tasks, "Connection task limit reached, rejecting CONNECT"
+            );
+            self.reject_connect(proto, client_id, "Task limit reached")
+                .await;
+            return;
+        }
+
+        // The id is still mapped: either the old connection has ended and
+        // awaits the runner's CLOSE, or the runner's allocator wrapped or
+        // collided onto a live connection
+        if let Some(existing) = self.connections.get(&client_id).filter(|_| !probe) {
+            if !existing.is_live() {
+                debug!(client_id, "Recycling id of a finished connection");
+            } else {
+                match self.config.duplicate_id_policy {
+                    DuplicateIdPolicy::Reject => {
+                        error!(
+                            client_id,
+                            "CONNECT reuses the id of a live connection, rejecting"
+                        );
+                        self.reject_connect(proto, client_id, "Client ID already in use")
+                            .await;
+                        return;
+                    }
+                    DuplicateIdPolicy::Replace => {
+                        // Removing it below aborts the old connection
+                        error!(
+                            client_id,
+                            "CONNECT reuses the id of a live connection, replacing it"
+                        );
+                    }
+                }
+            }
+            if let Some(existing) = self.connections.remove(&client_id) {
+                existing.close(CloseReason::Replaced);
+            }
+        }
+
+        // A reused id is no longer "recently closed"
+        if !probe {
+            self.recently_closed.remove(&client_id);
+        }
+
+        // UDP can be switched off entirely, independent of the allowlist
+        if proto == Proto::Udp && !self.config.udp_enabled() {
+            warn!(
+                client_id,
+                port, "UDP forwarding is disabled, rejecting CONNECT"
+            );
+            self.reject_connect(proto, client_id, "UDP forwarding is disabled")
+                .await;
+            return;
+        }
+
+        // Raw CONNECTs carry an IP protocol number where the port would be
+        if proto == Proto::Raw {
+            #[cfg(feature = "raw")]
+            let refused = raw::protocol_number(port).err();
+            #[cfg(not(feature = "raw"))]
+            let refused = Some("Raw forwarding is not supported".to_string());
+            if let Some(reason) = refused {
+                warn!(client_id, port, %reason, "Rejecting raw CONNECT");
+                self.reject_connect(proto, client_id, &reason).await;
+                return;
+            }
+        }
+
+        // Reject protocols and ports that the current rules don't allow
+        if let Err(reason) = self.policy.current().check(proto, port) {
+            warn!(client_id, port, proto = %proto, %reason, "Rejecting CONNECT");
+            self.reject_connect(proto, client_id, &reason).await;
+            return;
+        }
+        if let PolicyDecision::Deny(reason) = self.connect_policy.allow(client_id, proto, port) {
+            warn!(client_id, port, proto = %proto, %reason, "CONNECT denied by policy");
+            self.reject_connect(proto, client_id, &reason).await;
+            return;
+        }
+
+        // A configured target names the whole destination for its port
+        let target = match proto {
+            Proto::Raw => None,
+            _ => self.config.port_targets.get(port).cloned(),
+        };
+        if let Some(target) = target.as_ref().filter(|target| target.proto() != proto) {
+            warn!(client_id, port, proto = %proto, %target, "CONNECT protocol doesn't match port target");
+            let reason =
+                format!("Port {port} is forwarded to {target}, which {proto} cannot reach");
+            self.reject_connect(proto, client_id, &reason).await;
+            return;
+        }
+
+        // Held by the handler, so the count drops however the connection ends
+        let port_slot = match self
+            .config
+            .port_limits
+            .get(port)
+            .filter(|_| !probe && proto != Proto::Raw)
+        {
+            Some(limit) => {
+                let active = self.port_active.entry(port).or_default().clone();
+                if active.load(Ordering::Relaxed) >= limit {
+                    warn!(
+                        client_id,
+                        port, limit, "Port connection limit reached, rejecting CONNECT"
+                    );
+                    let reason = format!("Connection limit ({limit}) reached for port {port}");
+                    self.reject_connect(proto, client_id, &reason).await;
+                    return;
+                }
+                active.fetch_add(1, Ordering::Relaxed);
+                Some(PortSlot(active))
+            }
+            None => None,
+        };
+
+        // A port target overrides everything else for its port. Then a
+        // forced target replaces the requested port outright; otherwise
+        // pools take precedence over the port map for their requested port
+        let requested_port = port;
+        let forced = match target {
+            Some(_) => None,
+            None => self.config.force_target_port.get(proto),
+        };
+        let upstream = match forced {
+            Some(_) => None,
+            None if proto == Proto::Raw || target.is_some() => None,
+            None => self.upstream_pools.select(port),
+        };
+
+        // Translate the requested port to the local port
+        let local_port = match (&target, forced) {
+            (Some(Target::Tcp(addr) | Target::Udp(addr)), _) => addr.port(),
+            #[cfg(unix)]
+            (Some(Target::Unix(_)), _) => port,
+            (None, Some(forced)) => forced,
+            (None, None) if proto == Proto::Raw => port,
+            (None, None) => self.config.port_map.resolve(port),
+        };
+        if local_port != port {
+            debug!(client_id, port, local_port, "Mapped requested port");
+        }
+        let port = local_port;
+        let target_host = match &target {
+            Some(Target::Tcp(addr) | Target::Udp(addr)) => Some(addr.ip()),
+            _ => self.target_host,
+        };
+        if let Some(target) = &target {
+            debug!(client_id, requested_port, %target, "Forwarding to port target");
+        }
+
+        let resume =
+            (proto == Proto::Tcp && !probe && self.config.resume_enabled() && self.runner_resume)
+                .then(|| {
+                    Arc::new(ResumeState::new(
+                        self.config.resume_buffer,
+                        self.config.resume_grace,
+                    ))
+                });
+        // Byte counts cover TCP only, and not resumable connections, whose
+        // retransmits go out without them
+        let byte_count = self.runner_byte_count && proto == Proto::Tcp && resume.is_none();
+        // Likewise for compression, which also makes deltas pointless
+        let compression =
+            self.runner_compression && proto == Proto::Tcp && resume.is_none() && !byte_count;
+        let udp_batch = self.runner_udp_batch && proto == Proto::Udp;
+        let ctx = ConnContext {
+            client_id,
+            port,
+            transport: self.transport.clone(),
+            egress: self.egress.clone(),
+            priority: self.config.port_priorities.get(requested_port),
+            cipher: self.cipher.clone(),
+            upstream,
+            connect_slots: self.connect_slots.clone(),
+            metrics: self.metrics.clone(),
+            activity: Activity::new(),
+            keepalive_interval: self.config.conn_keepalive_interval,
+            tcp_keepalive: self.config.tcp_keepalive,
+            #[cfg(feature = "udp")]
+            udp_dont_fragment: self.config.udp_dont_fragment,
+            #[cfg(feature = "udp")]
+            udp_overflow: self.config.udp_overflow,
+            #[cfg(feature = "udp")]
+            udp_close_drain: self.config.udp_close_drain,
+            #[cfg(feature = "udp")]
+            udp_batch,
+            #[cfg(feature = "udp")]
+            udp_affinity: self.udp_affinity.clone(),
+            resume: resume.clone(),
+            source_addr: self.config.source_addr,
+            target_host,
+            #[cfg(unix)]
+            unix_socket: match target {
+                Some(Target::Unix(path)) => Some(path),
+                _ => None,
+            },
+            audit: ConnAudit::labeled(client_id, proto, requested_port, label),
+            webhook: self.webhook.clone(),
+            // Off when something else shapes the payloads: retransmits after
+            // a resume go out raw and would leave the runner's reference
+            // behind, and byte counts, compression and batches each rewrite
+            // the payloads the chain of references is built from
+            delta: self.runner_delta
+                && resume.is_none()
+                && !byte_count
+                && !compression
+                && !udp_batch,
+            #[cfg(feature = "compression")]
+            compression,
+            sent: byte_count.then(Arc::default),
+            reset: Arc::default(),
+            close_flags: self.runner.has(protocol::FEATURE_HALF_CLOSE),
+            runner_silence_timeout: self.config.runner_silence_timeout,
+            runner_spoke: Arc::default(),
+            runner_eof: Arc::default(),
+            trace_payloads: self.config.trace_payloads,
+            max_payload: match self.config.max_payload_size {
+                0 => usize::MAX,
+                max => max,
+            },
+        };
+
+        if probe {
+            // A connected UDP or raw socket can't tell whether anything
+            // listens
+            if proto != Proto::Tcp {
+                let reason = format!("{proto} ports cannot be probed");
+                self.reject_connect(proto, client_id, &reason).await;
+                return;
+            }
+            let span = info_span!("probe", client_id, port);
+            spawn_counted(&self.metrics, probe_local_tcp(ctx).instrument(span));
+            return;
+        }
+
+        // Create queue for forwarding data to the connection
+        let spill = self
+            .spill_pool
+            .as_ref()
+            .map(|pool| Spill::new(pool.clone(), client_id));
+        let (data_tx, data_rx) = spill::data_queue(
+            self.config.send_queue_depth.max(1),
+            spill,
+            self.buffer_budget.clone(),
+        );
+
+        // Every log line for this connection carries the span's fields; conn_id
+        // is a short random id for correlating with runner/host logs since
+        // client_ids are reused.
+        let conn_id = new_conn_id();
+        debug!(client_id, conn_id = %conn_id, "Assigned connection id");
+        let span = info_span!(
+            "conn",
+            conn_id = %conn_id,
+            client_id,
+            port,
+            proto = %proto,
+            label
+        );
+        let audit = ctx.audit.clone();
+        let delta = ctx.delta.then(DeltaDecoder::default);
+        let reset = ctx.reset.clone();
+        let runner_spoke = ctx.runner_spoke.clone();
+        let runner_eof = ctx.runner_eof.clone();
+
+        // Spawn connection handler based on protocol
+        let handle = match proto {
+            Proto::Tcp => spawn_counted(
+                &self.metrics,
+                async move {
+                    let _port_slot = port_slot;
+                    if let Err(e) = handle_tcp_connection(ctx, data_rx).await {
+                        error!(client_id, error = %e, "TCP connection failed");
+                    }
+                }
+                .instrument(span),
+            ),
+            #[cfg(feature = "udp")]
+            Proto::Udp => spawn_counted(
+                &self.metrics,
+                async move {
+                    let _port_slot = port_slot;
+                    if let Err(e) = handle_udp_connection(ctx, data_rx).await {
+                        error!(client_id, error = %e, "UDP connection failed");
+                    }
+                }
+                .instrument(span),
+            ),
+            #[cfg(not(feature = "udp"))]
+            Proto::Udp => unreachable!("UDP CONNECTs are rejected without the udp feature"),
+            #[cfg(feature = "raw")]
+            Proto::Raw => spawn_counted(
+                &self.metrics,
+                async move {
+                    let _port_slot = port_slot;
+                    if let Err(e) = handle_raw_connection(ctx, data_rx).await {
+                        error!(client_id, error = %e, "Raw connection failed");
+                    }
+                }
+                .instrument(span),
+            ),
+            #[cfg(not(feature = "raw"))]
+            Proto::Raw => unreachable!("raw CONNECTs are rejected without the raw feature"),
+        };
+
+        self.connections.insert(
+            client_id,
+            ActiveConnection {
+                data_tx,
+                resume,
+                audit,
+                delta,
+                #[cfg(feature = "compression")]
+                inflate: compression.then(Decompressor::default),
+                received: byte_count.then(ByteCounter::default),
+                reset,
+                runner_spoke,
+                runner_eof,
+                handle,
+            },
+        );
+    }
+
+    /// Answer a CONNECT with an ERROR instead of opening a connection
+    async fn reject_connect(&self, proto: Proto, client_id: u32, reason: &str) {
+        let error_msg = protocol::build_error(proto, client_id, reason);
+        if let Err(e) = self.send_message(error_msg).await {
+            throttled!(error!(error = %e, "Failed to send ERROR"));
+        }
+    }
+
+    /// Handle a DATA message - forward to the appropriate connection.
+    ///
+    /// An empty payload is a no-op for TCP and a zero-length datagram for UDP.
+    pub async fn handle_data(&mut self, client_id: u32, proto: Proto, data: &[u8]) {
+        debug!(
+            client_id,
+            proto = %proto,
+            len = data.len(),
+            "Forwarding data to connection"
+        );
+        self.deliver(MsgType::Data, client_id, proto, data).await;
+    }
+
+    /// Handle a DELTA message - rebuild the payload from the connection's
+    /// previous one and forward it like DATA
+    pub async fn handle_delta(&mut self, client_id: u32, proto: Proto, delta: &[u8]) {
+        debug!(
+            client_id,
+            proto = %proto,
+            len = delta.len(),
+            "Forwarding delta-encoded data to connection"
+        );
+        self.deliver(MsgType::Delta, client_id, proto, delta).await;
+    }
+
+    /// Handle a BATCH message - forward each datagram it carries like DATA
+    pub async fn handle_batch(&mut self, client_id: u32, proto: Proto, payload: &[u8]) {
+        if proto != Proto::Udp {
+            warn!(client_id, proto = %proto, "BATCH for a non-UDP connection, dropping");
+            return;
+        }
+        let payloads = match protocol::split_batch(payload) {
+            Ok(payloads) => payloads,
+            Err(e) => {
+                warn!(client_id, error = %e, "Dropping malformed BATCH");
+                return;
+            }
+        };
+        debug!(
+            client_id,
+            datagrams = payloads.len(),
+            "Forwarding batched datagrams to connection"
+        );
+        for payload in payloads {
+            self.deliver(MsgType::Data, client_id, proto, payload).await;
+        }
+    }
+
+    /// Decrypt and decode a DATA or DELTA payload and queue it for the
+    /// connection's writer
+    async fn deliver(&mut self, msg_type: MsgType, client_id: u32, proto: Proto, payload: &[u8]) {
+        if let Some(pending) = self.resolving.get_mut(&client_id) {
+            // Held no deeper than the connection's own queue
+            if pending.held.len() < self.config.send_queue_depth.max(1) {
+                let payload = Bytes::copy_from_slice(payload);
+                pending.held.push(Held::Payload(msg_type, proto, payload));
+                return;
+            }
+            warn!(
+                client_id,
+                service = %pending.service,
+                "Too much data while resolving service, rejecting"
+            );
+            let proto = pending.proto;
+            self.resolving.remove(&client_id);
+            self.reject_connect(proto, client_id, "Service lookup too slow")
+                .await;
+            self.remember_closed(client_id);
+            return;
+        }
+        if let Some(conn) = self.connections.get_mut(&client_id) {
+            conn.runner_spoke.store(true, Ordering::Relaxed);
+            let plaintext = match &self.cipher {
+                Some(cipher) => match cipher.open_as(msg_type, proto, client_id, payload) {
+                    Ok(plaintext) => Bytes::from(plaintext),
+                    Err(e) => {
+                        throttled!(
+                            warn!(client_id, error = %e, msg_type = ?msg_type, "Dropping undecryptable payload")
+                        );
+                        return;
+                    }
+                },
+                None => Bytes::copy_from_slice(payload),
+            };
+            let plaintext = match &mut conn.received {
+                Some(counter) if msg_type == MsgType::Data => match bytecount::split(&plaintext) {
+                    Ok((total, data)) => {
+                        if let Err(e) = counter.check(total, data.len()) {
+                            warn!(client_id, error = %e, "Runner and tunnel disagree on bytes relayed");
+                            self.metrics.byte_count_mismatch();
+                        }
+                        plaintext.slice(bytecount::COUNT_SIZE..)
+                    }
+                    Err(e) => {
+                        warn!(client_id, error = %e, "TCP DATA without a byte count");
+                        self.metrics.byte_count_mismatch();
+                        plaintext.clone()
+                    }
+                },
+                _ => plaintext,
+            };
+            let decoded = match (msg_type, &mut conn.delta) {
+                (MsgType::Delta, Some(decoder)) => {
+                    decoder.apply(&plaintext).map_err(|e| e.to_string())
+                }
+                (MsgType::Delta, None) => Err("delta encoding was not negotiated".to_string()),
+                (_, Some(decoder)) => {
+                    decoder.raw(&plaintext);
+                    Ok(plaintext)
+                }
+                (_, None) => Ok(plaintext),
+            };
+            #[cfg(feature = "compression")]
+            let decoded = match (decoded, &mut conn.inflate) {
+                (Ok(data), Some(inflate)) => inflate
+                    .decompress(&data)
+                    .map(Bytes::from)
+                    .map_err(|e| format!("gzip stream: {e}")),
+                (decoded, _) => decoded,
+            };
+            let data_bytes = match decoded {
+                Ok(data) => data,
+                Err(reason) => {
+                    // The byte stream can't be recovered past a lost payload
+                    warn!(client_id, %reason, "Undecodable payload, closing connection");
+                    conn.audit.close_reason(CloseReason::Error);
+                    if let Err(e) = self
+                        .send_message(protocol::build_close(proto, client_id))
+                        .await
+                    {
+                        debug!(client_id, error = %e, "Failed to send CLOSE");
+                    }
+                    self.handle_close(client_id).await;
+                    return;
+                }
+            };
+            hexdump::trace_payload(
+                self.config.trace_payloads,
+                client_id,
+                "from runner",
+                &data_bytes,
+            );
+            if data_bytes.is_empty() && proto == Proto::Tcp {
+                // Nothing to write to a byte stream; an empty UDP payload is
+                // still a datagram and goes through
+                debug!(client_id, "Ignoring empty TCP DATA");
+                return;
+            }
+            let conn = &self.connections[&client_id];
+            let len = data_bytes.len();
+            // Datagrams are lossy anyway; dropping one beats stalling every
+            // connection behind the exhausted memory budget
+            if proto != Proto::Tcp && conn.data_tx.budget_exhausted(len) {
+                self.metrics.buffer_budget_shed();
+                debug!(
+                    client_id,
+                    bytes = len,
+                    "Buffer budget exhausted, dropping datagram"
+                );
+                return;
+            }
+            match conn.data_tx.send(data_bytes).await {
+                Ok(()) => {}
+                Err(SendError::BudgetExhausted) => {
+                    // A byte stream can't skip the payload, and waiting for
+                    // room would stall every other connection behind this one
+                    self.metrics.buffer_budget_shed();
+                    warn!(
+                        client_id,
+                        bytes = len,
+                        "Buffer budget exhausted, closing connection"
+                    );
+                    conn.audit.close_reason(CloseReason::Error);
+                    if let Err(e) = self
+                        .send_message(protocol::build_close(proto, client_id))
+                        .await
+                    {
+                        debug!(client_id, error = %e, "Failed to send CLOSE");
+                    }
+                    self.handle_close(client_id).await;
+                    return;
+                }
+                Err(SendError::SpillFailed) => {
+                    // Dropping the payload would leave a hole in the stream
+                    warn!(
+                        client_id,
+                        bytes = len,
+                        "Failed to buffer data, aborting connection"
+                    );
+                    conn.audit.close_reason(CloseReason::Error);
+                    let close = if self.runner.has(protocol::FEATURE_HALF_CLOSE) {
+                        protocol::build_abort(proto, client_id)
+                    } else {
+                        protocol::build_close(proto, client_id)
+                    };
+                    if let Err(e) = self.send_message(close).await {
+                        debug!(client_id, error = %e, "Failed to send CLOSE");
+                    }
+                    self.handle_abort(client_id).await;
+                    return;
+                }
+                Err(e) => {
+                    throttled!(warn!(client_id, error = %e, "Failed to send data to connection"));
+                    return;
+                }
+            }
+            // Let the runner release what it retained for resume
+            if let Some(offset) = conn.resume.as_ref().and_then(|r| r.record_received(len)) {
+                if let Err(e) = self
+                    .send_message(protocol::build_ack(client_id, offset))
+                    .await
+                {
+                    debug!(client_id, error = %e, "Failed to send ACK");
+                }
+            }
+        } else if self.was_recently_closed(client_id) {
+            // Runner raced its own CLOSE with in-flight DATA; nothing to do
+            debug!(client_id, "DATA for recently closed connection, ignoring");
+        } else {
+            throttled!(warn!(client_id, "DATA for unknown connection"));
+        }
+    }
+
+    /// Whether `client_id` was closed within the grace period
+    fn was_recently_closed(&self, client_id: u32) -> bool {
+        self.recently_closed
+            .get(&client_id)
+            .is_some_and(|closed_at| closed_at.elapsed() < CLOSED_GRACE_PERIOD)
+    }
+
+    /// Remember a closed client_id, evicting expired (or, if full, oldest) entries
+    fn remember_closed(&mut self, client_id: u32) {
+        let now = Instant::now();
+        self.recently_closed
+            .retain(|_, closed_at| now.duration_since(*closed_at) < CLOSED_GRACE_PERIOD);
+        if self.recently_closed.len() >= MAX_RECENTLY_CLOSED {
+            if let Some(oldest) = self
+                .recently_closed
+                .iter()
+                .min_by_key(|(_, closed_at)| **closed_at)
+                .map(|(id, _)| *id)
+            {
+                self.recently_closed.remove(&oldest);
+            }
+        }
+        self.recently_closed.insert(client_id, now);
+    }
+
+    /// Handle a CLOSE message - close the connection
+    pub async fn handle_close(&mut self, client_id: u32) {
+        info!(client_id, "Closing connection");
+
+        if self.resolving.remove(&client_id).is_some() {
+            debug!(client_id, "Dropping service lookup for closed connection");
+            self.remember_closed(client_id);
+            return;
+        }
+        if let Some(conn) = self.connections.remove(&client_id) {
+            // Dropping the connection closes its data channel and aborts its
+            // tasks, closing the local socket
+            conn.close(CloseReason::Remote);
+            self.remember_closed(client_id);
+        }
+    }
+
+    /// Handle a CLOSE marked as a half-close: the runner sends nothing more,
+    /// so once the data already queued is written the local socket's write
+    /// side is shut down (FIN). The connection stays open, and data from the
+    /// local service keeps flowing to the runner until it closes too.
+    /// UDP and raw connections have no half-close and ignore it.
+    pub fn handle_half_close(&mut self, client_id: u32) {
+        if let Some(pending) = self.resolving.get_mut(&client_id) {
+            pending.held.push(Held::HalfClose);
+            return;
+        }
+        match self.connections.get(&client_id) {
+            Some(conn) => {
+                info!(
+                    client_id,
+                    "Runner finished sending, half-closing connection"
+                );
+                conn.runner_spoke.store(true, Ordering::Relaxed);
+                conn.runner_eof.notify_one();
+            }
+            None => debug!(client_id, "Half-close for unknown connection, ignoring"),
+        }
+    }
+
+    /// Handle a CLOSE marked as an abort: a TCP connection's local socket is
+    /// reset instead of shut down cleanly
+    pub async fn handle_abort(&mut self, client_id: u32) {
+        if let Some(conn) = self.connections.get(&client_id) {
+            conn.reset.store(true, Ordering::Relaxed);
+        }
+        self.handle_close(client_id).await;
+    }
+
+    /// Handle a PING message - respond with a PONG echoing its payload
+    ///
+    /// A timestamped PING thus comes back unchanged, letting the runner
+    /// compute the round-trip time.
+    pub async fn handle_ping(&self, client_id: u32, payload: &[u8]) {
+        debug!(client_id, "Received PING, sending PONG");
+
+        let pong = protocol::build_pong(client_id, payload);
+        if let Err(e) = self.send_message(pong).await {
+            throttled!(error!(error = %e, "Failed to send PONG"));
+        }
+    }
+
+    /// Handle an ACK - release retained data, and on a suspended connection
+    /// finish the resume handshake by retransmitting the unacknowledged tail
+    pub async fn handle_ack(&mut self, client_id: u32, payload: &[u8]) {
+        let Some(offset) = protocol::parse_ack(payload) else {
+            warn!(client_id, len = payload.len(), "Malformed ACK, ignoring");
+            return;
+        };
+        let Some(resume) = self
+            .connections
+            .get(&client_id)
+            .and_then(|conn| conn.resume.clone())
+        else {
+            debug!(client_id, "ACK for non-resumable connection, ignoring");
+            return;
+        };
+
+        // Held across the retransmit so the connection's own sends can't
+        // interleave with the tail
+        let mut sender = self.transport.lock().await;
+        match resume.on_ack(offset) {
+            Ok(None) => {}
+            Ok(Some(tail)) => {
+                let retransmitted: usize = tail.iter().map(Bytes::len).sum();
+                for chunk in &tail {
+                    let frame = data_frame(self.cipher.as_deref(), Proto::Tcp, client_id, chunk);
+                    if let Err(e) = sender.send(frame).await {
+                        // Stays suspended; the next reconnect starts over
+                        warn!(client_id, error = %e, "Retransmit failed");
+                        return;
+                    }
+                }
+                resume.resume();
+                info!(client_id, offset, retransmitted, "Connection resumed");
+            }
+            Err(e) => {
+                warn!(client_id, error = %e, "Cannot resume connection, closing");
+                let close = protocol::build_close(Proto::Tcp, client_id);
+                let _ = sender.send(close).await;
+                drop(sender);
+                if let Some(conn) = self.connections.get(&client_id) {
+                    conn.audit.close_reason(CloseReason::Error);
+                }
+                self.handle_close(client_id).await;
+            }
+        }
+    }
+
+    /// Suspend resumable connections after the WebSocket dropped and close the
+    /// rest. Returns true if any connection is waiting to be resumed.
+    pub fn suspend(&mut self) -> bool {
+        // Lookups can't be resumed; the runner's CONNECTs ended with the
+        // WebSocket
+        self.resolving.clear();
+        self.connections
+            .retain(|&client_id, conn| match &conn.resume {
+                Some(resume) => {
+                    resume.suspend();
+                    true
+                }
+                None => {
+                    debug!(client_id, "Closing non-resumable connection");
+                    conn.audit.close_reason(CloseReason::TransportLost);
+                    false
+                }
+            });
+        if !self.connections.is_empty() {
+            info!(
+                count = self.connections.len(),
+                "Connections suspended until reconnect"
+            );
+        }
+        !self.connections.is_empty()
+    }
+
+    /// Start resuming suspended connections on a new WebSocket by reporting
+    /// how much of each runner stream was received; the runner answers with
+    /// its own ACK per connection (see [`handle_ack`](Self::handle_ack))
+    pub async fn resume_handshake(&self) -> Result<()> {
+        for (&client_id, conn) in &self.connections {
+            if let Some(resume) = &conn.resume {
+                debug!(client_id, "Requesting resume");
+                self.send_message(protocol::build_ack(client_id, resume.received()))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a message to the runner
+    async fn send_message(&self, data: Bytes) -> Result<()> {
+        let mut sender = self.transport.lock().await;
+        sender.send(data).await?;
+        Ok(())
+    }
+
+    /// Shutdown all connections
+    pub async fn shutdown(&mut self) {
+        info!("Shutting down all connections");
+        self.resolving.clear();
+        for (client_id, conn) in self.connections.drain() {
+            debug!(client_id, "Closing connection");
+            conn.close(CloseReason::Shutdown);
+        }
+    }
+}
+
+/// Generate a short random connection id for log correlation
+fn new_conn_id() -> String {
+    format!("{:08x}", rand::random::<u32>())
+}
+
+// =============================================================================
+// TCP Connection Handler
+// =============================================================================
+
+/// Loopback address in the same family as `source` (IPv4 without one)
+fn loopback_for(source: Option<IpAddr>) -> IpAddr {
+    match source {
+        Some(IpAddr::V6(_)) => Ipv6Addr::LOCALHOST.into(),
+        _ => Ipv4Addr::LOCALHOST.into(),
+    }
+}
+
+/// Connect to `target`, from `source` if given
+async fn connect_from(source: Option<IpAddr>, target: SocketAddr) -> io::Result<TcpStream> {
+    let Some(source) = source else {
+        return TcpStream::connect(target).await;
+    };
+    let socket = match target {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(source, 0))?;
+    socket.connect(target).await
+}
+
+/// Connect to a loopback port, trying 127.0.0.1 first and then ::1 for
+/// services that only listen on IPv6 loopback. With a source address only
+/// the loopback of its family is tried.
+///
+/// If both fail, the IPv4 error is returned.
+async fn connect_loopback(port: u16, source: Option<IpAddr>) -> io::Result<TcpStream> {
+    if source.is_some() {
+        return connect_from(source, SocketAddr::new(loopback_for(source), port)).await;
+    }
+    let v4_err = match TcpStream::connect(SocketAddr::from((Ipv4Addr::LOCALHOST, port))).await {
+        Ok(stream) => return Ok(stream),
+        Err(e) => e,
+    };
+    match TcpStream::connect(SocketAddr::from((Ipv6Addr::LOCALHOST, port))).await {
+        Ok(stream) => {
+            debug!(port, "Connected via IPv6 loopback");
+            Ok(stream)
+        }
+        Err(_) => Err(v4_err),
+    }
+}
+
+/// Number of unanswered keepalive probes before the kernel drops a connection
+const TCP_KEEPALIVE_PROBES: u32 = 3;
+
+/// Enable kernel keepalive on `stream`, probing after `idle` without
+/// traffic. Where the OS allows, probes then go out every `idle / 3` (at
+/// least a second), so a dead peer is noticed within about twice `idle`.
+fn set_tcp_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    let keepalive = keepalive
+        .with_interval((idle / TCP_KEEPALIVE_PROBES).max(Duration::from_secs(1)))
+        .with_retries(TCP_KEEPALIVE_PROBES);
+    socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Connect to `port` on the target host, or on loopback without one
+async fn connect_target(ctx: &ConnContext, port: u16) -> io::Result<TcpStream> {
+    match ctx.target_host {
+        Some(host) => connect_from(ctx.source_addr, SocketAddr::new(host, port)).await,
+        None => connect_loopback(port, ctx.source_addr).await,
+    }
+}
+
+/// Stream to a local service: TCP, or a Unix socket named by a `unix://`
+/// port target
+enum LocalStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl LocalStream {
+    /// The TCP stream, for the socket options Unix sockets lack
+    fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Port the local service listens on (None for a Unix socket)
+    fn peer_port(&self) -> Option<u16> {
+        self.tcp()?.peer_addr().ok().map(|addr| addr.port())
+    }
+
+    fn into_split(self) -> (LocalReadHalf, LocalWriteHalf) {
+        match self {
+            Self::Tcp(stream) => {
+                let (read, write) = stream.into_split();
+                (LocalReadHalf::Tcp(read), LocalWriteHalf::Tcp(write))
+            }
+            #[cfg(unix)]
+            Self::Unix(stream) => {
+                let (read, write) = stream.into_split();
+                (LocalReadHalf::Unix(read), LocalWriteHalf::Unix(write))
+            }
+        }
+    }
+}
+
+/// Read half of a [`LocalStream`]
+enum LocalReadHalf {
+    Tcp(tcp::OwnedReadHalf),
+    #[cfg(unix)]
+    Unix(unix::OwnedReadHalf),
+}
+
+impl AsyncRead for LocalReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(half) => Pin::new(half).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(half) => Pin::new(half).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Write half of a [`LocalStream`]
+enum LocalWriteHalf {
+    Tcp(tcp::OwnedWriteHalf),
+    #[cfg(unix)]
+    Unix(unix::OwnedWriteHalf),
+}
+
+impl AsyncWrite for LocalWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(half) => Pin::new(half).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(half) => Pin::new(half).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(half) => Pin::new(half).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(half) => Pin::new(half).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(half) => Pin::new(half).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(half) => Pin::new(half).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connect to the local stream service: the Unix socket of a port target,
+/// or TCP, falling through an upstream pool if configured
+async fn connect_local_tcp(ctx: &ConnContext) -> io::Result<(LocalStream, Option<ActiveGuard>)> {
+    #[cfg(unix)]
+    if let Some(path) = &ctx.unix_socket {
+        let stream = UnixStream::connect(path).await?;
+        return Ok((LocalStream::Unix(stream), None));
+    }
+    let Some(candidates) = &ctx.upstream else {
+        let stream = connect_target(ctx, ctx.port).await?;
+        return Ok((LocalStream::Tcp(stream), None));
+    };
+
+    let mut last_err = None;
+    for (nth, upstream) in candidates.ports().into_iter().enumerate() {
+        match connect_target(ctx, upstream).await {
+            Ok(stream) => {
+                debug!(client_id = ctx.client_id, upstream, "Selected upstream");
+                return Ok((LocalStream::Tcp(stream), Some(candidates.acquire(nth))));
+            }
+            Err(e) => {
+                warn!(
+                    client_id = ctx.client_id,
+                    upstream,
+                    error = %e,
+                    "Upstream connect failed, trying next"
+                );
+                // A dead upstream shouldn't look busy to the next CONNECT
+                candidates.release();
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.expect("upstream pools are never empty"))
+}
+
+/// Try the local connect for a PROBE and report the outcome. The socket is
+/// closed again straight away; nothing is relayed.
+async fn probe_local_tcp(ctx: ConnContext) {
+    let client_id = ctx.client_id;
+    let result = {
+        let _slot = ctx.connect_slot().await;
+        connect_local_tcp(&ctx).await
+    };
+    let reply = match result {
+        Ok(_) => {
+            info!(client_id, port = ctx.port, "Probe succeeded");
+            protocol::build_connected(Proto::Tcp, client_id)
+        }
+        Err(e) => {
+            info!(client_id, port = ctx.port, error = %e, "Probe failed");
+            protocol::build_error(Proto::Tcp, client_id, &e.to_string())
+        }
+    };
+    let mut sender = ctx.transport.lock().await;
+    if let Err(e) = sender.send(reply).await {
+        debug!(client_id, error = %e, "Failed to report probe result");
+    }
+}
+
+/// Handle a single TCP connection to a local service
+async fn handle_tcp_connection(ctx: ConnContext, mut data_rx: DataReceiver) -> Result<()> {
+    let client_id = ctx.client_id;
+    let port = ctx.port;
+    let transport = ctx.transport.clone();
+
+    // Connect to local service (held guard counts us as active on the upstream).
+    // Only the connect itself holds a slot, so a burst of CONNECTs can't
+    // flood the local service with simultaneous handshakes.
+    let connect_result = {
+        let _slot = ctx.connect_slot().await;
+        connect_local_tcp(&ctx).await
+    };
+    let (stream, _upstream_guard) = match connect_result {
+        Ok((s, guard)) => {
+            info!(client_id, port, "TCP connection established");
+            if let Some(tcp) = s.tcp().filter(|_| !ctx.tcp_keepalive.is_zero()) {
+                if let Err(e) = set_tcp_keepalive(tcp, ctx.tcp_keepalive) {
+                    warn!(client_id, error = %e, "Failed to enable TCP keepalive");
+                }
+            }
+            (s, guard)
+        }
+        Err(e) => {
+            error!(client_id, port, error = %e, "Failed to connect to local service");
+
+            // Send ERROR message back
+            let error_msg = protocol::build_error(Proto::Tcp, client_id, &e.to_string());
+            let mut sender = transport.lock().await;
+            let _ = sender.send(error_msg).await;
+
+            return Err(e.into());
+        }
+    };
+
+    // Send CONNECTED message
+    let connected = protocol::build_connected(Proto::Tcp, client_id);
+    {
+        let mut sender = transport.lock().await;
+        sender.send(connected).await?;
+    }
+
+    // Logs the close event whenever this handler ends, even if aborted
+    let local_port = stream.peer_port().unwrap_or(port);
+    let _audit = ctx
+        .audit
+        .opened(local_port, &ctx.metrics)
+        .with_webhook(ctx.webhook.as_ref());
+    let (mut reader, writer) = stream.into_split();
+    let mut writer = LocalWriter {
+        half: Some(writer),
+        reset: ctx.reset.clone(),
+    };
+
+    // On local EOF the read task asks the write task to flush what the runner
+    // already queued, and waits (briefly) for it before sending CLOSE; false
+    // means some of it was lost
+    let (eof_tx, mut eof_rx) = oneshot::channel::<()>();
+    let (flushed_tx, flushed_rx) = oneshot::channel::<bool>();
+
+    let keepalive_ctx = ctx.clone();
+    let activity = ctx.activity.clone();
+    let audit = ctx.audit.clone();
+    let runner_eof = ctx.runner_eof.clone();
+
+    // Task to read from TCP and send to the runner
+    let mut read_task = keepalive_ctx.spawn(
+        async move {
+            let mut buf = vec![0u8; 65536];
+            let mut encoder = TcpEncoder::new(&ctx);
+            let mut abort = false;
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) => {
+                        debug!(client_id, "TCP connection closed by remote");
+                        ctx.audit.close_reason(CloseReason::LocalEof);
+                        let _ = eof_tx.send(());
+                        match tokio::time::timeout(EOF_FLUSH_TIMEOUT, flushed_rx).await {
+                            Ok(Ok(false)) => abort = true,
+                            Ok(_) => {}
+                            Err(_) => {
+                                debug!(client_id, "Timed out flushing queued data before CLOSE");
+                            }
+                        }
+                        // End the gzip stream so the runner sees all of it
+                        if let Some(trailer) = encoder.finish() {
+                            let trailer = ctx.data_frame(Proto::Tcp, &trailer);
+                            let _ = ctx.transport.lock().await.send(trailer).await;
+                        }
+                        break;
+                    }
+                    Ok(n) => {
+                        debug!(client_id, bytes = n, "Read from TCP, sending to runner");
+                        ctx.activity.touch();
+                        ctx.audit.record_out(n);
+                        // Reads larger than the payload cap go out in pieces
+                        let mut sent = true;
+                        for chunk in buf[..n].chunks(ctx.max_tcp_chunk()) {
+                            sent = forward_tcp_chunk(&ctx, &mut encoder, chunk).await;
+                            if !sent {
+                                break;
+                            }
+                        }
+                        if !sent {
+                            ctx.audit.close_reason(CloseReason::TransportLost);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!(client_id, error = %e, "TCP read error");
+                        ctx.audit.close_reason(CloseReason::Error);
+                        abort = true;
+                        break;
+                    }
+                }
+            }
+
+            // Send CLOSE message
+            send_tcp_close(&ctx, abort).await;
+        }
+        .in_current_span(),
+    );
+
+    // Task to receive data from channel and write to TCP
+    let mut write_task = keepalive_ctx.spawn(
+        async move {
+            loop {
+                tokio::select! {
+                    data = data_rx.recv() => {
+                        let Some(data) = data else {
+                            if data_rx.failed() {
+                                return WriteEnd::Failed;
+                            }
+                            debug!(client_id, "Write task ending (channel closed)");
+                            return WriteEnd::ChannelClosed;
+                        };
+                        let data = coalesce_queued(data, &mut data_rx).await;
+                        if write_tcp_chunk(client_id, &mut writer, &data).await.is_err() {
+                            return WriteEnd::Failed;
+                        }
+                        activity.touch();
+                        audit.record_in(data.len());
+                    }
+                    Ok(()) = &mut eof_rx => {
+                        // A service that answers and closes at once must still
+                        // receive the DATA queued ahead of its EOF
+                        while let Some(data) = data_rx.recv_queued().await {
+                            let data = coalesce_queued(data, &mut data_rx).await;
+                            if write_tcp_chunk(client_id, &mut writer, &data).await.is_err() {
+                                break;
+                            }
+                            audit.record_in(data.len());
+                        }
+                        let _ = flushed_tx.send(!data_rx.failed());
+                        debug!(client_id, "Write task ending (local EOF)");
+                        return WriteEnd::LocalEof;
+                    }
+                    () = runner_eof.notified() => {
+                        // Everything the runner sent before its half-close
+                        // goes out ahead of the FIN
+                        while let Some(data) = data_rx.recv_queued().await {
+                            let data = coalesce_queued(data, &mut data_rx).await;
+                            if write_tcp_chunk(client_id, &mut writer, &data).await.is_err() {
+                                return WriteEnd::Failed;
+                            }
+                            audit.record_in(data.len());
+                        }
+                        if data_rx.failed() {
+                            return WriteEnd::Failed;
+                        }
+                        if let Err(e) = writer.shutdown().await {
+                            debug!(client_id, error = %e, "Failed to shut down local write side");
+                        }
+                        debug!(client_id, "Write task ending (runner half-close)");
+                        return WriteEnd::RunnerEof;
+                    }
+                }
+            }
+        }
+        .in_current_span(),
+    );
+
+    // Wait for either task to complete; the other is aborted when its guard
+    // drops, so nothing outlives the connection. After a half-close from the
+    // runner only the read task is left to wait for.
+    let mut half_closed = false;
+    loop {
+        tokio::select! {
+            _ = &mut read_task => {
+                debug!(client_id, "Read task completed");
+            }
+            end = &mut write_task, if !half_closed => {
+                debug!(client_id, "Write task completed");
+                match end {
+                    Ok(WriteEnd::RunnerEof) => {
+                        half_closed = true;
+                        continue;
+                    }
+                    // The read task is about to send CLOSE
+                    Ok(WriteEnd::LocalEof) => {
+                        let _ = read_task.await;
+                    }
+                    Ok(WriteEnd::Failed) => {
+                        keepalive_ctx.audit.close_reason(CloseReason::Error);
+                        send_tcp_close(&keepalive_ctx, true).await;
+                    }
+                    Ok(WriteEnd::ChannelClosed) | Err(_) => {}
+                }
+            }
+            e = idle_keepalive(&keepalive_ctx, Proto::Tcp) => {
+                debug!(client_id, error = %e, "Keepalive failed");
+                keepalive_ctx.audit.close_reason(CloseReason::TransportLost);
+            }
+            _ = runner_silence(&keepalive_ctx) => {
+                warn!(client_id, "No data from the runner since CONNECTED, closing");
+                keepalive_ctx.audit.close_reason(CloseReason::RunnerSilent);
+                send_tcp_close(&keepalive_ctx, false).await;
+            }
+        }
+        break;
+    }
+
+    Ok(())
+}
+
+/// Why a connection's write task ended
+enum WriteEnd {
+    /// The connection was closed on our side (runner CLOSE or shutdown)
+    ChannelClosed,
+    /// The local service closed its end
+    LocalEof,
+    /// The runner half-closed the connection; the local write side is shut
+    /// down and the read side carries on
+    RunnerEof,
+    /// Writing to the local service failed, or data for it was lost
+    Failed,
+}
+
+/// Encoding of the byte stream read from a local TCP service
+struct TcpEncoder {
+    /// Previous payload sent (None = delta encoding not in use)
+    delta: Option<DeltaEncoder>,
+    /// Gzip stream to the runner (None = compression not in use)
+    #[cfg(feature = "compression")]
+    gzip: Option<Compressor>,
+}
+
+impl TcpEncoder {
+    fn new(ctx: &ConnContext) -> Self {
+        Self {
+            delta: ctx.delta.then(DeltaEncoder::default),
+            #[cfg(feature = "compression")]
+            gzip: ctx.compression.then(Compressor::default),
+        }
+    }
+
+    /// The frames carrying `chunk` to the runner: one, unless its gzip
+    /// output exceeds the payload cap
+    fn frames(&mut self, ctx: &ConnContext, chunk: &[u8]) -> Vec<Bytes> {
+        #[cfg(feature = "compression")]
+        if let Some(gzip) = &mut self.gzip {
+            hexdump::trace_payload(ctx.trace_payloads, ctx.client_id, "to runner", chunk);
+            // One gzip stream spans the frames, so it can be cut anywhere
+            return gzip
+                .compress(chunk)
+                .chunks(ctx.max_tcp_chunk())
+                .map(|piece| ctx.data_frame(Proto::Tcp, piece))
+                .collect();
+        }
+        vec![ctx.outgoing_frame(self.delta.as_mut(), Proto::Tcp, chunk)]
+    }
+
+    /// Payload ending the stream at local EOF, if the encoding has one (the
+    /// gzip trailer)
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        #[cfg(feature = "compression")]
+        if let Some(gzip) = self.gzip.take() {
+            return Some(gzip.finish());
+        }
+        None
+    }
+}
+
+/// Send one chunk read from the local service, retaining it for resume.
+///
+/// Returns false when the connection should end: the send failed without
+/// resume, or the connection was not resumed within the grace period.
+async fn forward_tcp_chunk(ctx: &ConnContext, encoder: &mut TcpEncoder, chunk: &[u8]) -> bool {
+    let frames = encoder.frames(ctx, chunk);
+    // Resumable sends stay on the transport lock, which also guards the
+    // retransmit buffer against a concurrent resume
+    let Some(resume) = &ctx.resume else {
+        for frame in frames {
+            if ctx.send_data(frame).await.is_err() {
+                return false;
+            }
+        }
+        return true;
+    };
+    // Resumable connections aren't compressed, so this is the only frame
+    let Some(frame) = frames.into_iter().next() else {
+        return true;
+    };
+
+    // Backpressure: stop reading the local service while the runner lags
+    if !resume.reserve(chunk.len()).await {
+        return false;
+    }
+    loop {
+        let mut sender = ctx.transport.lock().await;
+        if resume.is_suspended() {
+            drop(sender);
+            if !resume.wait_resumed().await {
+                return false;
+            }
+            continue;
+        }
+        resume.push(Bytes::copy_from_slice(chunk));
+        if sender.send(frame).await.is_err() {
+            // The chunk is retained, so the resume handshake retransmits it
+            resume.suspend();
+            drop(sender);
+            return resume.wait_resumed().await;
+        }
+        return true;
+    }
+}
+
+/// Send CLOSE for a TCP connection, waiting out a suspension if resumable.
+/// An abort is sent as a plain CLOSE to runners without CLOSE flags.
+async fn send_tcp_close(ctx: &ConnContext, abort: bool) {
+    let close = if abort && ctx.close_flags {
+        protocol::build_abort(Proto::Tcp, ctx.client_id)
+    } else {
+        protocol::build_close(Proto::Tcp, ctx.client_id)
+    };
+    loop {
+        let mut sender = ctx.transport.lock().await;
+        if let Some(resume) = &ctx.resume {
+            if resume.is_suspended() {
+                drop(sender);
+                if !resume.wait_resumed().await {
+                    return;
+                }
+                continue;
+            }
+        }
+        if sender.send(close.clone()).await.is_err() {
+            if let Some(resume) = &ctx.resume {
+                resume.suspend();
+                continue;
+            }
+        }
+        return;
+    }
+}
+
+/// Write half of a local stream connection, reset instead of shut down when
+/// dropped after the runner aborted the connection (TCP only; a Unix socket
+/// is just closed)
+struct LocalWriter {
+    half: Option<LocalWriteHalf>,
+    reset: Arc<AtomicBool>,
+}
+
+impl Deref for LocalWriter {
+    type Target = LocalWriteHalf;
+
+    fn deref(&self) -> &LocalWriteHalf {
+        self.half.as_ref().expect("only taken on drop")
+    }
+}
+
+impl DerefMut for LocalWriter {
+    fn deref_mut(&mut self) -> &mut LocalWriteHalf {
+        self.half.as_mut().expect("only taken on drop")
+    }
+}
+
+impl Drop for LocalWriter {
+    fn drop(&mut self) {
+        let Some(LocalWriteHalf::Tcp(half)) = self.half.take() else {
+            return;
+        };
+        if self.reset.load(Ordering::Relaxed) {
+            // A zero linger makes the final close send RST; forgetting the
+            // half skips the FIN it would otherwise send first
+            let socket = socket2::SockRef::from(half.as_ref());
+            if let Err(e) = socket.set_linger(Some(Duration::ZERO)) {
+                debug!(error = %e, "Failed to reset local connection");
+            }
+            half.forget();
+        }
+    }
+}
+
+/// Send a keepalive frame whenever the connection has been idle for the
+/// configured interval, so NAT mappings along the path don't expire.
+///
+/// TCP gets a PING carrying the connection's client_id, which never touches
+/// the byte stream. UDP gets an empty DATA frame, which the runner relays as
+/// a zero-length datagram to refresh the client-side mapping. Raw connections
+/// get a PING like TCP, since an empty IP packet is not a harmless no-op.
+/// Runs until a send fails; never returns when keepalives are disabled.
+async fn idle_keepalive(ctx: &ConnContext, proto: Proto) -> TunnelError {
+    let interval = ctx.keepalive_interval;
+    if interval.is_zero() {
+        return std::future::pending().await;
+    }
+
+    loop {
+        let deadline = ctx.activity.last() + interval;
+        if Instant::now() < deadline {
+            tokio::time::sleep_until(deadline).await;
+            continue;
+        }
+
+        // No keepalives while suspended; there is no WebSocket to send on
+        if let Some(resume) = &ctx.resume {
+            if !resume.wait_resumed().await {
+                return TunnelError::ConnectionLost("not resumed within the grace period".into());
+            }
+        }
+
+        let frame = match proto {
+            Proto::Tcp | Proto::Raw => {
+                protocol::build_ping_with_ts(ctx.client_id, protocol::timestamp_micros())
+            }
+            Proto::Udp => ctx.data_frame(Proto::Udp, &[]),
+        };
+        debug!(
+            client_id = ctx.client_id,
+            "Connection idle, sending keepalive"
+        );
+        let mut sender = ctx.transport.lock().await;
+        if let Err(e) = sender.send(frame).await {
+            match &ctx.resume {
+                Some(resume) => resume.suspend(),
+                None => return e,
+            }
+        }
+        drop(sender);
+        ctx.activity.touch();
+    }
+}
+
+/// Resolves if the runner has sent no DATA for the connection by the silence
+/// timeout, counted from when the handler starts waiting (right after
+/// CONNECTED). Never resolves once it has, or when the timeout is disabled.
+///
+/// Time spent suspended doesn't count against the runner; the check waits
+/// for the connection to resume.
+async fn runner_silence(ctx: &ConnContext) {
+    let timeout = ctx.runner_silence_timeout;
+    if timeout.is_zero() {
+        return std::future::pending().await;
+    }
+    tokio::time::sleep(timeout).await;
+    if let Some(resume) = &ctx.resume {
+        if !resume.wait_resumed().await {
+            return std::future::pending().await;
+        }
+    }
+    if ctx.runner_spoke.load(Ordering::Relaxed) {
+        std::future::pending().await
+    }
+}
+
+/// Join `first` with the runner data already queued behind it, up to
+/// [`COALESCE_MAX_BYTES`] or [`COALESCE_MAX_CHUNKS`], so a burst of small
+/// DATA frames costs one write and one flush instead of one each
+async fn coalesce_queued(first: Bytes, data_rx: &mut DataReceiver) -> Bytes {
+    if first.len() >= COALESCE_MAX_BYTES {
+        return first;
+    }
+    let Some(second) = data_rx.recv_queued().await else {
+        return first;
+    };
+    let mut joined = BytesMut::with_capacity(first.len() + second.len());
+    joined.extend_from_slice(&first);
+    joined.extend_from_slice(&second);
+    let mut chunks = 2;
+    while chunks < COALESCE_MAX_CHUNKS && joined.len() < COALESCE_MAX_BYTES {
+        let Some(next) = data_rx.recv_queued().await else {
+            break;
+        };
+        joined.extend_from_slice(&next);
+        chunks += 1;
+    }
+    joined.freeze()
+}
+
+/// Write one chunk of runner data to the local stream
+async fn write_tcp_chunk(
+    client_id: u32,
+    writer: &mut LocalWriteHalf,
+    data: &[u8],
+) -> io::Result<()> {
+    debug!(client_id, bytes = data.len(), "Writing to TCP");
+    if let Err(e) = writer.write_all(data).await {
+        error!(client_id, error = %e, "TCP write error");
+        return Err(e);
+    }
+    if let Err(e) = writer.flush().await {
+        error!(client_id, error = %e, "TCP flush error");
+        return Err(e);
+    }
+    Ok(())
+}
+
+// =============================================================================
+// UDP Connection Handler
+// =============================================================================
+
+/// Delay before retrying a UDP send that failed transiently
+#[cfg(feature = "udp")]
+const UDP_SEND_RETRY_DELAY: Duration = Duration::from_millis(5);
+
+/// Whether a UDP send error is transient (the session should survive it).
+///
+/// Full socket buffers (`EAGAIN`/`ENOBUFS`), interrupted calls, and ICMP
+/// port-unreachable reported on a connected socket (`ECONNREFUSED`, e.g. the
+/// service is restarting) only affect the datagram in flight.
+#[cfg(feature = "udp")]
+fn is_transient_udp_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::WouldBlock
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::ConnectionRefused => true,
+        #[cfg(unix)]
+        _ if e.raw_os_error() == Some(libc::ENOBUFS) => true,
+        _ => false,
+    }
+}
+
+/// Largest UDP payload that fits in an IPv4 datagram (65535 minus IP and UDP headers)
+#[cfg(feature = "udp")]
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// Whether a send error means the datagram can never fit (`EMSGSIZE`)
+#[cfg(feature = "udp")]
+fn is_too_large_udp_error(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        #[cfg(unix)]
+        Some(code) => code == libc::EMSGSIZE,
+        _ => false,
+    }
+}
+
+/// IP and UDP header bytes on top of a datagram's payload
+#[cfg(all(feature = "udp", any(target_os = "linux", target_os = "android")))]
+fn udp_overhead(ipv4: bool) -> usize {
+    if ipv4 {
+        20 + 8
+    } else {
+        40 + 8
+    }
+}
+
+/// Set Don't Fragment (`IP_PMTUDISC_DO`) on a UDP socket. Datagrams over the
+/// path MTU then fail on send with `EMSGSIZE` rather than leaving as
+/// fragments that a middlebox may silently drop.
+#[cfg(all(feature = "udp", any(target_os = "linux", target_os = "android")))]
+fn set_udp_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = if socket.local_addr()?.is_ipv4() {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    } else {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    };
+    // SAFETY: the fd is open for the socket's lifetime and `value` is a
+    // c_int, as the option expects
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(all(feature = "udp", not(any(target_os = "linux", target_os = "android"))))]
+fn set_udp_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Largest payload that fits the path MTU the kernel knows for a connected
+/// UDP socket (None = unknown)
+#[cfg(all(feature = "udp", any(target_os = "linux", target_os = "android")))]
+fn udp_path_max_payload(socket: &UdpSocket) -> Option<usize> {
+    use std::os::fd::AsRawFd;
+
+    let ipv4 = socket.local_addr().ok()?.is_ipv4();
+    let (level, name) = if ipv4 {
+        (libc::IPPROTO_IP, libc::IP_MTU)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU)
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `mtu` and `len` are valid for writes of the sizes given
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut mtu as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    let mtu = usize::try_from(mtu).ok().filter(|_| ret == 0)?;
+    mtu.checked_sub(udp_overhead(ipv4))
+}
+
+#[cfg(all(feature = "udp", not(any(target_os = "linux", target_os = "android"))))]
+fn udp_path_max_payload(_socket: &UdpSocket) -> Option<usize> {
+    None
+}
+
+/// What happened to a datagram handed to [`send_udp_datagram`]
+#[cfg(feature = "udp")]
+#[derive(Debug, PartialEq, Eq)]
+enum UdpSendOutcome {
+    Sent,
+    /// Dropped after repeated transient errors
+    Dropped,
+    /// Larger than a UDP datagram can be; never sent
+    TooLarge,
+    /// The socket accepted only this many bytes
+    Short(usize),
+}
+
+/// Send one datagram, retrying once on a transient error before dropping it.
+///
+/// UDP is lossy by nature, so dropping a single datagram is preferable to
+/// tearing down the whole session. Oversized and short sends are reported in
+/// the outcome; only fatal errors are returned to the caller.
+#[cfg(feature = "udp")]
+async fn send_udp_datagram<F, Fut>(
+    client_id: u32,
+    len: usize,
+    mut send: F,
+) -> io::Result<UdpSendOutcome>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<usize>>,
+{
+    if len > MAX_UDP_PAYLOAD {
+        return Ok(UdpSendOutcome::TooLarge);
+    }
+
+    for attempt in 0..2 {
+        match send().await {
+            Ok(n) if n < len => return Ok(UdpSendOutcome::Short(n)),
+            Ok(_) => return Ok(UdpSendOutcome::Sent),
+            Err(e) if is_too_large_udp_error(&e) => return Ok(UdpSendOutcome::TooLarge),
+            Err(e) if is_transient_udp_error(&e) => {
+                debug!(client_id, attempt, error = %e, "Transient UDP send error");
+                if attempt == 0 {
+                    tokio::time::sleep(UDP_SEND_RETRY_DELAY).await;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    warn!(
+        client_id,
+        "Dropping UDP datagram after repeated transient errors"
+    );
+    Ok(UdpSendOutcome::Dropped)
+}
+
+/// Next datagram to write to the local socket. Waits for one until `drain`
+/// fires (or its sender is dropped); from then on it takes only those
+/// already queued and returns None when they run out, even though the
+/// runner may still send more.
+#[cfg(feature = "udp")]
+async fn next_datagram(
+    data_rx: &mut DataReceiver,
+    drain: &mut oneshot::Receiver<()>,
+    draining: &mut bool,
+) -> Option<Bytes> {
+    if !*draining {
+        tokio::select! {
+            data = data_rx.recv() => return data,
+            _ = drain => *draining = true,
+        }
+    }
+    data_rx.recv_queued().await
+}
+
+/// Receive buffer for UDP datagrams from the local service.
+///
+/// The largest possible UDP payload is 65507 bytes over IPv4 (65535 minus IP
+/// and UDP headers), so every datagram fits in one read and is forwarded as
+/// exactly one DATA frame, never split or truncated. Larger datagrams cannot
+/// be produced by the local stack (IPv6 jumbograms are not supported).
+#[cfg(feature = "udp")]
+const UDP_RECV_BUFFER_SIZE: usize = 65536;
+
+/// Most bytes of datagrams, with their length fields, sent in one BATCH
+#[cfg(feature = "udp")]
+const UDP_BATCH_MAX_BYTES: usize = 64 << 10;
+
+/// Bind the local port last used for `client_id` to `target`, or a fresh
+/// one if there is none or it is no longer free
+#[cfg(feature = "udp")]
+fn bind_with_affinity(
+    client_id: u32,
+    source: IpAddr,
+    target: SocketAddr,
+    affinity: &UdpAffinity,
+) -> io::Result<UdpSocket> {
+    if let Some(port) = affinity.port(client_id, target) {
+        match affinity::bind_reusable(SocketAddr::new(source, port)) {
+            Ok(socket) => {
+                debug!(client_id, port, "Reusing local UDP port");
+                return Ok(socket);
+            }
+            Err(e) => {
+                warn!(client_id, port, error = %e, "Local UDP port taken, binding a new one");
+            }
+        }
+    }
+    affinity::bind_reusable(SocketAddr::new(source, 0))
+}
+
+/// Handle a single UDP "connection" to a local service
+#[cfg(feature = "udp")]
+async fn handle_udp_connection(ctx: ConnContext, mut data_rx: DataReceiver) -> Result<()> {
+    let client_id = ctx.client_id;
+    let port = ctx.port;
+    let transport = ctx.transport.clone();
+    let metrics = ctx.metrics.clone();
+    // Pools pick the first candidate; connected UDP sockets can't detect a
+    // dead upstream up front, so there is no fallthrough.
+    let (port, _upstream_guard) = match &ctx.upstream {
+        Some(candidates) => (candidates.ports()[0], Some(candidates.acquire(0))),
+        None => (port, None),
+    };
+
+    // Bind to a random local port; a remote target host needs a routable
+    // source rather than loopback
+    let (source, target) = match ctx.target_host {
+        Some(host) => {
+            let unspecified: IpAddr = match host {
+                IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            (ctx.source_addr.unwrap_or(unspecified), host)
+        }
+        None => (
+            ctx.source_addr.unwrap_or(Ipv4Addr::LOCALHOST.into()),
+            loopback_for(ctx.source_addr),
+        ),
+    };
+    let target = SocketAddr::new(target, port);
+    let socket = match &ctx.udp_affinity {
+        Some(affinity) => {
+            let socket = bind_with_affinity(client_id, source, target, affinity)?;
+            affinity.remember(client_id, target, socket.local_addr()?.port());
+            socket
+        }
+        None => UdpSocket::bind(SocketAddr::new(source, 0)).await?,
+    };
+
+    // Connect the UDP socket to the target (allows send/recv instead of send_to/recv_from)
+    socket.connect(target).await?;
+    if ctx.udp_dont_fragment {
+        if let Err(e) = set_udp_dont_fragment(&socket) {
+            warn!(client_id, error = %e, "Failed to set Don't Fragment on UDP socket");
+        }
+    }
+
+    info!(client_id, port, "UDP socket ready");
+
+    // Send CONNECTED message
+    let connected = protocol::build_connected(Proto::Udp, client_id);
+    {
+        let mut sender = transport.lock().await;
+        sender.send(connected).await?;
+    }
+    let _audit = ctx
+        .audit
+        .opened(port, &ctx.metrics)
+        .with_webhook(ctx.webhook.as_ref());
+
+    // Split socket for concurrent read/write
+    let socket = Arc::new(socket);
+    let socket_read = socket.clone();
+    let socket_write = socket.clone();
+
+    let keepalive_ctx = ctx.clone();
+    let activity = ctx.activity.clone();
+    let audit = ctx.audit.clone();
+
+    // Task to read from UDP and send to the runner. Reads go through a
+    // bounded queue so a slow runner link drops datagrams per the overflow
+    // policy instead of stalling the socket.
+    let read_task = keepalive_ctx.spawn(
+        async move {
+            let queue = DatagramQueue::new(datagram::QUEUE_DEPTH, ctx.udp_overflow);
+            let forward = async {
+                let mut delta = ctx.delta.then(DeltaEncoder::default);
+                let overhead = protocol::BATCH_LEN_SIZE + ctx.seal_overhead();
+                let batch_max = UDP_BATCH_MAX_BYTES.min(ctx.max_payload);
+                while let Some(first) = queue.recv().await {
+                    // Whatever queued up behind the previous send goes out
+                    // together
+                    let mut datagrams = vec![first];
+                    if ctx.udp_batch {
+                        if let Some(budget) =
+                            batch_max.checked_sub(datagrams[0].len() + overhead)
+                        {
+                            datagrams.extend(queue.recv_queued(budget, overhead));
+                        }
+                    }
+                    let data = ctx.udp_frame(delta.as_mut(), &datagrams);
+                    if ctx.send_data(data).await.is_err() {
+                        ctx.audit.close_reason(CloseReason::TransportLost);
+                        break;
+                    }
+                }
+                queue.close();
+            };
+            let receive = async {
+                let mut buf = vec![0u8; UDP_RECV_BUFFER_SIZE];
+                loop {
+                    match socket_read.recv(&mut buf).await {
+                        Ok(n) => {
+                            debug!(client_id, bytes = n, "Read from UDP, sending to runner");
+                            ctx.activity.touch();
+                            ctx.audit.record_out(n);
+                            if n + ctx.seal_overhead() > ctx.max_payload {
+                                // A datagram can't be split across frames
+                                ctx.metrics.udp_too_large();
+                                warn!(
+                                    client_id,
+                                    bytes = n,
+                                    max_payload = ctx.max_payload,
+                                    "Datagram from the local service exceeds the payload cap, dropping it"
+                                );
+                                continue;
+                            }
+                            match queue.send(Bytes::copy_from_slice(&buf[..n])).await {
+                                TrySend::Queued | TrySend::Full(_) => {}
+                                TrySend::Dropped => {
+                                    ctx.metrics.udp_dropped();
+                                    debug!(client_id, policy = ?ctx.udp_overflow, "Queue to the runner full, dropped a datagram");
+                                }
+                                TrySend::Closed => break,
+                            }
+                        }
+                        Err(e) => {
+                            error!(client_id, error = %e, "UDP recv error");
+                            ctx.audit.close_reason(CloseReason::Error);
+                            break;
+                        }
+                    }
+                }
+                // Let the queued datagrams go out before the CLOSE
+                queue.close();
+            };
+            tokio::join!(forward, receive);
+        }
+        .in_current_span(),
+    );
+
+    // Task to receive data from channel and write to UDP. Once told to
+    // drain, it writes only what is already queued and ends.
+    let (drain_tx, mut drain_rx) = oneshot::channel::<()>();
+    let mut write_task = keepalive_ctx.spawn(
+        async move {
+            let mut draining = false;
+            while let Some(data) = next_datagram(&mut data_rx, &mut drain_rx, &mut draining).await {
+                debug!(client_id, bytes = data.len(), "Writing to UDP");
+                match send_udp_datagram(client_id, data.len(), || socket_write.send(&data)).await {
+                    Ok(UdpSendOutcome::Sent) => {
+                        activity.touch();
+                        audit.record_in(data.len());
+                    }
+                    Ok(UdpSendOutcome::Dropped) => {}
+                    Ok(UdpSendOutcome::TooLarge) => {
+                        metrics.udp_too_large();
+                        let suggested_max = udp_path_max_payload(&socket_write)
+                            .map_or(MAX_UDP_PAYLOAD, |max| max.min(MAX_UDP_PAYLOAD));
+                        warn!(
+                            client_id,
+                            bytes = data.len(),
+                            suggested_max,
+                            "Datagram too large for the path to the local service \
+                             (EMSGSIZE), dropping it; keep datagrams within suggested_max"
+                        );
+                    }
+                    Ok(UdpSendOutcome::Short(sent)) => {
+                        metrics.udp_short_send();
+                        warn!(
+                            client_id,
+                            bytes = data.len(),
+                            sent,
+                            "UDP datagram was truncated on send"
+                        );
+                    }
+                    Err(e) => {
+                        error!(client_id, error = %e, "UDP send error");
+                        return WriteEnd::Failed;
+                    }
+                }
+            }
+            debug!(
+                client_id,
+                draining, "UDP write task ending (channel closed)"
+            );
+            WriteEnd::ChannelClosed
+        }
+        .in_current_span(),
+    );
+
+    // Wait for either task to complete; the other is aborted when its guard
+    // drops
+    tokio::select! {
+        _ = read_task => {
+            debug!(client_id, "UDP read task completed");
+            let drain = keepalive_ctx.udp_close_drain;
+            if !drain.is_zero() && drain_tx.send(()).is_ok() {
+                match tokio::time::timeout(drain, &mut write_task).await {
+                    Ok(_) => debug!(client_id, "Wrote queued datagrams before closing"),
+                    Err(_) => debug!(
+                        client_id,
+                        ?drain,
+                        "Queued datagrams not written within the drain timeout, dropping the rest"
+                    ),
+                }
+            }
+            let close = protocol::build_close(Proto::Udp, client_id);
+            let _ = transport.lock().await.send(close).await;
+        }
+        end = &mut write_task => {
+            debug!(client_id, "UDP write task completed");
+            if let Ok(WriteEnd::Failed) = end {
+                keepalive_ctx.audit.close_reason(CloseReason::Error);
+                let close = protocol::build_close(Proto::Udp, client_id);
+                let mut sender = transport.lock().await;
+                let _ = sender.send(close).await;
+            }
+        }
+        e = idle_keepalive(&keepalive_ctx, Proto::Udp) => {
+            debug!(client_id, error = %e, "Keepalive failed");
+            keepalive_ctx.audit.close_reason(CloseReason::TransportLost);
+        }
+        _ = runner_silence(&keepalive_ctx) => {
+            warn!(client_id, "No data from the runner since CONNECTED, closing");
+            keepalive_ctx.audit.close_reason(CloseReason::RunnerSilent);
+            let close = protocol::build_close(Proto::Udp, client_id);
+            let _ = transport.lock().await.send(close).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle a raw IP "connection": a raw socket for the protocol number the
+/// CONNECT carried in its port field
+#[cfg(feature = "raw")]
+async fn handle_raw_connection(ctx: ConnContext, mut data_rx: DataReceiver) -> Result<()> {
+    let client_id = ctx.client_id;
+    let protocol = ctx.port as u8;
+    let transport = ctx.transport.clone();
+    let target = ctx
+        .target_host
+        .unwrap_or_else(|| loopback_for(ctx.source_addr));
+
+    let socket = match RawSocket::connect(protocol, ctx.source_addr, target) {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            let error_msg = protocol::build_error(Proto::Raw, client_id, &e.to_string());
+            let mut sender = transport.lock().await;
+            let _ = sender.send(error_msg).await;
+            return Err(e.into());
+        }
+    };
+
+    info!(client_id, protocol, "Raw socket ready");
+
+    let connected = protocol::build_connected(Proto::Raw, client_id);
+    {
+        let mut sender = transport.lock().await;
+        sender.send(connected).await?;
+    }
+    let _audit = ctx
+        .audit
+        .opened(ctx.port, &ctx.metrics)
+        .with_webhook(ctx.webhook.as_ref());
+
+    let socket_read = socket.clone();
+    let keepalive_ctx = ctx.clone();
+    let activity = ctx.activity.clone();
+    let audit = ctx.audit.clone();
+
+    // Task to read packets and send them to the runner
+    let transport_clone = transport.clone();
+    let read_task = keepalive_ctx.spawn(
+        async move {
+            let mut buf = vec![0u8; 65536];
+            let mut delta = ctx.delta.then(DeltaEncoder::default);
+            loop {
+                match socket_read.recv(&mut buf).await {
+                    Ok(n) => {
+                        debug!(client_id, bytes = n, "Read raw packet, sending to runner");
+                        ctx.activity.touch();
+                        ctx.audit.record_out(n);
+                        if n + ctx.seal_overhead() > ctx.max_payload {
+                            warn!(
+                                client_id,
+                                bytes = n,
+                                max_payload = ctx.max_payload,
+                                "Packet exceeds the payload cap, dropping it"
+                            );
+                            continue;
+                        }
+                        let data = ctx.outgoing_frame(delta.as_mut(), Proto::Raw, &buf[..n]);
+                        if ctx.send_data(data).await.is_err() {
+                            ctx.audit.close_reason(CloseReason::TransportLost);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!(client_id, error = %e, "Raw recv error");
+                        ctx.audit.close_reason(CloseReason::Error);
+                        break;
+                    }
+                }
+            }
+
+            let close = protocol::build_close(Proto::Raw, client_id);
+            let mut sender = transport_clone.lock().await;
+            let _ = sender.send(close).await;
+        }
+        .in_current_span(),
+    );
+
+    // Task to write runner data as packets. IP is lossy, so a packet the
+    // kernel refuses is dropped rather than ending the connection.
+    let write_task = keepalive_ctx.spawn(
+        async move {
+            while let Some(data) = data_rx.recv().await {
+                match socket.send(&data).await {
+                    Ok(_) => {
+                        activity.touch();
+                        audit.record_in(data.len());
+                    }
+                    Err(e) => {
+                        warn!(client_id, bytes = data.len(), error = %e, "Dropping raw packet");
+                    }
+                }
+            }
+            debug!(client_id, "Raw write task ending (channel closed)");
+        }
+        .in_current_span(),
+    );
+
+    tokio::select! {
+        _ = read_task => {
+            debug!(client_id, "Raw read task completed");
+        }
+        _ = write_task => {
+            debug!(client_id, "Raw write task completed");
+        }
+        e = idle_keepalive(&keepalive_ctx, Proto::Raw) => {
+            debug!(client_id, error = %e, "Keepalive failed");
+            keepalive_ctx.audit.close_reason(CloseReason::TransportLost);
+        }
+        _ = runner_silence(&keepalive_ctx) => {
+            warn!(client_id, "No data from the runner since CONNECTED, closing");
+            keepalive_ctx.audit.close_reason(CloseReason::RunnerSilent);
+            let close = protocol::build_close(Proto::Raw, client_id);
+            let _ = transport.lock().await.send(close).await;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::Mutex;
+
+    use crate::protocol::{Header, MsgType, HEADER_SIZE};
+    use crate::transport::{self, MemoryPeer};
+
+    /// Create a (tunnel sender, runner end) pair over an in-memory transport
+    fn runner_pair() -> (TransportSender, MemoryPeer) {
+        let ((sink, _), runner) = transport::memory(1024);
+        (Arc::new(Mutex::new(sink)), runner)
+    }
+
+    /// Receive the next frame sent by the tunnel
+    async fn next_frame(runner: &mut MemoryPeer) -> Bytes {
+        runner.next_frame().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_proto_rejected_with_error() {
+        let (transport, mut runner) = runner_pair();
+        let config = TunnelConfig {
+            allowed_protos: vec![Proto::Tcp],
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(7, Proto::Udp, 5353).await;
+
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Error);
+        assert_eq!(header.proto, Proto::Udp);
+        assert_eq!(header.client_id, 7);
+        assert!(manager.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_udp_disabled_rejects_udp_connect() {
+        let (transport, mut runner) = runner_pair();
+        let config = TunnelConfig {
+            disable_udp: true,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(9, Proto::Udp, 5353).await;
+
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Error);
+        assert_eq!(header.proto, Proto::Udp);
+        assert_eq!(header.client_id, 9);
+        assert_eq!(protocol::get_payload(&frame), b"UDP forwarding is disabled");
+        assert!(manager.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_udp_disabled_still_allows_tcp() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            disable_udp: true,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(10, Proto::Tcp, port).await;
+        let _accepted = service.accept().await.unwrap();
+
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+        assert!(manager.connections.contains_key(&10));
+    }
+
+    #[tokio::test]
+    async fn test_raw_connect_for_tcp_protocol_rejected() {
+        let (transport, mut runner) = runner_pair();
+        let config = TunnelConfig {
+            allowed_protos: vec![Proto::Raw],
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(12, Proto::Raw, 6).await;
+
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(
+            (header.msg_type, header.proto),
+            (MsgType::Error, Proto::Raw)
+        );
+        #[cfg(feature = "raw")]
+        assert_eq!(protocol::get_payload(&frame), b"Use TCP for IP protocol 6");
+        #[cfg(not(feature = "raw"))]
+        assert_eq!(
+            protocol::get_payload(&frame),
+            b"Raw forwarding is not supported"
+        );
+        assert!(manager.connections.is_empty());
+    }
+
+    #[cfg(feature = "raw")]
+    #[tokio::test]
+    async fn test_raw_relays_packets() {
+        // Raw sockets need CAP_NET_RAW, which test environments often lack
+        if raw::check_capability().is_err() {
+            return;
+        }
+        const PROTOCOL: u8 = 253;
+        let (transport, mut runner) = runner_pair();
+        let config = TunnelConfig {
+            allowed_protos: vec![Proto::Raw],
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        let service = RawSocket::connect(PROTOCOL, None, Ipv4Addr::LOCALHOST.into()).unwrap();
+
+        manager
+            .handle_connect(13, Proto::Raw, PROTOCOL.into())
+            .await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        // Runner to service, with the IP header stripped
+        manager.handle_data(13, Proto::Raw, b"to service").await;
+        let mut buf = [0u8; 64];
+        let n = service.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"to service");
+
+        // Service to runner; loopback also hands the tunnel's own packet
+        // back to it, so skip past that
+        service.send(b"to runner").await.unwrap();
+        loop {
+            let frame = next_frame(&mut runner).await;
+            let header = Header::parse(&frame).unwrap();
+            assert_eq!((header.msg_type, header.proto), (MsgType::Data, Proto::Raw));
+            if protocol::get_payload(&frame) == b"to runner" {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_port_rejected_with_error() {
+        let (transport, mut runner) = runner_pair();
+        let config = TunnelConfig {
+            allowed_ports: Some("22,8000-8100".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(6, Proto::Tcp, 443).await;
+
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Error);
+        assert_eq!(protocol::get_payload(&frame), b"Port 443 is not allowed");
+        assert!(manager.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_policy_deny_sends_reason() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let policy = move |_client_id: u32, _proto: Proto, requested: u16| {
+            if requested == port {
+                PolicyDecision::Allow
+            } else {
+                PolicyDecision::Deny("outside business hours".into())
+            }
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()))
+            .with_connect_policy(Arc::new(policy));
+
+        manager.handle_connect(20, Proto::Tcp, 8080).await;
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Error);
+        assert_eq!(header.client_id, 20);
+        assert_eq!(protocol::get_payload(&frame), b"outside business hours");
+        assert!(!manager.connections.contains_key(&20));
+
+        manager.handle_connect(21, Proto::Tcp, port).await;
+        let _accepted = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+        assert_eq!(header.client_id, 21);
+    }
+
+    #[tokio::test]
+    async fn test_allowed_proto_opens_connection() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let config = TunnelConfig {
+            allowed_protos: vec![Proto::Tcp],
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(8, Proto::Tcp, port).await;
+        let _accepted = service.accept().await.unwrap();
+
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+        assert_eq!(header.client_id, 8);
+        assert!(manager.connections.contains_key(&8));
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_udp_max_datagram_roundtrip() {
+        let (transport, mut runner) = runner_pair();
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        manager.handle_connect(11, Proto::Udp, port).await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        // Runner -> local: a max-size datagram arrives intact as one datagram
+        let outbound: Vec<u8> = (0..MAX_UDP_PAYLOAD).map(|i| (i % 251) as u8).collect();
+        manager.handle_data(11, Proto::Udp, &outbound).await;
+        let mut buf = vec![0u8; UDP_RECV_BUFFER_SIZE];
+        let (n, tunnel_addr) = service.recv_from(&mut buf).await.unwrap();
+        assert_eq!(n, MAX_UDP_PAYLOAD);
+        assert_eq!(&buf[..n], &outbound[..]);
+
+        // Local -> runner: a max-size datagram becomes exactly one DATA frame
+        let inbound: Vec<u8> = (0..MAX_UDP_PAYLOAD).map(|i| (i % 241) as u8).collect();
+        service.send_to(&inbound, tunnel_addr).await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Data);
+        assert_eq!(header.client_id, 11);
+        assert_eq!(protocol::get_payload(&frame), &inbound[..]);
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_udp_close_drains_queued_writes() {
+        let (tx, mut rx) = spill::data_queue(256, None, None);
+        let (drain_tx, mut drain_rx) = oneshot::channel();
+        let mut draining = false;
+
+        // Until told to drain, an empty queue is waited on
+        let waited = tokio::time::timeout(
+            Duration::from_millis(20),
+            next_datagram(&mut rx, &mut drain_rx, &mut draining),
+        )
+        .await;
+        assert!(waited.is_err());
+
+        // Datagrams queued before the local socket failed are still written,
+        // then the write side ends although the runner's sender is still open
+        for n in 0..3u8 {
+            tx.send(Bytes::from(vec![n; 4])).await.unwrap();
+        }
+        drain_tx.send(()).unwrap();
+        let mut written = Vec::new();
+        while let Some(data) = next_datagram(&mut rx, &mut drain_rx, &mut draining).await {
+            written.push(data);
+        }
+        assert!(draining);
+        assert_eq!(
+            written,
+            (0..3u8)
+                .map(|n| Bytes::from(vec![n; 4]))
+                .collect::<Vec<_>>()
+        );
+        drop(tx);
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_udp_batch_roundtrip() {
+        // Room for one frame only, so datagrams queue up behind the send
+        let ((sink, _), mut runner) = transport::memory(1);
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let mut manager = ConnectionManager::new(
+            Arc::new(Mutex::new(sink)),
+            Arc::new(TunnelConfig::default()),
+        );
+        manager.set_runner_features(protocol::FEATURE_UDP_BATCH);
+        manager.handle_connect(12, Proto::Udp, port).await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        // Runner -> local: each datagram of a BATCH is delivered on its own
+        let batch = protocol::build_batch(12, &[&b"one"[..], b"", b"three"]);
+        manager
+            .handle_batch(12, Proto::Udp, protocol::get_payload(&batch))
+            .await;
+        let mut buf = vec![0u8; UDP_RECV_BUFFER_SIZE];
+        let mut received = Vec::new();
+        let mut tunnel_addr = None;
+        for _ in 0..3 {
+            let (n, addr) = service.recv_from(&mut buf).await.unwrap();
+            received.push(buf[..n].to_vec());
+            tunnel_addr = Some(addr);
+        }
+        assert_eq!(received, [&b"one"[..], b"", b"three"]);
+
+        // Local -> runner: a burst arrives in order, the backlog batched
+        let burst: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; i as usize + 1]).collect();
+        for datagram in &burst {
+            service
+                .send_to(datagram, tunnel_addr.unwrap())
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut forwarded = Vec::new();
+        let mut batches = 0;
+        while forwarded.len() < burst.len() {
+            let frame = next_frame(&mut runner).await;
+            let header = Header::parse(&frame).unwrap();
+            assert_eq!(header.client_id, 12);
+            match header.msg_type {
+                MsgType::Data => forwarded.push(protocol::get_payload(&frame).to_vec()),
+                MsgType::Batch => {
+                    batches += 1;
+                    let payloads = protocol::split_batch(protocol::get_payload(&frame));
+                    forwarded.extend(payloads.unwrap().into_iter().map(<[u8]>::to_vec));
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert_eq!(forwarded, burst);
+        assert!(batches > 0, "the queued datagrams should share a BATCH");
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_malformed_batch_dropped() {
+        let (transport, mut runner) = runner_pair();
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        manager.handle_connect(13, Proto::Udp, port).await;
+        next_frame(&mut runner).await; // CONNECTED
+
+        // A length running past the end drops the whole batch
+        manager
+            .handle_batch(13, Proto::Udp, &[0, 1, b'a', 0, 9, b'b'])
+            .await;
+        manager.handle_data(13, Proto::Udp, b"after").await;
+        let mut buf = [0u8; 16];
+        let (n, _) = service.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"after");
+        assert!(manager.connections.contains_key(&13));
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_udp_port_affinity_across_sessions() {
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = Arc::new(TunnelConfig {
+            udp_port_affinity: true,
+            ..Default::default()
+        });
+        let affinity = Arc::new(UdpAffinity::default());
+
+        // Without waiting for the first socket to close
+        let first = udp_source_port(&service, config.clone(), &affinity).await;
+        let second = udp_source_port(&service, config, &affinity).await;
+        assert_eq!(first, second);
+
+        // Without affinity every session gets a fresh port
+        let third = udp_source_port(&service, Arc::default(), &affinity).await;
+        assert_ne!(third, first);
+    }
+
+    /// Port a one-off session's UDP connection for client 9 sends to
+    /// `service` from
+    #[cfg(feature = "udp")]
+    async fn udp_source_port(
+        service: &UdpSocket,
+        config: Arc<TunnelConfig>,
+        affinity: &Arc<UdpAffinity>,
+    ) -> u16 {
+        let (transport, mut runner) = runner_pair();
+        let mut manager =
+            ConnectionManager::new(transport, config).with_udp_affinity(affinity.clone());
+        let port = service.local_addr().unwrap().port();
+        manager.handle_connect(9, Proto::Udp, port).await;
+        next_frame(&mut runner).await; // CONNECTED
+        manager.handle_data(9, Proto::Udp, b"hello").await;
+        let mut buf = [0u8; 16];
+        let (_, from) = service.recv_from(&mut buf).await.unwrap();
+        manager.shutdown().await;
+        from.port()
+    }
+
+    /// Open a TCP connection for `client_id` and accept it on `service`
+    async fn open_tcp(
+        manager: &mut ConnectionManager,
+        runner: &mut MemoryPeer,
+        service: &TcpListener,
+        client_id: u32,
+    ) -> TcpStream {
+        let port = service.local_addr().unwrap().port();
+        manager.handle_connect(client_id, Proto::Tcp, port).await;
+        let (local, _) = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+        assert_eq!(header.client_id, client_id);
+        local
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_buffer_budget_closes_only_that_connection() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            max_total_buffer_bytes: Some(16),
+            ..Default::default()
+        };
+        // Another session's queue holds the whole shared budget
+        let budget = Arc::new(MemoryBudget::new(16));
+        let (other, _other_rx) = spill::data_queue(8, None, Some(budget.clone()));
+        other.send(Bytes::from(vec![0; 16])).await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let mut manager = ConnectionManager::new(transport, Arc::new(config))
+            .with_buffer_budget(budget)
+            .with_metrics(metrics.clone());
+        let _local = open_tcp(&mut manager, &mut runner, &service, 40).await;
+
+        // Refused at once instead of holding up the message loop
+        manager.handle_data(40, Proto::Tcp, b"hello").await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Close, 40));
+        assert!(!manager.connections.contains_key(&40));
+        assert_eq!(metrics.buffer_budget_shed_count(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_interleaved_data_arrives_in_order() {
+        use rand::{Rng, SeedableRng};
+
+        const CONNS: u32 = 4;
+        const FRAMES: usize = 2000;
+
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // A tiny queue, so most payloads spill to disk and are replayed
+        let config = TunnelConfig {
+            send_queue_depth: 2,
+            spill_dir: Some(std::env::temp_dir()),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        let mut locals = Vec::new();
+        for client_id in 1..=CONNS {
+            locals.push(open_tcp(&mut manager, &mut runner, &service, client_id).await);
+        }
+
+        // Each connection's stream is a run of sequence numbers, cut into
+        // frames of random size and interleaved at random with the others
+        let mut rng = rand::rngs::StdRng::seed_from_u64(420);
+        let mut next = [0u32; CONNS as usize];
+        let mut sent = [0usize; CONNS as usize];
+        let mut remaining = [FRAMES; CONNS as usize];
+        while remaining.iter().any(|&n| n > 0) {
+            let conn = rng.gen_range(0..CONNS as usize);
+            if remaining[conn] == 0 {
+                continue;
+            }
+            remaining[conn] -= 1;
+            let words = rng.gen_range(1..200);
+            let payload: Vec<u8> = (0..words)
+                .flat_map(|_| {
+                    next[conn] += 1;
+                    next[conn].to_be_bytes()
+                })
+                .collect();
+            sent[conn] += payload.len();
+            manager
+                .handle_data(conn as u32 + 1, Proto::Tcp, &payload)
+                .await;
+        }
+
+        let readers = locals.into_iter().zip(sent).map(|(mut local, len)| {
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; len];
+                local.read_exact(&mut buf).await.unwrap();
+                buf
+            })
+        });
+        for (conn, reader) in readers.enumerate() {
+            let received = reader.await.unwrap();
+            let in_order = received
+                .chunks(4)
+                .zip(1u32..)
+                .all(|(word, n)| word == n.to_be_bytes());
+            assert!(in_order, "connection {} reordered", conn + 1);
+            assert_eq!(received.len() / 4, next[conn] as usize);
+        }
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_queued_bounds() {
+        let (tx, mut rx) = spill::data_queue(256, None, None);
+        for n in 0..100u8 {
+            tx.send(Bytes::from(vec![n; 10])).await.unwrap();
+        }
+        // Stops at the chunk count, keeping the order
+        let first = rx.recv_queued().await.unwrap();
+        let joined = coalesce_queued(first, &mut rx).await;
+        assert_eq!(joined.len(), COALESCE_MAX_CHUNKS * 10);
+        assert!(joined.chunks(10).zip(0u8..).all(|(c, n)| c == [n; 10]));
+
+        // The chunk crossing the byte bound is the last one taken
+        tx.send(Bytes::from(vec![0xEE; COALESCE_MAX_BYTES]))
+            .await
+            .unwrap();
+        tx.send(Bytes::from_static(b"tail")).await.unwrap();
+        let rest = coalesce_queued(rx.recv_queued().await.unwrap(), &mut rx).await;
+        assert_eq!(
+            rest.len(),
+            (100 - COALESCE_MAX_CHUNKS) * 10 + COALESCE_MAX_BYTES
+        );
+
+        assert_eq!(
+            coalesce_queued(rx.recv_queued().await.unwrap(), &mut rx).await,
+            Bytes::from_static(b"tail")
+        );
+
+        // A chunk at the bound goes out alone
+        tx.send(Bytes::from(vec![0xEE; COALESCE_MAX_BYTES]))
+            .await
+            .unwrap();
+        tx.send(Bytes::from_static(b"tail")).await.unwrap();
+        let big = coalesce_queued(rx.recv_queued().await.unwrap(), &mut rx).await;
+        assert_eq!(big.len(), COALESCE_MAX_BYTES);
+        assert_eq!(rx.recv_queued().await, Some(Bytes::from_static(b"tail")));
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_writes_keep_byte_stream() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 21).await;
+
+        // Many small frames of varying size, queued faster than written
+        let chunks: Vec<Vec<u8>> = (0..2000u32)
+            .map(|i| (0..(i % 97 + 1)).map(|j| (i * 31 + j) as u8).collect())
+            .collect();
+        let expected: Vec<u8> = chunks.concat();
+        let reader = async {
+            let mut received = vec![0u8; expected.len()];
+            local.read_exact(&mut received).await.unwrap();
+            received
+        };
+        let writer = async {
+            for chunk in &chunks {
+                manager.handle_data(21, Proto::Tcp, chunk).await;
+            }
+        };
+        let (received, ()) = tokio::join!(reader, writer);
+        assert!(received == expected, "byte stream changed in coalescing");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_falls_back_to_ipv6_loopback() {
+        let Ok(service) = TcpListener::bind("[::1]:0").await else {
+            // No IPv6 on this host
+            return;
+        };
+        let (transport, mut runner) = runner_pair();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 60).await;
+
+        manager.handle_data(60, Proto::Tcp, b"v6").await;
+        let mut buf = [0u8; 2];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"v6");
+    }
+
+    #[tokio::test]
+    async fn test_empty_tcp_data_is_noop() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 18).await;
+
+        // Nothing is queued for the writer
+        manager.handle_data(18, Proto::Tcp, &[]).await;
+        assert_eq!(manager.connections[&18].data_tx.queued(), 0);
+
+        manager.handle_data(18, Proto::Tcp, b"x").await;
+        let mut buf = [0u8; 1];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"x");
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_empty_udp_data_sends_empty_datagram() {
+        let (transport, mut runner) = runner_pair();
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        manager.handle_connect(19, Proto::Udp, port).await;
+        next_frame(&mut runner).await; // CONNECTED
+
+        manager.handle_data(19, Proto::Udp, &[]).await;
+        let mut buf = [0u8; 16];
+        let (n, tunnel_addr) = service.recv_from(&mut buf).await.unwrap();
+        assert_eq!(n, 0);
+
+        // ...and an empty datagram back becomes an empty DATA frame
+        service.send_to(&[], tunnel_addr).await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Data, 19));
+        assert!(protocol::get_payload(&frame).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_probe_reports_without_relaying() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+
+        manager.handle_probe(70, Proto::Tcp, port).await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(
+            (header.msg_type, header.client_id),
+            (MsgType::Connected, 70)
+        );
+
+        // The probe connection is closed at once and not tracked
+        let (mut local, _) = service.accept().await.unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(local.read(&mut buf).await.unwrap(), 0);
+        assert!(manager.connections.is_empty());
+
+        drop(service);
+        manager.handle_probe(71, Proto::Tcp, port).await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Error, 71));
+    }
+
+    #[tokio::test]
+    async fn test_close_stops_connection_tasks() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager =
+            ConnectionManager::new(transport.clone(), Arc::new(TunnelConfig::default()));
+        let idle = Arc::strong_count(&transport);
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 80).await;
+        assert!(Arc::strong_count(&transport) > idle);
+
+        // Every task holding the transport stops, though the local service
+        // never closed its end
+        manager.handle_close(80).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Arc::strong_count(&transport) > idle {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("connection tasks still running after CLOSE");
+
+        let mut buf = [0u8; 1];
+        assert_eq!(local.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_audit_records_bytes_and_close_reason() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+
+        // Closed by the runner
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 100).await;
+        let audit = manager.connections[&100].audit.clone();
+        manager.handle_data(100, Proto::Tcp, b"hello").await;
+        let mut buf = [0u8; 5];
+        local.read_exact(&mut buf).await.unwrap();
+        local.write_all(b"hi").await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Data);
+        manager.handle_close(100).await;
+        assert_eq!(audit.reason(), Some(CloseReason::Remote));
+        assert_eq!((audit.bytes_in(), audit.bytes_out()), (5, 2));
+
+        // Closed by the local service
+        let local = open_tcp(&mut manager, &mut runner, &service, 101).await;
+        let audit = manager.connections[&101].audit.clone();
+        drop(local);
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        manager.handle_close(101).await;
+        assert_eq!(audit.reason(), Some(CloseReason::LocalEof));
+
+        // Both land in the per-connection histograms once their tasks end
+        let metrics = manager.metrics.clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.closed_connections() < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        #[cfg(feature = "metrics")]
+        assert!(metrics.render().contains("tunnel_conn_bytes_sum 7\n"));
+    }
+
+    #[tokio::test]
+    async fn test_runner_half_close_keeps_reading() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 90).await;
+
+        // Data queued ahead of the half-close arrives, then EOF
+        manager.handle_data(90, Proto::Tcp, b"request").await;
+        manager.handle_half_close(90);
+        let mut received = Vec::new();
+        local.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"request");
+
+        // The other direction still flows
+        local.write_all(b"response").await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Data);
+        assert_eq!(&frame[HEADER_SIZE..], b"response");
+        assert!(manager.connections[&90].is_live());
+
+        // Until the local service closes its end too
+        drop(local);
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+    }
+
+    #[tokio::test]
+    async fn test_connect_by_service_name() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            service_map: format!("jupyter={port}").parse().unwrap(),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager
+            .handle_connect_labeled(1, Proto::Tcp, 0, b"jupyter")
+            .await;
+        let _local = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+        assert_eq!(manager.connections[&1].audit.label(), Some("jupyter"));
+
+        manager
+            .handle_connect_labeled(2, Proto::Tcp, 0, b"db")
+            .await;
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Error);
+        assert_eq!(
+            protocol::parse_error(&frame[HEADER_SIZE..]),
+            "Unknown service db"
+        );
+
+        // A custom resolver replaces the map
+        let resolver: crate::config::ServiceMap = format!("lab={port}").parse().unwrap();
+        let mut manager = manager.with_service_resolver(Arc::new(resolver));
+        manager
+            .handle_connect_labeled(3, Proto::Tcp, 0, b"lab")
+            .await;
+        let _local = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Connected, 3));
+    }
+
+    /// Answers every lookup with `port` once a permit is added
+    struct SlowResolver {
+        port: u16,
+        release: Semaphore,
+    }
+
+    impl ServiceResolver for SlowResolver {
+        fn resolve<'a>(&'a self, _name: &'a str) -> BoxFuture<'a, Option<u16>> {
+            Box::pin(async move {
+                let _ = self.release.acquire().await;
+                Some(self.port)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_service_lookup_holds_data_off_the_loop() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let resolver = Arc::new(SlowResolver {
+            port,
+            release: Semaphore::new(0),
+        });
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()))
+            .with_service_resolver(resolver.clone());
+
+        // The lookup doesn't block: DATA is held and other CONNECTs go ahead
+        manager
+            .handle_connect_labeled(1, Proto::Tcp, 0, b"slow")
+            .await;
+        manager.handle_data(1, Proto::Tcp, b"hello").await;
+        manager.handle_half_close(1);
+        manager.handle_connect(2, Proto::Tcp, port).await;
+        let _other = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Connected, 2));
+        assert!(!manager.connections.contains_key(&1));
+
+        // A CONNECT closed before its lookup finishes is never opened
+        manager
+            .handle_connect_labeled(3, Proto::Tcp, 0, b"slow")
+            .await;
+        manager.handle_close(3).await;
+
+        resolver.release.add_permits(2);
+        let resolved = std::future::poll_fn(|cx| manager.poll_resolved(cx)).await;
+        manager.finish_resolved(resolved).await;
+        let (mut local, _) = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Connected, 1));
+        assert_eq!(manager.connections[&1].audit.label(), Some("slow"));
+        let mut received = Vec::new();
+        local.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+
+        // The closed CONNECT's lookup is dropped when it answers
+        if let Ok(resolved) = tokio::time::timeout(
+            Duration::from_millis(100),
+            std::future::poll_fn(|cx| manager.poll_resolved(cx)),
+        )
+        .await
+        {
+            manager.finish_resolved(resolved).await;
+        }
+        assert!(!manager.connections.contains_key(&3));
+    }
+
+    #[tokio::test]
+    async fn test_connect_label() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+
+        manager
+            .handle_connect_labeled(1, Proto::Tcp, port, b"jupyter")
+            .await;
+        let _local = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+        assert_eq!(manager.connections[&1].audit.label(), Some("jupyter"));
+        #[cfg(feature = "metrics")]
+        assert!(manager
+            .metrics
+            .render()
+            .contains("tunnel_labeled_connections_total{label=\"jupyter\"} 1\n"));
+
+        // A label that doesn't fit into a metric is refused up front
+        manager
+            .handle_connect_labeled(2, Proto::Tcp, port, b"bad label\"")
+            .await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Error);
+        assert_eq!(header.client_id, 2);
+        assert!(!manager.connections.contains_key(&2));
+    }
+
+    #[tokio::test]
+    async fn test_per_port_limit() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            port_limits: format!("{port}=2").parse().unwrap(),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        let wait_for_count = |manager: &ConnectionManager, expected: usize| {
+            let active = manager.port_active[&port].clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while active.load(Ordering::Relaxed) != expected {
+                        tokio::task::yield_now().await;
+                    }
+                })
+                .await
+                .expect("port count never settled");
+            }
+        };
+
+        let _first = open_tcp(&mut manager, &mut runner, &service, 110).await;
+        let second = open_tcp(&mut manager, &mut runner, &service, 111).await;
+        assert_eq!(manager.port_connections(port), 2);
+
+        // Over the limit: rejected without touching the local service
+        manager.handle_connect(112, Proto::Tcp, port).await;
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Error);
+        assert_eq!(header.client_id, 112);
+        assert_eq!(
+            protocol::get_payload(&frame),
+            format!("Connection limit (2) reached for port {port}").as_bytes()
+        );
+        assert!(!manager.connections.contains_key(&112));
+
+        // A runner CLOSE frees a slot
+        manager.handle_close(110).await;
+        wait_for_count(&manager, 1).await;
+        let _third = open_tcp(&mut manager, &mut runner, &service, 113).await;
+        assert_eq!(manager.port_connections(port), 2);
+
+        // So does the local service closing, before the runner's CLOSE
+        drop(second);
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        wait_for_count(&manager, 1).await;
+    }
+
+    #[tokio::test]
+    async fn test_task_limit() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            max_tasks: TASKS_PER_CONNECTION as usize + 2,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        let metrics = manager.metrics.clone();
+        let wait_for_tasks = |expected: u64| {
+            let metrics = metrics.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while metrics.tasks() != expected {
+                        tokio::task::yield_now().await;
+                    }
+                })
+                .await
+                .expect("task count never settled");
+            }
+        };
+
+        // Handler plus read and write task
+        let first = open_tcp(&mut manager, &mut runner, &service, 1).await;
+        wait_for_tasks(TASKS_PER_CONNECTION).await;
+
+        // A second connection would exceed the ceiling
+        manager.handle_connect(2, Proto::Tcp, port).await;
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Error, 2));
+        assert_eq!(protocol::get_payload(&frame), b"Task limit reached");
+        assert!(!manager.connections.contains_key(&2));
+
+        // Closing the first frees its tasks
+        drop(first);
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        manager.handle_close(1).await;
+        wait_for_tasks(0).await;
+        let _second = open_tcp(&mut manager, &mut runner, &service, 2).await;
+    }
+
+    #[tokio::test]
+    async fn test_data_goes_through_priority_egress() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            port_priorities: format!("{port}=low").parse().unwrap(),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 43).await;
+
+        // DATA arrives through the egress queue, and CLOSE still follows it
+        local.write_all(b"bulk").await.unwrap();
+        drop(local);
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Data);
+        assert_eq!(protocol::get_payload(&frame), b"bulk");
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Close);
+    }
+
+    #[tokio::test]
+    async fn test_connections_use_source_addr() {
+        let source: IpAddr = "127.0.0.2".parse().unwrap();
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            source_addr: Some(source),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        let local = open_tcp(&mut manager, &mut runner, &service, 90).await;
+        assert_eq!(local.peer_addr().unwrap().ip(), source);
+
+        #[cfg(feature = "udp")]
+        {
+            let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let port = service.local_addr().unwrap().port();
+            manager.handle_connect(91, Proto::Udp, port).await;
+            next_frame(&mut runner).await;
+            manager.handle_data(91, Proto::Udp, b"hi").await;
+            let mut buf = [0u8; 2];
+            let (_, from) = service.recv_from(&mut buf).await.unwrap();
+            assert_eq!(from.ip(), source);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_id_of_live_connection_rejected() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 50).await;
+
+        manager.handle_connect(50, Proto::Tcp, port).await;
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Error);
+        assert_eq!(protocol::get_payload(&frame), b"Client ID already in use");
+
+        // The original connection is untouched
+        manager.handle_data(50, Proto::Tcp, b"still here").await;
+        let mut buf = [0u8; 10];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"still here");
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_id_replace_policy_reopens() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            duplicate_id_policy: DuplicateIdPolicy::Replace,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        let _old = open_tcp(&mut manager, &mut runner, &service, 51).await;
+
+        // A second CONNECT for the same id gets a fresh local connection
+        let mut new = open_tcp(&mut manager, &mut runner, &service, 51).await;
+        manager.handle_data(51, Proto::Tcp, b"new").await;
+        let mut buf = [0u8; 3];
+        new.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"new");
+    }
+
+    #[tokio::test]
+    async fn test_id_reuse_across_wraparound() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+
+        // The runner's counter wraps from u32::MAX to 0
+        let _max = open_tcp(&mut manager, &mut runner, &service, u32::MAX).await;
+        let _zero = open_tcp(&mut manager, &mut runner, &service, 0).await;
+
+        // After CLOSE the id is free again
+        manager.handle_close(u32::MAX).await;
+        let _reused = open_tcp(&mut manager, &mut runner, &service, u32::MAX).await;
+
+        // A connection whose local side ended is recycled without waiting
+        // for the runner's CLOSE
+        let local = open_tcp(&mut manager, &mut runner, &service, 1).await;
+        drop(local);
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        assert_eq!(header.client_id, 1);
+        while manager.connections[&1].is_live() {
+            tokio::task::yield_now().await;
+        }
+        let _recycled = open_tcp(&mut manager, &mut runner, &service, 1).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_data_after_close_is_recently_closed() {
+        let (transport, _runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+
+        manager.handle_connect(12, Proto::Tcp, port).await;
+        manager.handle_close(12).await;
+
+        // Stray DATA right after CLOSE is recognised as a late frame
+        assert!(manager.was_recently_closed(12));
+        manager.handle_data(12, Proto::Tcp, b"late").await;
+        assert!(!manager.connections.contains_key(&12));
+
+        // Ids that never existed are not
+        assert!(!manager.was_recently_closed(13));
+
+        // After the grace period the id is forgotten
+        tokio::time::advance(CLOSED_GRACE_PERIOD).await;
+        assert!(!manager.was_recently_closed(12));
+
+        // Reusing the id for a new connection clears the record
+        manager.remember_closed(14);
+        manager.handle_connect(14, Proto::Tcp, port).await;
+        assert!(!manager.was_recently_closed(14));
+    }
+
+    #[tokio::test]
+    async fn test_recently_closed_is_bounded() {
+        let (transport, _runner) = runner_pair();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        for id in 0..(MAX_RECENTLY_CLOSED as u32 + 100) {
+            manager.remember_closed(id);
+        }
+        assert_eq!(manager.recently_closed.len(), MAX_RECENTLY_CLOSED);
+    }
+
+    /// Fake send that replays a scripted sequence of results
+    #[cfg(feature = "udp")]
+    fn scripted_send(
+        results: Vec<io::Result<usize>>,
+    ) -> impl FnMut() -> std::future::Ready<io::Result<usize>> {
+        let mut results = results.into_iter();
+        move || std::future::ready(results.next().expect("unexpected extra send"))
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test(start_paused = true)]
+    async fn test_udp_send_transient_error_retried() {
+        let send = scripted_send(vec![Err(io::ErrorKind::WouldBlock.into()), Ok(10)]);
+        let outcome = send_udp_datagram(1, 10, send).await.unwrap();
+        assert_eq!(outcome, UdpSendOutcome::Sent);
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test(start_paused = true)]
+    async fn test_udp_send_persistent_transient_error_drops_datagram() {
+        let send = scripted_send(vec![
+            Err(io::ErrorKind::ConnectionRefused.into()),
+            Err(io::ErrorKind::ConnectionRefused.into()),
+        ]);
+        let outcome = send_udp_datagram(1, 10, send).await.unwrap();
+        assert_eq!(outcome, UdpSendOutcome::Dropped);
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_udp_send_fatal_error_propagates() {
+        let send = scripted_send(vec![Err(io::ErrorKind::PermissionDenied.into())]);
+        assert!(send_udp_datagram(1, 10, send).await.is_err());
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_udp_short_and_oversized_sends_reported() {
+        let send = scripted_send(vec![Ok(4)]);
+        let outcome = send_udp_datagram(1, 10, send).await.unwrap();
+        assert_eq!(outcome, UdpSendOutcome::Short(4));
+
+        // Oversized datagrams are never handed to the socket
+        let send = scripted_send(vec![]);
+        let outcome = send_udp_datagram(1, MAX_UDP_PAYLOAD + 1, send)
+            .await
+            .unwrap();
+        assert_eq!(outcome, UdpSendOutcome::TooLarge);
+
+        #[cfg(unix)]
+        {
+            let send = scripted_send(vec![Err(io::Error::from_raw_os_error(libc::EMSGSIZE))]);
+            let outcome = send_udp_datagram(1, 10, send).await.unwrap();
+            assert_eq!(outcome, UdpSendOutcome::TooLarge);
+        }
+    }
+
+    #[cfg(feature = "udp")]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn test_dont_fragment_oversized_send() {
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(service.local_addr().unwrap()).await.unwrap();
+        set_udp_dont_fragment(&socket).unwrap();
+
+        // Loopback's MTU is far above any real path's
+        let max = udp_path_max_payload(&socket).unwrap();
+        assert!(max >= 1472, "max: {max}");
+
+        // A send the kernel refuses is classified as too large, not fatal
+        let oversized = vec![0u8; max.max(MAX_UDP_PAYLOAD) + 1];
+        let err = socket.send(&oversized).await.unwrap_err();
+        assert!(is_too_large_udp_error(&err), "error: {err}");
+        let outcome = send_udp_datagram(1, MAX_UDP_PAYLOAD, || async {
+            Err(io::Error::from_raw_os_error(libc::EMSGSIZE))
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome, UdpSendOutcome::TooLarge);
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_oversized_udp_data_dropped_and_counted() {
+        let (transport, mut runner) = runner_pair();
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let metrics = Arc::new(Metrics::default());
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()))
+            .with_metrics(metrics.clone());
+        manager.handle_connect(17, Proto::Udp, port).await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        // The oversized frame is dropped; the session survives it
+        let oversized = vec![0xAB; MAX_UDP_PAYLOAD + 1];
+        manager.handle_data(17, Proto::Udp, &oversized).await;
+        manager.handle_data(17, Proto::Udp, b"small").await;
+
+        let mut buf = vec![0u8; UDP_RECV_BUFFER_SIZE];
+        let (n, _) = service.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"small");
+        assert_eq!(metrics.udp_too_large_count(), 1);
+        assert!(manager.connections.contains_key(&17));
+    }
+
+    #[cfg(feature = "udp")]
+    #[cfg(unix)]
+    #[test]
+    fn test_enobufs_is_transient() {
+        assert!(is_transient_udp_error(&io::Error::from_raw_os_error(
+            libc::ENOBUFS
+        )));
+        assert!(!is_transient_udp_error(&io::Error::from_raw_os_error(
+            libc::EBADF
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_data_roundtrip() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let config = TunnelConfig {
+            psk: Some("shared-secret".into()),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        manager.set_runner_features(protocol::FEATURE_ENCRYPTION);
+        let runner_cipher = PayloadCipher::new("shared-secret");
+
+        manager.handle_connect(15, Proto::Tcp, port).await;
+        let (mut local, _) = service.accept().await.unwrap();
+        next_frame(&mut runner).await; // CONNECTED
+
+        // Runner -> local: sealed payload is decrypted before delivery
+        let sealed = runner_cipher.seal(Proto::Tcp, 15, b"ping");
+        manager.handle_data(15, Proto::Tcp, &sealed).await;
+        let mut buf = [0u8; 4];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Plaintext (unauthenticated) DATA is dropped
+        manager
+            .handle_data(15, Proto::Tcp, b"plaintext-injection")
+            .await;
+
+        // Local -> runner: payload is sealed on the wire
+        local.write_all(b"pong").await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        let payload = protocol::get_payload(&frame);
+        assert_ne!(payload, b"pong");
+        assert_eq!(
+            runner_cipher.open(Proto::Tcp, 15, payload).unwrap(),
+            b"pong"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_connect_refused_without_runner_support() {
+        let (transport, mut runner) = runner_pair();
+        let config = TunnelConfig {
+            psk: Some("shared-secret".into()),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(16, Proto::Tcp, 22).await;
+
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Error);
+        assert_eq!(header.client_id, 16);
+        assert_eq!(
+            protocol::get_payload(&frame),
+            b"Runner does not support payload encryption"
+        );
+        assert!(manager.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_large_read_split_under_payload_cap() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            psk: Some("shared-secret".into()),
+            max_payload_size: 100,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        manager.set_runner_features(protocol::FEATURE_ENCRYPTION);
+        let runner_cipher = PayloadCipher::new("shared-secret");
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 22).await;
+
+        // Sealed payloads stay within the cap, and rejoin into the stream
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        local.write_all(&data).await.unwrap();
+        let mut received = Vec::new();
+        let mut largest = 0;
+        while received.len() < data.len() {
+            let frame = next_frame(&mut runner).await;
+            assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Data);
+            let payload = protocol::get_payload(&frame);
+            largest = largest.max(payload.len());
+            received.extend(runner_cipher.open(Proto::Tcp, 22, payload).unwrap());
+        }
+        assert_eq!(received, data);
+        assert!(largest <= 100, "payload of {largest} bytes over the cap");
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test]
+    async fn test_datagram_over_payload_cap_dropped() {
+        let (transport, mut runner) = runner_pair();
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            max_payload_size: 64,
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+        let mut manager =
+            ConnectionManager::new(transport, Arc::new(config)).with_metrics(metrics.clone());
+        manager.handle_connect(23, Proto::Udp, port).await;
+        next_frame(&mut runner).await; // CONNECTED
+        manager.handle_data(23, Proto::Udp, b"hi").await;
+        let mut buf = [0u8; 16];
+        let (_, tunnel_addr) = service.recv_from(&mut buf).await.unwrap();
+
+        // A datagram can't be split, so one over the cap is dropped
+        service.send_to(&[0xAB; 65], tunnel_addr).await.unwrap();
+        service.send_to(&[0xCD; 64], tunnel_addr).await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(protocol::get_payload(&frame), [0xCD; 64]);
+        assert_eq!(metrics.udp_too_large_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_delta_needs_runner_to_parse_delta() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            delta: true,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        // The feature bit alone isn't enough when DELTA is missing from the
+        // runner's message types
+        manager.set_runner_capabilities(Capabilities {
+            features: protocol::FEATURE_DELTA,
+            msg_types: protocol::BASE_MSG_TYPES | protocol::msg_type_bit(MsgType::Version),
+        });
+        assert!(!manager.runner_capabilities().accepts(MsgType::Delta));
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 16).await;
+
+        let first = b"poll temperature=21.5C humidity=40%";
+        let second = b"poll temperature=21.6C humidity=40%";
+        for payload in [first, second] {
+            local.write_all(payload).await.unwrap();
+            let frame = next_frame(&mut runner).await;
+            assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Data);
+            assert_eq!(protocol::get_payload(&frame), payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delta_roundtrip() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            delta: true,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        manager.set_runner_features(protocol::FEATURE_DELTA);
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 16).await;
+
+        // Runner -> local: a DELTA is rebuilt against the previous DATA
+        let first = b"poll temperature=21.5C humidity=40%";
+        let second = b"poll temperature=21.6C humidity=40%";
+        manager.handle_data(16, Proto::Tcp, first).await;
+        let delta = crate::delta::encode(first, second).unwrap();
+        manager.handle_delta(16, Proto::Tcp, &delta).await;
+        let mut buf = vec![0u8; first.len() + second.len()];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..first.len()], first);
+        assert_eq!(&buf[first.len()..], second);
+
+        // Local -> runner: the first payload goes raw, a similar one as DELTA
+        local.write_all(first).await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Data);
+        local.write_all(second).await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Delta);
+        let payload = protocol::get_payload(&frame);
+        assert!(payload.len() < second.len());
+        assert_eq!(crate::delta::apply(first, payload).unwrap(), second);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_compressed_stream_roundtrip() {
+        #[cfg(feature = "compression")]
+        use crate::compress::{Compressor, Decompressor};
+
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            compression: true,
+            delta: true,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        manager.set_runner_features(protocol::FEATURE_COMPRESSION | protocol::FEATURE_DELTA);
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 18).await;
+
+        // Runner -> local: payloads are pieces of one gzip stream
+        let request = b"GET / HTTP/1.1\r\nHost: example\r\n\r\n";
+        let mut compressor = Compressor::default();
+        for _ in 0..2 {
+            let payload = compressor.compress(request);
+            manager.handle_data(18, Proto::Tcp, &payload).await;
+            let mut buf = vec![0u8; request.len()];
+            local.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, request);
+        }
+
+        // Local -> runner: always DATA (never DELTA), decompressed in order
+        let mut decompressor = Decompressor::default();
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        for _ in 0..2 {
+            local.write_all(response).await.unwrap();
+            let frame = next_frame(&mut runner).await;
+            assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Data);
+            let payload = protocol::get_payload(&frame);
+            assert_eq!(decompressor.decompress(payload).unwrap(), response);
+        }
+
+        // Local EOF: the gzip trailer goes out right before CLOSE
+        drop(local);
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Data);
+        let trailer = protocol::get_payload(&frame);
+        assert!(decompressor.decompress(trailer).unwrap().is_empty());
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Close);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_compressed_output_split_under_payload_cap() {
+        use crate::compress::Decompressor;
+
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            compression: true,
+            max_payload_size: 100,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        manager.set_runner_features(protocol::FEATURE_COMPRESSION);
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 24).await;
+
+        // Incompressible, so gzip output outgrows each 100-byte piece
+        let mut seed = 1u32;
+        let data: Vec<u8> = (0..1000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        local.write_all(&data).await.unwrap();
+        let mut decompressor = Decompressor::default();
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            let frame = next_frame(&mut runner).await;
+            let payload = protocol::get_payload(&frame);
+            assert!(payload.len() <= 100, "payload over the cap");
+            received.extend(decompressor.decompress(payload).unwrap());
+        }
+        assert_eq!(received, data);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_corrupt_compressed_data_closes_connection() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            compression: true,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        manager.set_runner_features(protocol::FEATURE_COMPRESSION);
+        let _local = open_tcp(&mut manager, &mut runner, &service, 19).await;
+
+        manager.handle_data(19, Proto::Tcp, b"plain text").await;
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        assert_eq!(header.client_id, 19);
+    }
+
+    #[tokio::test]
+    async fn test_close_tells_eof_from_reset() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        manager.set_runner_features(protocol::FEATURE_HALF_CLOSE);
+
+        let local = open_tcp(&mut manager, &mut runner, &service, 19).await;
+        drop(local);
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Close);
+        assert!(!protocol::is_abort(protocol::get_payload(&frame)));
+
+        let local = open_tcp(&mut manager, &mut runner, &service, 20).await;
+        socket2::SockRef::from(&local)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(local);
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Close);
+        assert!(protocol::is_abort(protocol::get_payload(&frame)));
+    }
+
+    #[tokio::test]
+    async fn test_abort_sent_as_close_without_close_flags() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        // A runner that parses every message type but not CLOSE flags
+        manager.set_runner_capabilities(Capabilities {
+            features: 0,
+            msg_types: protocol::SUPPORTED_MSG_TYPES,
+        });
+
+        let local = open_tcp(&mut manager, &mut runner, &service, 20).await;
+        socket2::SockRef::from(&local)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(local);
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Close);
+        assert!(protocol::get_payload(&frame).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_abort_resets_local_socket() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+        let mut buf = [0u8; 1];
+
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 21).await;
+        manager.handle_close(21).await;
+        assert_eq!(local.read(&mut buf).await.unwrap(), 0);
+
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 22).await;
+        manager.handle_abort(22).await;
+        let err = local.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_byte_counts_verified_both_ways() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            verify_byte_counts: true,
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+        let mut manager =
+            ConnectionManager::new(transport, Arc::new(config)).with_metrics(metrics.clone());
+        manager.set_runner_features(protocol::FEATURE_BYTE_COUNT);
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 18).await;
+
+        // Runner -> local: the count is stripped and checked
+        manager
+            .handle_data(18, Proto::Tcp, &bytecount::prefix(5, b"hello"))
+            .await;
+        // A frame of 10 bytes went missing before this one
+        manager
+            .handle_data(18, Proto::Tcp, &bytecount::prefix(17, b"!!"))
+            .await;
+        let mut buf = [0u8; 7];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello!!");
+        assert_eq!(metrics.byte_count_mismatch_count(), 1);
+
+        // Local -> runner: each DATA carries the running total
+        local.write_all(b"abc").await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(
+            bytecount::split(protocol::get_payload(&frame)),
+            Ok((3, &b"abc"[..]))
+        );
+        local.write_all(b"de").await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(
+            bytecount::split(protocol::get_payload(&frame)),
+            Ok((5, &b"de"[..]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delta_not_negotiated_closes_connection() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // Enabled locally, but the runner didn't advertise it
+        let config = TunnelConfig {
+            delta: true,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        let _local = open_tcp(&mut manager, &mut runner, &service, 17).await;
+
+        manager
+            .handle_delta(17, Proto::Tcp, &[0, 0, 0, 1, 0, 0, 0, 1, b'x'])
+            .await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        assert_eq!(header.client_id, 17);
+        assert!(!manager.connections.contains_key(&17));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_pool_falls_through_dead_upstream() {
+        let (transport, mut runner) = runner_pair();
+
+        // Reserve a port with nothing listening on it
+        let dead_port = {
+            let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            l.local_addr().unwrap().port()
+        };
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_port = service.local_addr().unwrap().port();
+
+        let config = TunnelConfig {
+            upstream_pools: vec![format!("80=[{},{}]", dead_port, live_port).parse().unwrap()],
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(16, Proto::Tcp, 80).await;
+        let _accepted = service.accept().await.unwrap();
+
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+        assert_eq!(header.client_id, 16);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_connect_burst_bounded_by_pending_limit() {
+        const BURST: u32 = 16;
+        const LIMIT: usize = 4;
+
+        // A one-deep accept queue drained slowly: the kernel drops SYNs while
+        // it is full, so connects stay in progress until a retransmit fits.
+        // Without the limit all 16 would be connecting at once. Some may
+        // complete on a SYN cookie and never reach accept(), so the
+        // acceptor just runs until the test is done.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let service = socket.listen(1).unwrap();
+        let port = service.local_addr().unwrap().port();
+        let accept = tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            loop {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                accepted.push(service.accept().await.unwrap().0);
+            }
+        });
+
+        let (transport, mut runner) = runner_pair();
+        let config = TunnelConfig {
+            max_pending_connects: LIMIT,
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+        let mut manager =
+            ConnectionManager::new(transport, Arc::new(config)).with_metrics(metrics.clone());
+
+        for client_id in 0..BURST {
+            manager.handle_connect(client_id, Proto::Tcp, port).await;
+        }
+
+        // Every CONNECT is eventually served...
+        for _ in 0..BURST {
+            let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+            assert_eq!(header.msg_type, MsgType::Connected);
+        }
+        accept.abort();
+
+        // ...but never more than LIMIT were connecting at once
+        let peak = metrics.pending_connects_peak();
+        assert!(peak >= 1 && peak <= LIMIT as u64, "peak = {}", peak);
+        assert_eq!(metrics.pending_connects(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_queued_data_flushed_before_close_on_local_eof() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let mut manager = ConnectionManager::new(transport, Arc::new(TunnelConfig::default()));
+
+        // DATA queued right behind the CONNECT, before the service is up
+        manager.handle_connect(30, Proto::Tcp, port).await;
+        manager
+            .handle_data(30, Proto::Tcp, b"GET / HTTP/1.0\r\n\r\n")
+            .await;
+
+        // The service closes its side as soon as it accepts
+        let (mut local, _) = service.accept().await.unwrap();
+        local.shutdown().await.unwrap();
+
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+
+        // By the time CLOSE reached the runner, the queued request was delivered
+        let mut buf = [0u8; 64];
+        let n = local.try_read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"GET / HTTP/1.0\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_resume_retransmits_unacked_tail_on_new_websocket() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            resume_grace: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        manager.set_runner_features(protocol::FEATURE_RESUME);
+
+        manager.handle_connect(40, Proto::Tcp, port).await;
+        let (mut local, _) = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        local.write_all(b"hello").await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        assert_eq!(protocol::get_payload(&frame), b"hello");
+        manager
+            .handle_ack(40, &protocol::build_ack(40, 2)[HEADER_SIZE..])
+            .await;
+
+        // The WebSocket drops; the local service keeps talking meanwhile
+        assert!(manager.suspend());
+        local.write_all(b" world").await.unwrap();
+
+        let (next_sender, mut runner) = runner_pair();
+        let sink = Arc::try_unwrap(next_sender).ok().unwrap().into_inner();
+        *manager.transport().lock().await = sink;
+        manager.resume_handshake().await.unwrap();
+
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Ack);
+        assert_eq!(protocol::parse_ack(protocol::get_payload(&frame)), Some(0));
+
+        // The runner had received 2 bytes: everything after that arrives once
+        manager
+            .handle_ack(40, &protocol::build_ack(40, 2)[HEADER_SIZE..])
+            .await;
+        let mut stream = Vec::new();
+        while stream.len() < b"llo world".len() {
+            let frame = next_frame(&mut runner).await;
+            assert_eq!(Header::parse(&frame).unwrap().msg_type, MsgType::Data);
+            stream.extend_from_slice(protocol::get_payload(&frame));
+        }
+        assert_eq!(stream, b"llo world");
+    }
+
+    #[tokio::test]
+    async fn test_resume_rejects_offset_beyond_sent() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            resume_grace: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+        manager.set_runner_features(protocol::FEATURE_RESUME);
+
+        manager.handle_connect(41, Proto::Tcp, port).await;
+        let _local = service.accept().await.unwrap();
+        next_frame(&mut runner).await;
+
+        assert!(manager.suspend());
+        manager
+            .handle_ack(41, &protocol::build_ack(41, 100)[HEADER_SIZE..])
+            .await;
+
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        assert!(!manager.suspend());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tcp_keepalive_only_when_idle() {
+        const INTERVAL: Duration = Duration::from_secs(10);
+
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            conn_keepalive_interval: INTERVAL,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(40, Proto::Tcp, port).await;
+        let (mut local, _) = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        // Traffic every 4s keeps the connection busy for well over an interval.
+        // Waiting on the local socket here would let the paused clock run
+        // ahead, so just let the write task run before advancing.
+        for _ in 0..5 {
+            manager.handle_data(40, Proto::Tcp, b"tick").await;
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            tokio::time::advance(Duration::from_secs(4)).await;
+        }
+        let last_activity = Instant::now() - Duration::from_secs(4);
+
+        // The first frame the runner sees is a keepalive, sent only once idle
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Ping);
+        assert_eq!(header.client_id, 40);
+        assert!(Instant::now() - last_activity >= INTERVAL);
+
+        // The byte stream carries only the data, not the keepalive
+        let mut buf = [0u8; 20];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf.to_vec(), b"tick".repeat(5));
+        assert!(local.try_read(&mut buf).is_err());
+    }
+
+    #[cfg(feature = "udp")]
+    #[tokio::test(start_paused = true)]
+    async fn test_udp_keepalive_is_empty_data() {
+        let (transport, mut runner) = runner_pair();
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        let config = TunnelConfig {
+            conn_keepalive_interval: Duration::from_secs(25),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(41, Proto::Udp, port).await;
+        next_frame(&mut runner).await; // CONNECTED
+
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!(header.msg_type, MsgType::Data);
+        assert_eq!(header.client_id, 41);
+        assert!(protocol::get_payload(&frame).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_runner_silence_closes_connection() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            runner_silence_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        // CONNECTED goes out, then the runner never sends anything
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 41).await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        assert_eq!(header.client_id, 41);
+
+        // The local side sees the connection end
+        let mut buf = [0u8; 8];
+        assert_eq!(local.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_runner_data_cancels_silence_timeout() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            runner_silence_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        let mut local = open_tcp(&mut manager, &mut runner, &service, 42).await;
+        manager.handle_data(42, Proto::Tcp, b"hi").await;
+        let mut buf = [0u8; 2];
+        local.read_exact(&mut buf).await.unwrap();
+
+        // Well past the timeout, nothing was closed
+        let waited =
+            tokio::time::timeout(Duration::from_millis(300), next_frame(&mut runner)).await;
+        assert!(waited.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kernel_tcp_keepalive_options() {
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(service.local_addr().unwrap())
+            .await
+            .unwrap();
+        let socket = socket2::SockRef::from(&stream);
+        assert!(!socket.keepalive().unwrap());
+
+        set_tcp_keepalive(&stream, Duration::from_secs(30)).unwrap();
+        assert!(socket.keepalive().unwrap());
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(
+                socket.keepalive_interval().unwrap(),
+                Duration::from_secs(10)
+            );
+            assert_eq!(socket.keepalive_retries().unwrap(), TCP_KEEPALIVE_PROBES);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forced_target_port_overrides_request() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = service.local_addr().unwrap().port();
+
+        let config = TunnelConfig {
+            force_target_port: format!("tcp:{}", local_port).parse().unwrap(),
+            // Forced target beats the port map
+            port_map: "1:2".parse().unwrap(),
+            // The allowlist still applies to the requested port
+            allowed_ports: Some("1-10".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(9, Proto::Tcp, 1).await;
+        let _accepted = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        manager.handle_connect(10, Proto::Tcp, 11).await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Error);
+    }
+
+    #[tokio::test]
+    async fn test_connect_uses_port_map() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = service.local_addr().unwrap().port();
+
+        let config = TunnelConfig {
+            port_map: format!("1:{}", local_port).parse().unwrap(),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        // Runner asks for port 1; the mapping routes it to the real service
+        manager.handle_connect(9, Proto::Tcp, 1).await;
+        let _accepted = service.accept().await.unwrap();
+
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+    }
+
+    #[tokio::test]
+    async fn test_port_target_overrides_translations() {
+        let (transport, mut runner) = runner_pair();
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = service.local_addr().unwrap().port();
+
+        let config = TunnelConfig {
+            port_targets: format!("1=tcp://127.0.0.1:{}", local_port).parse().unwrap(),
+            // The target beats both of these for port 1
+            force_target_port: "tcp:2".parse().unwrap(),
+            port_map: "1:3".parse().unwrap(),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(9, Proto::Tcp, 1).await;
+        let _accepted = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        // A tcp:// target can't be reached over UDP
+        manager.handle_connect(10, Proto::Udp, 1).await;
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Error, 10));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_port_target_relays() {
+        let path = std::env::temp_dir().join(format!("tunnel-target-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let service = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let (transport, mut runner) = runner_pair();
+        let config = TunnelConfig {
+            port_targets: format!("80=unix://{}", path.display()).parse().unwrap(),
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new(transport, Arc::new(config));
+
+        manager.handle_connect(3, Proto::Tcp, 80).await;
+        let (mut local, _) = service.accept().await.unwrap();
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Connected);
+
+        manager.handle_data(3, Proto::Tcp, b"ping").await;
+        let mut buf = [0u8; 4];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        local.write_all(b"pong").await.unwrap();
+        let frame = next_frame(&mut runner).await;
+        let header = Header::parse(&frame).unwrap();
+        assert_eq!((header.msg_type, header.client_id), (MsgType::Data, 3));
+        assert_eq!(protocol::get_payload(&frame), b"pong");
+
+        // Local EOF closes the connection as for TCP
+        drop(local);
+        let header = Header::parse(&next_frame(&mut runner).await).unwrap();
+        assert_eq!(header.msg_type, MsgType::Close);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
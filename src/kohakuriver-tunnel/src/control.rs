@@ -0,0 +1,157 @@
+//! Optional JSON control channel over WebSocket text frames.
+//!
+//! With `--control`, the runner can send administrative commands that don't
+//! fit the binary protocol as text frames, e.g. `{"cmd":"drain"}`. Every
+//! command is answered with a JSON text frame carrying `"ok"`; unknown or
+//! malformed commands get `{"ok":false,"error":...}` and change nothing.
+//!
+//! A runner that is about to go away sends
+//! `{"cmd":"redirect","url":"wss://other-runner:8001"}` so the tunnel moves to
+//! its replacement instead of retrying the old address.
+
+use serde::{Deserialize, Serialize};
+
+/// Longest control message accepted, in bytes
+pub const MAX_CONTROL_LEN: usize = 4096;
+
+/// A command from the runner
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Refuse new CONNECTs; open connections carry on
+    Drain,
+    /// Report connection counts and latency
+    Stats,
+    /// Move to the runner at `url`, taking open connections along where
+    /// they can be resumed
+    Redirect { url: String },
+}
+
+impl ControlCommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlCommand::Drain => "drain",
+            ControlCommand::Stats => "stats",
+            ControlCommand::Redirect { .. } => "redirect",
+        }
+    }
+}
+
+/// Parse a control message, describing what is wrong with it on failure
+pub fn parse(text: &str) -> Result<ControlCommand, String> {
+    if text.len() > MAX_CONTROL_LEN {
+        return Err(format!(
+            "control message of {} bytes exceeds {MAX_CONTROL_LEN}",
+            text.len()
+        ));
+    }
+    serde_json::from_str(text).map_err(|e| e.to_string())
+}
+
+/// Snapshot returned by the `stats` command
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    /// Connections whose handler is still running
+    pub connections: usize,
+    /// Local connects in progress
+    pub pending_connects: u64,
+    /// Smoothed runner round-trip time, if measured
+    pub rtt_ms: Option<f64>,
+    /// Whether new CONNECTs are being refused
+    pub draining: bool,
+}
+
+/// Answer to a control message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ControlReply {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Stats>,
+}
+
+impl ControlReply {
+    pub fn ok(cmd: &ControlCommand) -> Self {
+        Self {
+            ok: true,
+            cmd: Some(cmd.name()),
+            error: None,
+            stats: None,
+        }
+    }
+
+    pub fn stats(stats: Stats) -> Self {
+        Self {
+            stats: Some(stats),
+            ..Self::ok(&ControlCommand::Stats)
+        }
+    }
+
+    pub fn error(error: String) -> Self {
+        Self {
+            ok: false,
+            cmd: None,
+            error: Some(error),
+            stats: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("control replies always serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse(r#"{"cmd":"drain"}"#), Ok(ControlCommand::Drain));
+        assert_eq!(
+            parse(r#"{"cmd":"stats","extra":1}"#),
+            Ok(ControlCommand::Stats)
+        );
+        assert_eq!(
+            parse(r#"{"cmd":"redirect","url":"wss://runner-2:8001"}"#),
+            Ok(ControlCommand::Redirect {
+                url: "wss://runner-2:8001".into()
+            })
+        );
+        assert!(parse(r#"{"cmd":"redirect"}"#)
+            .unwrap_err()
+            .contains("missing field `url`"));
+
+        assert!(parse(r#"{"cmd":"reboot"}"#)
+            .unwrap_err()
+            .contains("unknown variant"));
+        assert!(parse("not json").is_err());
+        assert!(parse(r#"{"command":"drain"}"#).is_err());
+        assert!(parse(&" ".repeat(MAX_CONTROL_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_reply_json() {
+        assert_eq!(
+            ControlReply::ok(&ControlCommand::Drain).to_json(),
+            r#"{"ok":true,"cmd":"drain"}"#
+        );
+        assert_eq!(
+            ControlReply::error("bad".into()).to_json(),
+            r#"{"ok":false,"error":"bad"}"#
+        );
+        let stats = ControlReply::stats(Stats {
+            connections: 2,
+            pending_connects: 0,
+            rtt_ms: None,
+            draining: false,
+        });
+        assert_eq!(
+            stats.to_json(),
+            r#"{"ok":true,"cmd":"stats","stats":{"connections":2,"pending_connects":0,"rtt_ms":null,"draining":false}}"#
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! Optional application-layer encryption of DATA payloads.
+//!
+//! When a pre-shared key is configured, every DATA (and DELTA) payload is sealed with
+//! XChaCha20-Poly1305, independent of whether the WebSocket itself runs over
+//! TLS end-to-end. Encrypted DATA payload layout:
+//!
+//! ```text
+//! ┌──────────────────┬──────────────┬─────────────────────────────┬───────────┐
+//! │ Session ID (16B) │ Counter (8B) │ Ciphertext (len = plaintext)│ Tag (16B) │
+//! └──────────────────┴──────────────┴─────────────────────────────┴───────────┘
+//! ```
+//!
+//! The key is derived from the pre-shared key with HKDF-SHA256. The first
+//! 24 bytes are the nonce: a random 128-bit ID drawn by each side when its
+//! cipher is created, then a 64-bit counter, so nonces don't repeat across
+//! sessions under the same key. The message type, protocol and client_id are
+//! authenticated as associated data, so a payload cannot be moved onto
+//! another connection.
+//!
+//! Replays are refused per peer session: a counter already accepted, or
+//! [`REPLAY_WINDOW`] or more behind the newest one, is rejected, and so is
+//! anything from a peer session that has since been replaced by a newer
+//! one. Frames recorded from a peer session this cipher never saw are not
+//! recognised; that needs keys negotiated per session.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::protocol::{MsgType, Proto};
+
+/// Nonce size in bytes: the session ID and counter
+pub const NONCE_SIZE: usize = SESSION_ID_SIZE + 8;
+
+/// Size of the random per-cipher session ID opening each nonce
+pub const SESSION_ID_SIZE: usize = 16;
+
+/// How far behind the newest counter a peer's frame may arrive
+pub const REPLAY_WINDOW: u64 = 4096;
+
+/// Peer sessions remembered after they are replaced, to refuse their frames
+const RETIRED_SESSIONS: usize = 64;
+
+/// HKDF info string binding the derived key to this use
+const KEY_INFO: &[u8] = b"kohakuriver-tunnel payload key v2";
+
+/// Authentication tag size in bytes
+pub const TAG_SIZE: usize = 16;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    #[error("Encrypted payload too short: {0} bytes")]
+    TooShort(usize),
+
+    #[error("Payload authentication failed")]
+    AuthenticationFailed,
+
+    #[error("Replayed payload (counter {0})")]
+    Replayed(u64),
+}
+
+/// Seals and opens DATA payloads with a key derived from the pre-shared key
+pub struct PayloadCipher {
+    aead: XChaCha20Poly1305,
+    session_id: [u8; SESSION_ID_SIZE],
+    counter: AtomicU64,
+    replay: Mutex<ReplayWindow>,
+}
+
+impl PayloadCipher {
+    /// Derive the cipher key from the pre-shared key with HKDF-SHA256
+    pub fn new(psk: &str) -> Self {
+        let mut key = Key::default();
+        Hkdf::<Sha256>::new(None, psk.as_bytes())
+            .expand(KEY_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self {
+            aead: XChaCha20Poly1305::new(&key),
+            session_id: rand::random(),
+            counter: AtomicU64::new(0),
+            replay: Mutex::new(ReplayWindow::default()),
+        }
+    }
+
+    /// Encrypt a DATA payload for the given connection
+    pub fn seal(&self, proto: Proto, client_id: u32, plaintext: &[u8]) -> Vec<u8> {
+        self.seal_as(MsgType::Data, proto, client_id, plaintext)
+    }
+
+    /// Encrypt the payload of a `msg_type` message (DATA or DELTA)
+    pub fn seal_as(
+        &self,
+        msg_type: MsgType,
+        proto: Proto,
+        client_id: u32,
+        plaintext: &[u8],
+    ) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..SESSION_ID_SIZE].copy_from_slice(&self.session_id);
+        nonce[SESSION_ID_SIZE..]
+            .copy_from_slice(&self.counter.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+
+        let aad = associated_data(msg_type, proto, client_id);
+        let ciphertext = self
+            .aead
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .expect("XChaCha20-Poly1305 encryption cannot fail for in-memory buffers");
+
+        let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    /// Decrypt and authenticate a DATA payload for the given connection
+    pub fn open(
+        &self,
+        proto: Proto,
+        client_id: u32,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.open_as(MsgType::Data, proto, client_id, payload)
+    }
+
+    /// Decrypt and authenticate the payload of a `msg_type` message,
+    /// refusing one that was already accepted
+    pub fn open_as(
+        &self,
+        msg_type: MsgType,
+        proto: Proto,
+        client_id: u32,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if payload.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(CryptoError::TooShort(payload.len()));
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_SIZE);
+        let (session, counter) = nonce.split_at(SESSION_ID_SIZE);
+        let session: [u8; SESSION_ID_SIZE] = session.try_into().unwrap();
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+        self.replay.lock().unwrap().check(&session, counter)?;
+        let aad = associated_data(msg_type, proto, client_id);
+
+        let plaintext = self
+            .aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| CryptoError::AuthenticationFailed)?;
+        // Only authentic payloads move the window, so forged ones can't
+        // push genuine frames out of it
+        self.replay.lock().unwrap().accept(session, counter)?;
+        Ok(plaintext)
+    }
+}
+
+/// Counters accepted from the peer's current session
+#[derive(Default)]
+struct ReplayWindow {
+    session: Option<[u8; SESSION_ID_SIZE]>,
+    /// Sessions the peer has moved on from, newest last
+    retired: VecDeque<[u8; SESSION_ID_SIZE]>,
+    /// Highest counter accepted in the current session
+    top: u64,
+    /// Counters accepted in `top - REPLAY_WINDOW + 1 ..= top`, by counter
+    /// modulo the window
+    seen: Vec<u64>,
+}
+
+impl ReplayWindow {
+    /// Fail if `counter` of `session` was already accepted or is too old
+    fn check(&self, session: &[u8; SESSION_ID_SIZE], counter: u64) -> Result<(), CryptoError> {
+        if self.retired.contains(session) {
+            return Err(CryptoError::Replayed(counter));
+        }
+        if self.session.as_ref() != Some(session) || counter > self.top {
+            return Ok(());
+        }
+        if self.top - counter >= REPLAY_WINDOW || self.is_seen(counter) {
+            return Err(CryptoError::Replayed(counter));
+        }
+        Ok(())
+    }
+
+    /// Record an authenticated `counter` of `session`. Checks again, as
+    /// another frame with the same counter may have been accepted meanwhile.
+    fn accept(&mut self, session: [u8; SESSION_ID_SIZE], counter: u64) -> Result<(), CryptoError> {
+        self.check(&session, counter)?;
+        if self.session != Some(session) {
+            // The peer started a new session; its old frames are now stale
+            if let Some(old) = self.session.replace(session) {
+                if self.retired.len() == RETIRED_SESSIONS {
+                    self.retired.pop_front();
+                }
+                self.retired.push_back(old);
+            }
+            self.seen = vec![0; (REPLAY_WINDOW / 64) as usize];
+            self.top = counter;
+        } else if counter > self.top {
+            if counter - self.top >= REPLAY_WINDOW {
+                self.seen.fill(0);
+            } else {
+                for skipped in self.top + 1..=counter {
+                    self.set_seen(skipped, false);
+                }
+            }
+            self.top = counter;
+        }
+        self.set_seen(counter, true);
+        Ok(())
+    }
+
+    fn is_seen(&self, counter: u64) -> bool {
+        let bit = counter % REPLAY_WINDOW;
+        self.seen[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    fn set_seen(&mut self, counter: u64, seen: bool) {
+        let bit = counter % REPLAY_WINDOW;
+        let word = &mut self.seen[(bit / 64) as usize];
+        if seen {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
+        }
+    }
+}
+
+/// Header fields bound to each sealed payload
+fn associated_data(msg_type: MsgType, proto: Proto, client_id: u32) -> [u8; 6] {
+    let id = client_id.to_be_bytes();
+    [msg_type as u8, proto as u8, id[0], id[1], id[2], id[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let sender = PayloadCipher::new("secret");
+        let receiver = PayloadCipher::new("secret");
+
+        let sealed = sender.seal(Proto::Tcp, 7, b"hello");
+        assert_eq!(sealed.len(), NONCE_SIZE + 5 + TAG_SIZE);
+        assert_eq!(receiver.open(Proto::Tcp, 7, &sealed).unwrap(), b"hello");
+
+        // Empty payloads still authenticate
+        let sealed = sender.seal(Proto::Udp, 7, b"");
+        assert_eq!(receiver.open(Proto::Udp, 7, &sealed).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_open_rejects_wrong_key_and_tampering() {
+        let sender = PayloadCipher::new("secret");
+        let sealed = sender.seal(Proto::Tcp, 7, b"hello");
+
+        let wrong = PayloadCipher::new("other");
+        assert_eq!(
+            wrong.open(Proto::Tcp, 7, &sealed),
+            Err(CryptoError::AuthenticationFailed)
+        );
+
+        // Bound to the connection it was sealed for
+        let receiver = PayloadCipher::new("secret");
+        assert!(receiver.open(Proto::Tcp, 8, &sealed).is_err());
+        assert!(receiver.open(Proto::Udp, 7, &sealed).is_err());
+        assert!(receiver
+            .open_as(MsgType::Delta, Proto::Tcp, 7, &sealed)
+            .is_err());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(receiver.open(Proto::Tcp, 7, &tampered).is_err());
+
+        assert_eq!(
+            receiver.open(Proto::Tcp, 7, &sealed[..10]),
+            Err(CryptoError::TooShort(10))
+        );
+    }
+
+    #[test]
+    fn test_replays_are_refused() {
+        let sender = PayloadCipher::new("secret");
+        let receiver = PayloadCipher::new("secret");
+        let first = sender.seal(Proto::Tcp, 7, b"one");
+        let second = sender.seal(Proto::Tcp, 7, b"two");
+
+        // Out of order within the window is fine, twice is not
+        assert_eq!(receiver.open(Proto::Tcp, 7, &second).unwrap(), b"two");
+        assert_eq!(receiver.open(Proto::Tcp, 7, &first).unwrap(), b"one");
+        assert_eq!(
+            receiver.open(Proto::Tcp, 7, &first),
+            Err(CryptoError::Replayed(0))
+        );
+        assert_eq!(
+            receiver.open(Proto::Tcp, 7, &second),
+            Err(CryptoError::Replayed(1))
+        );
+
+        // A forged payload doesn't use up its counter
+        let third = sender.seal(Proto::Tcp, 7, b"three");
+        let mut forged = third.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            receiver.open(Proto::Tcp, 7, &forged),
+            Err(CryptoError::AuthenticationFailed)
+        );
+        assert_eq!(receiver.open(Proto::Tcp, 7, &third).unwrap(), b"three");
+
+        // Too far behind the newest frame to tell
+        let stale = sender.seal(Proto::Tcp, 7, b"late");
+        for _ in 0..REPLAY_WINDOW {
+            receiver
+                .open(Proto::Tcp, 7, &sender.seal(Proto::Tcp, 7, b""))
+                .unwrap();
+        }
+        assert!(matches!(
+            receiver.open(Proto::Tcp, 7, &stale),
+            Err(CryptoError::Replayed(_))
+        ));
+
+        // Once the peer reconnects with a new session, the old one is over
+        let restarted = PayloadCipher::new("secret");
+        let fresh = restarted.seal(Proto::Tcp, 7, b"again");
+        assert_eq!(receiver.open(Proto::Tcp, 7, &fresh).unwrap(), b"again");
+        let old = sender.seal(Proto::Tcp, 7, b"old session");
+        assert!(matches!(
+            receiver.open(Proto::Tcp, 7, &old),
+            Err(CryptoError::Replayed(_))
+        ));
+    }
+
+    #[test]
+    fn test_nonces_are_unique() {
+        let cipher = PayloadCipher::new("secret");
+        let a = cipher.seal(Proto::Tcp, 1, b"x");
+        let b = cipher.seal(Proto::Tcp, 1, b"x");
+        assert_ne!(a[..NONCE_SIZE], b[..NONCE_SIZE]);
+        assert_ne!(a, b);
+    }
+}
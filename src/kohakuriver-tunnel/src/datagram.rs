@@ -0,0 +1,246 @@
+//! Bounded queue of datagrams from a local UDP socket to the runner
+//! (`--udp-overflow`).
+//!
+//! A TCP connection that can't send applies backpressure to the local
+//! service, but for UDP a stalled read only makes the kernel drop datagrams
+//! it can no longer buffer, usually the freshest ones. Each UDP connection
+//! therefore reads into a [`DatagramQueue`] drained by a separate sender, and
+//! when the runner link can't keep up the queue's [`UdpOverflow`] policy
+//! decides which datagram is lost. Datagrams that queue up behind a slow send
+//! go out together in one BATCH when the runner supports it.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use tokio::sync::Notify;
+
+/// Datagrams queued per connection before the overflow policy applies
+pub const QUEUE_DEPTH: usize = 64;
+
+/// What happens to a datagram read while the queue to the runner is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum UdpOverflow {
+    /// Drop the oldest queued datagram to make room, keeping the freshest
+    #[default]
+    DropOldest,
+    /// Drop the datagram just read
+    DropNewest,
+    /// Stop reading until there is room, as TCP does
+    Block,
+}
+
+/// Outcome of [`DatagramQueue::try_send`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrySend {
+    Queued,
+    /// Queued or not, one datagram was dropped by the overflow policy
+    Dropped,
+    /// The queue is full and the policy is [`UdpOverflow::Block`]; the
+    /// datagram is handed back
+    Full(Bytes),
+    /// The queue was closed; the datagram is discarded
+    Closed,
+}
+
+/// Single-producer, single-consumer queue of datagrams for the runner
+#[derive(Debug)]
+pub struct DatagramQueue {
+    depth: usize,
+    policy: UdpOverflow,
+    state: Mutex<QueueState>,
+    /// Notified when a datagram is queued or the queue is closed
+    readable: Notify,
+    /// Notified when a datagram is taken or the queue is closed
+    writable: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    datagrams: VecDeque<Bytes>,
+    closed: bool,
+}
+
+impl DatagramQueue {
+    pub fn new(depth: usize, policy: UdpOverflow) -> Self {
+        Self {
+            depth: depth.max(1),
+            policy,
+            state: Mutex::default(),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    /// Queue `datagram` without waiting, applying the overflow policy when the
+    /// queue is full
+    pub fn try_send(&self, datagram: Bytes) -> TrySend {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return TrySend::Closed;
+        }
+        if state.datagrams.len() < self.depth {
+            state.datagrams.push_back(datagram);
+            self.readable.notify_one();
+            return TrySend::Queued;
+        }
+        match self.policy {
+            UdpOverflow::DropOldest => {
+                state.datagrams.pop_front();
+                state.datagrams.push_back(datagram);
+                TrySend::Dropped
+            }
+            UdpOverflow::DropNewest => TrySend::Dropped,
+            UdpOverflow::Block => TrySend::Full(datagram),
+        }
+    }
+
+    /// Queue `datagram`, waiting for room only under [`UdpOverflow::Block`]
+    /// (never returns [`TrySend::Full`])
+    pub async fn send(&self, mut datagram: Bytes) -> TrySend {
+        loop {
+            match self.try_send(datagram) {
+                TrySend::Full(returned) => datagram = returned,
+                outcome => return outcome,
+            }
+            self.writable.notified().await;
+        }
+    }
+
+    /// Take the oldest datagram, waiting for one; None once the queue is closed
+    /// and drained
+    pub async fn recv(&self) -> Option<Bytes> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(datagram) = state.datagrams.pop_front() {
+                    self.writable.notify_one();
+                    return Some(datagram);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    /// Take the datagrams already queued, without waiting, as long as their
+    /// sizes plus `overhead` each add up to at most `budget`
+    pub fn recv_queued(&self, mut budget: usize, overhead: usize) -> Vec<Bytes> {
+        let mut state = self.state.lock().unwrap();
+        let mut datagrams = Vec::new();
+        while let Some(front) = state.datagrams.front() {
+            let Some(left) = budget.checked_sub(front.len() + overhead) else {
+                break;
+            };
+            budget = left;
+            datagrams.extend(state.datagrams.pop_front());
+        }
+        if !datagrams.is_empty() {
+            self.writable.notify_one();
+        }
+        datagrams
+    }
+
+    /// Refuse further datagrams; those already queued can still be received
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_one();
+        self.writable.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn frame(n: u8) -> Bytes {
+        Bytes::from(vec![n])
+    }
+
+    fn fill(queue: &DatagramQueue, count: u8) {
+        for n in 0..count {
+            assert_eq!(queue.try_send(frame(n)), TrySend::Queued);
+        }
+    }
+
+    async fn drain(queue: &DatagramQueue) -> Vec<u8> {
+        queue.close();
+        let mut out = Vec::new();
+        while let Some(frame) = queue.recv().await {
+            out.push(frame[0]);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_freshest() {
+        let queue = DatagramQueue::new(3, UdpOverflow::DropOldest);
+        fill(&queue, 3);
+        assert_eq!(queue.try_send(frame(3)), TrySend::Dropped);
+        assert_eq!(queue.try_send(frame(4)), TrySend::Dropped);
+        assert_eq!(drain(&queue).await, [2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest_keeps_queued() {
+        let queue = DatagramQueue::new(3, UdpOverflow::DropNewest);
+        fill(&queue, 3);
+        assert_eq!(queue.try_send(frame(3)), TrySend::Dropped);
+        assert_eq!(drain(&queue).await, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_room() {
+        let queue = Arc::new(DatagramQueue::new(2, UdpOverflow::Block));
+        fill(&queue, 2);
+        assert_eq!(queue.try_send(frame(2)), TrySend::Full(frame(2)));
+
+        let sender = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.send(frame(2)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(
+            !sender.is_finished(),
+            "send should wait while the queue is full"
+        );
+
+        assert_eq!(queue.recv().await, Some(frame(0)));
+        assert_eq!(sender.await.unwrap(), TrySend::Queued);
+        assert_eq!(drain(&queue).await, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_recv_queued_within_budget() {
+        let queue = DatagramQueue::new(8, UdpOverflow::Block);
+        assert!(queue.recv_queued(100, 0).is_empty());
+        for len in [10, 20, 30] {
+            queue.try_send(Bytes::from(vec![0; len]));
+        }
+        // 10+2 and 20+2 fit in 40, 30+2 doesn't
+        let taken = queue.recv_queued(40, 2);
+        assert_eq!(taken.iter().map(Bytes::len).collect::<Vec<_>>(), [10, 20]);
+        assert_eq!(queue.recv().await.map(|f| f.len()), Some(30));
+    }
+
+    #[tokio::test]
+    async fn test_close_wakes_blocked_sender() {
+        let queue = Arc::new(DatagramQueue::new(1, UdpOverflow::Block));
+        fill(&queue, 1);
+        let sender = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.send(frame(1)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        queue.close();
+        assert_eq!(sender.await.unwrap(), TrySend::Closed);
+        // What was queued before the close is still delivered
+        assert_eq!(drain(&queue).await, [0]);
+    }
+}
@@ -0,0 +1,214 @@
+//! Delta encoding of repetitive DATA payloads.
+//!
+//! Polling and heartbeat protocols often send payloads that differ from the
+//! previous one only in a few bytes (a counter, a timestamp). With `--delta`
+//! negotiated, such a payload can travel as a DELTA frame carrying only the
+//! changed middle section relative to the previous payload on the same
+//! connection and direction:
+//!
+//! ```text
+//! ┌────────────────┬────────────────┬──────────────────────┐
+//! │ Prefix (4B BE) │ Suffix (4B BE) │ Replacement (var)    │
+//! └────────────────┴────────────────┴──────────────────────┘
+//! ```
+//!
+//! The payload is rebuilt as `reference[..prefix] + replacement +
+//! reference[len - suffix..]`. Every non-empty DATA or DELTA payload becomes
+//! the next reference; empty payloads (UDP keepalives) leave it alone. A
+//! payload is only delta-encoded when that is smaller than sending it raw.
+
+use bytes::Bytes;
+use thiserror::Error;
+
+/// Size of the prefix and suffix lengths in front of the replacement
+pub const DELTA_HEADER_SIZE: usize = 8;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    #[error("DELTA payload too short: {0} bytes")]
+    TooShort(usize),
+
+    #[error("DELTA without a previous payload to reference")]
+    NoReference,
+
+    #[error("DELTA keeps {kept} bytes of a {reference}-byte reference")]
+    OutOfRange { kept: usize, reference: usize },
+}
+
+/// Encode `data` against `reference`, or None if the delta isn't smaller
+pub fn encode(reference: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if reference.is_empty() || data.is_empty() {
+        return None;
+    }
+    let prefix = common_prefix(reference, data);
+    // The suffix may not overlap the prefix in either payload
+    let max_suffix = (reference.len() - prefix).min(data.len() - prefix);
+    let suffix = common_suffix(&reference[prefix..], &data[prefix..]).min(max_suffix);
+    let replacement = &data[prefix..data.len() - suffix];
+    if DELTA_HEADER_SIZE + replacement.len() >= data.len() {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(DELTA_HEADER_SIZE + replacement.len());
+    out.extend_from_slice(&(prefix as u32).to_be_bytes());
+    out.extend_from_slice(&(suffix as u32).to_be_bytes());
+    out.extend_from_slice(replacement);
+    Some(out)
+}
+
+/// Rebuild a payload from `delta` and the previous payload
+pub fn apply(reference: &[u8], delta: &[u8]) -> Result<Vec<u8>, DeltaError> {
+    if delta.len() < DELTA_HEADER_SIZE {
+        return Err(DeltaError::TooShort(delta.len()));
+    }
+    if reference.is_empty() {
+        return Err(DeltaError::NoReference);
+    }
+    let prefix = u32::from_be_bytes(delta[0..4].try_into().unwrap()) as usize;
+    let suffix = u32::from_be_bytes(delta[4..8].try_into().unwrap()) as usize;
+    let kept = prefix.saturating_add(suffix);
+    if kept > reference.len() {
+        return Err(DeltaError::OutOfRange {
+            kept,
+            reference: reference.len(),
+        });
+    }
+
+    let replacement = &delta[DELTA_HEADER_SIZE..];
+    let mut out = Vec::with_capacity(kept + replacement.len());
+    out.extend_from_slice(&reference[..prefix]);
+    out.extend_from_slice(replacement);
+    out.extend_from_slice(&reference[reference.len() - suffix..]);
+    Ok(out)
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn common_suffix(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+/// Outgoing side of one connection: remembers the previous payload
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    reference: Bytes,
+}
+
+impl DeltaEncoder {
+    /// The DELTA payload for `data` if it beats sending it raw. Either way
+    /// `data` becomes the reference for the next payload.
+    pub fn encode(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.is_empty() {
+            return None;
+        }
+        let delta = encode(&self.reference, data);
+        self.reference = Bytes::copy_from_slice(data);
+        delta
+    }
+}
+
+/// Incoming side of one connection: remembers the previous payload
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    reference: Bytes,
+}
+
+impl DeltaDecoder {
+    /// Note a raw DATA payload as the next reference
+    pub fn raw(&mut self, data: &Bytes) {
+        if !data.is_empty() {
+            self.reference = data.clone();
+        }
+    }
+
+    /// Rebuild the payload of a DELTA and make it the next reference
+    pub fn apply(&mut self, delta: &[u8]) -> Result<Bytes, DeltaError> {
+        let data = Bytes::from(apply(&self.reference, delta)?);
+        self.raw(&data);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (
+                b"GET /status?seq=0001 HTTP/1.1\r\n",
+                b"GET /status?seq=0002 HTTP/1.1\r\n",
+            ),
+            (b"aaaaaaaaaaaaaaaaaaaaaaaa", b"aaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
+            (b"aaaaaaaaaaaaaaaaaaaaaaaaaaaa", b"aaaaaaaaaaaaaaaaaaaaaaaa"),
+            (
+                b"heartbeat 12:00:01 ok ok ok",
+                b"heartbeat 12:00:02 ok ok ok",
+            ),
+        ];
+        for (reference, data) in cases {
+            let delta = encode(reference, data).expect("delta should be smaller");
+            assert!(delta.len() < data.len());
+            assert_eq!(apply(reference, &delta).unwrap(), *data);
+        }
+    }
+
+    #[test]
+    fn test_raw_when_delta_not_smaller() {
+        assert_eq!(encode(b"abcdefgh", b"zyxwvuts"), None);
+        assert_eq!(encode(b"", b"anything at all"), None);
+        assert_eq!(encode(b"short", b"shorT"), None);
+    }
+
+    #[test]
+    fn test_apply_rejects_bad_deltas() {
+        assert_eq!(apply(b"ref", b"1234"), Err(DeltaError::TooShort(4)));
+        assert_eq!(
+            apply(b"", &[0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DeltaError::NoReference)
+        );
+        assert_eq!(
+            apply(b"ref", &[0, 0, 0, 2, 0, 0, 0, 2]),
+            Err(DeltaError::OutOfRange {
+                kept: 4,
+                reference: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_encoder_decoder_track_references() {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoder = DeltaDecoder::default();
+        let frames: [&[u8]; 4] = [
+            b"poll temperature=21.5C humidity=40%",
+            b"poll temperature=21.6C humidity=40%",
+            b"",
+            b"poll temperature=21.6C humidity=41%",
+        ];
+        let mut deltas = 0;
+        for frame in frames {
+            let received = match encoder.encode(frame) {
+                Some(delta) => {
+                    deltas += 1;
+                    decoder.apply(&delta).unwrap()
+                }
+                None => {
+                    let raw = Bytes::copy_from_slice(frame);
+                    decoder.raw(&raw);
+                    raw
+                }
+            };
+            assert_eq!(&received[..], frame);
+        }
+        // Only the first payload and the empty keepalive go raw
+        assert_eq!(deltas, 2);
+    }
+}
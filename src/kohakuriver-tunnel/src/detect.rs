@@ -0,0 +1,202 @@
+//! Discovery of the container ID and runner URL from inside the container
+//! (`--auto-detect`).
+//!
+//! The runner starts containers with [`CONTAINER_ID_ENV`] and
+//! [`RUNNER_URL_ENV`] set, so those come first. Failing that, the container
+//! ID is read from `/proc/self/cgroup` (cgroup v1 or v2) or, under a cgroup
+//! namespace where that only shows `/`, from the bind mounts Docker and
+//! Podman put in `/proc/self/mountinfo`. The runner is assumed to listen on
+//! [`DEFAULT_RUNNER_PORT`] at the default gateway, which is the host on a
+//! bridge network.
+
+use std::env;
+use std::fs;
+use std::net::Ipv4Addr;
+
+use tracing::debug;
+
+/// Container name the runner passes to containers it starts
+pub const CONTAINER_ID_ENV: &str = "KOHAKURIVER_CONTAINER_ID";
+
+/// Tunnel URL the runner passes to containers it starts
+pub const RUNNER_URL_ENV: &str = "KOHAKURIVER_TUNNEL_URL";
+
+/// Port the runner listens on unless configured otherwise
+pub const DEFAULT_RUNNER_PORT: u16 = 8001;
+
+/// Length of a full container ID in hex digits
+const CONTAINER_ID_LEN: usize = 64;
+
+/// Container ID or name of the container we run in, if it can be found
+pub fn container_id() -> Option<String> {
+    if let Some(id) = non_empty_env(CONTAINER_ID_ENV) {
+        debug!(source = CONTAINER_ID_ENV, "Detected container ID");
+        return Some(id);
+    }
+    if let Some(id) = read("/proc/self/cgroup").and_then(|c| parse_cgroup(&c)) {
+        debug!(source = "/proc/self/cgroup", "Detected container ID");
+        return Some(id);
+    }
+    let id = read("/proc/self/mountinfo").and_then(|m| parse_mountinfo(&m))?;
+    debug!(source = "/proc/self/mountinfo", "Detected container ID");
+    Some(id)
+}
+
+/// URL of the runner that started this container, if it can be found
+pub fn runner_url() -> Option<String> {
+    if let Some(url) = non_empty_env(RUNNER_URL_ENV) {
+        debug!(source = RUNNER_URL_ENV, "Detected runner URL");
+        return Some(url);
+    }
+    let gateway = read("/proc/net/route").and_then(|r| parse_default_gateway(&r))?;
+    debug!(%gateway, "Detected runner URL from the default gateway");
+    Some(format!("ws://{}:{}", gateway, DEFAULT_RUNNER_PORT))
+}
+
+fn non_empty_env(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn read(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+/// Container ID in the cgroup path of a `/proc/self/cgroup` listing.
+///
+/// Lines are `hierarchy:controllers:path`; cgroup v1 has one per
+/// controller, v2 a single `0::path`. The ID is the last path segment that
+/// holds one, e.g. `/docker/<id>`, `/system.slice/docker-<id>.scope` or
+/// `/kubepods/.../cri-containerd-<id>.scope`.
+pub fn parse_cgroup(contents: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let path = line.splitn(3, ':').nth(2)?;
+        path.split('/').rev().find_map(container_id_in)
+    })
+}
+
+/// Container ID in the mount sources of a `/proc/self/mountinfo` listing,
+/// e.g. `/var/lib/docker/containers/<id>/hostname` or Podman's
+/// `.../overlay-containers/<id>/userdata/hostname`
+pub fn parse_mountinfo(contents: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        // The fourth field is the root of the mount within its filesystem
+        let root = line.split_whitespace().nth(3)?;
+        let mut segments = root.split('/');
+        while let Some(segment) = segments.next() {
+            if segment == "containers" || segment == "overlay-containers" {
+                if let Some(id) = segments.next().and_then(container_id_in) {
+                    return Some(id);
+                }
+            }
+        }
+        None
+    })
+}
+
+/// The container ID a cgroup or mount path segment names, if any
+fn container_id_in(segment: &str) -> Option<String> {
+    let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+    let id = ["docker-", "cri-containerd-", "crio-", "libpod-"]
+        .iter()
+        .find_map(|prefix| segment.strip_prefix(prefix))
+        .unwrap_or(segment);
+    let is_id = id.len() == CONTAINER_ID_LEN && id.bytes().all(|b| b.is_ascii_hexdigit());
+    is_id.then(|| id.to_ascii_lowercase())
+}
+
+/// Gateway of the default route in a `/proc/net/route` listing
+pub fn parse_default_gateway(contents: &str) -> Option<Ipv4Addr> {
+    const RTF_GATEWAY: u16 = 0x2;
+    contents.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (destination, gateway, flags) = (fields.get(1)?, fields.get(2)?, fields.get(3)?);
+        let flags = u16::from_str_radix(flags, 16).ok()?;
+        if *destination != "00000000" || flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        // Printed as the address's bytes read as a native-endian integer
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f4e8b1c2d5a6978e0f1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f7";
+
+    #[test]
+    fn test_parse_cgroup_v1() {
+        let contents = format!(
+            "12:pids:/docker/{ID}\n\
+             11:memory:/docker/{ID}\n\
+             1:name=systemd:/docker/{ID}\n"
+        );
+        assert_eq!(parse_cgroup(&contents).as_deref(), Some(ID));
+
+        let kubepods = format!(
+            "4:cpu,cpuacct:/kubepods/besteffort/pod8d2a5f3e-1b2c-4d5e-8f90-a1b2c3d4e5f6/{ID}\n"
+        );
+        assert_eq!(parse_cgroup(&kubepods).as_deref(), Some(ID));
+    }
+
+    #[test]
+    fn test_parse_cgroup_v2() {
+        let systemd = format!("0::/system.slice/docker-{ID}.scope\n");
+        assert_eq!(parse_cgroup(&systemd).as_deref(), Some(ID));
+
+        let containerd = format!(
+            "0::/kubepods.slice/kubepods-pod1.slice/cri-containerd-{}.scope\n",
+            ID.to_uppercase()
+        );
+        assert_eq!(parse_cgroup(&containerd).as_deref(), Some(ID));
+
+        // Under a cgroup namespace there is nothing to find
+        assert_eq!(parse_cgroup("0::/\n"), None);
+        assert_eq!(
+            parse_cgroup("0::/user.slice/user-1000.slice/session-2.scope\n"),
+            None
+        );
+        assert_eq!(parse_cgroup(""), None);
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let contents = format!(
+            "736 700 0:52 / / rw,relatime master:1 - overlay overlay rw\n\
+             742 736 259:2 /var/lib/docker/containers/{ID}/resolv.conf /etc/resolv.conf rw,relatime - ext4 /dev/nvme0n1p2 rw\n"
+        );
+        assert_eq!(parse_mountinfo(&contents).as_deref(), Some(ID));
+
+        let podman = format!(
+            "1021 998 0:45 /containers/storage/overlay-containers/{ID}/userdata/hostname /etc/hostname rw - tmpfs tmpfs rw\n"
+        );
+        assert_eq!(parse_mountinfo(&podman).as_deref(), Some(ID));
+
+        assert_eq!(
+            parse_mountinfo("736 700 0:52 / / rw - overlay overlay rw\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_default_gateway() {
+        let route =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     eth0\t00000000\t010011AC\t0003\t0\t0\t0\t00000000\t0\t0\t0\n\
+                     eth0\t000011AC\t00000000\t0001\t0\t0\t0\t0000FFFF\t0\t0\t0\n";
+        #[cfg(target_endian = "little")]
+        assert_eq!(
+            parse_default_gateway(route),
+            Some(Ipv4Addr::new(172, 17, 0, 1))
+        );
+        #[cfg(target_endian = "big")]
+        assert!(parse_default_gateway(route).is_some());
+
+        // Only the local subnet, no default route
+        let local_only = "Iface\tDestination\tGateway \tFlags\n\
+                          eth0\t000011AC\t00000000\t0001\n";
+        assert_eq!(parse_default_gateway(local_only), None);
+    }
+}
@@ -0,0 +1,456 @@
+//! Outbound WebSocket dialing with explicit address-family selection.
+//!
+//! `connect_async` dials whatever address the resolver returns first, which in
+//! dual-stack networks with broken IPv6 can hang on an unreachable AAAA
+//! record. Here the runner host is resolved up front, candidates are filtered
+//! by the configured family, and the rest are raced as in RFC 8305 ("happy
+//! eyeballs"): families alternate, starting with the resolver's first, and a
+//! new attempt starts every [`ATTEMPT_DELAY`] while earlier ones are pending,
+//! or at once when one fails. An attempt that hasn't connected within the
+//! attempt timeout fails too, so a blackholed address can't hold up the dial
+//! once the others are exhausted. The first TCP connection to succeed wins,
+//! the other attempts are dropped, and the WebSocket (and TLS) handshake runs
+//! over it.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+#[cfg(feature = "tls")]
+use tokio_tungstenite::client_async_tls_with_config;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, USER_AGENT};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self};
+#[cfg(feature = "tls")]
+use tokio_tungstenite::Connector;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::error::{Result, TunnelError};
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::tls::ClientIdentity;
+
+/// WebSocket stream produced by [`dial`]
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Which address family to use when dialing the runner
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DialFamily {
+    /// Try every resolved address in resolver order
+    #[default]
+    Auto,
+    /// Only dial IPv4 addresses
+    Ipv4,
+    /// Only dial IPv6 addresses
+    Ipv6,
+}
+
+impl DialFamily {
+    fn accepts(self, addr: &SocketAddr) -> bool {
+        match self {
+            DialFamily::Auto => true,
+            DialFamily::Ipv4 => addr.is_ipv4(),
+            DialFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Keep only the addresses matching `family`, preserving resolver order
+pub fn filter_family(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    family: DialFamily,
+) -> Vec<SocketAddr> {
+    addrs.into_iter().filter(|a| family.accepts(a)).collect()
+}
+
+/// Resolve the runner URL's host into dial candidates for `family`
+pub async fn resolve_candidates(url: &Url, family: DialFamily) -> Result<Vec<SocketAddr>> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| TunnelError::InvalidUrl(format!("{} has no host", url)))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| TunnelError::InvalidUrl(format!("{} has no port", url)))?;
+    // IPv6 literals come back bracketed from host_str
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let resolved = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(connect_error)?;
+    let candidates = filter_family(resolved, family);
+
+    if candidates.is_empty() {
+        return Err(connect_error(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no {:?} addresses", host, family),
+        )));
+    }
+    Ok(candidates)
+}
+
+/// Head start of each connection attempt over the next while it is pending
+/// (RFC 8305's Connection Attempt Delay)
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Default limit on a single connection attempt
+pub const DEFAULT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Candidates in the order they are dialed: address families alternate,
+/// starting with the family of the resolver's first answer
+pub fn interleave_families(candidates: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = candidates.first() else {
+        return Vec::new();
+    };
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = candidates
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    let mut ordered = Vec::with_capacity(candidates.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Open a TCP connection to the first candidate that answers, racing
+/// staggered attempts that each get up to `attempt_timeout` (zero = no
+/// limit; see the module docs)
+pub async fn connect_any(
+    candidates: &[SocketAddr],
+    attempt_timeout: Duration,
+) -> Result<TcpStream> {
+    race(
+        candidates,
+        ATTEMPT_DELAY,
+        attempt_timeout,
+        TcpStream::connect,
+    )
+    .await
+    .map_err(connect_error)
+}
+
+/// Happy-eyeballs race of `connect` over `candidates`, starting the next
+/// attempt after `delay` or as soon as one fails or exceeds `attempt_timeout`
+async fn race<T, F, Fut>(
+    candidates: &[SocketAddr],
+    delay: Duration,
+    attempt_timeout: Duration,
+    connect: F,
+) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let start = |addr: SocketAddr| {
+        debug!(%addr, "Dialing runner");
+        let attempt = connect(addr);
+        async move {
+            if attempt_timeout.is_zero() {
+                return (addr, attempt.await);
+            }
+            let result = tokio::time::timeout(attempt_timeout, attempt)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no answer within {:?}", attempt_timeout),
+                    ))
+                });
+            (addr, result)
+        }
+    };
+    let mut pending = interleave_families(candidates).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(start(addr)),
+                None => {
+                    return Err(last_err.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no addresses to dial")
+                    }))
+                }
+            }
+        }
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => {
+                    if !attempts.is_empty() {
+                        debug!(%addr, cancelled = attempts.len(), "Dialed runner, dropping slower attempts");
+                    }
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    warn!(%addr, error = %e, "Failed to dial runner address, trying next");
+                    last_err = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(start(addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(delay), if !pending.as_slice().is_empty() => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(start(addr));
+                }
+            }
+        }
+    }
+}
+
+/// Resolve, dial (giving each address `attempt_timeout`) and perform the
+/// WebSocket handshake, sending `user_agent`
+/// as the upgrade request's User-Agent and, over wss://, presenting
+/// `identity` if the runner asks for a client certificate
+pub async fn dial(
+    url: &Url,
+    family: DialFamily,
+    attempt_timeout: Duration,
+    user_agent: &str,
+    config: WebSocketConfig,
+    identity: Option<&ClientIdentity>,
+) -> Result<(WsStream, Response)> {
+    let request = upgrade_request(url, user_agent)?;
+    let candidates = resolve_candidates(url, family).await?;
+    let stream = connect_any(&candidates, attempt_timeout).await?;
+    #[cfg(feature = "tls")]
+    let handshake = {
+        let connector = match identity {
+            Some(identity) => Some(Connector::NativeTls(tls::connector(Some(identity), &[])?)),
+            None => None,
+        };
+        client_async_tls_with_config(request, stream, Some(config), connector)
+    };
+    // Without TLS only ws:// URLs get this far, and there is no identity
+    #[cfg(not(feature = "tls"))]
+    let _ = identity;
+    #[cfg(not(feature = "tls"))]
+    let handshake = tokio_tungstenite::client_async_with_config(
+        request,
+        MaybeTlsStream::Plain(stream),
+        Some(config),
+    );
+    handshake.await.map_err(TunnelError::from_handshake)
+}
+
+/// The WebSocket upgrade request for `url`, with `user_agent` set
+pub fn upgrade_request(url: &Url, user_agent: &str) -> Result<Request> {
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| TunnelError::InvalidUrl(format!("{}: {}", url, e)))?;
+    let user_agent = HeaderValue::from_str(user_agent)
+        .map_err(|_| TunnelError::Config(format!("invalid User-Agent: {:?}", user_agent)))?;
+    request.headers_mut().insert(USER_AGENT, user_agent);
+    Ok(request)
+}
+
+fn connect_error(e: io::Error) -> TunnelError {
+    TunnelError::ConnectFailed(Box::new(tungstenite::Error::Io(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_hdr_async;
+    use tokio_tungstenite::tungstenite::handshake::server::{self, Callback, ErrorResponse};
+
+    #[test]
+    fn test_filter_family() {
+        let v4: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let v6: SocketAddr = "[::1]:80".parse().unwrap();
+        let addrs = [v6, v4];
+
+        assert_eq!(filter_family(addrs, DialFamily::Auto), vec![v6, v4]);
+        assert_eq!(filter_family(addrs, DialFamily::Ipv4), vec![v4]);
+        assert_eq!(filter_family(addrs, DialFamily::Ipv6), vec![v6]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_literal_with_wrong_family_fails() {
+        let url = Url::parse("ws://127.0.0.1:8001/").unwrap();
+        assert!(resolve_candidates(&url, DialFamily::Ipv4).await.is_ok());
+        assert!(matches!(
+            resolve_candidates(&url, DialFamily::Ipv6).await,
+            Err(TunnelError::ConnectFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connect_any_skips_unreachable_candidates() {
+        let dead = {
+            let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            l.local_addr().unwrap()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+
+        let stream = connect_any(&[dead, live], DEFAULT_ATTEMPT_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+        assert!(connect_any(&[dead], DEFAULT_ATTEMPT_TIMEOUT).await.is_err());
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let v4 = |n: u8| SocketAddr::from(([192, 0, 2, n], 80));
+        let v6 = |n: u16| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, n], 80));
+        assert_eq!(
+            interleave_families(&[v6(1), v6(2), v6(3), v4(1), v4(2)]),
+            vec![v6(1), v4(1), v6(2), v4(2), v6(3)]
+        );
+        assert_eq!(
+            interleave_families(&[v4(1), v6(1), v4(2)]),
+            vec![v4(1), v6(1), v4(2)]
+        );
+        assert_eq!(interleave_families(&[v4(1), v4(2)]), vec![v4(1), v4(2)]);
+        assert!(interleave_families(&[]).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_race_staggers_attempts() {
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 80));
+        let v4 = SocketAddr::from(([192, 0, 2, 1], 80));
+        let refused = SocketAddr::from(([192, 0, 2, 2], 80));
+        let started = std::sync::Mutex::new(Vec::new());
+        let connect = |addr: SocketAddr| {
+            started.lock().unwrap().push(addr);
+            async move {
+                match addr {
+                    // A blackholed address never answers
+                    addr if addr == v6 => std::future::pending().await,
+                    addr if addr == refused => Err(io::ErrorKind::ConnectionRefused.into()),
+                    _ => Ok(addr),
+                }
+            }
+        };
+
+        // The stalled IPv6 attempt is overtaken after the delay
+        let begin = tokio::time::Instant::now();
+        assert_eq!(
+            race(&[v6, v4], ATTEMPT_DELAY, Duration::ZERO, connect)
+                .await
+                .unwrap(),
+            v4
+        );
+        assert_eq!(begin.elapsed(), ATTEMPT_DELAY);
+
+        // A failed attempt starts the next one at once
+        started.lock().unwrap().clear();
+        let begin = tokio::time::Instant::now();
+        assert_eq!(
+            race(&[refused, v6, v4], ATTEMPT_DELAY, Duration::ZERO, connect)
+                .await
+                .unwrap(),
+            v4
+        );
+        assert_eq!(begin.elapsed(), ATTEMPT_DELAY);
+        assert_eq!(*started.lock().unwrap(), vec![refused, v6, v4]);
+
+        let err = race(&[refused], ATTEMPT_DELAY, Duration::ZERO, connect)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(race(&[], ATTEMPT_DELAY, Duration::ZERO, connect)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_race_gives_up_on_stalled_attempts() {
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 80));
+        let v4 = SocketAddr::from(([192, 0, 2, 1], 80));
+        let timeout = Duration::from_secs(3);
+        // Both addresses are blackholed
+        let connect = |_: SocketAddr| std::future::pending::<io::Result<()>>();
+
+        // The second attempt starts after the delay, so it times out last
+        let begin = tokio::time::Instant::now();
+        let err = race(&[v6, v4], ATTEMPT_DELAY, timeout, connect)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(begin.elapsed(), ATTEMPT_DELAY + timeout);
+
+        // A timed-out attempt hands over to the next one like a failure
+        let live = SocketAddr::from(([192, 0, 2, 2], 80));
+        let connect = |addr: SocketAddr| async move {
+            if addr == live {
+                Ok(addr)
+            } else {
+                std::future::pending().await
+            }
+        };
+        let begin = tokio::time::Instant::now();
+        let delay = Duration::from_secs(60);
+        assert_eq!(
+            race(&[v4, live], delay, timeout, connect).await.unwrap(),
+            live
+        );
+        assert_eq!(begin.elapsed(), timeout);
+    }
+
+    /// Handshake callback reporting the client's User-Agent
+    struct UserAgentOf(tokio::sync::oneshot::Sender<Option<HeaderValue>>);
+
+    impl Callback for UserAgentOf {
+        fn on_request(
+            self,
+            request: &Request,
+            response: server::Response,
+        ) -> std::result::Result<server::Response, ErrorResponse> {
+            let _ = self.0.send(request.headers().get(USER_AGENT).cloned());
+            Ok(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dial_performs_websocket_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            let _ws = accept_hdr_async(stream, UserAgentOf(tx)).await.unwrap();
+            rx.await.unwrap()
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/tunnel/abc", addr)).unwrap();
+        let (_ws, response) = dial(
+            &url,
+            DialFamily::Ipv4,
+            DEFAULT_ATTEMPT_TIMEOUT,
+            "kohakuriver-tunnel/1.2 (abc)",
+            WebSocketConfig::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status().as_u16(), 101);
+        assert_eq!(
+            server.await.unwrap().unwrap(),
+            "kohakuriver-tunnel/1.2 (abc)"
+        );
+    }
+
+    #[test]
+    fn test_upgrade_request_rejects_invalid_user_agent() {
+        let url = Url::parse("ws://127.0.0.1:8001/ws/tunnel/abc").unwrap();
+        assert!(matches!(
+            upgrade_request(&url, "bad\nagent"),
+            Err(TunnelError::Config(_))
+        ));
+    }
+}
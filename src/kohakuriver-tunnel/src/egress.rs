@@ -0,0 +1,187 @@
+//! Priority-ordered egress of DATA frames (`--port-priority`).
+//!
+//! Every connection task normally takes the transport lock to send, so when
+//! the link to the runner is the bottleneck a bulk transfer competes on
+//! equal terms with an interactive SSH session. With port priorities
+//! configured, connection DATA instead goes through one channel per
+//! [`Priority`], and a single task drains them into the transport, always
+//! taking the highest class with a frame waiting.
+//!
+//! A sender waits until its frame has been handed to the transport, so
+//! frames of one connection keep their order, a CLOSE sent afterwards can't
+//! overtake them, and a failed send is reported back as before.
+
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
+use tracing::debug;
+
+use crate::error::{Result, TunnelError};
+use crate::transport::TransportSender;
+
+/// Frames queued per class before senders wait
+const QUEUE_DEPTH: usize = 64;
+
+/// Egress class of a connection, from its requested port
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Interactive traffic (SSH, control ports), sent first
+    High,
+    #[default]
+    Normal,
+    /// Bulk transfers, sent only when nothing else is waiting
+    Low,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        })
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Priority::ALL
+            .into_iter()
+            .find(|p| s.trim().eq_ignore_ascii_case(&p.to_string()))
+            .ok_or_else(|| format!("Invalid priority {:?} (expected high, normal or low)", s))
+    }
+}
+
+/// A frame waiting for the egress task, and where to report the send
+struct Queued {
+    frame: Bytes,
+    done: oneshot::Sender<Result<()>>,
+}
+
+/// Handle to the egress task; the task ends once every handle is dropped
+#[derive(Clone)]
+pub struct Egress {
+    queues: [mpsc::Sender<Queued>; 3],
+}
+
+impl Egress {
+    /// Start the task draining into `transport`
+    pub fn spawn(transport: TransportSender) -> Self {
+        let (high_tx, high_rx) = mpsc::channel(QUEUE_DEPTH);
+        let (normal_tx, normal_rx) = mpsc::channel(QUEUE_DEPTH);
+        let (low_tx, low_rx) = mpsc::channel(QUEUE_DEPTH);
+        tokio::spawn(drain(transport, [high_rx, normal_rx, low_rx]));
+        Self {
+            queues: [high_tx, normal_tx, low_tx],
+        }
+    }
+
+    /// Send `frame` at `priority`, returning once the transport took it
+    pub async fn send(&self, priority: Priority, frame: Bytes) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        let lost = || TunnelError::ConnectionLost("egress task ended".into());
+        self.queues[priority.index()]
+            .send(Queued { frame, done })
+            .await
+            .map_err(|_| lost())?;
+        result.await.map_err(|_| lost())?
+    }
+}
+
+/// Hand queued frames to the transport, highest class first
+async fn drain(transport: TransportSender, mut queues: [mpsc::Receiver<Queued>; 3]) {
+    loop {
+        let [high, normal, low] = &mut queues;
+        let queued = tokio::select! {
+            biased;
+            Some(queued) = high.recv() => queued,
+            Some(queued) = normal.recv() => queued,
+            Some(queued) = low.recv() => queued,
+            else => break,
+        };
+        let result = transport.lock().await.send(queued.frame).await;
+        // The sender may have given up (connection aborted); nothing to do
+        let _ = queued.done.send(result);
+    }
+    debug!("Egress task ending");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::transport;
+
+    #[test]
+    fn test_parse_priority() {
+        assert_eq!("high".parse(), Ok(Priority::High));
+        assert_eq!(" Low ".parse(), Ok(Priority::Low));
+        assert_eq!("NORMAL".parse(), Ok(Priority::Normal));
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_higher_priority_sent_first_under_contention() {
+        let ((sink, _), mut runner) = transport::memory(64);
+        let transport: TransportSender = Arc::new(Mutex::new(sink));
+        let egress = Egress::spawn(transport.clone());
+
+        // Something else holds the transport, so everything queues up. The
+        // first frame is taken by the egress task right away and waits on
+        // the lock; the rest stay queued.
+        let busy = transport.lock().await;
+        let mut sends = Vec::new();
+        for (priority, frame) in [
+            (Priority::Low, "low-1"),
+            (Priority::Low, "low-2"),
+            (Priority::Normal, "normal"),
+            (Priority::Low, "low-3"),
+            (Priority::High, "high"),
+        ] {
+            let egress = egress.clone();
+            sends.push(tokio::spawn(async move {
+                egress.send(priority, Bytes::from(frame)).await
+            }));
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(busy);
+        for send in sends {
+            send.await.unwrap().unwrap();
+        }
+
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            let frame = runner.next_frame().await.unwrap();
+            order.push(String::from_utf8(frame.to_vec()).unwrap());
+        }
+        assert_eq!(order, ["low-1", "high", "normal", "low-2", "low-3"]);
+    }
+
+    #[tokio::test]
+    async fn test_send_failure_reported() {
+        let ((sink, _), runner) = transport::memory(1);
+        let egress = Egress::spawn(Arc::new(Mutex::new(sink)));
+        drop(runner);
+        assert!(egress
+            .send(Priority::High, Bytes::from_static(b"x"))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! Error types returned by the tunnel client's public API.
+
+use std::time::{Duration, SystemTime};
+
+use tokio_tungstenite::tungstenite;
+
+use crate::protocol::ProtocolError;
+
+/// Result alias using [`TunnelError`]
+pub type Result<T, E = TunnelError> = std::result::Result<T, E>;
+
+/// Failure kinds surfaced by [`crate::tunnel::TunnelClient`]
+#[derive(thiserror::Error, Debug)]
+pub enum TunnelError {
+    /// The runner URL could not be turned into a valid WebSocket URL
+    #[error("Invalid runner URL: {0}")]
+    InvalidUrl(String),
+
+    /// The transport connection or handshake failed
+    #[error("Failed to connect to runner: {0}")]
+    ConnectFailed(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// The runner refused the handshake (HTTP 401/403)
+    #[error("Runner rejected the tunnel handshake (HTTP {0})")]
+    AuthRejected(u16),
+
+    /// The runner is overloaded (HTTP 429/503) and said when to come back
+    #[error("Runner is overloaded (HTTP {status}), retry after {delay:?}")]
+    RetryAfter { status: u16, delay: Duration },
+
+    /// The challenge-response authentication failed or was refused
+    #[error("Tunnel authentication failed: {0}")]
+    AuthFailed(String),
+
+    /// The runner sent something that violates the tunnel protocol
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+
+    /// An established session died (e.g. keepalive timeout)
+    #[error("Connection lost: {0}")]
+    ConnectionLost(String),
+
+    /// Sending or receiving on the WebSocket failed
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[source] Box<tungstenite::Error>),
+
+    /// Sending or receiving on the HTTP/2 transport failed
+    #[error("HTTP/2 error: {0}")]
+    Http2(#[from] h2::Error),
+
+    /// Local socket I/O failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A configuration file could not be read or parsed
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    /// Reconnect attempts or budget exhausted
+    #[error("Max reconnection attempts exceeded: {0}")]
+    MaxRetriesExceeded(String),
+
+    /// `--self-test` found the tunnel unable to relay data locally
+    #[error("Self-test failed: {0}")]
+    SelfTestFailed(String),
+}
+
+impl TunnelError {
+    /// Whether the configuration itself is wrong, so reconnecting can't help
+    pub fn is_config_error(&self) -> bool {
+        matches!(self, TunnelError::InvalidUrl(_) | TunnelError::Config(_))
+    }
+
+    /// Classify an error returned while dialing the runner
+    pub fn from_handshake(e: tungstenite::Error) -> Self {
+        match &e {
+            tungstenite::Error::Http(response) => {
+                Self::from_status(response.status().as_u16(), response.headers())
+                    .unwrap_or_else(|| TunnelError::ConnectFailed(Box::new(e)))
+            }
+            _ => TunnelError::ConnectFailed(Box::new(e)),
+        }
+    }
+
+    /// Classify a refused handshake by its HTTP status, if it means more than
+    /// a plain connect failure
+    pub fn from_status(status: u16, headers: &http::HeaderMap) -> Option<Self> {
+        match status {
+            401 | 403 => Some(TunnelError::AuthRejected(status)),
+            429 | 503 => retry_after(headers, SystemTime::now())
+                .map(|delay| TunnelError::RetryAfter { status, delay }),
+            _ => None,
+        }
+    }
+}
+
+/// Longest Retry-After honored; a runner asking for more is retried then
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// Delay requested by a Retry-After header, either delta-seconds or an
+/// HTTP-date (a date in the past means now)
+pub fn retry_after(headers: &http::HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    let delay = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let at = httpdate::parse_http_date(value).ok()?;
+            at.duration_since(now).unwrap_or_default()
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+impl From<tungstenite::Error> for TunnelError {
+    fn from(e: tungstenite::Error) -> Self {
+        TunnelError::WebSocket(Box::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::http::Response;
+
+    #[test]
+    fn test_handshake_classification() {
+        let forbidden = Response::builder().status(403).body(None).unwrap();
+        assert!(matches!(
+            TunnelError::from_handshake(tungstenite::Error::Http(forbidden)),
+            TunnelError::AuthRejected(403)
+        ));
+
+        let unavailable = Response::builder().status(503).body(None).unwrap();
+        assert!(matches!(
+            TunnelError::from_handshake(tungstenite::Error::Http(unavailable)),
+            TunnelError::ConnectFailed(_)
+        ));
+
+        let throttled = Response::builder()
+            .status(429)
+            .header("Retry-After", "7")
+            .body(None)
+            .unwrap();
+        assert!(matches!(
+            TunnelError::from_handshake(tungstenite::Error::Http(throttled)),
+            TunnelError::RetryAfter { status: 429, delay } if delay == Duration::from_secs(7)
+        ));
+
+        assert!(matches!(
+            TunnelError::from_handshake(tungstenite::Error::ConnectionClosed),
+            TunnelError::ConnectFailed(_)
+        ));
+    }
+
+    #[test]
+    fn test_retry_after_forms() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let header = |value: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(http::header::RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(
+            retry_after(&header("120"), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&header("Sun, 06 Nov 1994 08:50:07 GMT"), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(&header("Sun, 06 Nov 1994 08:00:00 GMT"), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&header("999999"), now), Some(MAX_RETRY_AFTER));
+        assert_eq!(retry_after(&header("soon"), now), None);
+        assert_eq!(retry_after(&http::HeaderMap::new(), now), None);
+    }
+}
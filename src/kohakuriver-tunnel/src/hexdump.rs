@@ -0,0 +1,93 @@
+//! Hex dumps of DATA payloads for protocol debugging (`--trace-payloads`).
+//!
+//! Payloads are never logged unless the flag is given, and even then only at
+//! trace level and only their first bytes, capped at [`MAX_DUMP_BYTES`].
+
+use std::fmt;
+
+use tracing::{trace, Level};
+
+/// Most bytes of a payload ever dumped
+pub const MAX_DUMP_BYTES: usize = 1024;
+
+/// Displays the first `limit` bytes of a payload as hex and printable ASCII,
+/// e.g. `47 45 54 20 2f |GET /| (+120 bytes)`
+pub struct HexDump<'a> {
+    data: &'a [u8],
+    limit: usize,
+}
+
+impl<'a> HexDump<'a> {
+    pub fn new(data: &'a [u8], limit: usize) -> Self {
+        Self {
+            data,
+            limit: limit.min(MAX_DUMP_BYTES),
+        }
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = &self.data[..self.data.len().min(self.limit)];
+        for (i, byte) in shown.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        f.write_str(" |")?;
+        for &byte in shown {
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            write!(f, "{c}")?;
+        }
+        f.write_str("|")?;
+        let rest = self.data.len() - shown.len();
+        if rest > 0 {
+            write!(f, " (+{rest} bytes)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Log the start of a DATA payload at trace level, if `limit` is nonzero
+pub fn trace_payload(limit: usize, client_id: u32, direction: &str, data: &[u8]) {
+    if limit == 0 || !tracing::enabled!(Level::TRACE) {
+        return;
+    }
+    trace!(
+        client_id,
+        direction,
+        len = data.len(),
+        dump = %HexDump::new(data, limit),
+        "DATA payload"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(
+            HexDump::new(b"GET /\r\n", 64).to_string(),
+            "47 45 54 20 2f 0d 0a |GET /..|"
+        );
+        assert_eq!(
+            HexDump::new(b"hello world", 4).to_string(),
+            "68 65 6c 6c |hell| (+7 bytes)"
+        );
+        assert_eq!(HexDump::new(b"", 16).to_string(), " ||");
+    }
+
+    #[test]
+    fn test_hex_dump_capped() {
+        let data = vec![0u8; MAX_DUMP_BYTES + 10];
+        let dump = HexDump::new(&data, usize::MAX).to_string();
+        assert!(dump.ends_with("(+10 bytes)"));
+    }
+}
@@ -0,0 +1,398 @@
+//! HTTP/2 transport for networks that block or degrade WebSockets.
+//!
+//! The tunnel sends one long-lived `POST` to the same `/ws/tunnel/{container_id}`
+//! path: the request body carries frames to the runner and the response body
+//! frames from it. HTTP/2 DATA frames don't preserve message boundaries, so
+//! every tunnel frame is prefixed with its length (u32 big-endian).
+//!
+//! `wss://` URLs negotiate h2 over TLS via ALPN (with the `tls` feature);
+//! `ws://` URLs speak h2 with prior knowledge (h2c). There are no
+//! transport-level control messages, so keepalives are tunnel PING/PONG
+//! frames with client ID 0.
+
+use std::future::poll_fn;
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::future::BoxFuture;
+use h2::client::{self, SendRequest};
+use h2::{RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tls")]
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+use url::Url;
+
+use crate::auth;
+use crate::dial::{self, DialFamily};
+use crate::error::{Result, TunnelError};
+use crate::protocol::{self, Header, MsgType, Proto, ProtocolError, HEADER_SIZE};
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::tls::ClientIdentity;
+use crate::transport::{Incoming, TransportPair, TransportSink, TransportStream};
+
+/// Size of the length prefix in front of every frame
+pub const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Largest frame accepted from the runner
+pub const MAX_FRAME_LEN: usize = 16 << 20;
+
+/// Connect to the runner and open the tunnel stream, also returning the
+/// runner's auth nonce header if it sent one. Over https, `identity` is
+/// presented if the runner asks for a client certificate.
+pub async fn connect(
+    url: &Url,
+    family: DialFamily,
+    attempt_timeout: Duration,
+    user_agent: &str,
+    identity: Option<&ClientIdentity>,
+) -> Result<(TransportPair, Option<String>)> {
+    let uri = tunnel_uri(url)?;
+    let user_agent = http::HeaderValue::from_str(user_agent)
+        .map_err(|_| TunnelError::Config(format!("invalid User-Agent: {:?}", user_agent)))?;
+    let candidates = dial::resolve_candidates(url, family).await?;
+    let tcp = dial::connect_any(&candidates, attempt_timeout).await?;
+
+    #[cfg(feature = "tls")]
+    if url.scheme() == "wss" {
+        let tls = tls_connect(url, tcp, identity).await?;
+        return open(tls, uri, user_agent).await;
+    }
+    #[cfg(not(feature = "tls"))]
+    let _ = identity;
+    open(tcp, uri, user_agent).await
+}
+
+/// The `http(s)://` URI for a `ws(s)://` tunnel URL
+fn tunnel_uri(url: &Url) -> Result<http::Uri> {
+    let mut http_url = url.clone();
+    let scheme = if url.scheme() == "wss" {
+        "https"
+    } else {
+        "http"
+    };
+    http_url
+        .set_scheme(scheme)
+        .map_err(|()| TunnelError::InvalidUrl(format!("cannot use {} over HTTP/2", url)))?;
+    http_url
+        .as_str()
+        .parse()
+        .map_err(|e| TunnelError::InvalidUrl(format!("{}: {}", http_url, e)))
+}
+
+/// TLS handshake offering only h2 via ALPN
+#[cfg(feature = "tls")]
+async fn tls_connect(
+    url: &Url,
+    tcp: TcpStream,
+    identity: Option<&ClientIdentity>,
+) -> Result<tokio_native_tls::TlsStream<TcpStream>> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| TunnelError::InvalidUrl(format!("{} has no host", url)))?;
+    let connector = tls::connector(identity, &["h2"])?;
+    let tls = tokio_native_tls::TlsConnector::from(connector)
+        .connect(host, tcp)
+        .await
+        .map_err(|e| TunnelError::ConnectFailed(Box::new(e)))?;
+
+    let alpn = tls
+        .get_ref()
+        .negotiated_alpn()
+        .map_err(|e| TunnelError::ConnectFailed(Box::new(e)))?;
+    if alpn.as_deref() != Some(b"h2".as_slice()) {
+        return Err(TunnelError::ConnectFailed(
+            "runner did not negotiate HTTP/2 via ALPN".into(),
+        ));
+    }
+    Ok(tls)
+}
+
+/// HTTP/2 handshake and tunnel request over an established connection
+async fn open<T>(
+    io: T,
+    uri: http::Uri,
+    user_agent: http::HeaderValue,
+) -> Result<(TransportPair, Option<String>)>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (send_request, connection) = client::handshake(io)
+        .await
+        .map_err(|e| TunnelError::ConnectFailed(Box::new(e)))?;
+    let driver = tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!(error = %e, "HTTP/2 connection ended");
+        }
+    });
+
+    match start_stream(send_request, uri, user_agent).await {
+        Ok((send, recv, nonce)) => Ok((
+            (
+                Box::new(H2Sink { stream: send }),
+                Box::new(H2Source {
+                    body: recv,
+                    buf: BytesMut::new(),
+                    driver,
+                }),
+            ),
+            nonce,
+        )),
+        Err(e) => {
+            driver.abort();
+            Err(e)
+        }
+    }
+}
+
+/// Send the tunnel POST and wait for the runner to accept it
+async fn start_stream(
+    send_request: SendRequest<Bytes>,
+    uri: http::Uri,
+    user_agent: http::HeaderValue,
+) -> Result<(SendStream<Bytes>, RecvStream, Option<String>)> {
+    let request = http::Request::post(uri)
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(http::header::USER_AGENT, user_agent)
+        .body(())
+        .expect("static request parts are valid");
+
+    let mut send_request = send_request
+        .ready()
+        .await
+        .map_err(|e| TunnelError::ConnectFailed(Box::new(e)))?;
+    let (response, send) = send_request
+        .send_request(request, false)
+        .map_err(|e| TunnelError::ConnectFailed(Box::new(e)))?;
+    let response = response
+        .await
+        .map_err(|e| TunnelError::ConnectFailed(Box::new(e)))?;
+
+    let status = response.status();
+    if let Some(e) = TunnelError::from_status(status.as_u16(), response.headers()) {
+        return Err(e);
+    }
+    if !status.is_success() {
+        return Err(TunnelError::ConnectFailed(
+            format!("runner answered the tunnel request with HTTP {}", status).into(),
+        ));
+    }
+    info!(%status, "HTTP/2 tunnel stream open");
+    let nonce = auth::nonce_header(response.headers());
+    Ok((send, response.into_body(), nonce))
+}
+
+/// Prefix a frame with its length
+pub fn encode_frame(frame: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(LENGTH_PREFIX_SIZE + frame.len());
+    buf.put_u32(frame.len() as u32);
+    buf.put_slice(frame);
+    buf.freeze()
+}
+
+/// Take the next complete frame off the front of `buf`, if there is one
+pub fn decode_frame(buf: &mut BytesMut) -> std::result::Result<Option<Bytes>, ProtocolError> {
+    if buf.len() < LENGTH_PREFIX_SIZE {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(buf[..LENGTH_PREFIX_SIZE].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+    if buf.len() < LENGTH_PREFIX_SIZE + len {
+        return Ok(None);
+    }
+    buf.advance(LENGTH_PREFIX_SIZE);
+    Ok(Some(buf.split_to(len).freeze()))
+}
+
+/// Sending half of the HTTP/2 transport (the request body)
+pub struct H2Sink {
+    stream: SendStream<Bytes>,
+}
+
+impl H2Sink {
+    /// Write one length-prefixed frame, respecting HTTP/2 flow control
+    async fn write(&mut self, frame: &[u8]) -> Result<()> {
+        let mut data = encode_frame(frame);
+        while !data.is_empty() {
+            self.stream.reserve_capacity(data.len());
+            let mut capacity = self.stream.capacity();
+            if capacity == 0 {
+                capacity = match poll_fn(|cx| self.stream.poll_capacity(cx)).await {
+                    Some(capacity) => capacity?,
+                    None => {
+                        return Err(TunnelError::ConnectionLost(
+                            "HTTP/2 tunnel stream closed".into(),
+                        ))
+                    }
+                };
+            }
+            let chunk = data.split_to(capacity.min(data.len()));
+            self.stream.send_data(chunk, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl TransportSink for H2Sink {
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.write(&frame).await })
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let ping = protocol::build_message(MsgType::Ping, Proto::Tcp, 0, 0, &payload);
+            self.write(&ping).await
+        })
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.write(&protocol::build_pong(0, &payload)).await })
+    }
+}
+
+/// Receiving half of the HTTP/2 transport (the response body)
+pub struct H2Source {
+    body: RecvStream,
+    buf: BytesMut,
+    /// Drives the HTTP/2 connection; aborted with this half
+    driver: JoinHandle<()>,
+}
+
+impl Drop for H2Source {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+/// Client ID 0 PING/PONG frames are transport keepalives
+fn classify(frame: Bytes) -> Incoming {
+    if let Ok(header) = Header::parse(&frame) {
+        if header.client_id == 0 {
+            match header.msg_type {
+                MsgType::Ping => return Incoming::Ping(frame.slice(HEADER_SIZE..)),
+                MsgType::Pong => return Incoming::Pong(frame.slice(HEADER_SIZE..)),
+                _ => {}
+            }
+        }
+    }
+    Incoming::Frame(frame)
+}
+
+impl TransportStream for H2Source {
+    fn recv(&mut self) -> BoxFuture<'_, Option<Result<Incoming>>> {
+        Box::pin(async move {
+            loop {
+                match decode_frame(&mut self.buf) {
+                    Ok(Some(frame)) => return Some(Ok(classify(frame))),
+                    Ok(None) => {}
+                    Err(e) => return Some(Err(e.into())),
+                }
+                match self.body.data().await? {
+                    Ok(chunk) => {
+                        let _ = self.body.flow_control().release_capacity(chunk.len());
+                        self.buf.extend_from_slice(&chunk);
+                    }
+                    Err(e) => return Some(Err(e.into())),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_frame_codec_handles_partial_input() {
+        let encoded = encode_frame(b"abc");
+        let mut buf = BytesMut::from(&encoded[..5]);
+        assert_eq!(decode_frame(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&encoded[5..]);
+        buf.extend_from_slice(&encode_frame(b""));
+        assert_eq!(decode_frame(&mut buf).unwrap().unwrap(), &b"abc"[..]);
+        assert_eq!(decode_frame(&mut buf).unwrap().unwrap(), &b""[..]);
+        assert!(buf.is_empty());
+
+        let mut oversized = BytesMut::from(&((MAX_FRAME_LEN + 1) as u32).to_be_bytes()[..]);
+        assert!(matches!(
+            decode_frame(&mut oversized),
+            Err(ProtocolError::FrameTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_tunnel_uri_maps_scheme() {
+        let url = Url::parse("wss://runner:8001/ws/tunnel/abc").unwrap();
+        assert_eq!(
+            tunnel_uri(&url).unwrap().to_string(),
+            "https://runner:8001/ws/tunnel/abc"
+        );
+        let url = Url::parse("ws://runner/ws/tunnel/abc").unwrap();
+        assert_eq!(
+            tunnel_uri(&url).unwrap().to_string(),
+            "http://runner/ws/tunnel/abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_h2c_roundtrip_and_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Minimal runner: echo every frame back, answering PINGs with PONGs
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = h2::server::handshake(stream).await.unwrap();
+            let (request, mut respond) = conn.accept().await.unwrap().unwrap();
+            tokio::spawn(async move { while conn.accept().await.is_some() {} });
+
+            assert_eq!(request.method(), http::Method::POST);
+            assert_eq!(request.uri().path(), "/ws/tunnel/abc");
+            assert_eq!(request.headers()[http::header::USER_AGENT], "test-agent");
+            let mut body = request.into_body();
+            let mut send = respond
+                .send_response(http::Response::new(()), false)
+                .unwrap();
+            let mut buf = BytesMut::new();
+            while let Some(Ok(chunk)) = body.data().await {
+                let _ = body.flow_control().release_capacity(chunk.len());
+                buf.extend_from_slice(&chunk);
+                while let Some(frame) = decode_frame(&mut buf).unwrap() {
+                    let reply = match classify(frame.clone()) {
+                        Incoming::Ping(payload) => protocol::build_pong(0, &payload),
+                        _ => frame,
+                    };
+                    send.send_data(encode_frame(&reply), false).unwrap();
+                }
+            }
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/tunnel/abc", addr)).unwrap();
+        let ((mut sink, mut stream), _) = connect(
+            &url,
+            DialFamily::Ipv4,
+            dial::DEFAULT_ATTEMPT_TIMEOUT,
+            "test-agent",
+            None,
+        )
+        .await
+        .unwrap();
+
+        let data = protocol::build_data(Proto::Tcp, 7, &vec![0x5a; 100_000]);
+        sink.send(data.clone()).await.unwrap();
+        assert_eq!(stream.recv().await.unwrap().unwrap(), Incoming::Frame(data));
+
+        sink.ping(Bytes::from_static(b"12345678")).await.unwrap();
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            Incoming::Pong(Bytes::from_static(b"12345678"))
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! KohakuRiver Tunnel Client library
+//!
+//! Connects to the runner's WebSocket endpoint and forwards TCP/UDP
+//! connections to local services inside the container. The `tunnel-client`
+//! binary is a thin CLI over [`tunnel::TunnelClient`].
+
+#[cfg(feature = "udp")]
+pub mod affinity;
+pub mod audit;
+pub mod auth;
+pub mod bytecount;
+pub mod capture;
+pub mod chaos;
+#[cfg(feature = "compression")]
+pub mod compress;
+pub mod config;
+pub mod connection;
+pub mod control;
+pub mod crypto;
+pub mod datagram;
+pub mod delta;
+pub mod detect;
+pub mod dial;
+pub mod egress;
+pub mod error;
+pub mod hexdump;
+pub mod http2;
+pub mod metrics;
+pub mod mux;
+pub mod policy;
+pub mod protocol;
+#[cfg(feature = "raw")]
+pub mod raw;
+pub mod reassembly;
+pub mod resolver;
+pub mod resume;
+pub mod selftest;
+pub mod spill;
+pub mod task;
+pub mod throttle;
+pub mod tls;
+pub mod transport;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod tunnel;
+pub mod upstream;
+pub mod watchdog;
+pub mod webhook;
+
+pub use error::{Result, TunnelError};
+pub use tunnel::{TunnelClient, TunnelConfig};
@@ -12,77 +12,686 @@
 //! Or using environment variables:
 //!     RUNNER_URL=ws://192.168.1.100:8001 CONTAINER_ID=my-container tunnel-client
 
-mod connection;
-mod protocol;
-mod tunnel;
-
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use anyhow::Result;
-use clap::Parser;
-use tracing::info;
+use anyhow::{Context, Result};
+use clap::{ArgAction, Parser};
+use tracing::{info, warn};
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::EnvFilter;
 
-use tunnel::{TunnelClient, TunnelConfig};
+use kohakuriver_tunnel::capture::Replay;
+use kohakuriver_tunnel::chaos::ChaosConfig;
+use kohakuriver_tunnel::config::{
+    parse_duration, ForcedPorts, MuxContainer, PortLimits, PortMap, PortPriorities, PortSet,
+    PortTargets, RunnerPattern, ServiceMap,
+};
+use kohakuriver_tunnel::connection::DuplicateIdPolicy;
+use kohakuriver_tunnel::datagram::UdpOverflow;
+use kohakuriver_tunnel::detect;
+use kohakuriver_tunnel::dial::DialFamily;
+use kohakuriver_tunnel::hexdump;
+use kohakuriver_tunnel::protocol::Proto;
+use kohakuriver_tunnel::selftest;
+use kohakuriver_tunnel::transport::TransportKind;
+#[cfg(feature = "tui")]
+use kohakuriver_tunnel::tui;
+use kohakuriver_tunnel::tunnel::{ReconnectBudget, TunnelClient, TunnelConfig};
+use kohakuriver_tunnel::upstream::{UpstreamPolicy, UpstreamPoolSpec};
 
 /// KohakuRiver Tunnel Client - Port forwarding for containers
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Runner WebSocket URL (e.g., ws://192.168.1.100:8001)
-    #[arg(short, long, env = "RUNNER_URL")]
-    runner_url: String,
+    /// Runner WebSocket URL (e.g., ws://192.168.1.100:8001; ws:// is assumed if omitted)
+    #[arg(
+        short,
+        long,
+        env = "RUNNER_URL",
+        required_unless_present_any = ["self_test", "replay", "auto_detect"]
+    )]
+    runner_url: Option<String>,
 
     /// Container ID or name (used to identify this tunnel)
-    #[arg(short, long, env = "CONTAINER_ID")]
-    container_id: String,
+    #[arg(
+        short,
+        long,
+        env = "CONTAINER_ID",
+        required_unless_present_any = ["self_test", "replay", "auto_detect"]
+    )]
+    container_id: Option<String>,
+
+    /// Check that data can be relayed to local services through an
+    /// in-process mock runner, print pass/fail and exit
+    #[arg(long)]
+    self_test: bool,
+
+    /// Find --container-id and --runner-url when not given: from the
+    /// runner's KOHAKURIVER_* variables, else from the container's cgroup
+    /// and the default gateway
+    #[arg(long, env = "TUNNEL_AUTO_DETECT")]
+    auto_detect: bool,
+
+    /// Print the frames recorded in a --capture file and exit
+    #[arg(long, conflicts_with = "self_test")]
+    replay: Option<PathBuf>,
+
+    /// Record every frame sent to or received from the runner, with a
+    /// timestamp and direction, to this file (read it with --replay)
+    #[arg(long, env = "TUNNEL_CAPTURE")]
+    capture: Option<PathBuf>,
+
+    /// Size at which the capture file is rotated to <file>.1
+    #[arg(long, default_value = "67108864", env = "CAPTURE_MAX_BYTES")]
+    capture_max_bytes: u64,
+
+    /// POST a JSON event to this http(s):// URL whenever a forwarded
+    /// connection opens or closes
+    #[arg(long, env = "WEBHOOK_URL")]
+    webhook_url: Option<String>,
+
+    /// Serve a further container over the same connection, e.g.
+    /// web-2=172.17.0.3 (repeatable; its connections go to that host)
+    #[arg(long, env = "MUX_CONTAINERS", value_delimiter = ',')]
+    mux_container: Vec<MuxContainer>,
+
+    /// Inject faults into the runner link for resilience testing, e.g.
+    /// drop=0.01,corrupt=0.01,delay=0.1,max-delay=200ms,reconnect=30s,seed=42
+    #[arg(long, env = "TUNNEL_CHAOS", hide = true)]
+    chaos: Option<ChaosConfig>,
+
+    /// Use wss:// when the runner URL has no scheme
+    #[arg(long, env = "RUNNER_TLS")]
+    tls: bool,
+
+    /// PEM client certificate (chain) for runners that require mutual TLS
+    #[arg(long, env = "TUNNEL_CLIENT_CERT", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PKCS#8 PEM private key of --client-cert
+    #[arg(long, env = "TUNNEL_CLIENT_KEY", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Address family for dialing the runner (auto tries every resolved address)
+    #[arg(long, value_enum, default_value_t = DialFamily::Auto, env = "DIAL_FAMILY")]
+    dial_family: DialFamily,
+
+    /// How long each runner address gets to accept the TCP connection before
+    /// the dial moves on (e.g. 10s, 500ms; 0 = wait for the OS to give up)
+    #[arg(long, default_value = "10s", value_parser = parse_duration, env = "DIAL_ATTEMPT_TIMEOUT")]
+    dial_attempt_timeout: Duration,
+
+    /// Transport carrying the tunnel: websocket, or h2 for networks that block WebSockets
+    #[arg(long, value_enum, default_value_t = TransportKind::Websocket, env = "TUNNEL_TRANSPORT")]
+    transport: TransportKind,
+
+    /// User-Agent header sent when connecting to the runner
+    /// [default: kohakuriver-tunnel/<version> (<container-id>)]
+    #[arg(long, env = "TUNNEL_USER_AGENT")]
+    user_agent: Option<String>,
 
     /// Reconnect delay in seconds
     #[arg(long, default_value = "5", env = "RECONNECT_DELAY")]
     reconnect_delay: u64,
 
-    /// Maximum reconnect attempts (0 = infinite)
+    /// Maximum reconnect attempts in a row (0 = infinite); a session that
+    /// stays up for the stability window starts the count over
     #[arg(long, default_value = "0", env = "MAX_RECONNECT")]
     max_reconnect: u32,
 
+    /// Connection attempts allowed per time window before exiting nonzero
+    /// (e.g. 10/60s; by default the tunnel retries forever)
+    #[arg(long, default_value = "unlimited", env = "RECONNECT_BUDGET")]
+    reconnect_budget: ReconnectBudget,
+
+    /// Spread connects of tunnels sharing a runner over this window, e.g. 30s:
+    /// the first connect and every reconnect wait gain a fixed offset derived
+    /// from the container ID (0 = disabled)
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "SPREAD_RECONNECT")]
+    spread_reconnect: Duration,
+
+    /// Only reset the reconnect backoff after a session stayed up this long,
+    /// however it ended, e.g. 30s; shorter sessions double the delay up to
+    /// 32x (0 = fixed delay, reset by any session that reached the runner)
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "STABILITY_WINDOW")]
+    stability_window: Duration,
+
+    /// Protocols the runner may open connections for (comma-separated:
+    /// tcp,udp,raw; raw needs the raw feature and CAP_NET_RAW)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "tcp,udp",
+        env = "ALLOW_PROTO"
+    )]
+    allow_proto: Vec<Proto>,
+
+    /// Requested ports the runner may connect to (e.g. 22,8000-8100; default any)
+    #[arg(long, env = "ALLOW_PORTS")]
+    allow_ports: Option<PortSet>,
+
+    /// Reject every UDP CONNECT, regardless of --allow-proto
+    #[arg(long, env = "NO_UDP")]
+    no_udp: bool,
+
+    /// Set Don't Fragment on forwarded UDP sockets, so datagrams larger than
+    /// the path MTU are dropped with a warning instead of silently lost
+    /// (Linux only)
+    #[arg(long, env = "UDP_DONT_FRAGMENT")]
+    udp_dont_fragment: bool,
+
+    /// Which datagram to drop when a local UDP service sends faster than the
+    /// runner link carries (block = stop reading, as for TCP)
+    #[arg(long, value_enum, default_value = "drop-oldest", env = "UDP_OVERFLOW")]
+    udp_overflow: UdpOverflow,
+
+    /// When a local UDP socket fails, keep writing the datagrams already
+    /// queued from the runner for up to this long before closing, e.g. 200ms
+    /// (0 = drop them)
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "UDP_CLOSE_DRAIN")]
+    udp_close_drain: Duration,
+
+    /// Bind the same local UDP port again when the runner reconnects a
+    /// client ID, for services that key sessions on the source port
+    #[arg(long, env = "UDP_PORT_AFFINITY")]
+    udp_port_affinity: bool,
+
+    /// Translate requested ports to local ports (e.g. 8080:80,443:8443)
+    #[arg(long, env = "PORT_MAP")]
+    port_map: Option<PortMap>,
+
+    /// Send a requested port to a full target instead, e.g.
+    /// 9000=tcp://10.0.0.5:9000,80=unix:///run/app.sock (overrides
+    /// --port-map, --upstream-pool and --force-target-port for that port)
+    #[arg(long, env = "PORT_TARGET")]
+    port_target: Option<PortTargets>,
+
+    /// Ports of services a CONNECT for port 0 may name in its payload
+    /// (e.g. jupyter=8888,api=8000)
+    #[arg(long, env = "SERVICE_MAP")]
+    service_map: Option<ServiceMap>,
+
+    /// Command run with a service name not in --service-map; it prints the
+    /// service's port, or exits nonzero if there is none
+    #[arg(long, env = "SERVICE_RESOLVER_CMD")]
+    service_resolver_cmd: Option<PathBuf>,
+
+    /// Send every CONNECT to this local port whatever it requested, e.g. 8080
+    /// or tcp:8080,udp:5353 (overrides --port-map and --upstream-pool)
+    #[arg(long, env = "FORCE_TARGET_PORT")]
+    force_target_port: Option<ForcedPorts>,
+
+    /// Fan a requested port out across local upstreams, e.g. 80=[3001,3002]
+    /// (repeatable; overrides --port-map for that port)
+    #[arg(long, env = "UPSTREAM_POOL", value_delimiter = ';')]
+    upstream_pool: Vec<UpstreamPoolSpec>,
+
+    /// Upstream selection policy within a pool
+    #[arg(
+        long,
+        value_enum,
+        default_value = "round-robin",
+        env = "UPSTREAM_POLICY"
+    )]
+    upstream_policy: UpstreamPolicy,
+
+    /// Interval between WebSocket pings sent to the runner in seconds (0 = disabled)
+    #[arg(long, default_value = "30", env = "WS_PING_INTERVAL")]
+    ws_ping_interval: u64,
+
+    /// Seconds to wait for a WebSocket pong before reconnecting (at least 1,
+    /// and no more than the ping interval)
+    #[arg(long, default_value = "10", env = "WS_PING_TIMEOUT")]
+    ws_ping_timeout: u64,
+
+    /// Bytes of outgoing WebSocket frames buffered before writing to the
+    /// socket (0 = write each frame immediately; bursts are flushed together)
+    #[arg(long, default_value = "131072", env = "WS_WRITE_BUFFER_SIZE")]
+    ws_write_buffer_size: usize,
+
+    /// Cap on buffered outgoing WebSocket bytes; must exceed
+    /// --ws-write-buffer-size (unset = unlimited)
+    #[arg(long, env = "WS_MAX_WRITE_BUFFER_SIZE")]
+    ws_max_write_buffer_size: Option<usize>,
+
+    /// Local address to open forwarded connections from, e.g. 10.0.0.5
+    /// (unset = chosen by the OS)
+    #[arg(long, env = "SOURCE_ADDR")]
+    source_addr: Option<IpAddr>,
+
+    /// Send a keepalive on forwarded connections idle this long, e.g. 25s (0 = disabled)
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "CONN_KEEPALIVE_INTERVAL")]
+    conn_keepalive_interval: Duration,
+
+    /// Close a forwarded connection if the runner sends no DATA on it within
+    /// this long after CONNECTED, e.g. 60s (0 = disabled)
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "RUNNER_SILENCE_TIMEOUT")]
+    runner_silence_timeout: Duration,
+
+    /// Enable kernel TCP keepalive on forwarded local sockets, probing after
+    /// this much idle time, e.g. 30s (0 = OS default)
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "TCP_KEEPALIVE")]
+    tcp_keepalive: Duration,
+
+    /// Reject frames from the runner whose payload doesn't fit their type
+    /// (e.g. a CLOSE with a payload) instead of ignoring the extra bytes
+    #[arg(long, env = "STRICT_PROTOCOL")]
+    strict_protocol: bool,
+
+    /// Exit with an error if the first connection to the runner fails instead
+    /// of retrying (an invalid URL always exits)
+    #[arg(long, env = "FAIL_FAST_ON_FIRST_CONNECT")]
+    fail_fast_on_first_connect: bool,
+
+    /// Debugging aid: prefix TCP DATA with cumulative byte counts and warn
+    /// when the runner's don't match the bytes received
+    #[arg(long, env = "VERIFY_BYTE_COUNTS")]
+    verify_byte_counts: bool,
+
+    /// Debugging aid: hex-dump the first BYTES (default 64, at most 1024) of
+    /// every DATA payload at trace level; payloads are never logged otherwise
+    #[arg(
+        long,
+        value_name = "BYTES",
+        num_args = 0..=1,
+        default_missing_value = "64",
+        env = "TRACE_PAYLOADS"
+    )]
+    trace_payloads: Option<usize>,
+
+    /// Per-connection queue depth (frames) for data waiting to be written to the
+    /// local service. Worst-case memory is depth × 64 KiB × active connections.
+    #[arg(
+        long,
+        default_value = "256",
+        env = "SEND_QUEUE_DEPTH",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    send_queue_depth: u32,
+
+    /// Local connects allowed in progress at once; further CONNECTs queue (0 = unlimited)
+    #[arg(long, default_value = "0", env = "MAX_PENDING_CONNECTS")]
+    max_pending_connects: u32,
+
+    /// Connection tasks allowed at once, up to three per connection; further
+    /// CONNECTs are rejected (0 = unlimited)
+    #[arg(long, default_value = "30000", env = "MAX_TASKS")]
+    max_tasks: u32,
+
+    /// Largest DATA payload sent to the runner, in bytes; larger TCP reads are
+    /// split across frames and larger datagrams dropped (0 = no cap)
+    #[arg(long, default_value = "0", env = "MAX_PAYLOAD_SIZE")]
+    max_payload_size: u32,
+
+    /// Cap concurrent connections per requested port, e.g. 80=100,5432=20
+    /// (other ports unlimited)
+    #[arg(long, env = "PER_PORT_LIMIT")]
+    per_port_limit: Option<PortLimits>,
+
+    /// Egress priority per requested port, e.g. 22=high,9000=low (classes:
+    /// high, normal, low; other ports normal)
+    #[arg(long, env = "PORT_PRIORITY")]
+    port_priority: Option<PortPriorities>,
+
+    /// What to do when a CONNECT reuses the id of a live connection
+    #[arg(long, value_enum, default_value = "reject", env = "ON_DUPLICATE_ID")]
+    on_duplicate_id: DuplicateIdPolicy,
+
+    /// Directory for spilling DATA to disk when a connection's queue is full
+    /// (unset = keep everything in memory and apply backpressure)
+    #[arg(long, env = "SPILL_DIR")]
+    spill_dir: Option<PathBuf>,
+
+    /// Maximum total bytes spilled to disk across all connections
+    #[arg(long, default_value = "1073741824", env = "SPILL_MAX_BYTES")]
+    spill_max_bytes: u64,
+
+    /// Maximum bytes of DATA queued in memory across all connections (default
+    /// unbounded); TCP spills to --spill-dir or is closed, UDP datagrams are
+    /// dropped
+    #[arg(long, env = "MAX_TOTAL_BUFFER_BYTES")]
+    max_total_buffer_bytes: Option<u64>,
+
+    /// Pre-shared key enabling XChaCha20-Poly1305 encryption of DATA payloads
+    #[arg(long, env = "TUNNEL_PSK", hide_env_values = true)]
+    psk: Option<String>,
+
+    /// Shared secret for the runner's challenge-response auth: the first frame
+    /// is an HMAC over the nonce the runner sends in the upgrade response
+    #[arg(long, env = "TUNNEL_AUTH_SECRET", hide_env_values = true)]
+    auth_secret: Option<String>,
+
+    /// File holding the auth secret (e.g. /run/secrets/tunnel), re-read on
+    /// every reconnect; takes precedence over --auth-secret
+    #[arg(long, env = "TUNNEL_AUTH_SECRET_FILE")]
+    auth_secret_file: Option<PathBuf>,
+
+    /// Keep TCP connections open across a dropped WebSocket for this long and
+    /// resume them on reconnect, e.g. 30s (0 = disabled)
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "RESUME_GRACE")]
+    resume_grace: Duration,
+
+    /// Unacknowledged bytes retained per connection for retransmit on resume
+    #[arg(long, default_value = "4194304", env = "RESUME_BUFFER")]
+    resume_buffer: usize,
+
+    /// On SIGTERM/SIGINT, how long open connections may finish before they
+    /// are force-closed, e.g. 10s
+    #[arg(long, default_value = "10s", value_parser = parse_duration, env = "SHUTDOWN_GRACE")]
+    shutdown_grace: Duration,
+
+    /// Log a summary of frame types, protocols and bytes at this interval,
+    /// e.g. 60s (0 = disabled)
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "TRAFFIC_SUMMARY_INTERVAL")]
+    traffic_summary_interval: Duration,
+
+    /// Reconnect when the message loop makes no progress for this long,
+    /// e.g. 60s (0 = disabled). Waiting for a slow local service counts as
+    /// no progress, so allow for that
+    #[arg(long, default_value = "0", value_parser = parse_duration, env = "WATCHDOG_TIMEOUT")]
+    watchdog_timeout: Duration,
+
+    /// Accept JSON control commands ({"cmd":"drain"}, {"cmd":"stats"}) from
+    /// the runner in WebSocket text frames
+    #[arg(long, env = "TUNNEL_CONTROL")]
+    control: bool,
+
+    /// Runners a redirect control command may move the tunnel to: hostnames,
+    /// *.domain wildcards, IPs or CIDR blocks (comma-separated; default only
+    /// the --runner-url host)
+    #[arg(long, env = "ALLOWED_RUNNERS", value_delimiter = ',')]
+    allowed_runners: Vec<RunnerPattern>,
+
+    /// Offer delta encoding of repetitive DATA payloads (used only if the
+    /// runner supports it too)
+    #[arg(long, env = "TUNNEL_DELTA")]
+    delta: bool,
+
+    /// Offer per-connection gzip compression of TCP streams (used only if
+    /// the runner supports it too)
+    #[cfg(feature = "compression")]
+    #[arg(long, env = "TUNNEL_COMPRESSION")]
+    compression: bool,
+
+    /// Policy file overriding allow_proto/allow_ports; re-read on SIGHUP
+    #[arg(long, env = "TUNNEL_CONFIG")]
+    config: Option<PathBuf>,
+
     /// Log level (trace, debug, info, warn, error)
     #[arg(long, default_value = "info", env = "LOG_LEVEL")]
     log_level: String,
+
+    /// Show a live dashboard of connections and throughput instead of log
+    /// output; quitting it (q) stops the tunnel
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with = "self_test")]
+    tui: bool,
+
+    /// Less output: -q = warn, -qq = error (overrides --log-level)
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// More output: -v = debug, -vv = trace (overrides --log-level)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    if let Some(path) = &args.replay {
+        return replay(path);
+    }
 
-    // Initialize logging
-    init_logging(&args.log_level);
-
-    info!(
-        runner_url = %args.runner_url,
-        container_id = %args.container_id,
-        "Starting KohakuRiver Tunnel Client"
+    // Initialize logging; the dashboard shows log lines in its own pane
+    #[cfg(feature = "tui")]
+    let tui_logs = args.tui.then(tui::LogBuffer::default);
+    #[cfg(feature = "tui")]
+    match &tui_logs {
+        Some(logs) => init_logging(
+            &args.log_level,
+            args.quiet,
+            args.verbose,
+            logs.clone(),
+            false,
+        ),
+        None => init_logging(
+            &args.log_level,
+            args.quiet,
+            args.verbose,
+            std::io::stdout,
+            true,
+        ),
+    }
+    #[cfg(not(feature = "tui"))]
+    init_logging(
+        &args.log_level,
+        args.quiet,
+        args.verbose,
+        std::io::stdout,
+        true,
     );
 
+    // Required by clap unless --self-test, which doesn't use them, or
+    // --auto-detect, which fills in what is missing
+    let (mut runner_url, mut container_id) = (args.runner_url, args.container_id);
+    if args.auto_detect && !args.self_test {
+        container_id = container_id.or_else(detect::container_id);
+        runner_url = runner_url.or_else(detect::runner_url);
+        if container_id.is_none() {
+            anyhow::bail!("--auto-detect found no container ID; pass --container-id");
+        }
+        if runner_url.is_none() {
+            anyhow::bail!("--auto-detect found no runner URL; pass --runner-url");
+        }
+    }
+    let runner_url = runner_url.unwrap_or_default();
+    let container_id = container_id.unwrap_or_default();
     // Build configuration
     let config = TunnelConfig {
-        runner_url: args.runner_url,
-        container_id: args.container_id,
+        runner_url,
+        container_id,
+        tls: args.tls,
+        client_cert: args.client_cert,
+        client_key: args.client_key,
+        dial_family: args.dial_family,
+        dial_attempt_timeout: args.dial_attempt_timeout,
+        transport: args.transport,
+        user_agent: args.user_agent,
         reconnect_delay: Duration::from_secs(args.reconnect_delay),
         max_reconnect_attempts: args.max_reconnect,
+        reconnect_budget: args.reconnect_budget,
+        spread_reconnect: args.spread_reconnect,
+        stability_window: args.stability_window,
+        allowed_protos: args.allow_proto,
+        allowed_ports: args.allow_ports,
+        disable_udp: args.no_udp,
+        udp_dont_fragment: args.udp_dont_fragment,
+        udp_overflow: args.udp_overflow,
+        udp_close_drain: args.udp_close_drain,
+        udp_port_affinity: args.udp_port_affinity,
+        config_file: args.config,
+        port_map: args.port_map.unwrap_or_default(),
+        port_targets: args.port_target.unwrap_or_default(),
+        service_map: args.service_map.unwrap_or_default(),
+        service_resolver_cmd: args.service_resolver_cmd,
+        force_target_port: args.force_target_port.unwrap_or_default(),
+        upstream_pools: args.upstream_pool,
+        upstream_policy: args.upstream_policy,
+        ws_ping_interval: Duration::from_secs(args.ws_ping_interval),
+        ws_ping_timeout: Duration::from_secs(args.ws_ping_timeout),
+        ws_write_buffer_size: args.ws_write_buffer_size,
+        ws_max_write_buffer_size: args.ws_max_write_buffer_size,
+        source_addr: args.source_addr,
+        conn_keepalive_interval: args.conn_keepalive_interval,
+        runner_silence_timeout: args.runner_silence_timeout,
+        tcp_keepalive: args.tcp_keepalive,
+        strict_protocol: args.strict_protocol,
+        fail_fast_on_first_connect: args.fail_fast_on_first_connect,
+        verify_byte_counts: args.verify_byte_counts,
+        trace_payloads: args
+            .trace_payloads
+            .unwrap_or(0)
+            .min(hexdump::MAX_DUMP_BYTES),
+        capture: args.capture,
+        capture_max_bytes: args.capture_max_bytes,
+        webhook_url: args.webhook_url,
+        send_queue_depth: args.send_queue_depth as usize,
+        max_pending_connects: args.max_pending_connects as usize,
+        max_tasks: args.max_tasks as usize,
+        max_payload_size: args.max_payload_size as usize,
+        duplicate_id_policy: args.on_duplicate_id,
+        port_limits: args.per_port_limit.unwrap_or_default(),
+        port_priorities: args.port_priority.unwrap_or_default(),
+        spill_dir: args.spill_dir,
+        spill_max_bytes: args.spill_max_bytes,
+        max_total_buffer_bytes: args.max_total_buffer_bytes,
+        psk: args.psk,
+        auth_secret: args.auth_secret,
+        auth_secret_file: args.auth_secret_file,
+        resume_grace: args.resume_grace,
+        resume_buffer: args.resume_buffer,
+        shutdown_grace: args.shutdown_grace,
+        traffic_summary_interval: args.traffic_summary_interval,
+        watchdog_timeout: args.watchdog_timeout,
+        control: args.control,
+        allowed_runners: args.allowed_runners,
+        delta: args.delta,
+        #[cfg(feature = "compression")]
+        compression: args.compression,
+        #[cfg(not(feature = "compression"))]
+        compression: false,
+        mux_containers: args.mux_container,
+        chaos: args.chaos,
     };
 
+    if args.self_test {
+        return self_test(&config).await;
+    }
+    info!(config = %config.summary(), "Starting KohakuRiver Tunnel Client");
+    if let Some(chaos) = &config.chaos {
+        warn!(%chaos, "Chaos mode on: injecting faults into the runner link");
+    }
+
     // Create and run tunnel client
     let client = TunnelClient::new(config);
-    client.run().await?;
+    #[cfg(feature = "tui")]
+    if let Some(logs) = tui_logs {
+        return run_with_dashboard(&client, logs).await;
+    }
+    client.run_until(shutdown_signal()).await?;
 
     Ok(())
 }
 
-fn init_logging(level: &str) {
+/// Run the tunnel under the live dashboard until it is closed or a signal
+/// arrives
+#[cfg(feature = "tui")]
+async fn run_with_dashboard(client: &TunnelClient, logs: tui::LogBuffer) -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let dashboard = tokio::task::spawn_blocking({
+        let (metrics, stop) = (client.metrics().clone(), stop.clone());
+        move || tui::run(metrics, logs, stop)
+    });
+    let result = client
+        .run_until(async {
+            tokio::select! {
+                _ = shutdown_signal() => {}
+                closed = dashboard => {
+                    if let Ok(Err(e)) = closed {
+                        eprintln!("Dashboard failed: {e}");
+                    }
+                }
+            }
+        })
+        .await;
+    // The runtime waits for the dashboard thread before exiting
+    stop.store(true, Ordering::Relaxed);
+    Ok(result?)
+}
+
+/// Run the loopback self-test and report the outcome
+async fn self_test(config: &TunnelConfig) -> Result<()> {
+    match selftest::run(config).await {
+        Ok(report) => {
+            println!(
+                "Self-test passed: {} TCP bytes and {} UDP datagrams echoed in {:.0?}",
+                report.tcp_bytes, report.udp_datagrams, report.elapsed
+            );
+            Ok(())
+        }
+        Err(e) => {
+            println!("Self-test FAILED: {e}");
+            Err(e.into())
+        }
+    }
+}
+
+/// Print every frame of a capture file, one per line
+fn replay(path: &Path) -> Result<()> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open capture file {}", path.display()))?;
+    let mut out = io::stdout().lock();
+    for record in Replay::new(BufReader::new(file))? {
+        writeln!(out, "{}", record?)?;
+    }
+    Ok(())
+}
+
+/// Resolve on the first SIGTERM or SIGINT
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => info!("SIGTERM received"),
+                    _ = tokio::signal::ctrl_c() => info!("SIGINT received"),
+                }
+                return;
+            }
+            Err(e) => warn!(error = %e, "Failed to install SIGTERM handler"),
+        }
+    }
+    if tokio::signal::ctrl_c().await.is_ok() {
+        info!("SIGINT received");
+    } else {
+        // No way to be told to stop; run until killed
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Set up the tracing subscriber.
+///
+/// Precedence, highest first: `RUST_LOG`, then `-q`/`-v`, then `--log-level`.
+/// The count flags are relative to the default `info`, so `-v` means debug
+/// even if `--log-level` (or `LOG_LEVEL`) says otherwise.
+fn init_logging<W>(log_level: &str, quiet: u8, verbose: u8, writer: W, ansi: bool)
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let level = match (quiet, verbose) {
+        (0, 0) => log_level,
+        (1, _) => "warn",
+        (_, 0) => "error",
+        (_, 1) => "debug",
+        _ => "trace",
+    };
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
 
     tracing_subscriber::fmt()
         .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi)
         .with_target(false)
         .with_thread_ids(false)
         .compact()
@@ -0,0 +1,759 @@
+//! Runtime metrics for the tunnel.
+//!
+//! Values are plain atomics so the hot paths can update them without locking.
+//! With the `metrics` feature (on by default), `Metrics::render` formats
+//! them in the Prometheus text exposition format.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "metrics")]
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::audit::ConnAudit;
+use crate::protocol::{MsgType, Proto};
+
+/// An RTT sample counts as a spike when it exceeds the smoothed RTT by this factor...
+const RTT_SPIKE_FACTOR: u64 = 3;
+/// ...and by at least this much in absolute terms, so jitter on a fast link
+/// doesn't register
+const RTT_SPIKE_MIN_EXCESS: Duration = Duration::from_millis(50);
+
+/// Finite buckets per histogram
+const BUCKETS: usize = 7;
+
+/// Upper bounds of the per-connection byte buckets: 1 KiB to 1 GiB
+const CONN_BYTES_BOUNDS: [u64; BUCKETS] = [
+    1 << 10,
+    16 << 10,
+    256 << 10,
+    1 << 20,
+    16 << 20,
+    256 << 20,
+    1 << 30,
+];
+
+/// Upper bounds of the per-connection duration buckets, in milliseconds:
+/// 10ms to 1h
+const CONN_DURATION_BOUNDS_MS: [u64; BUCKETS] =
+    [10, 100, 1_000, 10_000, 60_000, 600_000, 3_600_000];
+
+/// Distinct connection labels tracked; connections with further labels are
+/// counted under [`OTHER_LABEL`], so a misbehaving runner can't grow the
+/// metrics without bound
+const MAX_LABELS: usize = 256;
+
+const OTHER_LABEL: &str = "other";
+
+/// Counters and gauges shared across sessions
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Most recent round-trip time, in microseconds (0 = no sample yet)
+    rtt_last_us: AtomicU64,
+    /// Smoothed round-trip time (EWMA, 1/8 gain), in microseconds
+    rtt_smoothed_us: AtomicU64,
+    /// Number of RTT samples recorded
+    rtt_samples: AtomicU64,
+    /// Number of samples flagged as latency spikes
+    rtt_spikes: AtomicU64,
+    /// Local connects currently in progress
+    pending_connects: AtomicU64,
+    /// Highest number of local connects in progress at once
+    pending_connects_peak: AtomicU64,
+    /// Connection tasks (handlers and their read/write tasks) running
+    tasks: AtomicU64,
+    /// DATA frames dropped for exceeding the maximum UDP datagram size
+    udp_too_large: AtomicU64,
+    /// UDP sends that wrote fewer bytes than the datagram held
+    udp_short_sends: AtomicU64,
+    /// Datagrams from local UDP services dropped by the overflow policy
+    udp_dropped: AtomicU64,
+    /// Datagrams dropped, and TCP connections closed, because the total
+    /// buffer budget was exhausted
+    buffer_budget_shed: AtomicU64,
+    /// TCP DATA whose byte count disagreed with the bytes received
+    byte_count_mismatches: AtomicU64,
+    /// Frame mix for the periodic traffic summary
+    traffic: TrafficCounters,
+    /// Bytes carried per closed connection, both directions together
+    conn_bytes: Histogram,
+    /// Lifetime of closed connections, in milliseconds
+    conn_duration_ms: Histogram,
+    /// Sessions established with the runner
+    sessions: AtomicU64,
+    /// Forwarded connections opened, over all sessions
+    connections: AtomicU64,
+    /// Whether a session is up right now
+    connected: AtomicBool,
+    /// Key for the next entry in `open_conns`
+    next_conn_key: AtomicU64,
+    /// Open connections, by order of opening
+    open_conns: Mutex<BTreeMap<u64, (Arc<ConnAudit>, Instant)>>,
+    /// Connections and bytes of closed connections, per connection label
+    labels: Mutex<BTreeMap<Box<str>, LabelTotals>>,
+}
+
+/// Totals for one connection label
+#[derive(Debug, Default, Clone, Copy)]
+struct LabelTotals {
+    connections: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// Key `label` is counted under, given the labels tracked so far
+fn label_key<'a, V>(labels: &BTreeMap<Box<str>, V>, label: &'a str) -> &'a str {
+    if labels.contains_key(label) || labels.len() < MAX_LABELS {
+        label
+    } else {
+        OTHER_LABEL
+    }
+}
+
+/// An open connection as seen by [`Metrics::open_connections`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnSnapshot {
+    /// Unique for the client's lifetime, unlike the client ID
+    pub key: u64,
+    pub client_id: u32,
+    pub proto: Proto,
+    /// Port requested by the runner
+    pub port: u16,
+    /// Bytes written to the local service
+    pub bytes_in: u64,
+    /// Bytes read from the local service
+    pub bytes_out: u64,
+    pub age: Duration,
+}
+
+impl Metrics {
+    /// Record a round-trip time sample, returning true if it is a spike
+    pub fn record_rtt(&self, rtt: Duration) -> bool {
+        let sample = rtt.as_micros().min(u64::MAX as u128) as u64;
+        let smoothed = self.rtt_smoothed_us.load(Ordering::Relaxed);
+
+        let spike = smoothed > 0
+            && sample > smoothed.saturating_mul(RTT_SPIKE_FACTOR)
+            && sample - smoothed > RTT_SPIKE_MIN_EXCESS.as_micros() as u64;
+
+        let next = if smoothed == 0 {
+            sample
+        } else {
+            // srtt = 7/8 srtt + 1/8 sample, as in TCP (RFC 6298)
+            smoothed - smoothed / 8 + sample / 8
+        };
+        self.rtt_smoothed_us.store(next.max(1), Ordering::Relaxed);
+        self.rtt_last_us.store(sample.max(1), Ordering::Relaxed);
+        self.rtt_samples.fetch_add(1, Ordering::Relaxed);
+        if spike {
+            self.rtt_spikes.fetch_add(1, Ordering::Relaxed);
+        }
+        spike
+    }
+
+    /// Most recent round-trip time, if any has been measured
+    pub fn last_rtt(&self) -> Option<Duration> {
+        match self.rtt_last_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    /// Smoothed round-trip time, if any has been measured
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        match self.rtt_smoothed_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    /// Note that a local connect has started
+    pub fn connect_started(&self) {
+        let pending = self.pending_connects.fetch_add(1, Ordering::Relaxed) + 1;
+        self.pending_connects_peak
+            .fetch_max(pending, Ordering::Relaxed);
+    }
+
+    /// Note that a local connect has finished (successfully or not)
+    pub fn connect_finished(&self) {
+        self.pending_connects.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Local connects currently in progress
+    pub fn pending_connects(&self) -> u64 {
+        self.pending_connects.load(Ordering::Relaxed)
+    }
+
+    /// Highest number of local connects that were in progress at once
+    pub fn pending_connects_peak(&self) -> u64 {
+        self.pending_connects_peak.load(Ordering::Relaxed)
+    }
+
+    /// Note that a connection task was spawned
+    pub fn task_started(&self) {
+        self.tasks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note that a connection task has finished or was aborted
+    pub fn task_ended(&self) {
+        self.tasks.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Connection tasks currently running
+    pub fn tasks(&self) -> u64 {
+        self.tasks.load(Ordering::Relaxed)
+    }
+
+    pub fn udp_too_large(&self) {
+        self.udp_too_large.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn udp_too_large_count(&self) -> u64 {
+        self.udp_too_large.load(Ordering::Relaxed)
+    }
+
+    pub fn udp_short_send(&self) {
+        self.udp_short_sends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn udp_short_send_count(&self) -> u64 {
+        self.udp_short_sends.load(Ordering::Relaxed)
+    }
+
+    pub fn udp_dropped(&self) {
+        self.udp_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn udp_dropped_count(&self) -> u64 {
+        self.udp_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn buffer_budget_shed(&self) {
+        self.buffer_budget_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn buffer_budget_shed_count(&self) -> u64 {
+        self.buffer_budget_shed.load(Ordering::Relaxed)
+    }
+
+    pub fn byte_count_mismatch(&self) {
+        self.byte_count_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn byte_count_mismatch_count(&self) -> u64 {
+        self.byte_count_mismatches.load(Ordering::Relaxed)
+    }
+
+    /// Record a closed connection's total bytes and lifetime
+    pub fn record_conn_closed(&self, bytes: u64, duration: Duration) {
+        self.conn_bytes.record(&CONN_BYTES_BOUNDS, bytes);
+        let ms = duration.as_millis().min(u64::MAX as u128) as u64;
+        self.conn_duration_ms.record(&CONN_DURATION_BOUNDS_MS, ms);
+    }
+
+    /// Add a closed labeled connection's bytes to its label's totals
+    pub fn record_label_closed(&self, label: &str, bytes_in: u64, bytes_out: u64) {
+        let mut labels = self.labels.lock().unwrap();
+        let key = label_key(&labels, label);
+        let totals = labels.entry(key.into()).or_default();
+        totals.bytes_in += bytes_in;
+        totals.bytes_out += bytes_out;
+    }
+
+    /// Per-label totals including the bytes of open connections so far
+    #[cfg(feature = "metrics")]
+    fn label_totals(&self) -> BTreeMap<Box<str>, LabelTotals> {
+        let mut totals = self.labels.lock().unwrap().clone();
+        for (audit, _) in self.open_conns.lock().unwrap().values() {
+            let Some(label) = audit.label() else {
+                continue;
+            };
+            let key = label_key(&totals, label);
+            let entry = totals.entry(key.into()).or_default();
+            entry.bytes_in += audit.bytes_in();
+            entry.bytes_out += audit.bytes_out();
+        }
+        totals
+    }
+
+    /// Number of connections recorded by [`Metrics::record_conn_closed`]
+    pub fn closed_connections(&self) -> u64 {
+        self.conn_bytes.count.load(Ordering::Relaxed)
+    }
+
+    /// List a connection as open until [`Metrics::unregister_conn`] with the
+    /// returned key
+    pub(crate) fn register_conn(&self, audit: Arc<ConnAudit>, opened_at: Instant) -> u64 {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        if let Some(label) = audit.label() {
+            let mut labels = self.labels.lock().unwrap();
+            let key = label_key(&labels, label);
+            labels.entry(key.into()).or_default().connections += 1;
+        }
+        let key = self.next_conn_key.fetch_add(1, Ordering::Relaxed);
+        self.open_conns
+            .lock()
+            .unwrap()
+            .insert(key, (audit, opened_at));
+        key
+    }
+
+    pub(crate) fn unregister_conn(&self, key: u64) {
+        self.open_conns.lock().unwrap().remove(&key);
+    }
+
+    /// Open connections with their byte counts so far, oldest first
+    pub fn open_connections(&self) -> Vec<ConnSnapshot> {
+        let now = Instant::now();
+        self.open_conns
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&key, (audit, opened_at))| ConnSnapshot {
+                key,
+                client_id: audit.client_id(),
+                proto: audit.proto(),
+                port: audit.port(),
+                bytes_in: audit.bytes_in(),
+                bytes_out: audit.bytes_out(),
+                age: now - *opened_at,
+            })
+            .collect()
+    }
+
+    /// Bytes relayed by all connections, closed and open, both directions
+    pub fn relayed_bytes(&self) -> u64 {
+        let open: u64 = self
+            .open_conns
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(audit, _)| audit.bytes_in() + audit.bytes_out())
+            .sum();
+        self.conn_bytes.sum.load(Ordering::Relaxed) + open
+    }
+
+    /// Record that a session with the runner came up
+    pub fn session_started(&self) {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        self.connected.store(true, Ordering::Relaxed);
+    }
+
+    /// Record that the current session ended
+    pub fn session_ended(&self) {
+        self.connected.store(false, Ordering::Relaxed);
+    }
+
+    /// Sessions established so far; all but the first were reconnects
+    pub fn sessions(&self) -> u64 {
+        self.sessions.load(Ordering::Relaxed)
+    }
+
+    /// Sessions established after the first
+    pub fn reconnects(&self) -> u64 {
+        self.sessions().saturating_sub(1)
+    }
+
+    /// Forwarded connections opened so far, open or closed
+    pub fn opened_connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Counters behind the periodic traffic summary
+    pub fn traffic(&self) -> &TrafficCounters {
+        &self.traffic
+    }
+
+    /// Render all metrics in the Prometheus text format
+    #[cfg(feature = "metrics")]
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        let seconds = |v: &AtomicU64| load(v) as f64 / 1e6;
+
+        write_metric(
+            &mut out,
+            "tunnel_connected",
+            "gauge",
+            "Whether a session with the runner is up",
+            u8::from(self.is_connected()),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_sessions_total",
+            "counter",
+            "Sessions established with the runner",
+            load(&self.sessions),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_reconnects_total",
+            "counter",
+            "Sessions established after the first",
+            self.reconnects(),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_connections_total",
+            "counter",
+            "Forwarded connections opened",
+            load(&self.connections),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_relayed_bytes_total",
+            "counter",
+            "Bytes relayed by forwarded connections, both directions",
+            self.relayed_bytes(),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_open_connections",
+            "gauge",
+            "Forwarded connections currently open",
+            self.open_conns.lock().unwrap().len(),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_rtt_seconds",
+            "gauge",
+            "Most recent runner round-trip time",
+            seconds(&self.rtt_last_us),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_rtt_smoothed_seconds",
+            "gauge",
+            "Smoothed runner round-trip time",
+            seconds(&self.rtt_smoothed_us),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_rtt_samples_total",
+            "counter",
+            "Round-trip time samples recorded",
+            load(&self.rtt_samples),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_rtt_spikes_total",
+            "counter",
+            "Round-trip time samples flagged as spikes",
+            load(&self.rtt_spikes),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_pending_connects",
+            "gauge",
+            "Local connects currently in progress",
+            load(&self.pending_connects),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_pending_connects_peak",
+            "gauge",
+            "Highest number of local connects in progress at once",
+            load(&self.pending_connects_peak),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_tasks",
+            "gauge",
+            "Connection tasks (handlers and their read/write tasks) running",
+            load(&self.tasks),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_udp_too_large_total",
+            "counter",
+            "DATA frames dropped for exceeding the maximum UDP datagram size",
+            load(&self.udp_too_large),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_udp_short_sends_total",
+            "counter",
+            "UDP sends that wrote fewer bytes than the datagram held",
+            load(&self.udp_short_sends),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_udp_dropped_total",
+            "counter",
+            "Datagrams from local UDP services dropped by the overflow policy",
+            load(&self.udp_dropped),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_buffer_budget_shed_total",
+            "counter",
+            "Datagrams dropped and TCP connections closed because the total buffer budget was exhausted",
+            load(&self.buffer_budget_shed),
+        );
+        write_metric(
+            &mut out,
+            "tunnel_byte_count_mismatches_total",
+            "counter",
+            "TCP DATA whose byte count disagreed with the bytes received",
+            load(&self.byte_count_mismatches),
+        );
+        let labels = self.label_totals();
+        if !labels.is_empty() {
+            type Field = fn(&LabelTotals) -> u64;
+            let families: [(&str, &str, Field); 3] = [
+                (
+                    "tunnel_labeled_connections_total",
+                    "Forwarded connections opened, per runner-supplied label",
+                    |t| t.connections,
+                ),
+                (
+                    "tunnel_labeled_bytes_in_total",
+                    "Bytes written to local services, per connection label",
+                    |t| t.bytes_in,
+                ),
+                (
+                    "tunnel_labeled_bytes_out_total",
+                    "Bytes read from local services, per connection label",
+                    |t| t.bytes_out,
+                ),
+            ];
+            for (name, help, field) in families {
+                let _ = writeln!(out, "# HELP {} {}", name, help);
+                let _ = writeln!(out, "# TYPE {} counter", name);
+                for (label, totals) in &labels {
+                    let _ = writeln!(out, "{}{{label=\"{}\"}} {}", name, label, field(totals));
+                }
+            }
+        }
+        self.conn_bytes.write(
+            &mut out,
+            "tunnel_conn_bytes",
+            "Bytes carried per closed connection, both directions",
+            &CONN_BYTES_BOUNDS,
+            1.0,
+        );
+        self.conn_duration_ms.write(
+            &mut out,
+            "tunnel_conn_duration_seconds",
+            "Lifetime of closed connections",
+            &CONN_DURATION_BOUNDS_MS,
+            1e-3,
+        );
+        out
+    }
+}
+
+/// Bucketed distribution with fixed upper bounds, kept non-cumulative and
+/// summed up when rendered
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket; those above every bound only count
+    /// towards `count`
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn record(&self, bounds: &[u64; BUCKETS], value: u64) {
+        if let Some(i) = bounds.iter().position(|&bound| value <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Append the histogram in Prometheus form, multiplying bounds and sum by
+    /// `scale` to convert to the metric's unit
+    #[cfg(feature = "metrics")]
+    fn write(&self, out: &mut String, name: &str, help: &str, bounds: &[u64; BUCKETS], scale: f64) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                *bound as f64 * scale,
+                cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 * scale;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Per-type counter slots, indexed by the message type byte
+const MSG_TYPE_SLOTS: usize = 16;
+
+/// Frames and bytes seen since the last traffic summary
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    /// Frames received from the runner, by message type
+    by_type: [AtomicU64; MSG_TYPE_SLOTS],
+    /// Frames received from the runner, by protocol
+    by_proto: [AtomicU64; 3],
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl TrafficCounters {
+    /// Count a frame received from the runner
+    pub fn record_frame(&self, msg_type: MsgType, proto: Proto) {
+        if let Some(slot) = self.by_type.get(msg_type as usize) {
+            slot.fetch_add(1, Ordering::Relaxed);
+        }
+        self.by_proto[proto as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count bytes received from the runner
+    pub fn record_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count bytes sent to the runner
+    pub fn record_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts since the previous call, resetting them
+    pub fn take(&self) -> TrafficSummary {
+        let take = |v: &AtomicU64| v.swap(0, Ordering::Relaxed);
+        let frames = (0..MSG_TYPE_SLOTS)
+            .filter_map(|i| {
+                let count = take(&self.by_type[i]);
+                let msg_type = MsgType::try_from(i as u8).ok()?;
+                (count > 0).then_some((msg_type, count))
+            })
+            .collect();
+        TrafficSummary {
+            frames,
+            tcp_frames: take(&self.by_proto[Proto::Tcp as usize]),
+            udp_frames: take(&self.by_proto[Proto::Udp as usize]),
+            bytes_in: take(&self.bytes_in),
+            bytes_out: take(&self.bytes_out),
+        }
+    }
+}
+
+/// Traffic over one summary interval
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficSummary {
+    /// Received frames per message type, omitting types not seen
+    pub frames: Vec<(MsgType, u64)>,
+    pub tcp_frames: u64,
+    pub udp_frames: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl TrafficSummary {
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty() && self.bytes_in == 0 && self.bytes_out == 0
+    }
+
+    /// Frame counts per type, e.g. `data=120 ping=2`
+    pub fn frame_mix(&self) -> String {
+        self.frames
+            .iter()
+            .map(|(msg_type, count)| {
+                format!("{}={}", format!("{:?}", msg_type).to_lowercase(), count)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Append one metric with its HELP and TYPE lines
+#[cfg(feature = "metrics")]
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_spike_detection() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.last_rtt(), None);
+
+        // The first sample seeds the average and is never a spike
+        assert!(!metrics.record_rtt(Duration::from_millis(20)));
+        for _ in 0..10 {
+            assert!(!metrics.record_rtt(Duration::from_millis(20)));
+        }
+        assert_eq!(metrics.smoothed_rtt(), Some(Duration::from_millis(20)));
+
+        // 3x but under the absolute floor: jitter, not a spike
+        assert!(!metrics.record_rtt(Duration::from_millis(65)));
+        assert!(metrics.record_rtt(Duration::from_millis(400)));
+        assert_eq!(metrics.last_rtt(), Some(Duration::from_millis(400)));
+
+        #[cfg(feature = "metrics")]
+        {
+            let text = metrics.render();
+            assert!(text.contains("tunnel_rtt_seconds 0.4\n"));
+            assert!(text.contains("tunnel_rtt_samples_total 13\n"));
+            assert!(text.contains("tunnel_rtt_spikes_total 1\n"));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_conn_histograms() {
+        let metrics = Metrics::default();
+        metrics.record_conn_closed(100, Duration::from_millis(5));
+        metrics.record_conn_closed(2 << 20, Duration::from_secs(30));
+        metrics.record_conn_closed(4 << 30, Duration::from_secs(7200));
+        assert_eq!(metrics.closed_connections(), 3);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE tunnel_conn_bytes histogram\n"));
+        assert!(text.contains("tunnel_conn_bytes_bucket{le=\"1024\"} 1\n"));
+        assert!(text.contains("tunnel_conn_bytes_bucket{le=\"1048576\"} 1\n"));
+        assert!(text.contains("tunnel_conn_bytes_bucket{le=\"16777216\"} 2\n"));
+        assert!(text.contains("tunnel_conn_bytes_bucket{le=\"1073741824\"} 2\n"));
+        assert!(text.contains("tunnel_conn_bytes_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("tunnel_conn_bytes_count 3\n"));
+
+        assert!(text.contains("tunnel_conn_duration_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(text.contains("tunnel_conn_duration_seconds_bucket{le=\"60\"} 2\n"));
+        assert!(text.contains("tunnel_conn_duration_seconds_bucket{le=\"3600\"} 2\n"));
+        assert!(text.contains("tunnel_conn_duration_seconds_sum 7230.005\n"));
+    }
+
+    #[test]
+    fn test_traffic_summary_resets() {
+        let traffic = TrafficCounters::default();
+        assert!(traffic.take().is_empty());
+
+        for _ in 0..3 {
+            traffic.record_frame(MsgType::Data, Proto::Tcp);
+        }
+        traffic.record_frame(MsgType::Connect, Proto::Udp);
+        traffic.record_in(100);
+        traffic.record_out(40);
+
+        let summary = traffic.take();
+        assert_eq!(summary.frame_mix(), "connect=1 data=3");
+        assert_eq!((summary.tcp_frames, summary.udp_frames), (3, 1));
+        assert_eq!((summary.bytes_in, summary.bytes_out), (100, 40));
+        assert!(traffic.take().is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+//! Several containers multiplexed over one tunnel connection.
+//!
+//! A sidecar serving more than one container can carry all of them on a
+//! single connection with `--mux-container ID=HOST`. The URL's own container
+//! is index 0 and keeps the plain 8-byte header; the N-th `--mux-container`
+//! is index N. Its frames set [`MUX_FLAG`] in the protocol byte and carry the
+//! index between the header and the payload:
+//!
+//! ```text
+//! ┌──────────────────────────┬──────────────────────┬─────────────────┐
+//! │ Header (8B, proto | 0x80)│ Container index (2B) │ Payload (var)   │
+//! └──────────────────────────┴──────────────────────┴─────────────────┘
+//! ```
+//!
+//! The runner learns the index assignment from the `containers` query
+//! parameter of the tunnel URL. Each container has its own
+//! [`ConnectionManager`](crate::connection::ConnectionManager), so
+//! connections are routed by (container, client ID, protocol) and client IDs
+//! may repeat across containers.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::future::BoxFuture;
+
+use crate::error::Result;
+use crate::protocol::{ProtocolError, HEADER_SIZE};
+use crate::transport::{TransportSender, TransportSink};
+
+/// Protocol byte flag marking a frame that carries a container index
+pub const MUX_FLAG: u8 = 0x80;
+
+/// Size of the container index following a flagged header
+pub const CONTAINER_INDEX_SIZE: usize = 2;
+
+/// Split a frame from the runner into its container index and the plain
+/// frame. Frames without [`MUX_FLAG`] belong to container 0.
+pub fn split(frame: &Bytes) -> Result<(u16, Bytes), ProtocolError> {
+    if frame.len() < HEADER_SIZE || frame[1] & MUX_FLAG == 0 {
+        return Ok((0, frame.clone()));
+    }
+    if frame.len() < HEADER_SIZE + CONTAINER_INDEX_SIZE {
+        return Err(ProtocolError::MessageTooShort(frame.len()));
+    }
+    let index = u16::from_be_bytes([frame[HEADER_SIZE], frame[HEADER_SIZE + 1]]);
+    let mut plain = BytesMut::with_capacity(frame.len() - CONTAINER_INDEX_SIZE);
+    plain.extend_from_slice(&frame[..HEADER_SIZE]);
+    plain[1] &= !MUX_FLAG;
+    plain.extend_from_slice(&frame[HEADER_SIZE + CONTAINER_INDEX_SIZE..]);
+    Ok((index, plain.freeze()))
+}
+
+/// Add the container index to a plain frame; container 0 is left as is
+pub fn tag(container: u16, frame: &[u8]) -> Bytes {
+    if container == 0 || frame.len() < HEADER_SIZE {
+        return Bytes::copy_from_slice(frame);
+    }
+    let mut tagged = BytesMut::with_capacity(frame.len() + CONTAINER_INDEX_SIZE);
+    tagged.extend_from_slice(&frame[..HEADER_SIZE]);
+    tagged[1] |= MUX_FLAG;
+    tagged.put_u16(container);
+    tagged.extend_from_slice(&frame[HEADER_SIZE..]);
+    tagged.freeze()
+}
+
+/// Sink tagging one container's frames on the way into the shared transport
+pub struct MuxSink {
+    inner: TransportSender,
+    container: u16,
+}
+
+impl MuxSink {
+    pub fn new(inner: TransportSender, container: u16) -> Self {
+        Self { inner, container }
+    }
+}
+
+impl TransportSink for MuxSink {
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        let frame = tag(self.container, &frame);
+        Box::pin(async move { self.inner.lock().await.send(frame).await })
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.inner.lock().await.ping(payload).await })
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.inner.lock().await.pong(payload).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{self, Frame, MsgType, Proto};
+
+    #[test]
+    fn test_tag_split_roundtrip() {
+        let frame = protocol::build_data(Proto::Udp, 42, b"hello");
+        let tagged = tag(3, &frame);
+        assert_eq!(tagged.len(), frame.len() + CONTAINER_INDEX_SIZE);
+        assert_eq!(tagged[1], Proto::Udp as u8 | MUX_FLAG);
+
+        let (index, plain) = split(&tagged).unwrap();
+        assert_eq!(index, 3);
+        assert_eq!(plain, frame);
+        let decoded = Frame::decode(&plain).unwrap();
+        assert_eq!(decoded.header.msg_type, MsgType::Data);
+        assert_eq!(decoded.payload, b"hello");
+
+        // Container 0 travels untagged
+        assert_eq!(tag(0, &frame), frame);
+        assert_eq!(split(&frame).unwrap(), (0, frame.clone()));
+    }
+
+    #[test]
+    fn test_split_rejects_truncated_index() {
+        let mut frame = protocol::build_close(Proto::Tcp, 1).to_vec();
+        frame[1] |= MUX_FLAG;
+        frame.push(0);
+        assert!(matches!(
+            split(&Bytes::from(frame)),
+            Err(ProtocolError::MessageTooShort(9))
+        ));
+    }
+}
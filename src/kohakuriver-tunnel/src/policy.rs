@@ -0,0 +1,446 @@
+//! Reloadable access rules for new connections.
+//!
+//! The rules start from the command-line values and can be overridden by a
+//! `--config` file of `key = value` lines:
+//!
+//! ```text
+//! # Only forward SSH and the dev server range
+//! allow_proto = tcp
+//! allow_ports = 22,8000-8100
+//! ```
+//!
+//! On SIGHUP the file is re-read and the rules are swapped in one step. Only
+//! new CONNECTs consult the rules, so established connections are unaffected.
+//! Keys removed from the file fall back to their command-line values.
+//!
+//! Embedders can add dynamic checks on top of the static rules by
+//! implementing [`ConnectPolicy`].
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use tracing::info;
+
+use crate::config::PortSet;
+use crate::error::{Result, TunnelError};
+use crate::protocol::Proto;
+use crate::tunnel::TunnelConfig;
+
+/// Rules a CONNECT has to pass before a connection is opened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRules {
+    /// Protocols the runner is allowed to open connections for
+    pub allowed_protos: Vec<Proto>,
+    /// Requested ports the runner may connect to (None = any port)
+    pub allowed_ports: Option<PortSet>,
+}
+
+impl AccessRules {
+    pub fn from_config(config: &TunnelConfig) -> Self {
+        Self {
+            allowed_protos: config.allowed_protos.clone(),
+            allowed_ports: config.allowed_ports.clone(),
+        }
+    }
+
+    /// Check a CONNECT, returning the reason to report if it is refused
+    pub fn check(&self, proto: Proto, port: u16) -> std::result::Result<(), String> {
+        if !self.allowed_protos.contains(&proto) {
+            return Err(format!("Protocol {} is not allowed", proto));
+        }
+        // The port of a raw CONNECT is an IP protocol number
+        if let Some(ports) = self.allowed_ports.as_ref().filter(|_| proto != Proto::Raw) {
+            if !ports.contains(port) {
+                return Err(format!("Port {} is not allowed", port));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if the rules, however they were merged from the command line,
+    /// environment and config file, would refuse every CONNECT
+    pub fn check_conflicts(&self, udp_enabled: bool) -> std::result::Result<(), String> {
+        if self.allowed_protos.is_empty() {
+            return Err(
+                "allow_proto is empty, so every CONNECT would be refused; list at least one of tcp, udp, raw"
+                    .into(),
+            );
+        }
+        if !udp_enabled && self.allowed_protos.iter().all(|&p| p == Proto::Udp) {
+            return Err(
+                "allow_proto only allows udp, but UDP forwarding is disabled (--no-udp / NO_UDP, or a build without the udp feature); allow tcp as well or enable UDP"
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Human-readable list of settings that differ from `other`
+    fn changes_from(&self, other: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.allowed_protos != other.allowed_protos {
+            changes.push(format!(
+                "allow_proto: {} -> {}",
+                ProtoList(&other.allowed_protos),
+                ProtoList(&self.allowed_protos)
+            ));
+        }
+        if self.allowed_ports != other.allowed_ports {
+            changes.push(format!(
+                "allow_ports: {} -> {}",
+                PortsDisplay(&other.allowed_ports),
+                PortsDisplay(&self.allowed_ports)
+            ));
+        }
+        changes
+    }
+}
+
+/// Comma-separated protocols, or `none`
+pub(crate) struct ProtoList<'a>(pub(crate) &'a [Proto]);
+
+impl fmt::Display for ProtoList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("none");
+        }
+        for (i, proto) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", proto)?;
+        }
+        Ok(())
+    }
+}
+
+/// A port allowlist, or `any`
+pub(crate) struct PortsDisplay<'a>(pub(crate) &'a Option<PortSet>);
+
+impl fmt::Display for PortsDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(ports) => write!(f, "{}", ports),
+            None => f.write_str("any"),
+        }
+    }
+}
+
+/// Outcome of a [`ConnectPolicy`] check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    Allow,
+    /// Refuse the CONNECT; the reason is sent to the runner in the ERROR
+    Deny(String),
+}
+
+/// Custom authorization for CONNECTs, e.g. time-of-day or per-port rules.
+///
+/// Consulted after the static allowlists pass, with the port the runner
+/// requested (before port mapping). Called on the message loop, so it should
+/// return quickly.
+pub trait ConnectPolicy: Send + Sync {
+    fn allow(&self, client_id: u32, proto: Proto, port: u16) -> PolicyDecision;
+}
+
+/// Default policy: the static allowlists are the only check
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl ConnectPolicy for AllowAll {
+    fn allow(&self, _client_id: u32, _proto: Proto, _port: u16) -> PolicyDecision {
+        PolicyDecision::Allow
+    }
+}
+
+impl<F> ConnectPolicy for F
+where
+    F: Fn(u32, Proto, u16) -> PolicyDecision + Send + Sync,
+{
+    fn allow(&self, client_id: u32, proto: Proto, port: u16) -> PolicyDecision {
+        self(client_id, proto, port)
+    }
+}
+
+/// Settings read from a `--config` file; absent keys keep the base value
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyFile {
+    pub allow_proto: Option<Vec<Proto>>,
+    pub allow_ports: Option<PortSet>,
+}
+
+impl PolicyFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| TunnelError::Config(format!("{}: {}", path.display(), e)))?;
+        Self::parse(&contents)
+            .map_err(|e| TunnelError::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parse `key = value` lines; `#` starts a comment, values may be quoted
+    pub fn parse(contents: &str) -> std::result::Result<Self, String> {
+        let mut file = Self::default();
+
+        for (lineno, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let lineno = lineno + 1;
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected key = value", lineno))?;
+            let value = value.trim().trim_matches('"');
+
+            match key.trim() {
+                "allow_proto" => {
+                    let protos = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|p| !p.is_empty())
+                        .map(|p| p.parse::<Proto>().map_err(|e| e.to_string()))
+                        .collect::<std::result::Result<Vec<_>, _>>()
+                        .map_err(|e| format!("line {}: {}", lineno, e))?;
+                    file.allow_proto = Some(protos);
+                }
+                "allow_ports" => {
+                    let ports = value
+                        .parse()
+                        .map_err(|e| format!("line {}: {}", lineno, e))?;
+                    file.allow_ports = Some(ports);
+                }
+                other => return Err(format!("line {}: unknown key {:?}", lineno, other)),
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Overlay the file's settings on `base`
+    pub fn apply(&self, base: &AccessRules) -> AccessRules {
+        AccessRules {
+            allowed_protos: self
+                .allow_proto
+                .clone()
+                .unwrap_or_else(|| base.allowed_protos.clone()),
+            allowed_ports: self
+                .allow_ports
+                .clone()
+                .or_else(|| base.allowed_ports.clone()),
+        }
+    }
+}
+
+/// Shared, swappable access rules
+///
+/// Clones share the same rules; [`reload`](Self::reload) replaces them for
+/// every clone at once.
+#[derive(Debug, Clone)]
+pub struct PolicyHandle {
+    base: Arc<AccessRules>,
+    path: Option<PathBuf>,
+    /// Whether UDP forwarding is available, for [`AccessRules::check_conflicts`]
+    udp_enabled: bool,
+    current: Arc<RwLock<Arc<AccessRules>>>,
+}
+
+impl PolicyHandle {
+    pub fn new(base: AccessRules, path: Option<PathBuf>) -> Self {
+        let base = Arc::new(base);
+        Self {
+            current: Arc::new(RwLock::new(base.clone())),
+            base,
+            path,
+            udp_enabled: true,
+        }
+    }
+
+    pub fn from_config(config: &TunnelConfig) -> Self {
+        Self {
+            udp_enabled: config.udp_enabled(),
+            ..Self::new(AccessRules::from_config(config), config.config_file.clone())
+        }
+    }
+
+    /// Snapshot of the rules in effect right now
+    pub fn current(&self) -> Arc<AccessRules> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Re-read the config file and swap in the resulting rules.
+    ///
+    /// On error, including rules that conflict with the rest of the
+    /// configuration, the previous rules stay in effect. Returns the list of
+    /// settings that changed.
+    pub fn reload(&self) -> Result<Vec<String>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let rules = Arc::new(PolicyFile::load(path)?.apply(&self.base));
+        rules
+            .check_conflicts(self.udp_enabled)
+            .map_err(|e| TunnelError::Config(format!("{}: {}", path.display(), e)))?;
+
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        let changes = rules.changes_from(&current);
+        *current = rules;
+        drop(current);
+
+        if changes.is_empty() {
+            info!(path = %path.display(), "Config reloaded, no changes");
+        }
+        for change in &changes {
+            info!(path = %path.display(), "Config changed: {}", change);
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> AccessRules {
+        AccessRules {
+            allowed_protos: vec![Proto::Tcp, Proto::Udp],
+            allowed_ports: None,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let rules = AccessRules {
+            allowed_protos: vec![Proto::Tcp],
+            allowed_ports: Some("22,8000-8100".parse().unwrap()),
+        };
+        assert!(rules.check(Proto::Tcp, 22).is_ok());
+        assert!(rules.check(Proto::Tcp, 8080).is_ok());
+        assert_eq!(
+            rules.check(Proto::Tcp, 443),
+            Err("Port 443 is not allowed".to_string())
+        );
+        assert_eq!(
+            rules.check(Proto::Udp, 22),
+            Err("Protocol UDP is not allowed".to_string())
+        );
+        assert!(base().check(Proto::Udp, 53).is_ok());
+
+        // The port allowlist doesn't apply to raw IP protocol numbers
+        let raw = AccessRules {
+            allowed_protos: vec![Proto::Raw],
+            ..rules
+        };
+        assert!(raw.check(Proto::Raw, 132).is_ok());
+    }
+
+    #[test]
+    fn test_parse_policy_file() {
+        let file = PolicyFile::parse(
+            "# comment\n\nallow_proto = \"tcp\"\nallow_ports = 22, 8000-8100 # dev\n",
+        )
+        .unwrap();
+        assert_eq!(file.allow_proto, Some(vec![Proto::Tcp]));
+        assert!(file.allow_ports.as_ref().unwrap().contains(8050));
+
+        assert_eq!(PolicyFile::parse("").unwrap(), PolicyFile::default());
+        assert!(PolicyFile::parse("allow_proto").is_err());
+        assert!(PolicyFile::parse("allow_proto = sctp").is_err());
+        assert!(PolicyFile::parse("allow_ports = 99999").is_err());
+        assert!(PolicyFile::parse("max_conns = 5").is_err());
+    }
+
+    #[test]
+    fn test_apply_keeps_base_for_absent_keys() {
+        let file = PolicyFile::parse("allow_ports = 22").unwrap();
+        let rules = file.apply(&base());
+        assert_eq!(rules.allowed_protos, vec![Proto::Tcp, Proto::Udp]);
+        assert_eq!(rules.allowed_ports, Some("22".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_reload_swaps_rules_and_reports_changes() {
+        let path = std::env::temp_dir().join(format!("tunnel-policy-{}.conf", std::process::id()));
+        std::fs::write(&path, "allow_ports = 22\n").unwrap();
+
+        let handle = PolicyHandle::new(base(), Some(path.clone()));
+        let shared = handle.clone();
+        assert!(shared.current().check(Proto::Tcp, 80).is_ok());
+
+        let changes = handle.reload().unwrap();
+        assert_eq!(changes, vec!["allow_ports: any -> 22".to_string()]);
+        assert!(shared.current().check(Proto::Tcp, 80).is_err());
+
+        // A broken file leaves the previous rules in place
+        std::fs::write(&path, "allow_ports = nope\n").unwrap();
+        assert!(matches!(handle.reload(), Err(TunnelError::Config(_))));
+        assert!(shared.current().check(Proto::Tcp, 22).is_ok());
+        assert!(shared.current().check(Proto::Tcp, 80).is_err());
+
+        // Removing the key reverts to the base value
+        std::fs::write(&path, "allow_proto = tcp\n").unwrap();
+        let changes = handle.reload().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(shared.current().check(Proto::Tcp, 80).is_ok());
+        assert!(shared.current().check(Proto::Udp, 80).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_check_conflicts() {
+        assert!(base().check_conflicts(true).is_ok());
+        // UDP is merely refused while TCP is still allowed
+        assert!(base().check_conflicts(false).is_ok());
+
+        let udp_only = AccessRules {
+            allowed_protos: vec![Proto::Udp],
+            allowed_ports: None,
+        };
+        assert!(udp_only.check_conflicts(true).is_ok());
+        assert!(udp_only
+            .check_conflicts(false)
+            .unwrap_err()
+            .contains("only allows udp"));
+
+        let nothing = AccessRules {
+            allowed_protos: Vec::new(),
+            allowed_ports: None,
+        };
+        assert!(nothing.check_conflicts(true).is_err());
+    }
+
+    #[test]
+    fn test_reload_rejects_conflicting_rules() {
+        let path = std::env::temp_dir().join(format!(
+            "tunnel-policy-conflict-{}.conf",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "allow_proto = tcp
+",
+        )
+        .unwrap();
+        let config = TunnelConfig {
+            disable_udp: true,
+            config_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let handle = PolicyHandle::from_config(&config);
+        handle.reload().unwrap();
+
+        // Neither leaves anything to forward; the previous rules stay
+        for contents in ["allow_proto = udp\n", "allow_proto =\n"] {
+            std::fs::write(&path, contents).unwrap();
+            let err = handle.reload().unwrap_err().to_string();
+            assert!(err.contains(&path.display().to_string()), "{}", err);
+            assert_eq!(handle.current().allowed_protos, vec![Proto::Tcp]);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,245 +1,1303 @@
-//! Tunnel protocol definitions and utilities.
-//!
-//! Wire format (binary, big-endian):
-//! ```text
-//! ┌──────────┬──────────┬──────────┬──────────┬─────────────────────┐
-//! │ Type (1B)│ Proto(1B)│ClientID  │ Port (2B)│  Payload (var)      │
-//! │          │          │  (4B)    │          │                     │
-//! └──────────┴──────────┴──────────┴──────────┴─────────────────────┘
-//! ```
-//! Total header: 8 bytes
-
-use bytes::{BufMut, Bytes, BytesMut};
-use thiserror::Error;
-
-/// Header size in bytes
-pub const HEADER_SIZE: usize = 8;
-
-// =============================================================================
-// Message Types
-// =============================================================================
-
-/// Message type constants
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum MsgType {
-    /// Server → Client: open connection to port
-    Connect = 0x01,
-    /// Client → Server: connection established
-    Connected = 0x02,
-    /// Bidirectional: relay data
-    Data = 0x03,
-    /// Bidirectional: close connection
-    Close = 0x04,
-    /// Client → Server: connection failed
-    Error = 0x05,
-    /// Keepalive ping
-    Ping = 0x06,
-    /// Keepalive pong
-    Pong = 0x07,
-}
-
-impl TryFrom<u8> for MsgType {
-    type Error = ProtocolError;
-
-    fn try_from(value: u8) -> Result<Self, <Self as TryFrom<u8>>::Error> {
-        match value {
-            0x01 => Ok(MsgType::Connect),
-            0x02 => Ok(MsgType::Connected),
-            0x03 => Ok(MsgType::Data),
-            0x04 => Ok(MsgType::Close),
-            0x05 => Ok(MsgType::Error),
-            0x06 => Ok(MsgType::Ping),
-            0x07 => Ok(MsgType::Pong),
-            _ => Err(ProtocolError::InvalidMsgType(value)),
-        }
-    }
-}
-
-// =============================================================================
-// Protocol Types
-// =============================================================================
-
-/// Protocol type (TCP or UDP)
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum Proto {
-    Tcp = 0x00,
-    Udp = 0x01,
-}
-
-impl TryFrom<u8> for Proto {
-    type Error = ProtocolError;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0x00 => Ok(Proto::Tcp),
-            0x01 => Ok(Proto::Udp),
-            _ => Err(ProtocolError::InvalidProto(value)),
-        }
-    }
-}
-
-impl std::fmt::Display for Proto {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Proto::Tcp => write!(f, "TCP"),
-            Proto::Udp => write!(f, "UDP"),
-        }
-    }
-}
-
-// =============================================================================
-// Protocol Errors
-// =============================================================================
-
-#[derive(Error, Debug)]
-pub enum ProtocolError {
-    #[error("Invalid message type: {0}")]
-    InvalidMsgType(u8),
-
-    #[error("Invalid protocol type: {0}")]
-    InvalidProto(u8),
-
-    #[error("Message too short: got {0} bytes, need at least {HEADER_SIZE}")]
-    MessageTooShort(usize),
-}
-
-// =============================================================================
-// Message Header
-// =============================================================================
-
-/// Parsed tunnel message header
-#[derive(Debug, Clone)]
-pub struct Header {
-    pub msg_type: MsgType,
-    pub proto: Proto,
-    pub client_id: u32,
-    pub port: u16,
-}
-
-impl Header {
-    /// Parse header from bytes
-    pub fn parse(data: &[u8]) -> Result<Self, ProtocolError> {
-        if data.len() < HEADER_SIZE {
-            return Err(ProtocolError::MessageTooShort(data.len()));
-        }
-
-        let msg_type = MsgType::try_from(data[0])?;
-        let proto = Proto::try_from(data[1])?;
-        let client_id = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
-        let port = u16::from_be_bytes([data[6], data[7]]);
-
-        Ok(Header {
-            msg_type,
-            proto,
-            client_id,
-            port,
-        })
-    }
-
-    /// Write header to buffer
-    pub fn write_to(&self, buf: &mut BytesMut) {
-        buf.put_u8(self.msg_type as u8);
-        buf.put_u8(self.proto as u8);
-        buf.put_u32(self.client_id);
-        buf.put_u16(self.port);
-    }
-}
-
-// =============================================================================
-// Message Building
-// =============================================================================
-
-/// Build a complete tunnel message
-pub fn build_message(
-    msg_type: MsgType,
-    proto: Proto,
-    client_id: u32,
-    port: u16,
-    payload: &[u8],
-) -> Bytes {
-    let mut buf = BytesMut::with_capacity(HEADER_SIZE + payload.len());
-
-    let header = Header {
-        msg_type,
-        proto,
-        client_id,
-        port,
-    };
-    header.write_to(&mut buf);
-    buf.put_slice(payload);
-
-    buf.freeze()
-}
-
-/// Build a CONNECTED message
-pub fn build_connected(proto: Proto, client_id: u32) -> Bytes {
-    build_message(MsgType::Connected, proto, client_id, 0, &[])
-}
-
-/// Build a DATA message
-pub fn build_data(proto: Proto, client_id: u32, data: &[u8]) -> Bytes {
-    build_message(MsgType::Data, proto, client_id, 0, data)
-}
-
-/// Build a CLOSE message
-pub fn build_close(proto: Proto, client_id: u32) -> Bytes {
-    build_message(MsgType::Close, proto, client_id, 0, &[])
-}
-
-/// Build an ERROR message
-pub fn build_error(proto: Proto, client_id: u32, error_msg: &str) -> Bytes {
-    build_message(MsgType::Error, proto, client_id, 0, error_msg.as_bytes())
-}
-
-/// Build a PONG message (response to PING)
-pub fn build_pong(client_id: u32) -> Bytes {
-    build_message(MsgType::Pong, Proto::Tcp, client_id, 0, &[])
-}
-
-/// Extract payload from a message (everything after header)
-pub fn get_payload(data: &[u8]) -> &[u8] {
-    if data.len() > HEADER_SIZE {
-        &data[HEADER_SIZE..]
-    } else {
-        &[]
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_header_roundtrip() {
-        let original = Header {
-            msg_type: MsgType::Connect,
-            proto: Proto::Tcp,
-            client_id: 12345,
-            port: 8080,
-        };
-
-        let mut buf = BytesMut::new();
-        original.write_to(&mut buf);
-
-        let parsed = Header::parse(&buf).unwrap();
-        assert_eq!(parsed.msg_type, original.msg_type);
-        assert_eq!(parsed.proto, original.proto);
-        assert_eq!(parsed.client_id, original.client_id);
-        assert_eq!(parsed.port, original.port);
-    }
-
-    #[test]
-    fn test_build_message() {
-        let msg = build_data(Proto::Tcp, 42, b"hello");
-        assert_eq!(msg.len(), HEADER_SIZE + 5);
-
-        let header = Header::parse(&msg).unwrap();
-        assert_eq!(header.msg_type, MsgType::Data);
-        assert_eq!(header.client_id, 42);
-
-        let payload = get_payload(&msg);
-        assert_eq!(payload, b"hello");
-    }
-}
+//! Tunnel protocol definitions and utilities.
+//!
+//! Wire format (binary, big-endian):
+//! ```text
+//! ┌──────────┬──────────┬──────────┬──────────┬─────────────────────┐
+//! │ Type (1B)│ Proto(1B)│ClientID  │ Port (2B)│  Payload (var)      │
+//! │          │          │  (4B)    │          │                     │
+//! └──────────┴──────────┴──────────┴──────────┴─────────────────────┘
+//! ```
+//! Total header: 8 bytes
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+/// Header size in bytes
+pub const HEADER_SIZE: usize = 8;
+
+/// Wire protocol version announced in VERSION messages
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// =============================================================================
+// Message Types
+// =============================================================================
+
+/// Message type constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MsgType {
+    /// Server → Client: open connection to port
+    Connect = 0x01,
+    /// Client → Server: connection established
+    Connected = 0x02,
+    /// Bidirectional: relay data
+    Data = 0x03,
+    /// Bidirectional: close connection
+    Close = 0x04,
+    /// Client → Server: connection failed
+    Error = 0x05,
+    /// Keepalive ping
+    Ping = 0x06,
+    /// Keepalive pong
+    Pong = 0x07,
+    /// Bidirectional: version and capability announcement
+    Version = 0x08,
+    /// Bidirectional: cumulative bytes received on a resumable connection
+    Ack = 0x09,
+    /// Server → Client: check that a port accepts connections, without relaying
+    Probe = 0x0A,
+    /// Bidirectional: challenge-response authentication, before VERSION
+    Auth = 0x0B,
+    /// Bidirectional: DATA encoded against the previous payload (see [`crate::delta`])
+    Delta = 0x0C,
+    /// Bidirectional: several UDP datagrams of one connection (see [`build_batch`])
+    Batch = 0x0D,
+}
+
+impl TryFrom<u8> for MsgType {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, <Self as TryFrom<u8>>::Error> {
+        match value {
+            0x01 => Ok(MsgType::Connect),
+            0x02 => Ok(MsgType::Connected),
+            0x03 => Ok(MsgType::Data),
+            0x04 => Ok(MsgType::Close),
+            0x05 => Ok(MsgType::Error),
+            0x06 => Ok(MsgType::Ping),
+            0x07 => Ok(MsgType::Pong),
+            0x08 => Ok(MsgType::Version),
+            0x09 => Ok(MsgType::Ack),
+            0x0A => Ok(MsgType::Probe),
+            0x0B => Ok(MsgType::Auth),
+            0x0C => Ok(MsgType::Delta),
+            0x0D => Ok(MsgType::Batch),
+            _ => Err(ProtocolError::InvalidMsgType(value)),
+        }
+    }
+}
+
+impl MsgType {
+    /// Whether a payload of `len` bytes is one the builders could produce
+    /// for this type. Types with free-form payloads accept any length.
+    pub fn accepts_payload_len(self, len: usize) -> bool {
+        match self {
+            MsgType::Connect => len <= MAX_LABEL_LEN,
+            MsgType::Connected | MsgType::Probe => len == 0,
+            MsgType::Close => len <= 1,
+            MsgType::Ping | MsgType::Pong => len == 0 || len == PING_TIMESTAMP_SIZE,
+            MsgType::Ack => len == ACK_OFFSET_SIZE,
+            MsgType::Error => len <= MAX_ERROR_LEN,
+            MsgType::Batch => len > 0,
+            MsgType::Data | MsgType::Version | MsgType::Auth | MsgType::Delta => true,
+        }
+    }
+}
+
+// =============================================================================
+// Protocol Types
+// =============================================================================
+
+/// Protocol type (TCP, UDP, or raw IP)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Proto {
+    Tcp = 0x00,
+    Udp = 0x01,
+    /// Raw IP packets; the port field carries the IP protocol number
+    Raw = 0x02,
+}
+
+impl TryFrom<u8> for Proto {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Proto::Tcp),
+            0x01 => Ok(Proto::Udp),
+            0x02 => Ok(Proto::Raw),
+            _ => Err(ProtocolError::InvalidProto(value)),
+        }
+    }
+}
+
+impl std::fmt::Display for Proto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Proto::Tcp => write!(f, "TCP"),
+            Proto::Udp => write!(f, "UDP"),
+            Proto::Raw => write!(f, "RAW"),
+        }
+    }
+}
+
+impl std::str::FromStr for Proto {
+    type Err = ProtocolError;
+
+    /// Parse a protocol name (case-insensitive), the inverse of `Display`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Proto::Tcp),
+            "udp" => Ok(Proto::Udp),
+            "raw" => Ok(Proto::Raw),
+            _ => Err(ProtocolError::UnknownProtoName(s.to_string())),
+        }
+    }
+}
+
+// =============================================================================
+// Protocol Errors
+// =============================================================================
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("Invalid message type: {0}")]
+    InvalidMsgType(u8),
+
+    #[error("Invalid protocol type: {0}")]
+    InvalidProto(u8),
+
+    #[error("Unknown protocol name: {0:?} (expected tcp, udp or raw)")]
+    UnknownProtoName(String),
+
+    #[error("Message too short: got {0} bytes, need at least {HEADER_SIZE}")]
+    MessageTooShort(usize),
+
+    #[error("Malformed VERSION payload")]
+    MalformedVersion,
+
+    #[error("Frame of {0} bytes exceeds the size limit")]
+    FrameTooLarge(usize),
+
+    #[error("Frame for unknown container index {0}")]
+    UnknownContainer(u16),
+
+    #[error("Unexpected {len}-byte payload on {msg_type:?}")]
+    UnexpectedPayload { msg_type: MsgType, len: usize },
+
+    #[error("BATCH payload cut short at byte {0}")]
+    MalformedBatch(usize),
+}
+
+// =============================================================================
+// Message Header
+// =============================================================================
+
+/// Parsed tunnel message header
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub msg_type: MsgType,
+    pub proto: Proto,
+    pub client_id: u32,
+    pub port: u16,
+}
+
+impl Header {
+    /// Parse header from bytes
+    pub fn parse(data: &[u8]) -> Result<Self, ProtocolError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ProtocolError::MessageTooShort(data.len()));
+        }
+
+        let msg_type = MsgType::try_from(data[0])?;
+        let proto = Proto::try_from(data[1])?;
+        let client_id = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let port = u16::from_be_bytes([data[6], data[7]]);
+
+        Ok(Header {
+            msg_type,
+            proto,
+            client_id,
+            port,
+        })
+    }
+
+    /// Write header to buffer
+    pub fn write_to(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.msg_type as u8);
+        buf.put_u8(self.proto as u8);
+        buf.put_u32(self.client_id);
+        buf.put_u16(self.port);
+    }
+}
+
+/// A decoded tunnel message, borrowing its payload from the input buffer
+#[derive(Debug, Clone)]
+pub struct Frame<'a> {
+    pub header: Header,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Decode a complete message (header + payload)
+    pub fn decode(data: &'a [u8]) -> Result<Self, ProtocolError> {
+        let header = Header::parse(data)?;
+        Ok(Frame {
+            header,
+            payload: get_payload(data),
+        })
+    }
+
+    /// Decode a message, also rejecting payloads its type never carries
+    /// (see [`MsgType::accepts_payload_len`])
+    pub fn decode_strict(data: &'a [u8]) -> Result<Self, ProtocolError> {
+        let frame = Self::decode(data)?;
+        let (msg_type, len) = (frame.header.msg_type, frame.payload.len());
+        if !msg_type.accepts_payload_len(len) {
+            return Err(ProtocolError::UnexpectedPayload { msg_type, len });
+        }
+        Ok(frame)
+    }
+}
+
+// =============================================================================
+// Message Building
+// =============================================================================
+
+/// Build a complete tunnel message
+pub fn build_message(
+    msg_type: MsgType,
+    proto: Proto,
+    client_id: u32,
+    port: u16,
+    payload: &[u8],
+) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_SIZE + payload.len());
+
+    let header = Header {
+        msg_type,
+        proto,
+        client_id,
+        port,
+    };
+    header.write_to(&mut buf);
+    buf.put_slice(payload);
+
+    buf.freeze()
+}
+
+/// Longest connection label a CONNECT payload may carry, in bytes
+pub const MAX_LABEL_LEN: usize = 64;
+
+/// The connection label carried in a CONNECT payload (None when empty).
+///
+/// Labels become metric label values and log fields, so they are limited to
+/// [`MAX_LABEL_LEN`] bytes of ASCII letters, digits and `-_.:`.
+pub fn parse_label(payload: &[u8]) -> Result<Option<&str>, String> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    if payload.len() > MAX_LABEL_LEN {
+        return Err(format!(
+            "Connection label of {} bytes exceeds {MAX_LABEL_LEN}",
+            payload.len()
+        ));
+    }
+    if !payload
+        .iter()
+        .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(b))
+    {
+        return Err("Connection label may only contain ASCII letters, digits and -_.:".into());
+    }
+    // All ASCII, checked above
+    Ok(std::str::from_utf8(payload).ok())
+}
+
+/// Build a CONNECTED message
+pub fn build_connected(proto: Proto, client_id: u32) -> Bytes {
+    build_message(MsgType::Connected, proto, client_id, 0, &[])
+}
+
+/// Build a DATA message
+pub fn build_data(proto: Proto, client_id: u32, data: &[u8]) -> Bytes {
+    build_message(MsgType::Data, proto, client_id, 0, data)
+}
+
+/// Build a CLOSE message for a connection that ended cleanly (EOF/FIN)
+pub fn build_close(proto: Proto, client_id: u32) -> Bytes {
+    build_message(MsgType::Close, proto, client_id, 0, &[])
+}
+
+/// CLOSE payload byte marking an abort (error or reset) rather than an EOF
+pub const CLOSE_ABORT: u8 = 0x01;
+
+/// Build a CLOSE for a connection that ended in an error or reset
+pub fn build_abort(proto: Proto, client_id: u32) -> Bytes {
+    build_message(MsgType::Close, proto, client_id, 0, &[CLOSE_ABORT])
+}
+
+/// Whether a CLOSE payload marks an abort; an empty one is a clean close
+pub fn is_abort(payload: &[u8]) -> bool {
+    payload.first() == Some(&CLOSE_ABORT)
+}
+
+/// CLOSE payload byte from the runner meaning it sends no more DATA for the
+/// connection but still accepts it (TCP half-close)
+pub const CLOSE_HALF: u8 = 0x02;
+
+/// Build a CLOSE that ends only the runner → local direction
+pub fn build_half_close(proto: Proto, client_id: u32) -> Bytes {
+    build_message(MsgType::Close, proto, client_id, 0, &[CLOSE_HALF])
+}
+
+/// Whether a CLOSE payload marks a half-close
+pub fn is_half_close(payload: &[u8]) -> bool {
+    payload.first() == Some(&CLOSE_HALF)
+}
+
+/// Longest error text carried in an ERROR payload, in bytes
+pub const MAX_ERROR_LEN: usize = 512;
+
+const ELLIPSIS: &str = "...";
+
+/// Build an ERROR message, truncating the text to [`MAX_ERROR_LEN`] bytes
+pub fn build_error(proto: Proto, client_id: u32, error_msg: &str) -> Bytes {
+    let payload: std::borrow::Cow<'_, str> = if error_msg.len() <= MAX_ERROR_LEN {
+        error_msg.into()
+    } else {
+        // Cut on a char boundary so the payload stays valid UTF-8
+        let mut end = MAX_ERROR_LEN - ELLIPSIS.len();
+        while !error_msg.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}{ELLIPSIS}", &error_msg[..end]).into()
+    };
+    build_message(MsgType::Error, proto, client_id, 0, payload.as_bytes())
+}
+
+/// Error text from an ERROR payload; invalid UTF-8 (e.g. a peer that cut
+/// mid-character) is replaced rather than rejected
+pub fn parse_error(payload: &[u8]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(payload)
+}
+
+/// Build a PONG message (response to PING), echoing the PING's payload
+pub fn build_pong(client_id: u32, payload: &[u8]) -> Bytes {
+    build_message(MsgType::Pong, Proto::Tcp, client_id, 0, payload)
+}
+
+/// Build a PING carrying a send timestamp for round-trip measurement
+pub fn build_ping_with_ts(client_id: u32, ts_micros: u64) -> Bytes {
+    build_message(
+        MsgType::Ping,
+        Proto::Tcp,
+        client_id,
+        0,
+        &encode_ping_timestamp(ts_micros),
+    )
+}
+
+/// Build a DELTA frame carrying an encoded payload
+pub fn build_delta(proto: Proto, client_id: u32, delta: &[u8]) -> Bytes {
+    build_message(MsgType::Delta, proto, client_id, 0, delta)
+}
+
+/// Size of the length in front of each datagram in a BATCH
+pub const BATCH_LEN_SIZE: usize = 2;
+
+/// Build a BATCH carrying several UDP DATA payloads of one connection, each
+/// as `[len (u16 BE)][payload]`. Every payload must fit the length field.
+pub fn build_batch<T: AsRef<[u8]>>(client_id: u32, payloads: &[T]) -> Bytes {
+    let mut body = Vec::with_capacity(
+        payloads
+            .iter()
+            .map(|p| BATCH_LEN_SIZE + p.as_ref().len())
+            .sum(),
+    );
+    for payload in payloads {
+        let payload = payload.as_ref();
+        let len = u16::try_from(payload.len()).expect("batched payload exceeds u16");
+        body.extend_from_slice(&len.to_be_bytes());
+        body.extend_from_slice(payload);
+    }
+    build_message(MsgType::Batch, Proto::Udp, client_id, 0, &body)
+}
+
+/// Split a BATCH payload into the DATA payloads it carries
+pub fn split_batch(payload: &[u8]) -> Result<Vec<&[u8]>, ProtocolError> {
+    let mut payloads = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let offset = payload.len() - rest.len();
+        if rest.len() < BATCH_LEN_SIZE {
+            return Err(ProtocolError::MalformedBatch(offset));
+        }
+        let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        let Some(item) = rest.get(BATCH_LEN_SIZE..BATCH_LEN_SIZE + len) else {
+            return Err(ProtocolError::MalformedBatch(offset));
+        };
+        payloads.push(item);
+        rest = &rest[BATCH_LEN_SIZE + len..];
+    }
+    Ok(payloads)
+}
+
+/// Build an AUTH carrying the response to the runner's challenge
+pub fn build_auth(response: &[u8]) -> Bytes {
+    build_message(MsgType::Auth, Proto::Tcp, 0, 0, response)
+}
+
+/// Size of the offset carried in ACK payloads
+pub const ACK_OFFSET_SIZE: usize = 8;
+
+/// Build an ACK reporting the bytes received so far (u64 big-endian)
+pub fn build_ack(client_id: u32, received: u64) -> Bytes {
+    build_message(
+        MsgType::Ack,
+        Proto::Tcp,
+        client_id,
+        0,
+        &received.to_be_bytes(),
+    )
+}
+
+/// Extract the cumulative offset from an ACK payload
+pub fn parse_ack(payload: &[u8]) -> Option<u64> {
+    let bytes: [u8; ACK_OFFSET_SIZE] = payload.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+// =============================================================================
+// PING Timestamps
+// =============================================================================
+
+/// Size of the timestamp carried in PING/PONG payloads
+pub const PING_TIMESTAMP_SIZE: usize = 8;
+
+/// Microseconds since the Unix epoch, as carried in PING payloads
+pub fn timestamp_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Encode a PING timestamp (u64 big-endian microseconds)
+pub fn encode_ping_timestamp(ts_micros: u64) -> [u8; PING_TIMESTAMP_SIZE] {
+    ts_micros.to_be_bytes()
+}
+
+/// Extract the timestamp from a PING/PONG payload, if it carries one
+pub fn parse_ping_timestamp(payload: &[u8]) -> Option<u64> {
+    let bytes: [u8; PING_TIMESTAMP_SIZE] = payload.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+/// Round-trip time for an echoed timestamp (None if the clock went backwards)
+pub fn ping_rtt(ts_micros: u64, now_micros: u64) -> Option<Duration> {
+    now_micros.checked_sub(ts_micros).map(Duration::from_micros)
+}
+
+// =============================================================================
+// Version / Capabilities
+// =============================================================================
+
+/// Feature bit: per-connection compression
+pub const FEATURE_COMPRESSION: u32 = 1 << 0;
+/// Feature bit: payload checksums
+pub const FEATURE_CHECKSUM: u32 = 1 << 1;
+/// Feature bit: TCP half-close
+pub const FEATURE_HALF_CLOSE: u32 = 1 << 2;
+/// Feature bit: DATA payloads are encrypted with the pre-shared key
+pub const FEATURE_ENCRYPTION: u32 = 1 << 3;
+/// Feature bit: TCP streams resume across reconnects via ACK offsets
+pub const FEATURE_RESUME: u32 = 1 << 4;
+/// Feature bit: PROBE messages are answered
+pub const FEATURE_PROBE: u32 = 1 << 5;
+/// Delta encoding of repetitive DATA payloads (DELTA messages)
+pub const FEATURE_DELTA: u32 = 1 << 6;
+/// Several containers multiplexed over one connection (see [`crate::mux`])
+pub const FEATURE_MUX: u32 = 1 << 7;
+/// TCP DATA carries cumulative byte counts (see [`crate::bytecount`])
+pub const FEATURE_BYTE_COUNT: u32 = 1 << 8;
+/// Several UDP datagrams may share one BATCH message
+pub const FEATURE_UDP_BATCH: u32 = 1 << 9;
+
+/// Features implemented by this build
+pub const SUPPORTED_FEATURES: u32 = FEATURE_PROBE | FEATURE_HALF_CLOSE;
+
+/// Bit for `msg_type` in a MsgType bitmask
+pub const fn msg_type_bit(msg_type: MsgType) -> u32 {
+    1 << msg_type as u8
+}
+
+/// Message types every runner parses, assumed until its VERSION says more
+pub const BASE_MSG_TYPES: u32 = msg_type_bit(MsgType::Connect)
+    | msg_type_bit(MsgType::Connected)
+    | msg_type_bit(MsgType::Data)
+    | msg_type_bit(MsgType::Close)
+    | msg_type_bit(MsgType::Error)
+    | msg_type_bit(MsgType::Ping)
+    | msg_type_bit(MsgType::Pong);
+
+/// Message types this build parses
+pub const SUPPORTED_MSG_TYPES: u32 = BASE_MSG_TYPES
+    | msg_type_bit(MsgType::Version)
+    | msg_type_bit(MsgType::Ack)
+    | msg_type_bit(MsgType::Probe)
+    | msg_type_bit(MsgType::Auth)
+    | msg_type_bit(MsgType::Delta)
+    | msg_type_bit(MsgType::Batch);
+
+/// Message types implied by the feature bits of a VERSION that carries no
+/// MsgType bitmask (older builds)
+pub fn implied_msg_types(features: u32) -> u32 {
+    [
+        (FEATURE_RESUME, MsgType::Ack),
+        (FEATURE_PROBE, MsgType::Probe),
+        (FEATURE_DELTA, MsgType::Delta),
+        (FEATURE_UDP_BATCH, MsgType::Batch),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| features & bit != 0)
+    .fold(
+        BASE_MSG_TYPES | msg_type_bit(MsgType::Version),
+        |mask, (_, msg_type)| mask | msg_type_bit(msg_type),
+    )
+}
+
+/// The message types set in a MsgType bitmask, in code order
+pub fn msg_types_in(mask: u32) -> Vec<MsgType> {
+    (0..32u8)
+        .filter(|code| mask & (1 << code) != 0)
+        .filter_map(|code| MsgType::try_from(code).ok())
+        .collect()
+}
+
+/// What a peer announced it understands: feature bits and the message
+/// types it parses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub features: u32,
+    pub msg_types: u32,
+}
+
+impl Capabilities {
+    /// Capabilities of a peer that announced only feature bits
+    pub fn from_features(features: u32) -> Self {
+        Self {
+            features,
+            msg_types: implied_msg_types(features),
+        }
+    }
+
+    /// Whether the peer advertised all of `features`
+    pub fn has(&self, features: u32) -> bool {
+        self.features & features == features
+    }
+
+    /// Whether the peer parses `msg_type`
+    pub fn accepts(&self, msg_type: MsgType) -> bool {
+        self.msg_types & msg_type_bit(msg_type) != 0
+    }
+}
+
+impl Default for Capabilities {
+    /// A peer that has not sent VERSION: no features, base messages only
+    fn default() -> Self {
+        Self {
+            features: 0,
+            msg_types: BASE_MSG_TYPES,
+        }
+    }
+}
+
+/// Human-readable names of the feature bits set in `features`
+pub fn feature_names(features: u32) -> Vec<&'static str> {
+    [
+        (FEATURE_COMPRESSION, "compression"),
+        (FEATURE_CHECKSUM, "checksum"),
+        (FEATURE_HALF_CLOSE, "half-close"),
+        (FEATURE_ENCRYPTION, "encryption"),
+        (FEATURE_RESUME, "resume"),
+        (FEATURE_PROBE, "probe"),
+        (FEATURE_DELTA, "delta"),
+        (FEATURE_MUX, "mux"),
+        (FEATURE_BYTE_COUNT, "byte-count"),
+        (FEATURE_UDP_BATCH, "udp-batch"),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| features & bit != 0)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+/// Contents of a VERSION message
+///
+/// Payload layout:
+/// ```text
+/// ┌──────────┬───────────────┬─────────┬───────────┬─────────┬───────────┬────────────────┐
+/// │ Proto ver│ Features (4B) │ Len (1B)│ Version   │ Len (1B)│ OS/arch   │ MsgTypes (4B)  │
+/// │   (1B)   │               │         │ (UTF-8)   │         │ (UTF-8)   │                │
+/// └──────────┴───────────────┴─────────┴───────────┴─────────┴───────────┴────────────────┘
+/// ```
+///
+/// The MsgTypes bitmask (bit N = message type N, see [`msg_type_bit`]) was
+/// added later; a VERSION without it gets [`implied_msg_types`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub protocol_version: u8,
+    pub features: u32,
+    pub version: String,
+    pub platform: String,
+    pub msg_types: u32,
+}
+
+impl VersionInfo {
+    /// Version info describing this binary
+    pub fn current() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            features: SUPPORTED_FEATURES,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            platform: format!("{}/{}", std::env::consts::OS, std::env::consts::ARCH),
+            msg_types: SUPPORTED_MSG_TYPES,
+        }
+    }
+
+    /// Features and message types announced
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            features: self.features,
+            msg_types: self.msg_types,
+        }
+    }
+
+    /// Encode as a VERSION payload (strings are truncated to 255 bytes)
+    pub fn encode(&self) -> Vec<u8> {
+        let version = truncate_str(&self.version, u8::MAX as usize);
+        let platform = truncate_str(&self.platform, u8::MAX as usize);
+
+        let mut buf = Vec::with_capacity(11 + version.len() + platform.len());
+        buf.push(self.protocol_version);
+        buf.extend_from_slice(&self.features.to_be_bytes());
+        buf.push(version.len() as u8);
+        buf.extend_from_slice(version.as_bytes());
+        buf.push(platform.len() as u8);
+        buf.extend_from_slice(platform.as_bytes());
+        buf.extend_from_slice(&self.msg_types.to_be_bytes());
+        buf
+    }
+
+    /// Parse a VERSION payload
+    pub fn parse(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let (&protocol_version, rest) = payload
+            .split_first()
+            .ok_or(ProtocolError::MalformedVersion)?;
+        if rest.len() < 4 {
+            return Err(ProtocolError::MalformedVersion);
+        }
+        let features = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        let (version, rest) = read_short_str(&rest[4..])?;
+        let (platform, rest) = read_short_str(rest)?;
+        let msg_types = match rest {
+            [a, b, c, d, ..] => u32::from_be_bytes([*a, *b, *c, *d]),
+            _ => implied_msg_types(features),
+        };
+
+        Ok(Self {
+            protocol_version,
+            features,
+            version,
+            platform,
+            msg_types,
+        })
+    }
+}
+
+/// Truncate a string to at most `max` bytes on a char boundary
+fn truncate_str(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Read a u8-length-prefixed UTF-8 string, returning it and the remaining bytes
+fn read_short_str(data: &[u8]) -> Result<(String, &[u8]), ProtocolError> {
+    let (&len, rest) = data.split_first().ok_or(ProtocolError::MalformedVersion)?;
+    let len = len as usize;
+    if rest.len() < len {
+        return Err(ProtocolError::MalformedVersion);
+    }
+    let s = std::str::from_utf8(&rest[..len]).map_err(|_| ProtocolError::MalformedVersion)?;
+    Ok((s.to_string(), &rest[len..]))
+}
+
+/// Build a VERSION message announcing this tunnel's version and capabilities
+pub fn build_version(info: &VersionInfo) -> Bytes {
+    build_message(MsgType::Version, Proto::Tcp, 0, 0, &info.encode())
+}
+
+/// Extract payload from a message (everything after header)
+pub fn get_payload(data: &[u8]) -> &[u8] {
+    if data.len() > HEADER_SIZE {
+        &data[HEADER_SIZE..]
+    } else {
+        &[]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let original = Header {
+            msg_type: MsgType::Connect,
+            proto: Proto::Tcp,
+            client_id: 12345,
+            port: 8080,
+        };
+
+        let mut buf = BytesMut::new();
+        original.write_to(&mut buf);
+
+        let parsed = Header::parse(&buf).unwrap();
+        assert_eq!(parsed.msg_type, original.msg_type);
+        assert_eq!(parsed.proto, original.proto);
+        assert_eq!(parsed.client_id, original.client_id);
+        assert_eq!(parsed.port, original.port);
+    }
+
+    #[test]
+    fn test_ping_timestamp_echo() {
+        let ping = build_ping_with_ts(0, 1_700_000_000_123_456);
+        let header = Header::parse(&ping).unwrap();
+        assert_eq!(header.msg_type, MsgType::Ping);
+
+        let pong = build_pong(header.client_id, get_payload(&ping));
+        assert_eq!(Header::parse(&pong).unwrap().msg_type, MsgType::Pong);
+        let ts = parse_ping_timestamp(get_payload(&pong)).unwrap();
+        assert_eq!(ts, 1_700_000_000_123_456);
+
+        assert_eq!(ping_rtt(ts, ts + 2_500), Some(Duration::from_micros(2_500)));
+        assert_eq!(ping_rtt(ts, ts - 1), None);
+        assert_eq!(parse_ping_timestamp(b""), None);
+        assert_eq!(parse_ping_timestamp(b"short"), None);
+    }
+
+    #[test]
+    fn test_ack_roundtrip() {
+        let ack = build_ack(42, 1 << 40);
+        let header = Header::parse(&ack).unwrap();
+        assert_eq!(header.msg_type, MsgType::Ack);
+        assert_eq!(header.client_id, 42);
+        assert_eq!(parse_ack(get_payload(&ack)), Some(1 << 40));
+        assert_eq!(parse_ack(b"short"), None);
+    }
+
+    #[test]
+    fn test_error_text_truncated() {
+        let short = build_error(Proto::Tcp, 1, "Connection refused");
+        assert_eq!(parse_error(get_payload(&short)), "Connection refused");
+
+        // Multi-byte chars straddle the cut point
+        let long = "é".repeat(1000);
+        let msg = build_error(Proto::Tcp, 1, &long);
+        let text = parse_error(get_payload(&msg));
+        assert!(text.len() <= MAX_ERROR_LEN);
+        assert!(text.ends_with("..."));
+        assert!(text.trim_end_matches("...").chars().all(|c| c == 'é'));
+
+        // Invalid UTF-8 from a peer doesn't fail parsing
+        assert_eq!(parse_error(b"bad \xc3"), "bad \u{fffd}");
+    }
+
+    #[test]
+    fn test_build_message() {
+        let msg = build_data(Proto::Tcp, 42, b"hello");
+        assert_eq!(msg.len(), HEADER_SIZE + 5);
+
+        let header = Header::parse(&msg).unwrap();
+        assert_eq!(header.msg_type, MsgType::Data);
+        assert_eq!(header.client_id, 42);
+
+        let payload = get_payload(&msg);
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn test_version_roundtrip() {
+        let info = VersionInfo::current();
+        let msg = build_version(&info);
+
+        let header = Header::parse(&msg).unwrap();
+        assert_eq!(header.msg_type, MsgType::Version);
+        assert_eq!(header.client_id, 0);
+
+        let parsed = VersionInfo::parse(get_payload(&msg)).unwrap();
+        assert_eq!(parsed, info);
+        assert_eq!(parsed.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(parsed.protocol_version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_feature_names() {
+        assert!(feature_names(0).is_empty());
+        assert_eq!(
+            feature_names(FEATURE_COMPRESSION | FEATURE_HALF_CLOSE),
+            vec!["compression", "half-close"]
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        assert_eq!(SUPPORTED_MSG_TYPES, 0x3ffe);
+        let none = Capabilities::default();
+        assert!(none.accepts(MsgType::Close) && !none.accepts(MsgType::Version));
+        assert!(!none.has(FEATURE_HALF_CLOSE));
+
+        let legacy = Capabilities::from_features(FEATURE_RESUME | FEATURE_HALF_CLOSE);
+        assert!(legacy.accepts(MsgType::Ack) && legacy.accepts(MsgType::Version));
+        assert!(!legacy.accepts(MsgType::Delta) && !legacy.accepts(MsgType::Batch));
+        assert!(legacy.has(FEATURE_HALF_CLOSE));
+        assert!(!legacy.has(FEATURE_HALF_CLOSE | FEATURE_DELTA));
+
+        // An explicit bitmask wins over the feature bits, and survives a
+        // roundtrip; unknown bits are kept but not listed
+        let info = VersionInfo {
+            features: FEATURE_DELTA,
+            msg_types: BASE_MSG_TYPES | 1 << 31,
+            ..VersionInfo::current()
+        };
+        let parsed = VersionInfo::parse(&info.encode()).unwrap();
+        assert_eq!(parsed, info);
+        assert!(!parsed.capabilities().accepts(MsgType::Delta));
+        assert_eq!(msg_types_in(parsed.msg_types).len(), 7);
+    }
+
+    #[test]
+    fn test_version_parse_malformed() {
+        assert!(VersionInfo::parse(&[]).is_err());
+        assert!(VersionInfo::parse(&[1, 0, 0]).is_err());
+        // Declared string length exceeds payload
+        assert!(VersionInfo::parse(&[1, 0, 0, 0, 0, 10, b'a']).is_err());
+        // Invalid UTF-8
+        assert!(VersionInfo::parse(&[1, 0, 0, 0, 0, 1, 0xff, 0]).is_err());
+    }
+
+    #[test]
+    fn test_decode_arbitrary_bytes_never_panics() {
+        use rand::{Rng, SeedableRng};
+
+        // Cheap in-tree complement to the cargo-fuzz targets in fuzz/
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x7e57);
+        for _ in 0..20_000 {
+            let len = rng.gen_range(0..64);
+            let mut data = vec![0u8; len];
+            rng.fill(&mut data[..]);
+            // Bias towards valid type/proto bytes to reach deeper paths
+            if len > 1 && rng.gen_bool(0.5) {
+                data[0] = rng.gen_range(0..=9);
+                data[1] = rng.gen_range(0..=2);
+            }
+
+            if let Ok(frame) = Frame::decode(&data) {
+                assert_eq!(frame.payload.len(), len - HEADER_SIZE);
+                if frame.header.msg_type == MsgType::Version {
+                    let _ = VersionInfo::parse(frame.payload);
+                }
+            } else {
+                assert!(Header::parse(&data).is_err());
+            }
+            let _ = get_payload(&data);
+        }
+    }
+
+    #[test]
+    fn test_decode_strict_payload_lengths() {
+        let valid = [
+            build_connected(Proto::Tcp, 1),
+            build_close(Proto::Udp, 2),
+            build_abort(Proto::Tcp, 2),
+            build_ack(3, 42),
+            build_ping_with_ts(4, 1),
+            build_pong(5, &[]),
+            build_error(Proto::Tcp, 6, &"x".repeat(2 * MAX_ERROR_LEN)),
+            build_data(Proto::Tcp, 7, &[0; 100]),
+        ];
+        for frame in &valid {
+            assert!(Frame::decode_strict(frame).is_ok(), "{:?}", frame);
+        }
+
+        let invalid = [
+            (build_message(MsgType::Connected, Proto::Tcp, 1, 0, b"x"), 1),
+            (
+                build_message(MsgType::Connect, Proto::Tcp, 1, 80, &[b'x'; 65]),
+                65,
+            ),
+            (build_message(MsgType::Close, Proto::Tcp, 1, 0, &[0; 2]), 2),
+            (build_message(MsgType::Ack, Proto::Tcp, 1, 0, &[0; 4]), 4),
+            (build_message(MsgType::Ping, Proto::Tcp, 1, 0, &[0; 9]), 9),
+        ];
+        for (frame, len) in &invalid {
+            let msg_type = MsgType::try_from(frame[0]).unwrap();
+            assert!(matches!(
+                Frame::decode_strict(frame),
+                Err(ProtocolError::UnexpectedPayload { msg_type: t, len: l }) if t == msg_type && l == *len
+            ));
+            // Lenient decoding takes them as they are
+            assert!(Frame::decode(frame).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(parse_label(b""), Ok(None));
+        assert_eq!(parse_label(b"api"), Ok(Some("api")));
+        assert_eq!(
+            parse_label(b"tenant-7.svc:http_2"),
+            Ok(Some("tenant-7.svc:http_2"))
+        );
+        assert_eq!(
+            parse_label(&[b'a'; MAX_LABEL_LEN]).unwrap().unwrap().len(),
+            MAX_LABEL_LEN
+        );
+        assert!(parse_label(&[b'a'; MAX_LABEL_LEN + 1]).is_err());
+        for bad in [&b"a b"[..], b"a\"b", b"a\n", b"caf\xc3\xa9", b"a{b}"] {
+            assert!(parse_label(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_proto_from_str() {
+        assert_eq!("tcp".parse::<Proto>().unwrap(), Proto::Tcp);
+        assert_eq!("UDP".parse::<Proto>().unwrap(), Proto::Udp);
+        assert_eq!(" Tcp ".parse::<Proto>().unwrap(), Proto::Tcp);
+        assert!(matches!(
+            "sctp".parse::<Proto>(),
+            Err(ProtocolError::UnknownProtoName(_))
+        ));
+        assert!("".parse::<Proto>().is_err());
+
+        // Display and FromStr round-trip
+        for proto in [Proto::Tcp, Proto::Udp] {
+            assert_eq!(proto.to_string().parse::<Proto>().unwrap(), proto);
+        }
+    }
+}
+
+/// Canonical byte vectors for every message type. The runner implements the
+/// same format independently, so these are written out by hand rather than
+/// derived from the builders: a change that breaks one is a wire format
+/// change.
+#[cfg(test)]
+mod conformance {
+    use super::*;
+
+    /// Decode hex, ignoring whitespace
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    struct Vector {
+        name: &'static str,
+        built: Bytes,
+        wire: &'static str,
+        msg_type: MsgType,
+        proto: Proto,
+        client_id: u32,
+        port: u16,
+    }
+
+    fn vectors() -> Vec<Vector> {
+        let version = VersionInfo {
+            protocol_version: 1,
+            features: FEATURE_PROBE | FEATURE_COMPRESSION,
+            version: "1.2".into(),
+            platform: "linux/x86_64".into(),
+            msg_types: SUPPORTED_MSG_TYPES,
+        };
+        vec![
+            Vector {
+                name: "CONNECT tcp",
+                built: build_message(MsgType::Connect, Proto::Tcp, 1, 8080, &[]),
+                wire: "01 00 00000001 1f90",
+                msg_type: MsgType::Connect,
+                proto: Proto::Tcp,
+                client_id: 1,
+                port: 8080,
+            },
+            Vector {
+                name: "CONNECT udp, max client_id and port",
+                built: build_message(MsgType::Connect, Proto::Udp, u32::MAX, u16::MAX, &[]),
+                wire: "01 01 ffffffff ffff",
+                msg_type: MsgType::Connect,
+                proto: Proto::Udp,
+                client_id: u32::MAX,
+                port: u16::MAX,
+            },
+            Vector {
+                name: "CONNECT raw (protocol number in the port field)",
+                built: build_message(MsgType::Connect, Proto::Raw, 2, 132, &[]),
+                wire: "01 02 00000002 0084",
+                msg_type: MsgType::Connect,
+                proto: Proto::Raw,
+                client_id: 2,
+                port: 132,
+            },
+            Vector {
+                name: "CONNECT with a label",
+                built: build_message(MsgType::Connect, Proto::Tcp, 1, 443, b"api"),
+                wire: "01 00 00000001 01bb 617069",
+                msg_type: MsgType::Connect,
+                proto: Proto::Tcp,
+                client_id: 1,
+                port: 443,
+            },
+            Vector {
+                name: "CONNECTED",
+                built: build_connected(Proto::Tcp, 0x0102_0304),
+                wire: "02 00 01020304 0000",
+                msg_type: MsgType::Connected,
+                proto: Proto::Tcp,
+                client_id: 0x0102_0304,
+                port: 0,
+            },
+            Vector {
+                name: "DATA",
+                built: build_data(Proto::Tcp, 7, b"hi"),
+                wire: "03 00 00000007 0000 6869",
+                msg_type: MsgType::Data,
+                proto: Proto::Tcp,
+                client_id: 7,
+                port: 0,
+            },
+            Vector {
+                name: "DATA empty (UDP keepalive)",
+                built: build_data(Proto::Udp, 7, &[]),
+                wire: "03 01 00000007 0000",
+                msg_type: MsgType::Data,
+                proto: Proto::Udp,
+                client_id: 7,
+                port: 0,
+            },
+            Vector {
+                name: "CLOSE",
+                built: build_close(Proto::Tcp, 42),
+                wire: "04 00 0000002a 0000",
+                msg_type: MsgType::Close,
+                proto: Proto::Tcp,
+                client_id: 42,
+                port: 0,
+            },
+            Vector {
+                name: "CLOSE abort",
+                built: build_abort(Proto::Tcp, 42),
+                wire: "04 00 0000002a 0000 01",
+                msg_type: MsgType::Close,
+                proto: Proto::Tcp,
+                client_id: 42,
+                port: 0,
+            },
+            Vector {
+                name: "CLOSE half-close",
+                built: build_half_close(Proto::Tcp, 42),
+                wire: "04 00 0000002a 0000 02",
+                msg_type: MsgType::Close,
+                proto: Proto::Tcp,
+                client_id: 42,
+                port: 0,
+            },
+            Vector {
+                name: "ERROR",
+                built: build_error(Proto::Udp, 3, "refused"),
+                wire: "05 01 00000003 0000 72656675736564",
+                msg_type: MsgType::Error,
+                proto: Proto::Udp,
+                client_id: 3,
+                port: 0,
+            },
+            Vector {
+                name: "PING with timestamp",
+                built: build_ping_with_ts(0, 0x0102_0304_0506_0708),
+                wire: "06 00 00000000 0000 0102030405060708",
+                msg_type: MsgType::Ping,
+                proto: Proto::Tcp,
+                client_id: 0,
+                port: 0,
+            },
+            Vector {
+                name: "PONG echoing a timestamp",
+                built: build_pong(9, &hex("0102030405060708")),
+                wire: "07 00 00000009 0000 0102030405060708",
+                msg_type: MsgType::Pong,
+                proto: Proto::Tcp,
+                client_id: 9,
+                port: 0,
+            },
+            Vector {
+                name: "PONG empty",
+                built: build_pong(9, &[]),
+                wire: "07 00 00000009 0000",
+                msg_type: MsgType::Pong,
+                proto: Proto::Tcp,
+                client_id: 9,
+                port: 0,
+            },
+            Vector {
+                name: "VERSION",
+                built: build_version(&version),
+                wire:
+                    "08 00 00000000 0000 01 00000021 03 312e32 0c 6c696e75782f7838365f3634 00003ffe",
+                msg_type: MsgType::Version,
+                proto: Proto::Tcp,
+                client_id: 0,
+                port: 0,
+            },
+            Vector {
+                name: "ACK past 4 GiB",
+                built: build_ack(5, 1 << 32),
+                wire: "09 00 00000005 0000 0000000100000000",
+                msg_type: MsgType::Ack,
+                proto: Proto::Tcp,
+                client_id: 5,
+                port: 0,
+            },
+            Vector {
+                name: "PROBE",
+                built: build_message(MsgType::Probe, Proto::Tcp, 9, 65535, &[]),
+                wire: "0a 00 00000009 ffff",
+                msg_type: MsgType::Probe,
+                proto: Proto::Tcp,
+                client_id: 9,
+                port: 65535,
+            },
+            Vector {
+                name: "AUTH",
+                built: build_auth(&[0xaa; 4]),
+                wire: "0b 00 00000000 0000 aaaaaaaa",
+                msg_type: MsgType::Auth,
+                proto: Proto::Tcp,
+                client_id: 0,
+                port: 0,
+            },
+            Vector {
+                name: "DELTA",
+                built: build_delta(Proto::Tcp, 3, &hex("00000001 00000001 78")),
+                wire: "0c 00 00000003 0000 00000001 00000001 78",
+                msg_type: MsgType::Delta,
+                proto: Proto::Tcp,
+                client_id: 3,
+                port: 0,
+            },
+            Vector {
+                name: "BATCH of two datagrams, one empty",
+                built: build_batch(4, &[&b"hi"[..], &[]]),
+                wire: "0d 01 00000004 0000 0002 6869 0000",
+                msg_type: MsgType::Batch,
+                proto: Proto::Udp,
+                client_id: 4,
+                port: 0,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_builders_match_vectors() {
+        for v in vectors() {
+            assert_eq!(&v.built[..], &hex(v.wire)[..], "{}", v.name);
+        }
+    }
+
+    #[test]
+    fn test_vectors_parse() {
+        for v in vectors() {
+            let wire = hex(v.wire);
+            let frame = Frame::decode_strict(&wire).unwrap_or_else(|e| panic!("{}: {e}", v.name));
+            assert_eq!(frame.header.msg_type, v.msg_type, "{}", v.name);
+            assert_eq!(frame.header.proto, v.proto, "{}", v.name);
+            assert_eq!(frame.header.client_id, v.client_id, "{}", v.name);
+            assert_eq!(frame.header.port, v.port, "{}", v.name);
+            assert_eq!(frame.payload, &wire[HEADER_SIZE..], "{}", v.name);
+        }
+    }
+
+    #[test]
+    fn test_payload_fields() {
+        assert!(is_abort(&hex("01")));
+        assert!(!is_abort(&[]));
+        assert!(is_half_close(&hex("02")));
+        assert!(!is_half_close(&hex("01")) && !is_abort(&hex("02")));
+        assert_eq!(parse_error(&hex("72656675736564")), "refused");
+        assert_eq!(
+            split_batch(&hex("0002 6869 0000")).unwrap(),
+            [&b"hi"[..], &[]]
+        );
+        assert!(matches!(
+            split_batch(&hex("0002 6869 0003 00")),
+            Err(ProtocolError::MalformedBatch(4))
+        ));
+        assert!(split_batch(&hex("00")).is_err());
+        assert_eq!(parse_ack(&hex("0000000100000000")), Some(1 << 32));
+        assert_eq!(
+            parse_ping_timestamp(&hex("0102030405060708")),
+            Some(0x0102_0304_0506_0708)
+        );
+        let version =
+            VersionInfo::parse(&hex("01 00000021 03 312e32 0c 6c696e75782f7838365f3634")).unwrap();
+        assert_eq!(version.features, FEATURE_PROBE | FEATURE_COMPRESSION);
+        assert_eq!(version.version, "1.2");
+        assert_eq!(version.platform, "linux/x86_64");
+        // Without a MsgType bitmask, the feature bits stand in for one
+        assert_eq!(
+            msg_types_in(version.msg_types),
+            [
+                MsgType::Connect,
+                MsgType::Connected,
+                MsgType::Data,
+                MsgType::Close,
+                MsgType::Error,
+                MsgType::Ping,
+                MsgType::Pong,
+                MsgType::Version,
+                MsgType::Probe,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_payload() {
+        // The largest DATA the tunnel builds: one full 64 KiB read
+        let payload = vec![0x5a; 64 << 10];
+        let built = build_data(Proto::Tcp, u32::MAX, &payload);
+        assert_eq!(&built[..HEADER_SIZE], &hex("03 00 ffffffff 0000")[..]);
+        assert_eq!(built.len(), HEADER_SIZE + payload.len());
+        let frame = Frame::decode_strict(&built).unwrap();
+        assert_eq!(frame.payload, &payload[..]);
+
+        // ERROR text is capped, with the cut marked
+        let built = build_error(Proto::Tcp, 1, &"e".repeat(MAX_ERROR_LEN + 1));
+        assert_eq!(built.len(), HEADER_SIZE + MAX_ERROR_LEN);
+        assert!(built.ends_with(b"..."));
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        assert!(matches!(
+            Header::parse(&hex("03 00 00000001 00")),
+            Err(ProtocolError::MessageTooShort(7))
+        ));
+        assert!(matches!(
+            Header::parse(&hex("00 00 00000001 0000")),
+            Err(ProtocolError::InvalidMsgType(0x00))
+        ));
+        assert!(matches!(
+            Header::parse(&hex("0e 00 00000001 0000")),
+            Err(ProtocolError::InvalidMsgType(0x0e))
+        ));
+        assert!(Header::parse(&hex("03 03 00000001 0000")).is_err());
+    }
+}
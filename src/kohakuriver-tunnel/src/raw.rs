@@ -0,0 +1,142 @@
+//! Raw IP forwarding (`Proto::Raw`, behind the `raw` feature).
+//!
+//! For protocols other than TCP and UDP, e.g. SCTP (132). A raw CONNECT
+//! carries the IP protocol number in the port field; the tunnel opens a raw
+//! socket for that protocol, connected to the target host so the kernel only
+//! delivers packets from it, and relays each DATA payload as the payload of
+//! one IP packet. The kernel builds the IP header on send. IPv4 raw sockets
+//! return the header on receive, so it is stripped; IPv6 ones never do.
+//!
+//! Raw sockets need `CAP_NET_RAW` (or root), which [`check_capability`]
+//! verifies at startup when raw is in the allowed protocols.
+
+#[cfg(not(unix))]
+compile_error!("the raw feature is only supported on Unix");
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::unix::AsyncFd;
+
+/// IP protocol number reserved for experimentation (RFC 3692), used to try
+/// opening a raw socket without claiming a real protocol's packets
+const EXPERIMENTAL_PROTOCOL: i32 = 253;
+
+/// Check that this process may open raw sockets
+pub fn check_capability() -> io::Result<()> {
+    Socket::new(
+        Domain::IPV4,
+        Type::RAW,
+        Some(Protocol::from(EXPERIMENTAL_PROTOCOL)),
+    )
+    .map(drop)
+}
+
+/// The IP protocol number a raw CONNECT asks for, or the reason to refuse it
+pub fn protocol_number(port: u16) -> Result<u8, String> {
+    match port {
+        6 => Err("Use TCP for IP protocol 6".to_string()),
+        17 => Err("Use UDP for IP protocol 17".to_string()),
+        // 0 is the IPv6 hop-by-hop option and 255 is send-only
+        1..=254 => Ok(port as u8),
+        _ => Err(format!("Invalid IP protocol number {port}")),
+    }
+}
+
+/// Length of the IPv4 header at the start of `packet`, if it is well formed
+fn ipv4_header_len(packet: &[u8]) -> Option<usize> {
+    let first = *packet.first()?;
+    let len = usize::from(first & 0x0f) * 4;
+    (first >> 4 == 4 && len >= 20 && len <= packet.len()).then_some(len)
+}
+
+/// A raw socket for one IP protocol, connected to a single host
+#[derive(Debug)]
+pub struct RawSocket {
+    inner: AsyncFd<Socket>,
+    /// IPv4 raw sockets hand back the IP header with each packet
+    strip_header: bool,
+}
+
+impl RawSocket {
+    /// Open a raw socket for `protocol`, bound to `source` if given and
+    /// connected to `target`
+    pub fn connect(protocol: u8, source: Option<IpAddr>, target: IpAddr) -> io::Result<Self> {
+        let target = SocketAddr::new(target, 0);
+        let socket = Socket::new(
+            Domain::for_address(target),
+            Type::RAW,
+            Some(Protocol::from(i32::from(protocol))),
+        )?;
+        socket.set_nonblocking(true)?;
+        if let Some(source) = source {
+            socket.bind(&SocketAddr::new(source, 0).into())?;
+        }
+        socket.connect(&target.into())?;
+        Ok(Self {
+            inner: AsyncFd::new(socket)?,
+            strip_header: target.is_ipv4(),
+        })
+    }
+
+    /// Receive the payload of one packet into `buf`, returning its length
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = loop {
+            let mut guard = self.inner.readable().await?;
+            if let Ok(result) = guard.try_io(|socket| socket.get_ref().read(buf)) {
+                break result?;
+            }
+        };
+        if !self.strip_header {
+            return Ok(n);
+        }
+        let header = ipv4_header_len(&buf[..n])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed IPv4 header"))?;
+        buf.copy_within(header..n, 0);
+        Ok(n - header)
+    }
+
+    /// Send `data` as the payload of one packet
+    pub async fn send(&self, data: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.inner.writable().await?;
+            if let Ok(result) = guard.try_io(|socket| socket.get_ref().write(data)) {
+                return result;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_number() {
+        assert_eq!(protocol_number(132), Ok(132));
+        assert_eq!(protocol_number(1), Ok(1));
+        assert!(protocol_number(6).unwrap_err().contains("TCP"));
+        assert!(protocol_number(17).unwrap_err().contains("UDP"));
+        assert!(protocol_number(0).is_err());
+        assert!(protocol_number(255).is_err());
+        assert!(protocol_number(1000).is_err());
+    }
+
+    #[test]
+    fn test_ipv4_header_len() {
+        let mut packet = vec![0x45u8; 28];
+        assert_eq!(ipv4_header_len(&packet), Some(20));
+        // Header with options
+        packet[0] = 0x46;
+        assert_eq!(ipv4_header_len(&packet), Some(24));
+        // Longer than the packet, too short, or not IPv4
+        packet[0] = 0x48;
+        assert_eq!(ipv4_header_len(&packet), None);
+        packet[0] = 0x44;
+        assert_eq!(ipv4_header_len(&packet), None);
+        packet[0] = 0x65;
+        assert_eq!(ipv4_header_len(&packet), None);
+        assert_eq!(ipv4_header_len(&[]), None);
+    }
+}
@@ -0,0 +1,207 @@
+//! Rejoining tunnel frames a runner split across transport messages.
+//!
+//! Frames carry no length field: the end of the WebSocket message is the end
+//! of the payload. The rule is one frame per message, and a frame with a
+//! variable-length payload (DATA, ERROR, VERSION, …) must arrive in a single
+//! message; there is no way to tell where a split one ends. Two kinds of
+//! split are still unambiguous and are rejoined here instead of being dropped:
+//!
+//! - a message shorter than a header, which can only be the start of a frame
+//! - a frame whose type has a fixed payload size (ACK) that arrived short
+//!
+//! Bytes are only held back if their message type and protocol bytes are
+//! valid, and are dropped if the next message starts a frame of its own, so
+//! the tail of a split DATA payload is never glued onto the following frame.
+//! Once a whole header is held, only a message of exactly the missing
+//! payload length continues it; before that, a continuation that happens to
+//! start with a valid header is taken for a new frame.
+//!
+//! The bytes held back are bounded by the largest frame that can be
+//! rejoined, a mux-tagged ACK. The HTTP/2 transport length-prefixes its
+//! frames and never needs this.
+
+use bytes::{Bytes, BytesMut};
+use tracing::debug;
+
+use crate::mux::{CONTAINER_INDEX_SIZE, MUX_FLAG};
+use crate::protocol::{MsgType, Proto, ACK_OFFSET_SIZE, HEADER_SIZE};
+
+/// Most bytes ever held back waiting for the rest of a frame
+pub const MAX_PENDING: usize = HEADER_SIZE + CONTAINER_INDEX_SIZE + ACK_OFFSET_SIZE;
+
+/// Bytes still missing from the frame starting `data`, if it can be told
+fn missing(data: &[u8]) -> usize {
+    if data.len() < HEADER_SIZE {
+        return HEADER_SIZE - data.len();
+    }
+    let index = if data[1] & MUX_FLAG != 0 {
+        CONTAINER_INDEX_SIZE
+    } else {
+        0
+    };
+    let payload = match MsgType::try_from(data[0]) {
+        Ok(MsgType::Ack) => ACK_OFFSET_SIZE,
+        _ => 0,
+    };
+    (HEADER_SIZE + index + payload).saturating_sub(data.len())
+}
+
+/// Whether the message type and protocol bytes present in `data` are valid,
+/// i.e. it could be the start of a frame
+fn plausible_start(data: &[u8]) -> bool {
+    let msg_type = data.first().is_none_or(|&t| MsgType::try_from(t).is_ok());
+    let proto = data
+        .get(1)
+        .is_none_or(|&p| Proto::try_from(p & !MUX_FLAG).is_ok());
+    msg_type && proto
+}
+
+/// Reassembly state for one transport session
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    pending: BytesMut,
+}
+
+impl Reassembler {
+    /// Feed one binary message, returning a complete frame if there is one.
+    ///
+    /// A message that completes nothing yet is held (None). Messages that
+    /// already hold a whole frame pass through without copying, and so do
+    /// ones that can't start a frame, for the decoder to reject.
+    pub fn push(&mut self, message: Bytes) -> Option<Bytes> {
+        let data = if self.pending.is_empty() {
+            message
+        } else if self.starts_new_frame(&message) {
+            debug!(
+                dropped = self.pending.len(),
+                "Next message starts a new frame, dropping partial frame"
+            );
+            self.pending.clear();
+            message
+        } else {
+            self.pending.extend_from_slice(&message);
+            self.pending.split().freeze()
+        };
+        let missing = missing(&data);
+        if missing == 0 || !plausible_start(&data) {
+            return Some(data);
+        }
+        debug!(
+            held = data.len(),
+            missing, "Partial frame, waiting for the next message"
+        );
+        self.pending.extend_from_slice(&data);
+        None
+    }
+
+    /// Whether `message` is a frame of its own rather than the rest of the
+    /// held bytes: once their header is complete only the exact number of
+    /// missing payload bytes continues it, before that anything starting
+    /// with a valid header is taken for a new frame
+    fn starts_new_frame(&self, message: &[u8]) -> bool {
+        if self.pending.len() >= HEADER_SIZE {
+            message.len() != missing(&self.pending)
+        } else {
+            message.len() >= HEADER_SIZE && plausible_start(message)
+        }
+    }
+
+    /// Bytes held back from an incomplete frame
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux;
+    use crate::protocol::{self, Proto};
+
+    #[test]
+    fn test_whole_frames_pass_through() {
+        let mut reassembler = Reassembler::default();
+        for frame in [
+            protocol::build_data(Proto::Tcp, 1, b"hello"),
+            protocol::build_close(Proto::Tcp, 1),
+            protocol::build_ack(1, 42),
+        ] {
+            assert_eq!(reassembler.push(frame.clone()), Some(frame));
+        }
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_split_header() {
+        let frame = protocol::build_data(Proto::Udp, 7, b"payload");
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(frame.slice(..3)), None);
+        assert_eq!(reassembler.push(frame.slice(3..6)), None);
+        assert_eq!(reassembler.pending(), 6);
+        // The rest of the header arrives along with the whole payload
+        assert_eq!(reassembler.push(frame.slice(6..)), Some(frame));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_split_fixed_size_payload() {
+        let frame = protocol::build_ack(3, 1 << 40);
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(frame.slice(..HEADER_SIZE + 3)), None);
+        assert_eq!(
+            reassembler.push(frame.slice(HEADER_SIZE + 3..)),
+            Some(frame)
+        );
+
+        // Same for a container's ACK, whose payload follows the index
+        let tagged = mux::tag(2, &protocol::build_ack(3, 99));
+        assert_eq!(tagged.len(), MAX_PENDING);
+        assert_eq!(reassembler.push(tagged.slice(..HEADER_SIZE + 1)), None);
+        assert_eq!(
+            reassembler.push(tagged.slice(HEADER_SIZE + 1..)),
+            Some(tagged)
+        );
+
+        // Anything but exactly the missing bytes is a frame of its own
+        let ack = protocol::build_ack(3, 7);
+        assert_eq!(reassembler.push(ack.slice(..HEADER_SIZE + 3)), None);
+        let close = protocol::build_close(Proto::Tcp, 3);
+        assert_eq!(reassembler.push(close.clone()), Some(close));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_invalid_start_not_held() {
+        let mut reassembler = Reassembler::default();
+        // No message type 0xFF
+        let garbage = Bytes::from_static(&[0xFF, 0x00, 0x01]);
+        assert_eq!(reassembler.push(garbage.clone()), Some(garbage));
+        // Valid type, but no protocol 0x7F
+        let garbage = Bytes::from_static(&[MsgType::Data as u8, 0x7F]);
+        assert_eq!(reassembler.push(garbage.clone()), Some(garbage));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_split_data_tail_not_glued_onto_next_frame() {
+        // The tail of a split DATA payload that looks like a frame start
+        let data = protocol::build_data(Proto::Tcp, 1, &[0xAA, 0xBB, 0x03, 0x01, 0x00]);
+        let mut reassembler = Reassembler::default();
+        let head = data.slice(..HEADER_SIZE + 2);
+        assert_eq!(reassembler.push(head.clone()), Some(head));
+        assert_eq!(reassembler.push(data.slice(HEADER_SIZE + 2..)), None);
+
+        // The next frame arrives intact, and the tail is gone
+        let next = protocol::build_close(Proto::Tcp, 2);
+        assert_eq!(reassembler.push(next.clone()), Some(next));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_variable_payload_not_held() {
+        // Nothing says where a DATA payload ends but the message boundary
+        let frame = protocol::build_data(Proto::Tcp, 1, b"part");
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(frame.clone()), Some(frame));
+    }
+}
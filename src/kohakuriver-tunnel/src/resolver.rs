@@ -0,0 +1,163 @@
+//! Resolving service names in CONNECTs to local ports.
+//!
+//! A CONNECT for port 0 whose payload is a name (e.g. `jupyter`) asks for a
+//! service rather than a port, for containers whose services don't listen on
+//! fixed ports. The [`ServiceResolver`] answers with the port, which is then
+//! treated as if the runner had requested it: allowlists, port maps and
+//! limits apply to it as usual. The name also becomes the connection's label.
+//!
+//! The built-in resolver checks `--service-map` first, then asks
+//! `--service-resolver-cmd` if one is configured.
+
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::config::ServiceMap;
+use crate::tunnel::TunnelConfig;
+
+/// How long the resolver command may take before the CONNECT is refused
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maps a service name to the local port it listens on.
+///
+/// A lookup that doesn't answer on its first poll runs off the message loop;
+/// until it does, the runner's DATA for the connection is held, so a slow
+/// lookup only delays the connection it is for.
+pub trait ServiceResolver: Send + Sync {
+    /// Port of the service called `name` (None = unknown service)
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<u16>>;
+}
+
+impl ServiceResolver for ServiceMap {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<u16>> {
+        Box::pin(async move { self.get(name) })
+    }
+}
+
+/// Runs an external command with the service name as its only argument; it
+/// prints the port on stdout and exits 0, or exits nonzero for an unknown
+/// service
+#[derive(Debug, Clone)]
+pub struct CommandResolver {
+    program: PathBuf,
+}
+
+impl CommandResolver {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+}
+
+impl ServiceResolver for CommandResolver {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<u16>> {
+        Box::pin(async move {
+            let output = Command::new(&self.program)
+                .arg(name)
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .output();
+            let output = match tokio::time::timeout(COMMAND_TIMEOUT, output).await {
+                Ok(Ok(output)) => output,
+                Ok(Err(e)) => {
+                    warn!(program = %self.program.display(), error = %e, "Failed to run service resolver");
+                    return None;
+                }
+                Err(_) => {
+                    warn!(program = %self.program.display(), service = name, "Service resolver timed out");
+                    return None;
+                }
+            };
+            if !output.status.success() {
+                debug!(service = name, status = %output.status, "Service resolver found no port");
+                return None;
+            }
+            let port = String::from_utf8_lossy(&output.stdout).trim().parse().ok();
+            if port.is_none() {
+                warn!(service = name, "Service resolver printed no valid port");
+            }
+            port.filter(|&p| p != 0)
+        })
+    }
+}
+
+/// Resolver built from the configuration: the static map, then the command
+#[derive(Debug, Clone, Default)]
+pub struct ConfigResolver {
+    map: ServiceMap,
+    command: Option<CommandResolver>,
+}
+
+impl ConfigResolver {
+    pub fn from_config(config: &TunnelConfig) -> Self {
+        Self {
+            map: config.service_map.clone(),
+            command: config
+                .service_resolver_cmd
+                .as_ref()
+                .map(CommandResolver::new),
+        }
+    }
+}
+
+impl ServiceResolver for ConfigResolver {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<u16>> {
+        Box::pin(async move {
+            if let Some(port) = self.map.get(name) {
+                return Some(port);
+            }
+            match &self.command {
+                Some(command) => command.resolve(name).await,
+                None => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_map_before_command() {
+        let config = TunnelConfig {
+            service_map: "api=8000".parse().unwrap(),
+            service_resolver_cmd: Some("/nonexistent/resolver".into()),
+            ..Default::default()
+        };
+        let resolver = ConfigResolver::from_config(&config);
+        assert_eq!(resolver.resolve("api").await, Some(8000));
+        // Falls through to the command, which can't run
+        assert_eq!(resolver.resolve("db").await, None);
+        assert_eq!(ConfigResolver::default().resolve("api").await, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_resolver() {
+        let script =
+            std::env::temp_dir().join(format!("kohakuriver-resolver-{}.sh", std::process::id()));
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ncase \"$1\" in\n  jupyter) echo 8888 ;;\n  junk) echo soon ;;\n  *) exit 1 ;;\nesac\n",
+        )
+        .unwrap();
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let resolver = CommandResolver::new(&script);
+        assert_eq!(resolver.resolve("jupyter").await, Some(8888));
+        assert_eq!(resolver.resolve("junk").await, None);
+        assert_eq!(resolver.resolve("db").await, None);
+        std::fs::remove_file(&script).unwrap();
+    }
+}
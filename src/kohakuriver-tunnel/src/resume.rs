@@ -0,0 +1,338 @@
+//! Resumable TCP streams across WebSocket reconnects.
+//!
+//! With `--resume-grace` set, a dropped WebSocket no longer tears down the
+//! forwarded TCP connections. Each side counts the bytes it has received per
+//! connection and reports them in ACK messages (cumulative u64 offsets). The
+//! tunnel retains everything it has sent but the runner has not yet ACKed, up
+//! to `--resume-buffer` bytes per connection; when the buffer is full, reading
+//! from the local service pauses until an ACK frees space.
+//!
+//! When the WebSocket drops, the connections are suspended: nothing more is
+//! sent and the local sockets stay open. If the tunnel reconnects within the
+//! grace window it sends an ACK with its received offset for every suspended
+//! connection. The runner answers with its own ACK (and retransmits its tail
+//! past our offset); the tunnel then retransmits the retained bytes past the
+//! runner's offset and the connection carries on. Connections the runner no
+//! longer knows are closed by it with CLOSE as usual.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// The tunnel ACKs the runner's stream after receiving this many new bytes
+pub const ACK_INTERVAL_BYTES: u64 = 64 * 1024;
+
+/// Why a connection could not be resumed
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ResumeError {
+    #[error("peer offset {peer} is older than retained data starting at {retained_from}")]
+    OffsetTooOld { peer: u64, retained_from: u64 },
+
+    #[error("peer offset {peer} is beyond the {sent} bytes sent")]
+    OffsetAhead { peer: u64, sent: u64 },
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Stream offset of the first retained byte (= highest ACK from the runner)
+    acked: u64,
+    /// Sent but unacknowledged chunks, covering `acked..sent`
+    retained: VecDeque<Bytes>,
+    /// Total bytes sent to the runner
+    sent: u64,
+    /// Total bytes received from the runner
+    received: u64,
+    /// `received` as of the last ACK we sent
+    ack_sent: u64,
+    /// When the connection was suspended (None = live)
+    suspended_at: Option<Instant>,
+}
+
+/// Per-connection resume bookkeeping, shared by the connection's tasks and
+/// the connection manager
+#[derive(Debug)]
+pub struct ResumeState {
+    inner: Mutex<Inner>,
+    /// Retained-bytes cap
+    capacity: u64,
+    /// How long a suspended connection waits to be resumed
+    grace: Duration,
+    /// Signalled on resume and whenever an ACK frees buffer space
+    changed: Notify,
+}
+
+impl ResumeState {
+    pub fn new(capacity: usize, grace: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                acked: 0,
+                retained: VecDeque::new(),
+                sent: 0,
+                received: 0,
+                ack_sent: 0,
+                suspended_at: None,
+            }),
+            capacity: capacity.max(1) as u64,
+            grace,
+            changed: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until `len` more bytes fit in the retained buffer.
+    ///
+    /// Returns false if the connection stayed suspended past the grace window.
+    pub async fn reserve(&self, len: usize) -> bool {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let inner = self.lock();
+                let in_flight = inner.sent - inner.acked;
+                // Always admit one chunk into an empty buffer so a chunk larger
+                // than the cap can't deadlock
+                if in_flight == 0 || in_flight + len as u64 <= self.capacity {
+                    return true;
+                }
+            }
+            if !self.wait(changed).await {
+                return false;
+            }
+        }
+    }
+
+    /// Retain a chunk as it is sent.
+    ///
+    /// Must be called under the transport sender lock, after checking the
+    /// connection is not suspended, so the chunk is either sent live or
+    /// covered by the resume retransmit, never both.
+    pub fn push(&self, data: Bytes) {
+        let mut inner = self.lock();
+        inner.sent += data.len() as u64;
+        inner.retained.push_back(data);
+    }
+
+    /// Apply an ACK from the runner.
+    ///
+    /// For a suspended connection this completes the resume handshake and
+    /// returns the retained bytes past the runner's offset, which must be sent
+    /// before calling [`resume`](Self::resume).
+    pub fn on_ack(&self, peer: u64) -> Result<Option<Vec<Bytes>>, ResumeError> {
+        let mut inner = self.lock();
+        if peer > inner.sent {
+            return Err(ResumeError::OffsetAhead {
+                peer,
+                sent: inner.sent,
+            });
+        }
+        if peer < inner.acked {
+            if inner.suspended_at.is_none() {
+                // A stale ACK overtaken by a newer one; harmless
+                return Ok(None);
+            }
+            return Err(ResumeError::OffsetTooOld {
+                peer,
+                retained_from: inner.acked,
+            });
+        }
+
+        // Drop everything the runner has confirmed, splitting a partial chunk
+        let mut to_drop = peer - inner.acked;
+        while to_drop > 0 {
+            let front = inner
+                .retained
+                .front_mut()
+                .expect("retained covers acked..sent");
+            if (front.len() as u64) <= to_drop {
+                to_drop -= front.len() as u64;
+                inner.retained.pop_front();
+            } else {
+                let _ = front.split_to(to_drop as usize);
+                to_drop = 0;
+            }
+        }
+        inner.acked = peer;
+        drop(inner);
+        self.changed.notify_waiters();
+
+        let inner = self.lock();
+        Ok(inner
+            .suspended_at
+            .is_some()
+            .then(|| inner.retained.iter().cloned().collect()))
+    }
+
+    /// Count bytes received from the runner, returning the offset to ACK once
+    /// enough new data has arrived
+    pub fn record_received(&self, len: usize) -> Option<u64> {
+        let mut inner = self.lock();
+        inner.received += len as u64;
+        if inner.received - inner.ack_sent >= ACK_INTERVAL_BYTES {
+            inner.ack_sent = inner.received;
+            Some(inner.received)
+        } else {
+            None
+        }
+    }
+
+    /// Offset to report in the resume handshake
+    pub fn received(&self) -> u64 {
+        let mut inner = self.lock();
+        inner.ack_sent = inner.received;
+        inner.received
+    }
+
+    /// Bytes sent but not yet acknowledged
+    pub fn unacked(&self) -> u64 {
+        let inner = self.lock();
+        inner.sent - inner.acked
+    }
+
+    /// Stop sending until resumed (idempotent)
+    pub fn suspend(&self) {
+        let mut inner = self.lock();
+        if inner.suspended_at.is_none() {
+            inner.suspended_at = Some(Instant::now());
+        }
+        drop(inner);
+        // Waiters re-arm with the grace deadline
+        self.changed.notify_waiters();
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.lock().suspended_at.is_some()
+    }
+
+    /// Allow sending again after the handshake's retransmit
+    pub fn resume(&self) {
+        self.lock().suspended_at = None;
+        self.changed.notify_waiters();
+    }
+
+    /// Wait while suspended; false if the grace window ran out
+    pub async fn wait_resumed(&self) -> bool {
+        loop {
+            let changed = self.changed.notified();
+            if !self.is_suspended() {
+                return true;
+            }
+            if !self.wait(changed).await {
+                return false;
+            }
+        }
+    }
+
+    /// Wait for a change notification, bounded by the grace window while
+    /// suspended. False once the grace window has run out.
+    async fn wait(&self, changed: tokio::sync::futures::Notified<'_>) -> bool {
+        let suspended_at = self.lock().suspended_at;
+        match suspended_at {
+            Some(at) => {
+                let deadline = at + self.grace;
+                if Instant::now() >= deadline {
+                    return false;
+                }
+                tokio::select! {
+                    _ = changed => true,
+                    _ = tokio::time::sleep_until(deadline) => !self.is_suspended(),
+                }
+            }
+            None => {
+                changed.await;
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn concat(chunks: &[Bytes]) -> Vec<u8> {
+        chunks.iter().flat_map(|c| c.iter().copied()).collect()
+    }
+
+    #[test]
+    fn test_ack_trims_retained_including_partial_chunks() {
+        let state = ResumeState::new(1024, Duration::from_secs(30));
+        state.push(Bytes::from_static(b"hello "));
+        state.push(Bytes::from_static(b"world"));
+        assert_eq!(state.unacked(), 11);
+
+        assert_eq!(state.on_ack(8), Ok(None));
+        assert_eq!(state.unacked(), 3);
+
+        // Suspended: the handshake ACK yields the tail to retransmit
+        state.suspend();
+        let tail = state.on_ack(8).unwrap().unwrap();
+        assert_eq!(concat(&tail), b"rld");
+
+        assert_eq!(
+            state.on_ack(20),
+            Err(ResumeError::OffsetAhead { peer: 20, sent: 11 })
+        );
+        assert_eq!(
+            state.on_ack(2),
+            Err(ResumeError::OffsetTooOld {
+                peer: 2,
+                retained_from: 8
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reserve_blocks_at_capacity_until_acked() {
+        let state = std::sync::Arc::new(ResumeState::new(8, Duration::from_secs(30)));
+        assert!(state.reserve(100).await);
+        state.push(Bytes::from_static(b"12345678"));
+
+        let blocked = tokio::spawn({
+            let state = state.clone();
+            async move { state.reserve(1).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!blocked.is_finished());
+
+        state.on_ack(4).unwrap();
+        assert!(blocked.await.unwrap());
+        assert_eq!(state.unacked(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_resumed_respects_grace() {
+        let state = std::sync::Arc::new(ResumeState::new(8, Duration::from_secs(30)));
+        assert!(state.wait_resumed().await);
+
+        state.suspend();
+        let waiter = tokio::spawn({
+            let state = state.clone();
+            async move { state.wait_resumed().await }
+        });
+        tokio::time::advance(Duration::from_secs(10)).await;
+        state.resume();
+        assert!(waiter.await.unwrap());
+
+        state.suspend();
+        assert!(!state.wait_resumed().await);
+    }
+
+    #[test]
+    fn test_record_received_acks_periodically() {
+        let state = ResumeState::new(8, Duration::from_secs(30));
+        assert_eq!(state.record_received(1000), None);
+        assert_eq!(
+            state.record_received(ACK_INTERVAL_BYTES as usize),
+            Some(ACK_INTERVAL_BYTES + 1000)
+        );
+        assert_eq!(state.record_received(10), None);
+        assert_eq!(state.received(), ACK_INTERVAL_BYTES + 1010);
+    }
+}
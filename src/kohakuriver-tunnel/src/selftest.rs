@@ -0,0 +1,294 @@
+//! Loopback self-test (`--self-test`).
+//!
+//! Starts local echo services, runs a real tunnel session against an
+//! in-process mock runner (see [`transport::memory`]), and pushes a known
+//! pattern through CONNECT/DATA/CLOSE. The bytes take the same path as in
+//! production, through the message loop, [`crate::connection`] and the
+//! container's loopback networking, so a pass means the tunnel can reach
+//! local services from here. Nothing touches the network beyond loopback.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Instant};
+
+use crate::crypto::PayloadCipher;
+use crate::error::{Result, TunnelError};
+use crate::protocol::{self, Frame, MsgType, Proto, VersionInfo};
+use crate::task::AbortOnDrop;
+use crate::transport::{self, Incoming, MemoryPeer};
+use crate::tunnel::{TunnelClient, TunnelConfig};
+
+/// Bytes pushed through the TCP echo
+pub const TCP_PATTERN_LEN: usize = 256 << 10;
+
+/// Size of each DATA frame carrying the TCP pattern
+const TCP_CHUNK: usize = 16 << 10;
+
+/// Datagrams sent through the UDP echo
+#[cfg(feature = "udp")]
+const UDP_DATAGRAMS: usize = 8;
+
+/// How long any single step may take
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a passing self-test exercised
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// Bytes echoed through the TCP connection
+    pub tcp_bytes: usize,
+    /// Datagrams echoed through the UDP connection (0 when UDP is disabled)
+    pub udp_datagrams: usize,
+    pub elapsed: Duration,
+}
+
+/// Run the self-test with the local-side settings of `config` (source
+/// address, encryption, queue depth), failing with
+/// [`TunnelError::SelfTestFailed`] if any byte doesn't come back intact
+pub async fn run(config: &TunnelConfig) -> Result<SelfTestReport> {
+    let started = Instant::now();
+    let config = TunnelConfig {
+        ws_ping_interval: Duration::ZERO,
+        source_addr: config.source_addr,
+        psk: config.psk.clone(),
+        send_queue_depth: config.send_queue_depth,
+        disable_udp: config.disable_udp,
+        ..Default::default()
+    };
+    let cipher = config.psk.as_deref().map(PayloadCipher::new);
+    let udp = config.udp_enabled();
+    let host = match config.source_addr {
+        Some(IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    };
+
+    let (transport, mut runner) = transport::memory(64);
+    let (stop, stopped) = oneshot::channel::<()>();
+    let client = TunnelClient::new(config);
+    let session = AbortOnDrop::spawn(async move {
+        let _ = client
+            .run_transport(transport, async {
+                let _ = stopped.await;
+            })
+            .await;
+    });
+    expect(&mut runner, MsgType::Version, 0).await?;
+    if cipher.is_some() {
+        // The tunnel only opens connections for a runner that can read them
+        let info = VersionInfo {
+            features: protocol::FEATURE_ENCRYPTION,
+            msg_types: protocol::implied_msg_types(protocol::FEATURE_ENCRYPTION),
+            version: "self-test".into(),
+            ..VersionInfo::current()
+        };
+        send(&runner, protocol::build_version(&info)).await?;
+    }
+
+    let tcp_bytes = tcp_echo(&mut runner, cipher.as_ref(), host).await?;
+    #[cfg(feature = "udp")]
+    let udp_datagrams = if udp {
+        udp_echo(&mut runner, cipher.as_ref(), host).await?
+    } else {
+        0
+    };
+    #[cfg(not(feature = "udp"))]
+    let udp_datagrams = {
+        let _ = udp;
+        0
+    };
+
+    // Let the session wind down; it's aborted if that takes too long
+    let _ = stop.send(());
+    let _ = timeout(STEP_TIMEOUT, session).await;
+    Ok(SelfTestReport {
+        tcp_bytes,
+        udp_datagrams,
+        elapsed: started.elapsed(),
+    })
+}
+
+fn failed(reason: impl Into<String>) -> TunnelError {
+    TunnelError::SelfTestFailed(reason.into())
+}
+
+/// The byte at `offset` of the test pattern; not periodic at any power of
+/// two, so shifted or reordered chunks don't line up by accident
+fn pattern_byte(offset: usize) -> u8 {
+    (offset % 251) as u8 ^ (offset / 251) as u8
+}
+
+/// Next frame from the tunnel, within the step timeout
+async fn next(runner: &mut MemoryPeer) -> Result<Bytes> {
+    match timeout(STEP_TIMEOUT, runner.next_frame()).await {
+        Ok(Some(frame)) => Ok(frame),
+        Ok(None) => Err(failed("tunnel session ended unexpectedly")),
+        Err(_) => Err(failed("timed out waiting for the tunnel")),
+    }
+}
+
+/// Wait for a frame of `msg_type` for `client_id`, failing on an ERROR
+async fn expect(runner: &mut MemoryPeer, msg_type: MsgType, client_id: u32) -> Result<Bytes> {
+    let frame = next(runner).await?;
+    let Frame { header, payload } = Frame::decode(&frame)?;
+    if header.msg_type == MsgType::Error {
+        return Err(failed(protocol::parse_error(payload).into_owned()));
+    }
+    if (header.msg_type, header.client_id) != (msg_type, client_id) {
+        return Err(failed(format!(
+            "expected {:?} for client {}, got {:?} for client {}",
+            msg_type, client_id, header.msg_type, header.client_id
+        )));
+    }
+    Ok(Bytes::copy_from_slice(payload))
+}
+
+/// Send a frame to the tunnel as the runner would
+async fn send(runner: &MemoryPeer, frame: Bytes) -> Result<()> {
+    if runner.send(Incoming::Frame(frame)).await {
+        Ok(())
+    } else {
+        Err(failed("tunnel session ended unexpectedly"))
+    }
+}
+
+/// A DATA frame from the runner, sealed when encryption is on
+fn data(cipher: Option<&PayloadCipher>, proto: Proto, client_id: u32, payload: &[u8]) -> Bytes {
+    match cipher {
+        Some(cipher) => {
+            protocol::build_data(proto, client_id, &cipher.seal(proto, client_id, payload))
+        }
+        None => protocol::build_data(proto, client_id, payload),
+    }
+}
+
+/// Open a DATA payload from the tunnel
+fn open(
+    cipher: Option<&PayloadCipher>,
+    proto: Proto,
+    client_id: u32,
+    payload: Bytes,
+) -> Result<Bytes> {
+    match cipher {
+        Some(cipher) => cipher
+            .open(proto, client_id, &payload)
+            .map(Bytes::from)
+            .map_err(|e| failed(format!("undecryptable DATA from the tunnel: {e}"))),
+        None => Ok(payload),
+    }
+}
+
+/// Push the pattern through a TCP echo service, returning the bytes echoed
+async fn tcp_echo(
+    runner: &mut MemoryPeer,
+    cipher: Option<&PayloadCipher>,
+    host: IpAddr,
+) -> Result<usize> {
+    const CLIENT_ID: u32 = 1;
+
+    let listener = TcpListener::bind(SocketAddr::new(host, 0)).await?;
+    let port = listener.local_addr()?.port();
+    let _echo = AbortOnDrop::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                let _ = writer.shutdown().await;
+            });
+        }
+    });
+
+    let connect = protocol::build_message(MsgType::Connect, Proto::Tcp, CLIENT_ID, port, &[]);
+    send(runner, connect).await?;
+    expect(runner, MsgType::Connected, CLIENT_ID).await?;
+
+    let pattern: Vec<u8> = (0..TCP_PATTERN_LEN).map(pattern_byte).collect();
+    let mut echoed = Vec::with_capacity(TCP_PATTERN_LEN);
+    // The pattern fits in the transport's queue, so it can all go out
+    // before the echo is collected
+    for chunk in pattern.chunks(TCP_CHUNK) {
+        send(runner, data(cipher, Proto::Tcp, CLIENT_ID, chunk)).await?;
+    }
+    while echoed.len() < TCP_PATTERN_LEN {
+        let payload = expect(runner, MsgType::Data, CLIENT_ID).await?;
+        echoed.extend_from_slice(&open(cipher, Proto::Tcp, CLIENT_ID, payload)?);
+    }
+    if echoed != pattern {
+        let at = echoed.iter().zip(&pattern).position(|(a, b)| a != b);
+        return Err(failed(format!(
+            "TCP echo mismatch at byte {}",
+            at.unwrap_or(TCP_PATTERN_LEN)
+        )));
+    }
+
+    send(runner, protocol::build_close(Proto::Tcp, CLIENT_ID)).await?;
+    Ok(echoed.len())
+}
+
+/// Send datagrams through a UDP echo service, returning the number echoed
+#[cfg(feature = "udp")]
+async fn udp_echo(
+    runner: &mut MemoryPeer,
+    cipher: Option<&PayloadCipher>,
+    host: IpAddr,
+) -> Result<usize> {
+    use tokio::net::UdpSocket;
+
+    const CLIENT_ID: u32 = 2;
+
+    let socket = UdpSocket::bind(SocketAddr::new(host, 0)).await?;
+    let port = socket.local_addr()?.port();
+    let _echo = AbortOnDrop::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..n], peer).await;
+        }
+    });
+
+    let connect = protocol::build_message(MsgType::Connect, Proto::Udp, CLIENT_ID, port, &[]);
+    send(runner, connect).await?;
+    expect(runner, MsgType::Connected, CLIENT_ID).await?;
+
+    // One at a time, since loopback UDP may still drop under a burst
+    for i in 0..UDP_DATAGRAMS {
+        let datagram: Vec<u8> = (0..1000).map(|j| pattern_byte(i * 1000 + j)).collect();
+        send(runner, data(cipher, Proto::Udp, CLIENT_ID, &datagram)).await?;
+        let payload = expect(runner, MsgType::Data, CLIENT_ID).await?;
+        if open(cipher, Proto::Udp, CLIENT_ID, payload)? != datagram {
+            return Err(failed(format!("UDP echo mismatch in datagram {i}")));
+        }
+    }
+
+    send(runner, protocol::build_close(Proto::Udp, CLIENT_ID)).await?;
+    Ok(UDP_DATAGRAMS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_self_test_passes() {
+        let report = run(&TunnelConfig::default()).await.unwrap();
+        assert_eq!(report.tcp_bytes, TCP_PATTERN_LEN);
+        #[cfg(feature = "udp")]
+        assert_eq!(report.udp_datagrams, UDP_DATAGRAMS);
+    }
+
+    #[tokio::test]
+    async fn test_self_test_with_encryption() {
+        let config = TunnelConfig {
+            psk: Some("self-test".into()),
+            disable_udp: true,
+            ..Default::default()
+        };
+        let report = run(&config).await.unwrap();
+        assert_eq!(
+            (report.tcp_bytes, report.udp_datagrams),
+            (TCP_PATTERN_LEN, 0)
+        );
+    }
+}
@@ -0,0 +1,752 @@
+//! Disk-backed overflow buffering for slow links.
+//!
+//! Each connection forwards runner → local data through a bounded in-memory
+//! channel. When that channel is full (the local side or the link is slower
+//! than the runner), excess DATA is appended to a per-connection temp file and
+//! replayed in order once the channel drains. Total disk usage across all
+//! connections is bounded by a shared byte budget, and so, optionally, is the
+//! memory held by all the channels together (see [`MemoryBudget`]).
+//!
+//! Spill files are read and written on tokio's blocking threads, so a slow
+//! disk holds up only the connection that spilled, not the message loop.
+//!
+//! Spill file format: a sequence of `[len: u32 BE][payload]` records.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Size of the length prefix of each spilled record
+const RECORD_HEADER_SIZE: u64 = 4;
+
+/// Shared spill settings and disk usage accounting for all connections
+#[derive(Debug)]
+pub struct SpillPool {
+    /// Directory where spill files are created
+    dir: PathBuf,
+    /// Maximum bytes on disk across all spill files
+    max_bytes: u64,
+    /// Bytes currently held in spill files
+    used: AtomicU64,
+    /// Signalled whenever any spill file frees bytes
+    freed: Notify,
+}
+
+impl SpillPool {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes,
+            used: AtomicU64::new(0),
+            freed: Notify::new(),
+        }
+    }
+
+    /// Bytes currently held in spill files
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Reserve `n` bytes of the disk budget, failing if it would be exceeded
+    fn try_reserve(&self, n: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used + n <= self.max_bytes).then_some(used + n)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, n: u64) {
+        self.used.fetch_sub(n, Ordering::AcqRel);
+        self.freed.notify_waiters();
+    }
+}
+
+/// Mutable state of a single spill file
+struct SpillState {
+    file: Option<File>,
+    read_pos: u64,
+    write_pos: u64,
+}
+
+/// Per-connection overflow file.
+///
+/// [`push`](Self::push) and [`pop`](Self::pop) do file I/O and belong on a
+/// blocking thread; the queue halves call them through `spawn_blocking`.
+pub struct Spill {
+    pool: Arc<SpillPool>,
+    path: PathBuf,
+    state: Mutex<SpillState>,
+    /// Records waiting to be replayed, readable without taking the lock
+    records: AtomicUsize,
+    /// Signalled when a record is appended
+    pushed: Notify,
+}
+
+impl Spill {
+    pub fn new(pool: Arc<SpillPool>, client_id: u32) -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let path = pool.dir.join(format!(
+            "kohakuriver-tunnel-{}-{}-{}.spill",
+            std::process::id(),
+            client_id,
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        Self {
+            pool,
+            path,
+            state: Mutex::new(SpillState {
+                file: None,
+                read_pos: 0,
+                write_pos: 0,
+            }),
+            records: AtomicUsize::new(0),
+            pushed: Notify::new(),
+        }
+    }
+
+    /// Number of records waiting to be replayed
+    pub fn len(&self) -> usize {
+        self.records.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append a record, returning `Ok(false)` if the disk budget is exhausted
+    pub fn push(&self, data: &[u8]) -> io::Result<bool> {
+        let size = RECORD_HEADER_SIZE + data.len() as u64;
+        if !self.pool.try_reserve(size) {
+            return Ok(false);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = Self::append(&self.path, &mut state, data) {
+            self.pool.release(size);
+            return Err(e);
+        }
+        state.write_pos += size;
+        self.records.fetch_add(1, Ordering::AcqRel);
+        drop(state);
+
+        self.pushed.notify_one();
+        Ok(true)
+    }
+
+    fn append(path: &Path, state: &mut SpillState, data: &[u8]) -> io::Result<()> {
+        if state.file.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            state.file = Some(file);
+        }
+        let write_pos = state.write_pos;
+        let file = state.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(write_pos))?;
+        file.write_all(&(data.len() as u32).to_be_bytes())?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    /// Remove and return the oldest record, if any
+    pub fn pop(&self) -> io::Result<Option<Bytes>> {
+        let mut state = self.state.lock().unwrap();
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        let read_pos = state.read_pos;
+        let file = state
+            .file
+            .as_mut()
+            .expect("spill file exists while records remain");
+        file.seek(SeekFrom::Start(read_pos))?;
+        let mut len_buf = [0u8; 4];
+        file.read_exact(&mut len_buf)?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+        let mut data = vec![0u8; len];
+        file.read_exact(&mut data)?;
+
+        let size = RECORD_HEADER_SIZE + len as u64;
+        state.read_pos += size;
+
+        // Reclaim the file once everything has been replayed
+        if self.records.fetch_sub(1, Ordering::AcqRel) == 1 {
+            state.read_pos = 0;
+            state.write_pos = 0;
+            if let Some(file) = state.file.as_mut() {
+                file.set_len(0)?;
+            }
+        }
+        drop(state);
+
+        self.pool.release(size);
+        Ok(Some(Bytes::from(data)))
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        let remaining = state.write_pos - state.read_pos;
+        if remaining > 0 {
+            debug!(
+                path = %self.path.display(),
+                bytes = remaining,
+                "Discarding unreplayed spill data"
+            );
+            self.pool.release(remaining);
+        }
+        if state.file.take().is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// =============================================================================
+// Memory Budget
+// =============================================================================
+
+/// Bytes that all connections' in-memory queues may hold together
+/// (`--max-total-buffer-bytes`), across every session of the client.
+///
+/// Each queued chunk holds permits for its length until the connection takes
+/// it off the queue. Per-connection queue depths alone don't bound memory
+/// once many busy connections fan out. Nothing waits for the budget: data
+/// that doesn't fit spills to disk or is refused.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    permits: Arc<Semaphore>,
+    max_bytes: u32,
+}
+
+impl MemoryBudget {
+    pub fn new(max_bytes: u64) -> Self {
+        let max_bytes = max_bytes.clamp(1, u32::MAX.into()) as u32;
+        Self {
+            permits: Arc::new(Semaphore::new(max_bytes as usize)),
+            max_bytes,
+        }
+    }
+
+    /// Bytes not currently held by any queue
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    /// Permits for a `len`-byte chunk; a chunk larger than the whole budget
+    /// takes all of it rather than waiting forever
+    fn permits_for(&self, len: usize) -> u32 {
+        len.clamp(1, self.max_bytes as usize) as u32
+    }
+
+    fn try_acquire(&self, len: usize) -> Option<OwnedSemaphorePermit> {
+        self.permits
+            .clone()
+            .try_acquire_many_owned(self.permits_for(len))
+            .ok()
+    }
+}
+
+// =============================================================================
+// Channel Wrappers
+// =============================================================================
+
+/// A chunk in a connection's channel, with the budget it holds
+struct Queued {
+    data: Bytes,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Sending half of a connection's data queue, spilling to disk when full
+pub struct DataSender {
+    tx: mpsc::Sender<Queued>,
+    spill: Option<Arc<Spill>>,
+    budget: Option<Arc<MemoryBudget>>,
+}
+
+/// Receiving half of a connection's data queue, replaying spilled data in order
+pub struct DataReceiver {
+    rx: mpsc::Receiver<Queued>,
+    spill: Option<Arc<Spill>>,
+    /// Spill read still running on a blocking thread, kept when the future
+    /// waiting on it is dropped so the record isn't lost
+    popping: Option<JoinHandle<io::Result<Option<Bytes>>>>,
+    /// Reading the spill file failed; nothing more is received
+    failed: bool,
+}
+
+/// Create a data queue with the given in-memory depth, optional spill file
+/// and optional memory budget shared with other queues
+pub fn data_queue(
+    depth: usize,
+    spill: Option<Spill>,
+    budget: Option<Arc<MemoryBudget>>,
+) -> (DataSender, DataReceiver) {
+    let (tx, rx) = mpsc::channel(depth);
+    let spill = spill.map(Arc::new);
+    (
+        DataSender {
+            tx,
+            spill: spill.clone(),
+            budget,
+        },
+        DataReceiver {
+            rx,
+            spill,
+            popping: None,
+            failed: false,
+        },
+    )
+}
+
+/// Why data couldn't be queued for a connection
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The receiving side of the queue has gone away
+    Closed,
+    /// The shared memory budget has no room and there is no spill file to
+    /// take the data instead
+    BudgetExhausted,
+    /// Writing the spill file failed while earlier data was still on disk,
+    /// so the data can't be queued without overtaking it
+    SpillFailed,
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Closed => write!(f, "connection data queue closed"),
+            Self::BudgetExhausted => write!(f, "total buffer budget exhausted"),
+            Self::SpillFailed => write!(f, "failed to spill data to disk"),
+        }
+    }
+}
+
+impl DataSender {
+    /// Chunks waiting in memory for the receiver
+    pub fn queued(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    /// Whether the shared memory budget has no room for `len` more bytes
+    pub fn budget_exhausted(&self, len: usize) -> bool {
+        self.budget
+            .as_ref()
+            .is_some_and(|budget| budget.available() < budget.permits_for(len) as usize)
+    }
+
+    /// Queue data for the connection.
+    ///
+    /// Without a spill file this awaits channel capacity, and fails at once if
+    /// the memory budget has no room: waiting for that would hold up every
+    /// other connection. With one, data overflows to disk instead of blocking
+    /// on either; once anything has spilled, all later data goes to disk too
+    /// so ordering is preserved. If the disk budget is exhausted this waits
+    /// for channel capacity when nothing of this connection is on disk, and
+    /// otherwise for any spill file to free space. A failed write to disk
+    /// falls back to the channel only when nothing is on disk yet.
+    pub async fn send(&self, mut data: Bytes) -> Result<(), SendError> {
+        let Some(spill) = &self.spill else {
+            return self.send_in_memory(data).await;
+        };
+
+        loop {
+            if self.tx.is_closed() {
+                return Err(SendError::Closed);
+            }
+
+            let permit = match &self.budget {
+                Some(budget) => budget.try_acquire(data.len()).map(Some),
+                None => Some(None),
+            };
+            if let Some(permit) = permit.filter(|_| spill.is_empty()) {
+                match self.tx.try_send(Queued {
+                    data,
+                    _permit: permit,
+                }) {
+                    Ok(()) => return Ok(()),
+                    Err(TrySendError::Full(queued)) => data = queued.data,
+                    Err(TrySendError::Closed(_)) => return Err(SendError::Closed),
+                }
+            }
+
+            let freed = spill.pool.freed.notified();
+            let push = {
+                let (spill, data) = (spill.clone(), data.clone());
+                tokio::task::spawn_blocking(move || spill.push(&data))
+            };
+            match push.await.unwrap_or_else(|e| Err(io::Error::other(e))) {
+                Ok(true) => return Ok(()),
+                Ok(false) if spill.is_empty() => {
+                    // Other connections hold the disk budget, so there is
+                    // nothing of ours to wait for
+                    debug!(
+                        used = spill.pool.used(),
+                        "Spill budget exhausted, waiting for channel capacity"
+                    );
+                    return self.send_in_memory(data).await;
+                }
+                Ok(false) => {
+                    debug!(
+                        used = spill.pool.used(),
+                        "Spill budget exhausted, waiting for replay"
+                    );
+                    freed.await;
+                }
+                Err(e) if spill.is_empty() => {
+                    warn!(error = %e, "Failed to spill data to disk, blocking on channel");
+                    return self.send_in_memory(data).await;
+                }
+                Err(e) => {
+                    // In memory it would reach the connection ahead of the
+                    // data already on disk
+                    warn!(error = %e, "Failed to spill data to disk behind earlier data");
+                    return Err(SendError::SpillFailed);
+                }
+            }
+        }
+    }
+
+    /// Take memory budget for `data` if there is room, then wait for
+    /// channel capacity and queue it
+    async fn send_in_memory(&self, data: Bytes) -> Result<(), SendError> {
+        let permit = match &self.budget {
+            Some(budget) => Some(
+                budget
+                    .try_acquire(data.len())
+                    .ok_or(SendError::BudgetExhausted)?,
+            ),
+            None => None,
+        };
+        self.tx
+            .send(Queued {
+                data,
+                _permit: permit,
+            })
+            .await
+            .map_err(|_| SendError::Closed)
+    }
+}
+
+impl DataReceiver {
+    /// Whether spilled data was lost to a read error. `None` from
+    /// [`recv`](Self::recv) then means the stream is broken, not finished.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Receive the next chunk of data in order, or `None` once closed and
+    /// drained or once spilled data couldn't be read (see [`Self::failed`])
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        if self.failed {
+            return None;
+        }
+        let Some(spill) = self.spill.clone() else {
+            return self.rx.recv().await.map(|queued| queued.data);
+        };
+        // A record already read off disk predates anything queued since
+        if self.popping.is_some() {
+            return self.pop().await;
+        }
+
+        loop {
+            // Channel contents always predate anything in the spill file
+            match self.rx.try_recv() {
+                Ok(queued) => return Some(queued.data),
+                Err(TryRecvError::Disconnected) => return self.pop().await,
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let pushed = spill.pushed.notified();
+            if let Some(data) = self.pop().await {
+                return Some(data);
+            }
+            if self.failed {
+                return None;
+            }
+
+            tokio::select! {
+                queued = self.rx.recv() => match queued {
+                    Some(queued) => return Some(queued.data),
+                    None => return self.pop().await,
+                },
+                _ = pushed => {}
+            }
+        }
+    }
+
+    /// Take the next chunk if one is already queued, in memory or on disk,
+    /// without waiting for the runner to send more
+    pub async fn recv_queued(&mut self) -> Option<Bytes> {
+        if self.failed {
+            return None;
+        }
+        if self.popping.is_some() {
+            return self.pop().await;
+        }
+        match self.rx.try_recv() {
+            Ok(queued) => Some(queued.data),
+            Err(_) => self.pop().await,
+        }
+    }
+
+    /// Replay the oldest spilled record, if any
+    async fn pop(&mut self) -> Option<Bytes> {
+        let popping = match &mut self.popping {
+            Some(popping) => popping,
+            None => {
+                let spill = self.spill.clone().filter(|spill| !spill.is_empty())?;
+                self.popping
+                    .insert(tokio::task::spawn_blocking(move || spill.pop()))
+            }
+        };
+        let popped = popping.await;
+        self.popping = None;
+        match popped.unwrap_or_else(|e| Err(io::Error::other(e))) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!(error = %e, "Failed to read spilled data, dropping the rest");
+                self.failed = true;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Poll;
+
+    fn pool(max_bytes: u64) -> Arc<SpillPool> {
+        Arc::new(SpillPool::new(std::env::temp_dir(), max_bytes))
+    }
+
+    #[test]
+    fn test_spill_push_pop_order() {
+        let pool = pool(1 << 20);
+        let spill = Spill::new(pool.clone(), 1);
+
+        assert!(spill.push(b"first").unwrap());
+        assert!(spill.push(b"second").unwrap());
+        assert_eq!(pool.used(), 2 * RECORD_HEADER_SIZE + 11);
+
+        assert_eq!(spill.pop().unwrap().unwrap(), &b"first"[..]);
+        assert_eq!(spill.pop().unwrap().unwrap(), &b"second"[..]);
+        assert!(spill.pop().unwrap().is_none());
+        assert_eq!(pool.used(), 0);
+    }
+
+    #[test]
+    fn test_spill_respects_budget() {
+        let pool = pool(RECORD_HEADER_SIZE + 4);
+        let spill = Spill::new(pool.clone(), 2);
+
+        assert!(spill.push(b"abcd").unwrap());
+        assert!(!spill.push(b"e").unwrap());
+        spill.pop().unwrap();
+        assert!(spill.push(b"e").unwrap());
+    }
+
+    #[test]
+    fn test_spill_drop_releases_budget_and_file() {
+        let pool = pool(1 << 20);
+        let spill = Spill::new(pool.clone(), 3);
+        spill.push(b"leftover").unwrap();
+        let path = spill.path.clone();
+        assert!(path.exists());
+
+        drop(spill);
+        assert_eq!(pool.used(), 0);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflows_to_disk_in_order() {
+        let pool = pool(1 << 20);
+        let (tx, mut rx) = data_queue(2, Some(Spill::new(pool.clone(), 4)), None);
+
+        for i in 0..50u32 {
+            tx.send(Bytes::from(i.to_be_bytes().to_vec()))
+                .await
+                .unwrap();
+        }
+        assert!(pool.used() > 0);
+        drop(tx);
+
+        let mut received = Vec::new();
+        while let Some(data) = rx.recv().await {
+            received.push(u32::from_be_bytes(data[..].try_into().unwrap()));
+        }
+        assert_eq!(received, (0..50).collect::<Vec<_>>());
+        assert_eq!(pool.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_queue_waits_when_budget_exhausted() {
+        let pool = pool(2 * (RECORD_HEADER_SIZE + 1));
+        let (tx, mut rx) = data_queue(1, Some(Spill::new(pool, 5)), None);
+
+        let producer = tokio::spawn(async move {
+            for i in 0..20u8 {
+                tx.send(Bytes::from(vec![i])).await.unwrap();
+            }
+        });
+
+        let mut received = Vec::new();
+        while let Some(data) = rx.recv().await {
+            received.push(data[0]);
+        }
+        producer.await.unwrap();
+        assert_eq!(received, (0..20).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_spill_budget_held_elsewhere_falls_back_to_channel() {
+        let pool = pool(RECORD_HEADER_SIZE + 2);
+        let (tx_a, _rx_a) = data_queue(1, Some(Spill::new(pool.clone(), 7)), None);
+        tx_a.send(Bytes::from_static(b"a0")).await.unwrap();
+        tx_a.send(Bytes::from_static(b"a1")).await.unwrap();
+        assert_eq!(pool.used(), RECORD_HEADER_SIZE + 2);
+
+        // Another connection whose channel is full has nothing on disk to
+        // wait for, so it waits for its own reader instead of the pool
+        let (tx_b, mut rx_b) = data_queue(1, Some(Spill::new(pool.clone(), 8)), None);
+        tx_b.send(Bytes::from_static(b"b0")).await.unwrap();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(data) = rx_b.recv().await {
+                received.push(data);
+            }
+            received
+        });
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            tx_b.send(Bytes::from_static(b"b1")),
+        )
+        .await
+        .expect("a full pool must not stall other connections")
+        .unwrap();
+        drop(tx_b);
+        assert_eq!(reader.await.unwrap(), [&b"b0"[..], &b"b1"[..]]);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_recv_keeps_spilled_record() {
+        let pool = pool(1 << 20);
+        let (tx, mut rx) = data_queue(1, Some(Spill::new(pool, 9)), None);
+        for chunk in [&b"first"[..], b"second", b"third"] {
+            tx.send(Bytes::from_static(chunk)).await.unwrap();
+        }
+        assert_eq!(rx.recv().await.unwrap(), &b"first"[..]);
+
+        // Give up on a replay while it is read off disk, as a select! would
+        let mut rest = vec![&b"second"[..], b"third"];
+        {
+            let recv = rx.recv();
+            tokio::pin!(recv);
+            // Unless the read already finished
+            if let Poll::Ready(data) = futures_util::poll!(recv.as_mut()) {
+                assert_eq!(data.unwrap(), rest.remove(0));
+            }
+        }
+        for chunk in rest {
+            assert_eq!(rx.recv_queued().await.unwrap(), chunk);
+        }
+        assert_eq!(rx.recv_queued().await, None);
+    }
+
+    /// Swap the open spill file for a handle opened with `options`, so that
+    /// later reads or writes fail
+    fn reopen_spill_file(tx: &DataSender, options: &OpenOptions) {
+        let spill = tx.spill.as_ref().unwrap();
+        let file = options.open(&spill.path).unwrap();
+        spill.state.lock().unwrap().file = Some(file);
+    }
+
+    #[tokio::test]
+    async fn test_failed_spill_write_never_overtakes_disk() {
+        let pool = pool(1 << 20);
+        let (tx, mut rx) = data_queue(1, Some(Spill::new(pool, 10)), None);
+        tx.send(Bytes::from_static(b"first")).await.unwrap();
+        tx.send(Bytes::from_static(b"second")).await.unwrap();
+
+        reopen_spill_file(&tx, OpenOptions::new().read(true));
+        assert_eq!(
+            tx.send(Bytes::from_static(b"third")).await,
+            Err(SendError::SpillFailed)
+        );
+        reopen_spill_file(&tx, OpenOptions::new().read(true).write(true));
+        assert_eq!(rx.recv().await.unwrap(), &b"first"[..]);
+        assert_eq!(rx.recv().await.unwrap(), &b"second"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_spill_read_is_not_a_clean_end() {
+        let pool = pool(1 << 20);
+        let (tx, mut rx) = data_queue(1, Some(Spill::new(pool, 11)), None);
+        tx.send(Bytes::from_static(b"first")).await.unwrap();
+        tx.send(Bytes::from_static(b"second")).await.unwrap();
+
+        reopen_spill_file(&tx, OpenOptions::new().write(true));
+        drop(tx);
+        assert_eq!(rx.recv().await.unwrap(), &b"first"[..]);
+        assert!(!rx.failed());
+        assert_eq!(rx.recv().await, None);
+        assert!(rx.failed());
+        assert_eq!(rx.recv_queued().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_shared_across_queues() {
+        let budget = Arc::new(MemoryBudget::new(10));
+        let (tx_a, mut rx_a) = data_queue(8, None, Some(budget.clone()));
+        let (tx_b, _rx_b) = data_queue(8, None, Some(budget.clone()));
+
+        tx_a.send(Bytes::from_static(b"123456")).await.unwrap();
+        tx_b.send(Bytes::from_static(b"7890")).await.unwrap();
+        assert_eq!(budget.available(), 0);
+        assert!(tx_b.budget_exhausted(1));
+
+        // The next chunk is refused rather than waiting, until a queued one
+        // is taken off
+        assert_eq!(
+            tx_b.send(Bytes::from_static(b"x")).await,
+            Err(SendError::BudgetExhausted)
+        );
+        assert_eq!(rx_a.recv().await.unwrap(), &b"123456"[..]);
+        tx_b.send(Bytes::from_static(b"x")).await.unwrap();
+        assert_eq!(budget.available(), 5);
+
+        // A chunk larger than the whole budget still goes through
+        let (tx_c, mut rx_c) = data_queue(8, None, Some(Arc::new(MemoryBudget::new(4))));
+        tx_c.send(Bytes::from_static(b"too large")).await.unwrap();
+        assert_eq!(rx_c.recv().await.unwrap(), &b"too large"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_overflows_to_disk() {
+        let pool = pool(1 << 20);
+        let budget = Arc::new(MemoryBudget::new(4));
+        let (tx, mut rx) = data_queue(8, Some(Spill::new(pool.clone(), 6)), Some(budget));
+
+        tx.send(Bytes::from_static(b"abcd")).await.unwrap();
+        tx.send(Bytes::from_static(b"efgh")).await.unwrap();
+        assert!(pool.used() > 0);
+        assert_eq!(rx.recv().await.unwrap(), &b"abcd"[..]);
+        assert_eq!(rx.recv().await.unwrap(), &b"efgh"[..]);
+    }
+}
@@ -0,0 +1,228 @@
+//! Rate-limited logging for warnings a misbehaving runner can trigger once
+//! per frame.
+//!
+//! Each [`throttled!`] call site has its own token bucket: [`BURST`] lines
+//! get through at once, then one per [`REFILL`]. Lines dropped in between are
+//! counted, and the next one that gets through is preceded by a line carrying
+//! the count as `occurrences`, so a storm of DATA for an unknown connection
+//! costs a line a second instead of one per frame. If the storm stops first,
+//! the count is logged on its own once the next token is due, so it isn't
+//! held until the call site happens to log again.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Lines a call site logs before it is throttled
+pub const BURST: u32 = 10;
+
+/// Time for a throttled call site to earn another line
+pub const REFILL: Duration = Duration::from_secs(1);
+
+/// Token bucket of one call site
+pub struct Throttle {
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: u32,
+    /// When tokens were last added (None = never used)
+    refilled: Option<Instant>,
+    /// Lines dropped since the last one logged
+    suppressed: u64,
+    /// A task will report `suppressed` when the next token is due
+    report_armed: bool,
+}
+
+impl Throttle {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(Bucket {
+                tokens: BURST,
+                refilled: None,
+                suppressed: 0,
+                report_armed: false,
+            }),
+        }
+    }
+
+    /// Take a token. Returns how many lines were dropped since the last
+    /// one if this one may be logged, None if it is dropped.
+    pub fn admit(&self) -> Option<u64> {
+        let now = Instant::now();
+        let mut bucket = self.state.lock().unwrap();
+        let refilled = *bucket.refilled.get_or_insert(now);
+        let earned = (now.duration_since(refilled).as_nanos() / REFILL.as_nanos()) as u32;
+        if earned > 0 {
+            bucket.tokens = bucket.tokens.saturating_add(earned).min(BURST);
+            // A full bucket doesn't bank time towards the next token
+            bucket.refilled = Some(if bucket.tokens == BURST {
+                now
+            } else {
+                refilled + REFILL * earned
+            });
+        }
+        if bucket.tokens == 0 {
+            bucket.suppressed += 1;
+            return None;
+        }
+        bucket.tokens -= 1;
+        Some(std::mem::take(&mut bucket.suppressed))
+    }
+
+    /// After a dropped line, have `report` called with the count of dropped
+    /// lines once the next token is due, unless a logged line has carried it
+    /// by then. Does nothing outside a Tokio runtime.
+    pub fn report_suppressed(&'static self, report: fn(u64)) {
+        let mut bucket = self.state.lock().unwrap();
+        if bucket.report_armed || bucket.suppressed == 0 {
+            return;
+        }
+        let (Ok(runtime), Some(refilled)) =
+            (tokio::runtime::Handle::try_current(), bucket.refilled)
+        else {
+            return;
+        };
+        bucket.report_armed = true;
+        runtime.spawn(async move {
+            tokio::time::sleep_until(refilled + REFILL).await;
+            let suppressed = {
+                let mut bucket = self.state.lock().unwrap();
+                bucket.report_armed = false;
+                std::mem::take(&mut bucket.suppressed)
+            };
+            if suppressed > 0 {
+                report(suppressed);
+            }
+        });
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Log through `tracing` at most [`BURST`] times at once and once per
+/// [`REFILL`] after that, e.g. `throttled!(warn!(client_id, "DATA for
+/// unknown connection"))`
+macro_rules! throttled {
+    ($level:ident!($($arg:tt)+)) => {{
+        static THROTTLE: $crate::throttle::Throttle = $crate::throttle::Throttle::new();
+        match THROTTLE.admit() {
+            Some(occurrences) => {
+                if occurrences > 0 {
+                    tracing::$level!(occurrences, "Repeats of the next message were suppressed");
+                }
+                tracing::$level!($($arg)+);
+            }
+            None => THROTTLE.report_suppressed(|occurrences| {
+                tracing::$level!(
+                    occurrences,
+                    site = concat!(file!(), ":", line!()),
+                    "Repeats of a throttled message were suppressed"
+                )
+            }),
+        }
+    }};
+}
+
+pub(crate) use throttled;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flood_is_suppressed() {
+        let throttle = Throttle::new();
+        let logged = (0..10_000).filter(|_| throttle.admit().is_some()).count();
+        assert_eq!(logged, BURST as usize);
+
+        // One line a second, carrying the count of those dropped
+        tokio::time::advance(REFILL).await;
+        assert_eq!(throttle.admit(), Some(10_000 - BURST as u64));
+        assert_eq!(throttle.admit(), None);
+        tokio::time::advance(REFILL / 2).await;
+        assert_eq!(throttle.admit(), None);
+        tokio::time::advance(REFILL / 2).await;
+        assert_eq!(throttle.admit(), Some(2));
+
+        // A quiet spell refills the burst, but no further
+        tokio::time::advance(REFILL * 60).await;
+        let logged = (0..100).filter(|_| throttle.admit().is_some()).count();
+        assert_eq!(logged, BURST as usize);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled_collapses_repeats() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let flood = |frames: u32| {
+            for client_id in 0..frames {
+                throttled!(warn!(client_id, "DATA for unknown connection"));
+            }
+        };
+        flood(1000);
+        tokio::time::advance(REFILL).await;
+        flood(1);
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), BURST as usize + 2);
+        assert!(lines[..BURST as usize]
+            .iter()
+            .all(|line| line.contains("DATA for unknown connection")));
+        assert!(lines[BURST as usize].contains(&format!("occurrences={}", 1000 - BURST)));
+        assert!(lines[BURST as usize + 1].contains("DATA for unknown connection client_id=0"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_suppressed_count_reported_after_flood_stops() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        for client_id in 0..100 {
+            throttled!(warn!(client_id, "DATA for unknown connection"));
+        }
+        let lines = || String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(lines().lines().count(), BURST as usize);
+
+        // Nothing else is logged from that call site, but the count still
+        // comes out once the next token is due
+        tokio::time::sleep(REFILL * 2).await;
+        let output = lines();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), BURST as usize + 1);
+        assert!(lines[BURST as usize].contains(&format!("occurrences={}", 100 - BURST)));
+        assert!(lines[BURST as usize].contains("throttle.rs"));
+    }
+
+    /// Collects formatted log output
+    #[derive(Clone, Default)]
+    struct Captured(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
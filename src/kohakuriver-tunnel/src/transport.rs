@@ -0,0 +1,669 @@
+//! Transports carrying tunnel protocol frames to and from the runner.
+//!
+//! The message loop and the connection tasks only see [`TransportSink`] and
+//! [`TransportStream`], so the frames can travel over a WebSocket (the
+//! default) or an HTTP/2 stream (see [`crate::http2`]) for networks that block
+//! WebSockets. [`memory`] connects an in-process transport for tests.
+
+use std::future::poll_fn;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Waker};
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{Sink, SinkExt, StreamExt};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::{debug, info, trace};
+use url::Url;
+
+use crate::auth;
+use crate::dial::{self, WsStream};
+use crate::error::{Result, TunnelError};
+use crate::http2;
+use crate::metrics::Metrics;
+use crate::task::AbortOnDrop;
+use crate::tunnel::TunnelConfig;
+
+/// Which transport carries the tunnel protocol
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TransportKind {
+    /// Binary WebSocket messages
+    #[default]
+    Websocket,
+    /// A long-lived HTTP/2 POST with length-prefixed frames in both directions
+    H2,
+}
+
+/// Something received from the runner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incoming {
+    /// A tunnel protocol frame
+    Frame(Bytes),
+    /// Transport keepalive from the runner; answer with [`TransportSink::pong`]
+    Ping(Bytes),
+    /// Reply to [`TransportSink::ping`], echoing its payload
+    Pong(Bytes),
+    /// Control message (a WebSocket text frame), see [`crate::control`]
+    Text(String),
+}
+
+/// Sending half of a transport
+pub trait TransportSink: Send {
+    /// Send one tunnel protocol frame
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>>;
+
+    /// Send a transport keepalive; the runner echoes the payload back
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>>;
+
+    /// Answer a keepalive from the runner
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>>;
+
+    /// Send a control message. Transports without a text channel never
+    /// receive control commands and drop it.
+    fn text(&mut self, text: String) -> BoxFuture<'_, Result<()>> {
+        debug!(
+            len = text.len(),
+            "No text channel, dropping control message"
+        );
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Receiving half of a transport
+pub trait TransportStream: Send {
+    /// Next item from the runner; None once the transport is closed
+    fn recv(&mut self) -> BoxFuture<'_, Option<Result<Incoming>>>;
+}
+
+/// Sending half shared by the message loop and every connection task
+pub type TransportSender = Arc<Mutex<Box<dyn TransportSink>>>;
+
+/// Both halves of a freshly connected transport
+pub type TransportPair = (Box<dyn TransportSink>, Box<dyn TransportStream>);
+
+/// Connect to the runner over the configured transport, also returning the
+/// runner's auth nonce header if it sent one (see [`crate::auth`])
+pub async fn connect(url: &Url, config: &TunnelConfig) -> Result<(TransportPair, Option<String>)> {
+    // Checked at startup; failing now means the files changed since, which
+    // a later attempt may not see
+    let identity = config
+        .load_client_identity()
+        .map_err(|e| TunnelError::ConnectFailed(e.to_string().into()))?;
+    match config.transport {
+        TransportKind::Websocket => {
+            let (ws_stream, response) = dial::dial(
+                url,
+                config.dial_family,
+                config.dial_attempt_timeout,
+                &config.user_agent(),
+                config.ws_config()?,
+                identity.as_ref(),
+            )
+            .await?;
+            info!(status = %response.status(), "WebSocket connected");
+            let nonce = auth::nonce_header(response.headers());
+            Ok((websocket(ws_stream), nonce))
+        }
+        TransportKind::H2 => {
+            http2::connect(
+                url,
+                config.dial_family,
+                config.dial_attempt_timeout,
+                &config.user_agent(),
+                identity.as_ref(),
+            )
+            .await
+        }
+    }
+}
+
+/// Sink wrapper counting the frame bytes sent to the runner
+pub struct MeteredSink {
+    inner: Box<dyn TransportSink>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredSink {
+    pub fn new(inner: Box<dyn TransportSink>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl TransportSink for MeteredSink {
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        self.metrics.traffic().record_out(frame.len());
+        self.inner.send(frame)
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        self.inner.ping(payload)
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        self.inner.pong(payload)
+    }
+
+    fn text(&mut self, text: String) -> BoxFuture<'_, Result<()>> {
+        self.inner.text(text)
+    }
+}
+
+/// Raised once any send on a session's transport fails.
+///
+/// None of the transports recover from a failed send, so the transport is
+/// dead for every connection sharing it. The message loop waits on this to
+/// tear the session down and reconnect, rather than leaving each connection
+/// to find out on its next send.
+#[derive(Debug, Clone, Default)]
+pub struct SendFailure(Arc<SendFailureState>);
+
+#[derive(Debug, Default)]
+struct SendFailureState {
+    failed: AtomicBool,
+    notify: Notify,
+}
+
+impl SendFailure {
+    fn raise(&self) {
+        self.0.failed.store(true, Ordering::Release);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.0.failed.load(Ordering::Acquire)
+    }
+
+    /// Resolves once a send has failed
+    pub async fn wait(&self) {
+        // Registered before the check, so a raise in between isn't missed
+        let notified = self.0.notify.notified();
+        if self.has_failed() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Sink wrapper raising a [`SendFailure`] when anything fails to send
+pub struct WatchedSink {
+    inner: Box<dyn TransportSink>,
+    failure: SendFailure,
+}
+
+impl WatchedSink {
+    pub fn new(inner: Box<dyn TransportSink>, failure: SendFailure) -> Self {
+        Self { inner, failure }
+    }
+}
+
+fn watch<'a>(
+    failure: &'a SendFailure,
+    send: BoxFuture<'a, Result<()>>,
+) -> BoxFuture<'a, Result<()>> {
+    Box::pin(async move {
+        let result = send.await;
+        if result.is_err() {
+            failure.raise();
+        }
+        result
+    })
+}
+
+impl TransportSink for WatchedSink {
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        watch(&self.failure, self.inner.send(frame))
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        watch(&self.failure, self.inner.ping(payload))
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        watch(&self.failure, self.inner.pong(payload))
+    }
+
+    fn text(&mut self, text: String) -> BoxFuture<'_, Result<()>> {
+        watch(&self.failure, self.inner.text(text))
+    }
+}
+
+// =============================================================================
+// WebSocket
+// =============================================================================
+
+/// Most yields the flusher spends waiting for more messages before flushing
+const WS_FLUSH_BATCH: usize = 64;
+
+/// Split a WebSocket into transport halves
+pub fn websocket(ws_stream: WsStream) -> TransportPair {
+    let (sink, stream) = ws_stream.split();
+    (Box::new(WsSink::spawn(sink)), Box::new(WsSource(stream)))
+}
+
+/// Sending half of a WebSocket transport.
+///
+/// Senders feed messages into the WebSocket without flushing, and a flusher
+/// task flushes once they stop coming, so a burst of small frames from many
+/// connections leaves in a few writes while a lone frame still goes out
+/// straight away. Feeding waits for `poll_ready`, so when the runner link
+/// can't keep up senders wait, pushing back on the connections reading
+/// local sockets instead of piling up in the WebSocket write buffer.
+///
+/// A failed flush is recorded and the WebSocket closed, so the link goes
+/// down for the receiving half too; later sends fail with the recorded
+/// reason. Dropping the handle stops the flusher.
+pub struct WsSink<S = SplitSink<WsStream, Message>>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    shared: Arc<WsShared<S>>,
+    _flusher: AbortOnDrop<()>,
+}
+
+struct WsShared<S> {
+    sink: Mutex<S>,
+    /// Fed messages are waiting for a flush
+    unflushed: Notify,
+    /// Messages fed so far, to tell when senders have stopped
+    fed: AtomicU64,
+    /// Why the last flush failed, once one has
+    failed: StdMutex<Option<String>>,
+}
+
+impl<S> WsSink<S>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+{
+    /// Wrap `sink`, starting its flusher task
+    pub fn spawn(sink: S) -> Self {
+        let shared = Arc::new(WsShared {
+            sink: Mutex::new(sink),
+            unflushed: Notify::new(),
+            fed: AtomicU64::new(0),
+            failed: StdMutex::new(None),
+        });
+        let flusher = AbortOnDrop::spawn(flush_ws(shared.clone()));
+        Self {
+            shared,
+            _flusher: flusher,
+        }
+    }
+
+    async fn feed(&mut self, msg: Message) -> Result<()> {
+        if let Some(reason) = self.shared.failed.lock().unwrap().clone() {
+            return Err(TunnelError::ConnectionLost(reason));
+        }
+        feed_ws(&mut *self.shared.sink.lock().await, msg).await?;
+        self.shared.fed.fetch_add(1, Ordering::Relaxed);
+        self.shared.unflushed.notify_one();
+        Ok(())
+    }
+}
+
+impl<S> Drop for WsSink<S>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    fn drop(&mut self) {
+        // The flusher goes with the handle, so write out whatever the socket
+        // takes right away rather than leave the last frames unflushed
+        if let Ok(mut sink) = self.shared.sink.try_lock() {
+            let _ = sink.poll_flush_unpin(&mut Context::from_waker(Waker::noop()));
+        }
+    }
+}
+
+impl<S> TransportSink for WsSink<S>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+{
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.feed(Message::Binary(frame.to_vec())))
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.feed(Message::Ping(payload.to_vec())))
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.feed(Message::Pong(payload.to_vec())))
+    }
+
+    fn text(&mut self, text: String) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.feed(Message::Text(text)))
+    }
+}
+
+/// Flush whatever senders fed since the last flush, until a flush fails;
+/// the task is aborted when the handle is dropped
+async fn flush_ws<S>(shared: Arc<WsShared<S>>)
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    loop {
+        shared.unflushed.notified().await;
+        // Hold off while senders keep feeding, so a burst goes out in one
+        // flush, but no longer than a batch's worth
+        let mut fed = shared.fed.load(Ordering::Relaxed);
+        for _ in 0..WS_FLUSH_BATCH {
+            tokio::task::yield_now().await;
+            let now = shared.fed.load(Ordering::Relaxed);
+            if now == fed {
+                break;
+            }
+            fed = now;
+        }
+        let mut sink = shared.sink.lock().await;
+        if let Err(e) = sink.flush().await {
+            debug!(error = %e, "WebSocket flush failed, closing it");
+            *shared.failed.lock().unwrap() = Some(format!("WebSocket send failed: {}", e));
+            let _ = sink.close().await;
+            return;
+        }
+    }
+}
+
+/// Hand one message to the sink once it is ready to take it
+async fn feed_ws<S>(sink: &mut S, msg: Message) -> std::result::Result<(), tungstenite::Error>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let mut stalled = false;
+    poll_fn(|cx| {
+        let ready = sink.poll_ready_unpin(cx);
+        if ready.is_pending() && !stalled {
+            stalled = true;
+            trace!("WebSocket not ready, holding back egress");
+        }
+        ready
+    })
+    .await?;
+    sink.start_send_unpin(msg)
+}
+
+/// Receiving half of a WebSocket transport
+pub struct WsSource(pub SplitStream<WsStream>);
+
+impl TransportStream for WsSource {
+    fn recv(&mut self) -> BoxFuture<'_, Option<Result<Incoming>>> {
+        Box::pin(async move {
+            loop {
+                let msg = match self.0.next().await? {
+                    Ok(msg) => msg,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                match msg {
+                    Message::Binary(data) => return Some(Ok(Incoming::Frame(data.into()))),
+                    Message::Ping(data) => return Some(Ok(Incoming::Ping(data.into()))),
+                    Message::Pong(data) => return Some(Ok(Incoming::Pong(data.into()))),
+                    Message::Text(text) => return Some(Ok(Incoming::Text(text))),
+                    Message::Close(frame) => {
+                        info!(?frame, "WebSocket closed by server");
+                        return None;
+                    }
+                    Message::Frame(_) => {
+                        // Raw frame, usually not received
+                    }
+                }
+            }
+        })
+    }
+}
+
+// =============================================================================
+// In-memory
+// =============================================================================
+
+/// Connect an in-memory transport, e.g. to drive a
+/// [`ConnectionManager`](crate::connection::ConnectionManager) in tests.
+///
+/// Returns the tunnel's halves and the runner's end. `capacity` bounds the
+/// items in flight in each direction, so a slow peer applies backpressure.
+pub fn memory(capacity: usize) -> (TransportPair, MemoryPeer) {
+    let (to_runner, from_tunnel) = mpsc::channel(capacity.max(1));
+    let (to_tunnel, from_runner) = mpsc::channel(capacity.max(1));
+    (
+        (
+            Box::new(MemorySink(to_runner)),
+            Box::new(MemorySource(from_runner)),
+        ),
+        MemoryPeer {
+            tx: to_tunnel,
+            rx: from_tunnel,
+        },
+    )
+}
+
+/// Sending half of an in-memory transport
+pub struct MemorySink(mpsc::Sender<Incoming>);
+
+impl MemorySink {
+    async fn deliver(&mut self, item: Incoming) -> Result<()> {
+        self.0
+            .send(item)
+            .await
+            .map_err(|_| TunnelError::ConnectionLost("in-memory peer dropped".into()))
+    }
+}
+
+impl TransportSink for MemorySink {
+    fn send(&mut self, frame: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.deliver(Incoming::Frame(frame)))
+    }
+
+    fn ping(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.deliver(Incoming::Ping(payload)))
+    }
+
+    fn pong(&mut self, payload: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.deliver(Incoming::Pong(payload)))
+    }
+
+    fn text(&mut self, text: String) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.deliver(Incoming::Text(text)))
+    }
+}
+
+/// Receiving half of an in-memory transport
+pub struct MemorySource(mpsc::Receiver<Incoming>);
+
+impl TransportStream for MemorySource {
+    fn recv(&mut self) -> BoxFuture<'_, Option<Result<Incoming>>> {
+        Box::pin(async move { self.0.recv().await.map(Ok) })
+    }
+}
+
+/// The runner's end of an in-memory transport; dropping it closes the
+/// transport for the tunnel
+pub struct MemoryPeer {
+    tx: mpsc::Sender<Incoming>,
+    rx: mpsc::Receiver<Incoming>,
+}
+
+impl MemoryPeer {
+    /// Deliver an item to the tunnel; false if the tunnel side is gone
+    pub async fn send(&self, item: Incoming) -> bool {
+        self.tx.send(item).await.is_ok()
+    }
+
+    /// Next item sent by the tunnel; None once every tunnel sink is dropped
+    pub async fn recv(&mut self) -> Option<Incoming> {
+        self.rx.recv().await
+    }
+
+    /// Stop accepting items from the tunnel, so its sends fail while the
+    /// runner can still deliver
+    pub fn close_receiver(&mut self) {
+        self.rx.close();
+    }
+
+    /// Next tunnel protocol frame, skipping keepalives and control messages
+    pub async fn next_frame(&mut self) -> Option<Bytes> {
+        loop {
+            match self.recv().await? {
+                Incoming::Frame(frame) => return Some(frame),
+                Incoming::Ping(_) | Incoming::Pong(_) | Incoming::Text(_) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_transport_roundtrip() {
+        let ((mut sink, mut stream), mut peer) = memory(4);
+
+        sink.ping(Bytes::from_static(b"ts")).await.unwrap();
+        sink.send(Bytes::from_static(b"frame")).await.unwrap();
+        assert_eq!(
+            peer.recv().await,
+            Some(Incoming::Ping(Bytes::from_static(b"ts")))
+        );
+        assert_eq!(peer.next_frame().await, Some(Bytes::from_static(b"frame")));
+
+        assert!(peer.send(Incoming::Pong(Bytes::from_static(b"ts"))).await);
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            Incoming::Pong(Bytes::from_static(b"ts"))
+        );
+
+        // A failed send raises the signal of a watched sink
+        let failure = SendFailure::default();
+        let ((sink, _stream), mut peer) = memory(4);
+        let mut watched = WatchedSink::new(sink, failure.clone());
+        watched.send(Bytes::new()).await.unwrap();
+        assert!(!failure.has_failed());
+        peer.close_receiver();
+        assert!(watched.send(Bytes::new()).await.is_err());
+        failure.wait().await;
+        assert!(failure.has_failed());
+    }
+
+    /// WebSocket sink that takes messages only while `open`, recording what
+    /// it was fed and how often it was flushed, and failing flushes once
+    /// `broken`
+    #[derive(Default)]
+    struct GatedSink {
+        open: Arc<AtomicBool>,
+        broken: Arc<AtomicBool>,
+        closed: Arc<AtomicBool>,
+        fed: Arc<StdMutex<Vec<Message>>>,
+        flushes: Arc<AtomicUsize>,
+        waker: Arc<StdMutex<Option<std::task::Waker>>>,
+    }
+
+    impl Sink<Message> for GatedSink {
+        type Error = tungstenite::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            if self.open.load(Ordering::SeqCst) {
+                return Poll::Ready(Ok(()));
+            }
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            self.fed.lock().unwrap().push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            if self.broken.load(Ordering::SeqCst) {
+                return Poll::Ready(Err(tungstenite::Error::ConnectionClosed));
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.closed.store(true, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_sink_batches_flushes_and_waits_for_readiness() {
+        let gated = GatedSink::default();
+        let (open, fed, flushes, waker) = (
+            gated.open.clone(),
+            gated.fed.clone(),
+            gated.flushes.clone(),
+            gated.waker.clone(),
+        );
+        let mut sink = WsSink::spawn(gated);
+
+        // Not ready: the sender waits and nothing is fed
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(50),
+            sink.send(Bytes::from_static(b"x")),
+        )
+        .await;
+        assert!(blocked.is_err());
+        assert!(fed.lock().unwrap().is_empty());
+
+        // Once ready, a burst goes out in a few flushes (the sender may be
+        // made to yield partway through by tokio's task budget)
+        open.store(true, Ordering::SeqCst);
+        waker.lock().unwrap().take().unwrap().wake();
+        for _ in 0..100 {
+            sink.send(Bytes::from_static(b"x")).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(fed.lock().unwrap().len(), 100);
+        let burst_flushes = flushes.load(Ordering::SeqCst);
+        assert!(burst_flushes <= 3, "{} flushes", burst_flushes);
+
+        // A lone message is flushed without waiting for more
+        sink.ping(Bytes::from_static(b"ts")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(matches!(fed.lock().unwrap().last(), Some(Message::Ping(_))));
+        assert_eq!(flushes.load(Ordering::SeqCst), burst_flushes + 1);
+    }
+
+    #[tokio::test]
+    async fn test_ws_sink_closes_on_failed_flush() {
+        let gated = GatedSink::default();
+        gated.open.store(true, Ordering::SeqCst);
+        let (broken, closed) = (gated.broken.clone(), gated.closed.clone());
+        let mut sink = WsSink::spawn(gated);
+        sink.send(Bytes::from_static(b"x")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!closed.load(Ordering::SeqCst));
+
+        // The flusher closes the sink itself, without waiting for a send
+        broken.store(true, Ordering::SeqCst);
+        sink.send(Bytes::from_static(b"x")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(closed.load(Ordering::SeqCst));
+        assert!(matches!(
+            sink.send(Bytes::from_static(b"x")).await,
+            Err(TunnelError::ConnectionLost(reason)) if reason.starts_with("WebSocket send failed")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_memory_peer_drop_closes_transport() {
+        let ((mut sink, mut stream), peer) = memory(4);
+
+        // Dropping the peer closes both directions for the tunnel
+        drop(peer);
+        assert!(stream.recv().await.is_none());
+        assert!(matches!(
+            sink.send(Bytes::new()).await,
+            Err(TunnelError::ConnectionLost(_))
+        ));
+    }
+}
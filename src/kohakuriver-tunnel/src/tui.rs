@@ -0,0 +1,312 @@
+//! Live terminal dashboard (`--tui`, behind the `tui` feature).
+//!
+//! Redraws once a second from the client's [`Metrics`]: whether a session is
+//! up, how often it reconnected, the bytes relayed so far, and every open
+//! connection with its throughput since the previous redraw. Log lines go to
+//! a pane at the bottom (see [`LogBuffer`]) so they don't tear up the screen.
+//! `q`, Esc or Ctrl-C closes the dashboard.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::metrics::Metrics;
+
+/// How often the dashboard redraws
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Log lines kept for the log pane
+const LOG_LINES: usize = 200;
+
+/// Recent log lines, written by the tracing subscriber and shown in the
+/// dashboard's log pane
+#[derive(Debug, Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<VecDeque<String>>>);
+
+impl LogBuffer {
+    fn push(&self, line: &str) {
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() == LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
+
+    /// The last `n` lines, oldest first
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        let lines = self.0.lock().unwrap();
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+}
+
+/// One log event being formatted; its lines go to the buffer when dropped
+pub struct LogWriter {
+    buffer: LogBuffer,
+    pending: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        for line in String::from_utf8_lossy(&self.pending).lines() {
+            self.buffer.push(line);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBuffer {
+    type Writer = LogWriter;
+
+    fn make_writer(&'a self) -> LogWriter {
+        LogWriter {
+            buffer: self.clone(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Format a byte count with a binary unit, e.g. `1.5 MiB`
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0} {}", UNITS[0])
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Dashboard state carried between redraws
+struct Dashboard {
+    metrics: Arc<Metrics>,
+    logs: LogBuffer,
+    /// Byte counts per open connection at the previous redraw
+    previous: HashMap<u64, (u64, u64)>,
+    last_draw: Instant,
+}
+
+impl Dashboard {
+    fn new(metrics: Arc<Metrics>, logs: LogBuffer) -> Self {
+        Self {
+            metrics,
+            logs,
+            previous: HashMap::new(),
+            last_draw: Instant::now(),
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let now = Instant::now();
+        let elapsed = (now - self.last_draw).as_secs_f64().max(1e-3);
+        self.last_draw = now;
+
+        let metrics = &self.metrics;
+        let connections = metrics.open_connections();
+        let [summary_area, table_area, log_area] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Min(5),
+            Constraint::Length(8),
+        ])
+        .areas(frame.area());
+
+        let (state, color) = if metrics.is_connected() {
+            ("connected", Color::Green)
+        } else {
+            ("disconnected", Color::Red)
+        };
+        let rtt = metrics.smoothed_rtt().map_or("-".to_string(), |rtt| {
+            format!("{:.1} ms", rtt.as_secs_f64() * 1e3)
+        });
+        let summary = vec![
+            Line::from(vec![
+                "State: ".into(),
+                Span::styled(state, Style::default().fg(color)),
+                format!("   Reconnects: {}   RTT: {rtt}", metrics.reconnects()).into(),
+            ]),
+            Line::from(format!(
+                "Open connections: {}   Closed: {}   Relayed: {}",
+                connections.len(),
+                metrics.closed_connections(),
+                human_bytes(metrics.relayed_bytes() as f64)
+            )),
+        ];
+        frame.render_widget(
+            Paragraph::new(summary).block(Block::default().borders(Borders::ALL).title("Tunnel")),
+            summary_area,
+        );
+
+        let mut current = HashMap::with_capacity(connections.len());
+        let rows: Vec<Row> = connections
+            .iter()
+            .map(|conn| {
+                current.insert(conn.key, (conn.bytes_in, conn.bytes_out));
+                let (prev_in, prev_out) = self.previous.get(&conn.key).copied().unwrap_or_default();
+                let rate = |now: u64, before: u64| {
+                    format!(
+                        "{}/s",
+                        human_bytes(now.saturating_sub(before) as f64 / elapsed)
+                    )
+                };
+                Row::new(vec![
+                    conn.client_id.to_string(),
+                    conn.proto.to_string(),
+                    conn.port.to_string(),
+                    human_bytes(conn.bytes_in as f64),
+                    human_bytes(conn.bytes_out as f64),
+                    rate(conn.bytes_in, prev_in),
+                    rate(conn.bytes_out, prev_out),
+                    format!("{:.0?}", Duration::from_secs(conn.age.as_secs())),
+                ])
+            })
+            .collect();
+        self.previous = current;
+        let header = Row::new([
+            "Client", "Proto", "Port", "In", "Out", "In/s", "Out/s", "Age",
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let widths = [
+            Constraint::Length(10),
+            Constraint::Length(6),
+            Constraint::Length(6),
+            Constraint::Length(11),
+            Constraint::Length(11),
+            Constraint::Length(13),
+            Constraint::Length(13),
+            Constraint::Min(6),
+        ];
+        frame.render_widget(
+            Table::new(rows, widths).header(header).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Connections (q to quit)"),
+            ),
+            table_area,
+        );
+
+        let lines = self.logs.tail(log_area.height.saturating_sub(2) as usize);
+        frame.render_widget(
+            Paragraph::new(lines.into_iter().map(Line::from).collect::<Vec<_>>())
+                .block(Block::default().borders(Borders::ALL).title("Log")),
+            log_area,
+        );
+    }
+}
+
+/// Show the dashboard until the user quits it or `stop` is set (checked on
+/// every redraw). Blocks the calling thread, so run it with
+/// [`tokio::task::spawn_blocking`].
+pub fn run(metrics: Arc<Metrics>, logs: LogBuffer, stop: Arc<AtomicBool>) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = redraw_until_quit(&mut terminal, Dashboard::new(metrics, logs), &stop);
+    ratatui::restore();
+    result
+}
+
+fn redraw_until_quit(
+    terminal: &mut DefaultTerminal,
+    mut dashboard: Dashboard,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    while !stop.load(Ordering::Relaxed) {
+        terminal.draw(|frame| dashboard.draw(frame))?;
+        let deadline = Instant::now() + REFRESH_INTERVAL;
+        while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+            if !event::poll(wait)? {
+                break;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            // Raw mode turns Ctrl-C into a key press rather than SIGINT
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.kind == KeyEventKind::Press
+                && (ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc))
+            {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(0.0), "0 B");
+        assert_eq!(human_bytes(1023.0), "1023 B");
+        assert_eq!(human_bytes(1536.0), "1.5 KiB");
+        assert_eq!(human_bytes(3.0 * (1u64 << 30) as f64), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_log_buffer_keeps_recent_lines() {
+        let logs = LogBuffer::default();
+        for i in 0..LOG_LINES + 5 {
+            let mut writer = logs.make_writer();
+            writeln!(writer, "line {i}").unwrap();
+        }
+        assert_eq!(logs.tail(2), vec!["line 203", "line 204"]);
+        assert_eq!(logs.tail(usize::MAX).len(), LOG_LINES);
+    }
+
+    #[test]
+    fn test_dashboard_renders_state_and_logs() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.session_started();
+        metrics.session_ended();
+        metrics.session_started();
+        let logs = LogBuffer::default();
+        logs.push("Connected to runner");
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        let mut dashboard = Dashboard::new(metrics, logs);
+        terminal.draw(|frame| dashboard.draw(frame)).unwrap();
+
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("State: connected"));
+        assert!(screen.contains("Reconnects: 1"));
+        assert!(screen.contains("Client"));
+        assert!(screen.contains("Connected to runner"));
+    }
+}
@@ -0,0 +1,306 @@
+//! Fan-out of a single requested port across a pool of local upstreams.
+//!
+//! A pool such as `80=[3001,3002,3003]` spreads CONNECTs for port 80 over
+//! several local processes. The selection policy orders the candidates; the
+//! connection handler tries them in that order and falls through to the next
+//! one if a connect fails. The first candidate counts as active from the
+//! moment it is picked, so a burst of CONNECTs spreads out under least-conn
+//! instead of all landing on the upstream that was idle when they arrived.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+
+/// How the first upstream is chosen for each new connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum UpstreamPolicy {
+    /// Rotate through the upstreams in order
+    #[default]
+    RoundRobin,
+    /// Prefer the upstream with the fewest active connections
+    LeastConn,
+}
+
+/// Pool definition: a requested port and the local ports serving it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamPoolSpec {
+    pub port: u16,
+    pub upstreams: Vec<u16>,
+}
+
+impl FromStr for UpstreamPoolSpec {
+    type Err = String;
+
+    /// Parse `PORT=[UP1,UP2,...]` (brackets optional)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, list) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid upstream pool {:?} (expected PORT=[P1,P2])", s))?;
+        let port: u16 = port
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid port in upstream pool {:?}", s))?;
+
+        let list = list.trim();
+        let list = list
+            .strip_prefix('[')
+            .and_then(|l| l.strip_suffix(']'))
+            .unwrap_or(list);
+        let upstreams = list
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                p.parse::<u16>()
+                    .map_err(|_| format!("Invalid upstream port {:?} in {:?}", p, s))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if upstreams.is_empty() {
+            return Err(format!("Upstream pool {:?} has no upstreams", s));
+        }
+        if let Some(dup) = upstreams
+            .iter()
+            .enumerate()
+            .find_map(|(i, p)| upstreams[..i].contains(p).then_some(p))
+        {
+            return Err(format!("Upstream port {} listed twice in {:?}", dup, s));
+        }
+
+        Ok(Self { port, upstreams })
+    }
+}
+
+/// Runtime state of one pool
+#[derive(Debug)]
+struct Pool {
+    ports: Vec<u16>,
+    active: Vec<AtomicUsize>,
+    next: AtomicUsize,
+}
+
+/// All configured pools plus the selection policy
+#[derive(Debug, Default)]
+pub struct UpstreamPools {
+    pools: HashMap<u16, Arc<Pool>>,
+    policy: UpstreamPolicy,
+}
+
+impl UpstreamPools {
+    pub fn new(specs: &[UpstreamPoolSpec], policy: UpstreamPolicy) -> Self {
+        let pools = specs
+            .iter()
+            .map(|spec| {
+                let pool = Pool {
+                    ports: spec.upstreams.clone(),
+                    active: spec.upstreams.iter().map(|_| AtomicUsize::new(0)).collect(),
+                    next: AtomicUsize::new(0),
+                };
+                (spec.port, Arc::new(pool))
+            })
+            .collect();
+        Self { pools, policy }
+    }
+
+    /// Candidate upstreams for a requested port, in the order to try them.
+    /// The first one is reserved as active until the connection settles.
+    pub fn select(&self, port: u16) -> Option<Candidates> {
+        let pool = self.pools.get(&port)?;
+        let n = pool.ports.len();
+
+        let first = match self.policy {
+            UpstreamPolicy::RoundRobin => {
+                let first = pool.next.fetch_add(1, Ordering::Relaxed) % n;
+                pool.active[first].fetch_add(1, Ordering::Relaxed);
+                first
+            }
+            // Claim the least loaded upstream in the same step as picking
+            // it; retry if another CONNECT changed its count in between
+            UpstreamPolicy::LeastConn => loop {
+                let (i, load) = (0..n)
+                    .map(|i| (i, pool.active[i].load(Ordering::Relaxed)))
+                    .min_by_key(|&(_, load)| load)
+                    .unwrap_or((0, 0));
+                if pool.active[i]
+                    .compare_exchange(load, load + 1, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break i;
+                }
+            },
+        };
+
+        let reserved = ActiveGuard {
+            pool: pool.clone(),
+            index: first,
+        };
+        Some(Candidates {
+            pool: pool.clone(),
+            order: (0..n).map(|i| (first + i) % n).collect(),
+            reserved: Arc::new(Mutex::new(Some(reserved))),
+        })
+    }
+}
+
+/// Ordered upstream candidates for one connection
+#[derive(Debug, Clone)]
+pub struct Candidates {
+    pool: Arc<Pool>,
+    order: Vec<usize>,
+    /// Active slot taken on the first candidate at selection, until
+    /// `acquire` or `release` (or dropping every clone) gives it up
+    reserved: Arc<Mutex<Option<ActiveGuard>>>,
+}
+
+impl Candidates {
+    /// Local ports to try, most preferred first
+    pub fn ports(&self) -> Vec<u16> {
+        self.order.iter().map(|&i| self.pool.ports[i]).collect()
+    }
+
+    /// Mark the `nth` candidate as carrying an active connection, taking
+    /// over the slot reserved on the first one
+    pub fn acquire(&self, nth: usize) -> ActiveGuard {
+        let reserved = self.reserved.lock().unwrap().take();
+        if let Some(guard) = reserved {
+            if nth == 0 {
+                return guard;
+            }
+        }
+        let index = self.order[nth];
+        self.pool.active[index].fetch_add(1, Ordering::Relaxed);
+        ActiveGuard {
+            pool: self.pool.clone(),
+            index,
+        }
+    }
+
+    /// Give up the slot reserved on the first candidate, e.g. once
+    /// connecting to it failed
+    pub fn release(&self) {
+        self.reserved.lock().unwrap().take();
+    }
+}
+
+/// Keeps an upstream's active count incremented until dropped
+#[derive(Debug)]
+pub struct ActiveGuard {
+    pool: Arc<Pool>,
+    index: usize,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.pool.active[self.index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pools(policy: UpstreamPolicy) -> UpstreamPools {
+        let spec: UpstreamPoolSpec = "80=[3001,3002,3003]".parse().unwrap();
+        UpstreamPools::new(&[spec], policy)
+    }
+
+    #[test]
+    fn test_parse_pool_spec() {
+        let spec: UpstreamPoolSpec = "80=[3001, 3002]".parse().unwrap();
+        assert_eq!(spec.port, 80);
+        assert_eq!(spec.upstreams, vec![3001, 3002]);
+
+        let spec: UpstreamPoolSpec = "443=8443".parse().unwrap();
+        assert_eq!(spec.upstreams, vec![8443]);
+
+        assert!("80".parse::<UpstreamPoolSpec>().is_err());
+        assert!("80=[]".parse::<UpstreamPoolSpec>().is_err());
+        assert!("80=[x]".parse::<UpstreamPoolSpec>().is_err());
+
+        let err = "80=[3001,3002,3001]"
+            .parse::<UpstreamPoolSpec>()
+            .unwrap_err();
+        assert!(err.contains("3001 listed twice"), "{}", err);
+    }
+
+    #[test]
+    fn test_round_robin_rotates_with_fallthrough_order() {
+        let pools = pools(UpstreamPolicy::RoundRobin);
+        assert_eq!(pools.select(80).unwrap().ports(), vec![3001, 3002, 3003]);
+        assert_eq!(pools.select(80).unwrap().ports(), vec![3002, 3003, 3001]);
+        assert_eq!(pools.select(80).unwrap().ports(), vec![3003, 3001, 3002]);
+        assert_eq!(pools.select(80).unwrap().ports(), vec![3001, 3002, 3003]);
+        assert!(pools.select(81).is_none());
+    }
+
+    #[test]
+    fn test_least_conn_prefers_idle_upstream() {
+        let pools = pools(UpstreamPolicy::LeastConn);
+
+        let first = pools.select(80).unwrap();
+        let _a = first.acquire(0);
+        assert_eq!(first.ports()[0], 3001);
+
+        let second = pools.select(80).unwrap();
+        let b = second.acquire(0);
+        assert_eq!(second.ports()[0], 3002);
+
+        // Releasing 3002 makes it (tied) least loaded again
+        drop(b);
+        drop(second);
+        assert_eq!(pools.select(80).unwrap().ports()[0], 3002);
+    }
+
+    #[test]
+    fn test_least_conn_burst_spreads_before_connects_finish() {
+        let pools = pools(UpstreamPolicy::LeastConn);
+
+        // Nothing has connected yet, but each pick already counts
+        let burst: Vec<_> = (0..6).map(|_| pools.select(80).unwrap()).collect();
+        let firsts: Vec<_> = burst.iter().map(|c| c.ports()[0]).collect();
+        assert_eq!(firsts, vec![3001, 3002, 3003, 3001, 3002, 3003]);
+
+        let pool = &pools.pools[&80];
+        drop(burst);
+        assert!(pool.active.iter().all(|a| a.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn test_failed_first_candidate_releases_reservation() {
+        let pools = pools(UpstreamPolicy::LeastConn);
+        let pool = &pools.pools[&80];
+        let candidates = pools.select(80).unwrap();
+        assert_eq!(pool.active[0].load(Ordering::Relaxed), 1);
+
+        // 3001 refused the connect, 3002 took it
+        candidates.release();
+        assert_eq!(pool.active[0].load(Ordering::Relaxed), 0);
+        let guard = candidates.acquire(1);
+        assert_eq!(pool.active[0].load(Ordering::Relaxed), 0);
+        assert_eq!(pool.active[1].load(Ordering::Relaxed), 1);
+        drop(guard);
+        assert_eq!(pool.active[1].load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_guard_tracks_active_count() {
+        let pools = pools(UpstreamPolicy::RoundRobin);
+        let candidates = pools.select(80).unwrap();
+        let pool = &pools.pools[&80];
+        assert_eq!(pool.active[0].load(Ordering::Relaxed), 1);
+
+        // Acquiring the first candidate takes over its reservation
+        let guard = candidates.acquire(0);
+        assert_eq!(pool.active[0].load(Ordering::Relaxed), 1);
+        drop(guard);
+        assert_eq!(pool.active[0].load(Ordering::Relaxed), 0);
+
+        let guard = candidates.acquire(1);
+        assert_eq!(pool.active[1].load(Ordering::Relaxed), 1);
+        drop(guard);
+        assert_eq!(pool.active[1].load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! Liveness watchdog for the session's message loop (`--watchdog-timeout`).
+//!
+//! Every frame from the runner is handled inline by the message loop, so an
+//! await that never completes there (a send into a channel nobody drains, a
+//! lock held across a stuck write) wedges the whole tunnel without a word.
+//! The loop bumps a heartbeat on each iteration and wakes at least every half
+//! timeout to do so even when idle; a separate task checks the heartbeat and,
+//! if it hasn't moved for a whole timeout, trips. The session then abandons
+//! the loop and the tunnel reconnects, surfacing the hang instead of hiding it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::sleep;
+use tracing::error;
+
+use crate::task::AbortOnDrop;
+
+/// Watches one session's message loop; stops watching when dropped
+#[derive(Debug)]
+pub struct Watchdog {
+    timeout: Duration,
+    heartbeat: Arc<AtomicU64>,
+    tripped: Arc<Notify>,
+    _task: Option<AbortOnDrop<()>>,
+}
+
+impl Watchdog {
+    /// Start watching; a zero `timeout` disables the watchdog
+    pub fn spawn(timeout: Duration) -> Self {
+        let heartbeat = Arc::new(AtomicU64::new(0));
+        let tripped = Arc::new(Notify::new());
+        let task = (!timeout.is_zero()).then(|| {
+            let heartbeat = heartbeat.clone();
+            let tripped = tripped.clone();
+            AbortOnDrop::spawn(async move {
+                let mut last = heartbeat.load(Ordering::Relaxed);
+                loop {
+                    sleep(timeout).await;
+                    let current = heartbeat.load(Ordering::Relaxed);
+                    if current == last {
+                        error!(
+                            timeout_secs = timeout.as_secs_f64(),
+                            "Message loop made no progress, forcing a reconnect"
+                        );
+                        tripped.notify_one();
+                        return;
+                    }
+                    last = current;
+                }
+            })
+        });
+        Self {
+            timeout,
+            heartbeat,
+            tripped,
+            _task: task,
+        }
+    }
+
+    /// Record progress of the message loop
+    pub fn beat(&self) {
+        self.heartbeat.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How often an idle loop must wake to beat (None when disabled)
+    pub fn beat_interval(&self) -> Option<Duration> {
+        (!self.timeout.is_zero()).then(|| self.timeout / 2)
+    }
+
+    /// Complete once the loop has stalled; never completes when disabled
+    pub async fn tripped(&self) {
+        self.tripped.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_beating_keeps_watchdog_quiet() {
+        let watchdog = Watchdog::spawn(Duration::from_secs(10));
+        let interval = watchdog.beat_interval().unwrap();
+        let beating = async {
+            for _ in 0..20 {
+                watchdog.beat();
+                sleep(interval).await;
+            }
+        };
+        tokio::select! {
+            _ = beating => {}
+            _ = watchdog.tripped() => panic!("watchdog tripped while the loop was beating"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stall_trips_watchdog() {
+        let watchdog = Watchdog::spawn(Duration::from_secs(10));
+        watchdog.beat();
+        let start = tokio::time::Instant::now();
+        watchdog.tripped().await;
+        // Tripped within two timeouts of the last beat
+        assert!(start.elapsed() <= Duration::from_secs(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_zero_timeout_disables() {
+        let watchdog = Watchdog::spawn(Duration::ZERO);
+        assert_eq!(watchdog.beat_interval(), None);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(3600), watchdog.tripped())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Connection open/close events pushed to an HTTP endpoint (`--webhook-url`).
+//!
+//! Each audit event (see [`crate::audit`]) is also POSTed as a small JSON
+//! object, e.g.
+//!
+//! ```text
+//! {"event":"close","client_id":7,"proto":"TCP","port":8080,"local_port":80,"bytes_in":512,
+//!  "bytes_out":2048,"duration_ms":1530,"reason":"remote","container_id":"abc"}
+//! ```
+//!
+//! Delivery is fire-and-forget: events go through a bounded queue to a
+//! single task that posts them in order, retrying a failed POST a few times
+//! with a timeout on each attempt. When the endpoint is slow or down the
+//! queue fills and further events are dropped, so tunneling never waits for
+//! the webhook.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::error::{Result, TunnelError};
+use crate::tunnel::TunnelConfig;
+
+/// Events queued for delivery before new ones are dropped
+const QUEUE_DEPTH: usize = 256;
+
+/// POST attempts per event
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Limit on each attempt, from dialing to the response status line
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait before the first retry, doubling for each further one
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest response head read while looking for the status line
+const MAX_STATUS_LINE: usize = 1024;
+
+/// One connection event, as POSTed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnEvent {
+    /// `open` or `close`
+    pub event: &'static str,
+    pub client_id: u32,
+    pub proto: String,
+    /// Port requested by the runner
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub local_port: u16,
+    /// Close events only, like the fields below
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_in: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_out: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Body of a POST: the event tagged with the container it came from
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    conn: &'a ConnEvent,
+    container_id: &'a str,
+}
+
+/// Parse and check a webhook URL: `http://`, or `https://` with the `tls`
+/// feature
+pub fn parse_url(url: &str) -> Result<Url> {
+    let parsed = Url::parse(url)
+        .map_err(|e| TunnelError::Config(format!("invalid webhook URL {:?}: {}", url, e)))?;
+    match parsed.scheme() {
+        "http" => {}
+        #[cfg(feature = "tls")]
+        "https" => {}
+        #[cfg(not(feature = "tls"))]
+        "https" => {
+            return Err(TunnelError::Config(
+                "https:// webhook URLs need the tls feature".into(),
+            ))
+        }
+        other => {
+            return Err(TunnelError::Config(format!(
+                "webhook URL must be http:// or https://, not {}://",
+                other
+            )))
+        }
+    }
+    if parsed.host_str().is_none() {
+        return Err(TunnelError::Config(format!(
+            "webhook URL {:?} has no host",
+            url
+        )));
+    }
+    Ok(parsed)
+}
+
+/// Sends connection events to the webhook, shared by every session
+#[derive(Debug)]
+pub struct Webhook {
+    url: Url,
+    container_id: String,
+    /// Started with the delivery task on the first event, inside the runtime
+    queue: OnceLock<mpsc::Sender<ConnEvent>>,
+}
+
+impl Webhook {
+    pub fn new(url: Url, container_id: impl Into<String>) -> Self {
+        Self {
+            url,
+            container_id: container_id.into(),
+            queue: OnceLock::new(),
+        }
+    }
+
+    /// Webhook for `config.webhook_url`, if set and valid (see
+    /// [`TunnelConfig::check_webhook_url`])
+    pub fn from_config(config: &TunnelConfig) -> Option<Self> {
+        let url = parse_url(config.webhook_url.as_deref()?).ok()?;
+        Some(Self::new(url, &config.container_id))
+    }
+
+    /// Queue `event` for delivery, dropping it if the queue is full
+    pub fn send(&self, event: ConnEvent) {
+        let queue = self.queue.get_or_init(|| {
+            let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+            // Outside a runtime the receiver is dropped and events with it
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(deliver(self.url.clone(), self.container_id.clone(), rx));
+            }
+            tx
+        });
+        if queue.try_send(event).is_err() {
+            debug!("Webhook queue full, dropping event");
+        }
+    }
+}
+
+/// Post queued events in order until every sender is gone
+async fn deliver(url: Url, container_id: String, mut queue: mpsc::Receiver<ConnEvent>) {
+    while let Some(event) = queue.recv().await {
+        let body = serde_json::to_vec(&Payload {
+            conn: &event,
+            container_id: &container_id,
+        })
+        .unwrap_or_default();
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = match timeout(ATTEMPT_TIMEOUT, post(&url, &body)).await {
+                Ok(result) => result,
+                Err(_) => Err("timed out".to_string()),
+            };
+            match result {
+                Ok(()) => break,
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    debug!(attempt, error = %e, "Webhook POST failed, retrying");
+                    sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    warn!(
+                        event = event.event,
+                        client_id = event.client_id,
+                        error = %e,
+                        "Webhook POST failed, dropping event"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// POST `body` as JSON to `url`, succeeding on a 2xx status
+async fn post(url: &Url, body: &[u8]) -> std::result::Result<(), String> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let tcp = TcpStream::connect((host.trim_matches(|c| c == '[' || c == ']'), port))
+        .await
+        .map_err(|e| e.to_string())?;
+    #[cfg(feature = "tls")]
+    if url.scheme() == "https" {
+        let connector = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+        let tls = tokio_native_tls::TlsConnector::from(connector)
+            .connect(host, tcp)
+            .await
+            .map_err(|e| e.to_string())?;
+        return exchange(tls, url, body).await;
+    }
+    exchange(tcp, url, body).await
+}
+
+/// Write the request and check the response's status line
+async fn exchange<S>(mut stream: S, url: &Url, body: &[u8]) -> std::result::Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: kohakuriver-tunnel/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    stream.write_all(body).await.map_err(|e| e.to_string())?;
+    stream.flush().await.map_err(|e| e.to_string())?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 256];
+    let line_end = loop {
+        if let Some(end) = response.windows(2).position(|w| w == b"\r\n") {
+            break end;
+        }
+        if response.len() >= MAX_STATUS_LINE {
+            return Err("response status line too long".into());
+        }
+        let n = stream.read(&mut buf).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("connection closed before a response".into());
+        }
+        response.extend_from_slice(&buf[..n]);
+    };
+    let line = String::from_utf8_lossy(&response[..line_end]);
+    match line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') && status.len() == 3 => Ok(()),
+        _ => Err(format!("unexpected response {:?}", line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    fn close_event() -> ConnEvent {
+        ConnEvent {
+            event: "close",
+            client_id: 7,
+            proto: "TCP".into(),
+            port: 8080,
+            label: None,
+            local_port: 80,
+            bytes_in: Some(512),
+            bytes_out: Some(2048),
+            duration_ms: Some(1530),
+            reason: Some("remote".into()),
+        }
+    }
+
+    /// Accept one request, answer it with `status` and return its body
+    async fn answer(listener: &TcpListener, status: &str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        let body_start = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..body_start]).to_string();
+        let len: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        while request.len() < body_start + len {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        assert!(head.starts_with("POST /hooks/tunnel?src=test HTTP/1.1\r\n"));
+        stream
+            .write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes())
+            .await
+            .unwrap();
+        String::from_utf8(request[body_start..].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_event_posted_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/hooks/tunnel?src=test",
+            listener.local_addr().unwrap()
+        );
+        let webhook = Webhook::new(parse_url(&url).unwrap(), "abc");
+        webhook.send(close_event());
+
+        assert_eq!(
+            answer(&listener, "204 No Content").await,
+            r#"{"event":"close","client_id":7,"proto":"TCP","port":8080,"local_port":80,"bytes_in":512,"bytes_out":2048,"duration_ms":1530,"reason":"remote","container_id":"abc"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_sends_open_and_close() {
+        use crate::audit::{CloseReason, ConnAudit};
+        use crate::metrics::Metrics;
+        use crate::protocol::Proto;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/hooks/tunnel?src=test",
+            listener.local_addr().unwrap()
+        );
+        let webhook = Arc::new(Webhook::new(parse_url(&url).unwrap(), "abc"));
+        let metrics = Arc::new(Metrics::default());
+
+        let audit = ConnAudit::labeled(3, Proto::Udp, 53, Some("dns"));
+        let guard = audit.opened(5353, &metrics).with_webhook(Some(&webhook));
+        audit.record_in(40);
+        audit.close_reason(CloseReason::LocalEof);
+        drop(guard);
+
+        assert_eq!(
+            answer(&listener, "200 OK").await,
+            r#"{"event":"open","client_id":3,"proto":"UDP","port":53,"label":"dns","local_port":5353,"container_id":"abc"}"#
+        );
+        let close = answer(&listener, "200 OK").await;
+        assert!(close.starts_with(
+            r#"{"event":"close","client_id":3,"proto":"UDP","port":53,"label":"dns","local_port":5353,"bytes_in":40,"bytes_out":0,"duration_ms":"#
+        ));
+        assert!(close.ends_with(r#","reason":"local_eof","container_id":"abc"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_failed_post_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/hooks/tunnel?src=test",
+            listener.local_addr().unwrap()
+        );
+        let webhook = Webhook::new(parse_url(&url).unwrap(), "abc");
+        webhook.send(close_event());
+
+        let first = answer(&listener, "503 Service Unavailable").await;
+        let retried = answer(&listener, "200 OK").await;
+        assert_eq!(first, retried);
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert!(parse_url("http://hooks.example.com/tunnel").is_ok());
+        for bad in ["ftp://example.com", "hooks.example.com", "http://"] {
+            assert!(
+                matches!(parse_url(bad), Err(TunnelError::Config(_))),
+                "{:?}",
+                bad
+            );
+        }
+    }
+}